@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+use crate::harehandler::HareError;
+
+/// Name of the accounting directory, inside the state directory.
+pub const ACCOUNTING_DIR: &str = "accounting";
+
+/// Resources used by the executions of a handler, over a day.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct Usage {
+    pub executions: u64,    // number of executions
+    pub wall_ms: u64,       // cumulated wall clock time, in milliseconds
+    pub cpu_ms: u64,        // cumulated CPU time (user and system), in milliseconds
+    pub output_bytes: u64,  // cumulated size of the output (stdout and stderr)
+}
+
+/// Usage of a handler on a day, as exported.
+#[derive(Serialize, Debug)]
+pub struct UsageRecord {
+    pub date: String,       // UTC day, e.g. "2024-12-05"
+    pub handler: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// Records the resources used by the handlers, aggregated per UTC day and per handler.
+///
+/// Each day is a JSON file in the accounting directory of the state directory, named after
+/// the day, e.g. `accounting/2024-12-05.json`.
+pub struct Accounting {
+    dir: Option<PathBuf>,   // accounting directory, None without state directory
+    lock: Mutex<()>,        // serializes the updates of the day files
+}
+
+impl Accounting {
+
+    /// Creates the accounting, recording nothing without state directory.
+    ///
+    /// @return Accounting
+    ///
+    pub fn new(state_dir: Option<&str>) -> Self {
+        Accounting { dir: state_dir.map(|dir| Path::new(dir).join(ACCOUNTING_DIR)), lock: Mutex::new(()) }
+    }
+
+    /// Records an execution of a handler.
+    pub fn record(&self, handler: &str, wall: Duration, cpu: Duration, output_bytes: usize) {
+        let Some(dir) = &self.dir else { return };
+        let _guard = self.lock.lock().unwrap();
+
+        let path = dir.join(format!("{}.json", today()));
+        let result = read_day(&path).and_then(|mut day| {
+            let usage = day.entry(handler.to_string()).or_default();
+            usage.executions += 1;
+            usage.wall_ms += wall.as_millis() as u64;
+            usage.cpu_ms += cpu.as_millis() as u64;
+            usage.output_bytes += output_bytes as u64;
+
+            fs::create_dir_all(dir)?;
+            let tmp = path.with_extension("json.tmp");
+            fs::write(&tmp, serde_json::to_vec_pretty(&day).unwrap_or_default())?;
+            fs::rename(&tmp, &path)?;
+            Ok(())
+        });
+        if let Err(error) = result {
+            log::error!("Could not record the usage of handler {}: {}", handler, error);
+        }
+    }
+}
+
+/// Exports the recorded usage, sorted by day and handler.
+///
+/// # Arguments
+///
+/// * `state_dir` - the state directory
+/// * `from` - first day to export (included), e.g. "2024-12-01"
+/// * `to` - last day to export (included)
+///
+/// @return the usage of each handler, for each day
+///
+/// # Errors
+///
+/// This function will return an error if a day file cannot be read.
+pub fn export(state_dir: &Path, from: Option<&str>, to: Option<&str>) -> Result<Vec<UsageRecord>, HareError> {
+    let dir = state_dir.join(ACCOUNTING_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut days: Vec<String> = fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_string_lossy().strip_suffix(".json").map(str::to_string))
+        .filter(|day| from.is_none_or(|from| day.as_str() >= from) && to.is_none_or(|to| day.as_str() <= to))
+        .collect();
+    days.sort();
+
+    let mut records = Vec::new();
+    for date in days {
+        for (handler, usage) in read_day(&dir.join(format!("{}.json", date)))? {
+            records.push(UsageRecord { date: date.clone(), handler, usage });
+        }
+    }
+    Ok(records)
+}
+
+/// Reads the usage of a day, empty if the day file does not exist.
+fn read_day(path: &Path) -> Result<BTreeMap<String, Usage>, HareError> {
+    match fs::read(path) {
+        Ok(content) => serde_json::from_slice(&content)
+            .map_err(|error| HareError::StateError(format!("invalid accounting file {}: {}", path.display(), error))),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Current UTC day, e.g. "2024-12-05".
+fn today() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()[..10].to_string()
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use lapin::message::Delivery;
+use lapin::types::AMQPValue;
+use crate::harehandler::Execution;
+
+/// Header counting the deliveries of a message, set by the quorum queues of RabbitMQ.
+pub const DELIVERY_COUNT_HEADER: &str = "x-delivery-count";
+
+/// Header listing the dead-letterings of a message, set by RabbitMQ.
+pub const DEATH_HEADER: &str = "x-death";
+
+/// What to do with the delivery of a message, once its handler ran.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AckDecision {
+    Ack,        // acknowledge the message
+    Nack,       // nack the message, the broker drops it, or dead-letters it if the queue has a dead letter exchange
+    Requeue,    // nack the message with requeue, to run it again
+    DeadLetter, // publish the message to HARE_DEAD_LETTER_EXCHANGE, then acknowledge it
+    Retry,      // publish the message to a delay queue, back to its queue after a backoff, or to the parking lot queue after the last attempt
+}
+
+/// The delivery of a message, as given to an ack policy.
+#[derive(Debug, Clone, Copy)]
+pub struct DeliveryInfo<'a> {
+    pub handler: &'a str,           // message type, name of the handler
+    pub exchange: &'a str,          // exchange the message was published to
+    pub routing_key: &'a str,       // routing key of the message
+    pub redelivered: bool,          // whether the broker delivered the message before
+    pub previous_deliveries: Option<u64>, // deliveries of the message before this one, None if the broker does not count them
+}
+
+impl<'a> DeliveryInfo<'a> {
+
+    /// The metadata of a delivery.
+    ///
+    /// The previous deliveries are read from the `x-delivery-count` header of the quorum queues,
+    /// or else from the `x-death` header of the messages coming back from a dead letter exchange.
+    /// Without either, only the redelivered flag tells whether the message was delivered before.
+    ///
+    /// @return DeliveryInfo
+    ///
+    pub fn new(delivery: &'a Delivery, handler: &'a str) -> Self {
+        let headers = delivery.properties.headers().as_ref();
+        let delivery_count = headers.and_then(|headers| headers.inner().get(DELIVERY_COUNT_HEADER)).and_then(integer);
+        let deaths = headers.and_then(|headers| match headers.inner().get(DEATH_HEADER) {
+            Some(AMQPValue::FieldArray(deaths)) => Some(deaths.as_slice().iter()
+                .filter_map(|death| match death {
+                    AMQPValue::FieldTable(death) => death.inner().get("count").and_then(integer),
+                    _ => None,
+                })
+                .sum()),
+            _ => None,
+        });
+        DeliveryInfo {
+            handler,
+            exchange: delivery.exchange.as_str(),
+            routing_key: delivery.routing_key.as_str(),
+            redelivered: delivery.redelivered,
+            previous_deliveries: delivery_count.or(deaths).or((!delivery.redelivered).then_some(0)),
+        }
+    }
+}
+
+/// Decides what to do with the delivery of a message, once its handler ran.
+///
+/// By default, hare acknowledges the message of a successful execution, and settles the others
+/// per HARE_ON_FAILURE ([`OnFailure`]). The programs embedding hare can set another policy with
+/// [`crate::HareHandler::set_ack_policy`] : the built-in [`AlwaysAck`], [`ExitCodeMap`] and
+/// [`RetryThenDeadLetter`], or their own. The policy applies to the messages of the queues ; the
+/// jobs of the agent mode are kept in the spool instead.
+pub trait AckPolicy: Send + Sync {
+
+    /// Decides what to do with the delivery of a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `execution` - the execution of the handler, None when there is no script for the message type
+    /// * `delivery` - the delivery of the message
+    ///
+    /// @return the decision
+    ///
+    fn decide(&self, execution: Option<&Execution>, delivery: &DeliveryInfo) -> AckDecision;
+}
+
+/// Whether an execution succeeded, None meaning that there was no script for the message.
+fn succeeded(execution: Option<&Execution>) -> bool {
+    execution.is_some_and(|execution| execution.exit_code == Some(0))
+}
+
+/// The default policy : the message of a successful execution is acknowledged, the others get the same decision (HARE_ON_FAILURE).
+#[derive(Debug, Clone, Copy)]
+pub struct OnFailure(pub AckDecision);
+
+impl AckPolicy for OnFailure {
+    fn decide(&self, execution: Option<&Execution>, _delivery: &DeliveryInfo) -> AckDecision {
+        match succeeded(execution) {
+            true => AckDecision::Ack,
+            false => self.0,
+        }
+    }
+}
+
+/// Acknowledges every message, whatever the outcome of its execution.
+#[derive(Debug, Clone, Copy)]
+pub struct AlwaysAck;
+
+impl AckPolicy for AlwaysAck {
+    fn decide(&self, _execution: Option<&Execution>, _delivery: &DeliveryInfo) -> AckDecision {
+        AckDecision::Ack
+    }
+}
+
+/// Decides by the exit code of the script, e.g. requeue on 75 (EX_TEMPFAIL), dead-letter on 65 (EX_DATAERR).
+///
+/// The exit codes without decision, the scripts killed by a signal and the missing scripts get the
+/// default decision ; a successful execution is acknowledged unless 0 has a decision.
+#[derive(Debug, Clone)]
+pub struct ExitCodeMap {
+    decisions: HashMap<i32, AckDecision>,   // decision by exit code
+    default: AckDecision,                   // decision of the other failures
+}
+
+impl ExitCodeMap {
+
+    /// Creates a map without decision, every failure getting the default decision.
+    ///
+    /// @return ExitCodeMap
+    ///
+    pub fn new(default: AckDecision) -> Self {
+        ExitCodeMap { decisions: HashMap::new(), default }
+    }
+
+    /// Sets the decision of an exit code.
+    ///
+    /// @return ExitCodeMap
+    ///
+    pub fn on(mut self, exit_code: i32, decision: AckDecision) -> Self {
+        self.decisions.insert(exit_code, decision);
+        self
+    }
+}
+
+impl AckPolicy for ExitCodeMap {
+    fn decide(&self, execution: Option<&Execution>, _delivery: &DeliveryInfo) -> AckDecision {
+        let exit_code = execution.and_then(|execution| execution.exit_code);
+        match exit_code.and_then(|code| self.decisions.get(&code)) {
+            Some(decision) => *decision,
+            None if exit_code == Some(0) => AckDecision::Ack,
+            None => self.default,
+        }
+    }
+}
+
+/// Requeues the message of a failed execution to run it again, and dead-letters it after a number of retries.
+///
+/// The retries are counted from the previous deliveries of the message (see [`DeliveryInfo::new`]) :
+/// with the quorum queues, which count the deliveries, any number of retries can be given ; with the
+/// classic queues, only whether the message was redelivered is known, so that a message is retried once.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryThenDeadLetter {
+    pub retries: u64,   // executions after the first one, before the message is dead-lettered
+}
+
+impl AckPolicy for RetryThenDeadLetter {
+    fn decide(&self, execution: Option<&Execution>, delivery: &DeliveryInfo) -> AckDecision {
+        let retry = match delivery.previous_deliveries {
+            Some(previous) => previous < self.retries,
+            // without count, a message is retried once, as its redelivery cannot be told from the next ones
+            None => false,
+        };
+        match (succeeded(execution), retry) {
+            (true, _) => AckDecision::Ack,
+            (false, true) => AckDecision::Requeue,
+            (false, false) => AckDecision::DeadLetter,
+        }
+    }
+}
+
+/// The value of an integer header.
+fn integer(value: &AMQPValue) -> Option<u64> {
+    match value {
+        AMQPValue::ShortShortInt(value) => u64::try_from(*value).ok(),
+        AMQPValue::ShortShortUInt(value) => Some(u64::from(*value)),
+        AMQPValue::ShortInt(value) => u64::try_from(*value).ok(),
+        AMQPValue::ShortUInt(value) => Some(u64::from(*value)),
+        AMQPValue::LongInt(value) => u64::try_from(*value).ok(),
+        AMQPValue::LongUInt(value) => Some(u64::from(*value)),
+        AMQPValue::LongLongInt(value) => u64::try_from(*value).ok(),
+        _ => None,
+    }
+}
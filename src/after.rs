@@ -0,0 +1,81 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use lapin::message::Delivery;
+
+/// Header of a message naming the jobs (their correlation ids, comma separated) it runs after.
+pub const AFTER_HEADER: &str = "x-hare-after";
+
+/// Default delay before a message waiting for a prior job is tried again.
+pub const DEFAULT_RETRY: Duration = Duration::from_secs(10);
+
+/// Default time after its publication past which a message stops waiting for a prior job.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Number of completed jobs remembered, the oldest ones are forgotten past it.
+const MAX_COMPLETIONS: usize = 10_000;
+
+/// Whether the prior jobs of a message completed.
+#[derive(Debug, PartialEq)]
+pub enum Prior {
+    Completed,          // every prior job completed successfully
+    Failed(String),     // a prior job failed
+    Pending(String),    // a prior job is not known to have completed yet
+}
+
+/// Jobs known to have completed, by correlation id : run by this instance, or reported by the result
+/// messages of the other instances.
+pub struct Completions {
+    jobs: Mutex<(HashMap<String, bool>, VecDeque<String>)>, // success per correlation id, and the ids in completion order
+}
+
+impl Completions {
+
+    /// Creates an empty record.
+    ///
+    /// @return Completions
+    ///
+    pub fn new() -> Self {
+        Completions { jobs: Mutex::new((HashMap::new(), VecDeque::new())) }
+    }
+
+    /// Records the completion of a job.
+    pub fn record(&self, correlation_id: &str, success: bool) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let (status, order) = &mut *jobs;
+        if status.insert(correlation_id.to_string(), success).is_none() {
+            order.push_back(correlation_id.to_string());
+            if order.len() > MAX_COMPLETIONS {
+                if let Some(oldest) = order.pop_front() {
+                    status.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    /// Records the completion reported by a result message : its correlation id, and the `exit_code`
+    /// of its body. Other messages of the result exchange are ignored.
+    pub fn observe(&self, delivery: &Delivery) {
+        let Some(correlation_id) = delivery.properties.correlation_id() else { return };
+        let Ok(result) = serde_json::from_slice::<serde_json::Value>(&delivery.data) else { return };
+        if let Some(exit_code) = result.get("exit_code") {
+            self.record(correlation_id.as_str(), exit_code.as_i64() == Some(0));
+        }
+    }
+
+    /// Checks the prior jobs named by the `x-hare-after` header of a message.
+    ///
+    /// @return Completed when every job completed successfully, otherwise the first failed or pending job
+    ///
+    pub fn check(&self, after: &str) -> Prior {
+        let jobs = self.jobs.lock().unwrap();
+        for id in after.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+            match jobs.0.get(id) {
+                Some(true) => {}
+                Some(false) => return Prior::Failed(id.to_string()),
+                None => return Prior::Pending(id.to_string()),
+            }
+        }
+        Prior::Completed
+    }
+}
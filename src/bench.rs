@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures_lite::StreamExt;
+use lapin::options::*;
+use lapin::types::FieldTable;
+use crate::message::HareMessageBuilder;
+use crate::harehandler::HareError;
+use crate::tls::{self, TlsSettings};
+
+/// How long the results are awaited after the last message is published.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// What a benchmark publishes.
+pub struct BenchOptions {
+    pub handler: String,                    // message type of the synthetic messages
+    pub rate: f64,                          // messages per second
+    pub duration: Duration,                 // how long messages are published
+    pub headers: Vec<(String, String)>,     // additional headers of the messages
+    pub body: Vec<u8>,                      // body of the messages
+}
+
+/// Outcome of a benchmark.
+pub struct BenchReport {
+    pub sent: usize,                // messages published
+    pub latencies: Vec<Duration>,   // end-to-end latency of each result received, from publication to result
+    pub elapsed: Duration,          // from the first publication to the last result
+    pub body_size: usize,           // size of the body of the messages, in bytes
+}
+
+/// Parses a rate, e.g. "50/s", "300/m" or "50" (per second).
+///
+/// @return the rate, in messages per second
+///
+/// # Errors
+///
+/// This function will return an error if the rate is not a positive number, or its unit is unknown.
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    let (count, unit) = value.split_once('/').unwrap_or((value, "s"));
+    let count: f64 = count.trim().parse().map_err(|_| format!("invalid rate {:?}", value))?;
+    let per = match unit.trim() {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("invalid rate unit {:?}, expected s, m or h", unit)),
+    };
+    Some(count / per).filter(|rate| *rate > 0.0 && rate.is_finite()).ok_or_else(|| format!("invalid rate {:?}", value))
+}
+
+/// Parses a body size, e.g. "512", "64KiB" or "4MiB".
+///
+/// @return the size, in bytes
+///
+/// # Errors
+///
+/// This function will return an error if the size is not a number, or its unit is unknown.
+pub fn parse_size(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let count: usize = value[..split].parse().map_err(|_| format!("invalid size {:?}", value))?;
+    let unit = match value[split..].trim() {
+        "" | "B" => 1,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        unit => return Err(format!("invalid size unit {:?}, expected B, KiB or MiB", unit)),
+    };
+    count.checked_mul(unit).ok_or_else(|| format!("invalid size {:?}", value))
+}
+
+/// Publishes synthetic messages to a hare queue, and measures their end-to-end latency.
+///
+/// Each message gets a unique correlation id, copied by hare into the result message : the
+/// latency of a message is the time from its publication to the reception of its result, on
+/// an exclusive queue bound to the result exchange.
+///
+/// # Arguments
+///
+/// * `url` - the broker URL
+/// * `tls` - the TLS settings of the connection, if any
+/// * `queue` - the queue consumed by the hare instances under test
+/// * `result_exchange` - the exchange the hare instances publish their results to
+/// * `handler_key` - the header giving the message type
+/// * `options` - what to publish
+///
+/// @return the benchmark report
+///
+/// # Errors
+///
+/// This function will return an error if the broker cannot be reached, or a message cannot be published.
+pub async fn run(url: &str, tls: Option<&TlsSettings>, queue: &str, result_exchange: &str, handler_key: &str, options: &BenchOptions) -> Result<BenchReport, HareError> {
+    let connection = tls::connect(url, tls).await?;
+
+    // results, received on an exclusive queue
+    let results = connection.create_channel().await?;
+    let result_queue = results.queue_declare("", QueueDeclareOptions { exclusive: true, auto_delete: true, ..QueueDeclareOptions::default() }, FieldTable::default()).await?;
+    results.queue_bind(result_queue.name().as_str(), result_exchange, &options.handler, QueueBindOptions::default(), FieldTable::default()).await?;
+    let mut consumer = results.basic_consume(result_queue.name().as_str(), "hare_bench", BasicConsumeOptions { no_ack: true, ..BasicConsumeOptions::default() }, FieldTable::default()).await?;
+
+    let sent: Arc<Mutex<HashMap<String, Instant>>> = Arc::new(Mutex::new(HashMap::new()));
+    let total = (options.rate * options.duration.as_secs_f64()).ceil().max(1.0) as usize;
+    let started = Instant::now();
+    log::info!("Publishing {} messages of type {} to queue {} at {:.1}/s", total, options.handler, queue, options.rate);
+
+    // publish at the requested rate, in the background
+    let channel = connection.create_channel().await?;
+    let publication = {
+        let sent = sent.clone();
+        let queue = queue.to_string();
+        let handler = options.handler.clone();
+        let handler_key = handler_key.to_string();
+        let headers = options.headers.clone();
+        let body = options.body.clone();
+        let interval = Duration::from_secs_f64(1.0 / options.rate);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            for sequence in 0..total {
+                ticker.tick().await;
+                let id = format!("bench-{}-{}", std::process::id(), sequence);
+                let message = headers.iter()
+                    .fold(HareMessageBuilder::new(&handler).handler_key(&handler_key), |builder, (name, value)| builder.header(name, value))
+                    .body(body.clone())
+                    .correlation_id(&id)
+                    .build()
+                    .map_err(|error| HareError::PublishError(error.to_string()))?;
+
+                sent.lock().unwrap().insert(id, Instant::now());
+                channel.basic_publish("", &queue, BasicPublishOptions::default(), &message.body, message.properties).await?;
+            }
+            Ok::<(), HareError>(())
+        })
+    };
+
+    // collect the results, until every message is answered or the drain timeout expires
+    let mut latencies = Vec::with_capacity(total);
+    let mut last_result = started;
+    let deadline = started + options.duration + DRAIN_TIMEOUT;
+    while latencies.len() < total {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let Ok(Some(delivery)) = tokio::time::timeout(remaining, consumer.next()).await else { break };
+        let delivery = delivery?;
+        let Some(id) = delivery.properties.correlation_id() else { continue };
+        if let Some(published) = sent.lock().unwrap().remove(id.as_str()) {
+            latencies.push(published.elapsed());
+            last_result = Instant::now();
+        }
+    }
+
+    publication.await.map_err(|error| HareError::PublishError(error.to_string()))??;
+    let _ = connection.close(200, "bench done").await;
+    Ok(BenchReport { sent: total, latencies, elapsed: last_result - started, body_size: options.body.len() })
+}
+
+impl BenchReport {
+
+    /// Prints the throughput, the latency percentiles, and the latency histogram.
+    pub fn print(&self) {
+        let mut latencies = self.latencies.clone();
+        latencies.sort();
+        let received = latencies.len();
+        println!("sent: {}\treceived: {}\tmissing: {}", self.sent, received, self.sent - received);
+        if received == 0 {
+            return;
+        }
+
+        let percentile = |p: f64| latencies[((received as f64 * p).ceil() as usize).clamp(1, received) - 1];
+        let rate = received as f64 / self.elapsed.as_secs_f64().max(0.001);
+        match self.body_size {
+            0 => println!("throughput: {:.1} results/s", rate),
+            size => println!("throughput: {:.1} results/s\t{:.1} MiB/s of bodies", rate, rate * size as f64 / (1 << 20) as f64),
+        }
+        println!("latency: p50 {:?}\tp90 {:?}\tp99 {:?}\tmax {:?}",
+                 percentile(0.5), percentile(0.9), percentile(0.99), latencies[received - 1]);
+
+        let mut lower = 0.0;
+        for bound in BUCKETS.iter().copied().chain([f64::INFINITY]) {
+            let count = latencies.iter().filter(|latency| { let secs = latency.as_secs_f64(); (lower == 0.0 || secs > lower) && secs <= bound }).count();
+            let label = if bound.is_finite() { format!("<= {}s", bound) } else { format!("> {}s", lower) };
+            println!("{:>10} {:>7} {}", label, count, "#".repeat((count * 50).div_ceil(received)));
+            lower = bound;
+        }
+    }
+}
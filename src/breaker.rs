@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::manifest::BreakerPolicy;
+
+/// State of the circuit of a handler.
+#[derive(Default)]
+struct Circuit {
+    failures: u32,              // consecutive failures
+    opened: Option<Instant>,    // when the circuit was opened, if it is open
+    probing: bool,              // whether a probe execution is running (half-open circuit)
+}
+
+/// Circuit breakers of the handlers with a `[circuit_breaker]` policy.
+///
+/// After `failures` consecutive failures of a handler, its circuit is opened : its messages
+/// are deferred during the cooldown. After the cooldown, a single execution is let through
+/// as a probe : the circuit is closed if it succeeds, and opened again if it fails.
+pub struct CircuitBreakers {
+    circuits: Mutex<HashMap<String, Circuit>>,  // circuit per handler
+}
+
+impl CircuitBreakers {
+
+    /// Creates the circuit breakers, all circuits closed.
+    ///
+    /// @return CircuitBreakers
+    ///
+    pub fn new() -> Self {
+        CircuitBreakers { circuits: Mutex::new(HashMap::new()) }
+    }
+
+    /// Checks whether a handler may run.
+    ///
+    /// @return None if the handler may run, or the remaining cooldown if its circuit is open
+    ///
+    pub fn admit(&self, handler: &str, policy: &BreakerPolicy) -> Option<Duration> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(handler.to_string()).or_default();
+
+        let opened = circuit.opened?;
+        let elapsed = opened.elapsed();
+        if elapsed < policy.cooldown {
+            return Some(policy.cooldown - elapsed);
+        }
+        if circuit.probing {
+            return Some(policy.cooldown);
+        }
+
+        log::info!("Circuit of handler {} is half-open, probing with a single execution", handler);
+        circuit.probing = true;
+        None
+    }
+
+    /// Records the result of an execution of a handler.
+    ///
+    /// An alert is logged when the circuit opens, and when it closes again.
+    pub fn record(&self, handler: &str, policy: &BreakerPolicy, success: bool) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(handler.to_string()).or_default();
+
+        if success {
+            if circuit.opened.is_some() {
+                log::info!("Circuit of handler {} closed, the probe execution succeeded", handler);
+            }
+            *circuit = Circuit::default();
+            return;
+        }
+
+        circuit.failures += 1;
+        if circuit.probing {
+            log::error!(
+                "ALERT handler {} probe execution failed, circuit opened again for {}",
+                handler, humantime::format_duration(policy.cooldown)
+            );
+            circuit.opened = Some(Instant::now());
+            circuit.probing = false;
+        } else if circuit.opened.is_none() && circuit.failures >= policy.failures {
+            log::error!(
+                "ALERT handler {} failed {} times in a row, circuit opened for {}",
+                handler, circuit.failures, humantime::format_duration(policy.cooldown)
+            );
+            circuit.opened = Some(Instant::now());
+        }
+    }
+}
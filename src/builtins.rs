@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Prefix of the message types reserved for the built-in handlers.
+pub const BUILTIN_PREFIX: &str = "_hare.";
+
+/// Longest sleep of `_hare.sleep`, whatever its `seconds` header.
+pub const MAX_SLEEP: Duration = Duration::from_secs(300);
+
+/// Runs a built-in diagnostic handler.
+///
+/// Built-in handlers let operators smoke-test the queue wiring without writing scripts :
+///
+/// * `_hare.echo` logs the headers and the body of the message,
+/// * `_hare.sleep` sleeps for the number of seconds given in the `seconds` header (default 1), up to `max_sleep`,
+/// * `_hare.fail` fails with the exit code given in the `code` header (default 1).
+///
+/// # Arguments
+///
+/// * `name` - the name of the handler, without the `_hare.` prefix
+/// * `headers` - the message headers
+/// * `body` - the message body
+/// * `max_sleep` - the longest sleep of `_hare.sleep`, e.g. the script timeout
+///
+/// @return the exit code of the handler, or None if there is no such built-in handler
+///
+pub async fn run(name: &str, headers: &HashMap<String, String>, body: &[u8], max_sleep: Duration) -> Option<i32> {
+    match name {
+        "echo" => {
+            let mut keys: Vec<&String> = headers.keys().collect();
+            keys.sort();
+            for key in keys {
+                log::info!("echo header {}: {}", key, headers[key]);
+            }
+            log::info!("echo body ({} bytes): {}", body.len(), String::from_utf8_lossy(body));
+            Some(0)
+        }
+        "sleep" => {
+            let duration = sleep_duration(headers, max_sleep);
+            log::info!("sleep for {}", humantime::format_duration(duration));
+            tokio::time::sleep(duration).await;
+            Some(0)
+        }
+        "fail" => {
+            let code = headers.get("code").and_then(|v| v.parse::<i32>().ok()).unwrap_or(1);
+            log::info!("fail with code {}", code);
+            Some(code)
+        }
+        _ => None,
+    }
+}
+
+/// The sleep of `_hare.sleep` : its `seconds` header (default 1), up to `max_sleep`, nothing if the header is invalid.
+fn sleep_duration(headers: &HashMap<String, String>, max_sleep: Duration) -> Duration {
+    let seconds = headers.get("seconds").and_then(|v| v.parse::<f64>().ok()).unwrap_or(1.0);
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(duration) => duration.min(max_sleep),
+        // too long for a duration (but not NaN or negative)
+        Err(_) if seconds > 0.0 => max_sleep,
+        Err(_) => Duration::ZERO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(value: &str) -> HashMap<String, String> {
+        HashMap::from([("seconds".to_string(), value.to_string())])
+    }
+
+    #[test]
+    fn the_sleep_is_clamped() {
+        let max = Duration::from_secs(60);
+        assert_eq!(sleep_duration(&HashMap::new(), max), Duration::from_secs(1));
+        assert_eq!(sleep_duration(&seconds("0.5"), max), Duration::from_millis(500));
+        assert_eq!(sleep_duration(&seconds("60"), max), max);
+        assert_eq!(sleep_duration(&seconds("86400"), max), max);
+        assert_eq!(sleep_duration(&seconds("1e300"), max), max);
+        assert_eq!(sleep_duration(&seconds("inf"), max), max);
+        assert_eq!(sleep_duration(&seconds("-5"), max), Duration::ZERO);
+        assert_eq!(sleep_duration(&seconds("NaN"), max), Duration::ZERO);
+        assert_eq!(sleep_duration(&seconds("soon"), max), Duration::from_secs(1));
+        assert_eq!(sleep_duration(&seconds("2"), Duration::from_millis(100)), Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn a_long_sleep_ends_at_the_maximum() {
+        let started = std::time::Instant::now();
+        assert_eq!(run("sleep", &seconds("86400"), b"", Duration::from_millis(50)).await, Some(0));
+        assert!(started.elapsed() >= Duration::from_millis(50) && started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,337 @@
+use std::fs;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::harehandler::HareError;
+use crate::manifest;
+
+/// Name of the bundle description file, at the root of a bundle archive.
+pub const BUNDLE_FILE: &str = "bundle.toml";
+
+/// Name of the link to the active version of a bundle.
+const CURRENT_LINK: &str = "current";
+
+/// Name of the lock file of the bundle directory.
+const LOCK_FILE: &str = ".lock";
+
+/// Description of a handler bundle.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BundleDescription {
+    pub name: String,           // bundle name
+    pub version: String,        // bundle version
+    pub handlers: Vec<String>,  // handlers shipped in the bundle
+}
+
+/// An installed bundle.
+pub struct InstalledBundle {
+    pub name: String,
+    pub active: Option<String>, // active version
+    pub versions: Vec<String>,  // installed versions
+}
+
+/// Installs a handler bundle, and activates it.
+///
+/// A bundle is a `.tar.zst` archive holding a `bundle.toml` description file, the handler
+/// scripts and their optional manifests. The archive is unpacked in a new staging directory
+/// and validated (description, handler names, executable scripts or render and remote handlers, valid manifests) before
+/// being moved to `<bundle_dir>/<name>/<version>`. The version is then activated by
+/// atomically replacing the `<bundle_dir>/<name>/current` link.
+///
+/// Each install has its own staging directory, and the move and activation hold the lock of the
+/// bundle directory, so that concurrent installs (control messages, `hare bundle install`) do not
+/// interleave.
+///
+/// @return the description of the installed bundle
+///
+/// # Errors
+///
+/// This function will return an error if the archive cannot be unpacked, if the bundle is
+/// invalid, or if this version of the bundle is already installed.
+pub fn install(bundle_dir: &Path, archive: &Path) -> Result<BundleDescription, HareError> {
+    fs::create_dir_all(bundle_dir)?;
+
+    // removed when dropped, unless it was moved to its version directory
+    let staging = tempfile::Builder::new().prefix(".staging-").tempdir_in(bundle_dir)?;
+    unpack(archive, staging.path())?;
+    let description = validate(staging.path())?;
+
+    let _lock = lock(bundle_dir)?;
+    let target = bundle_dir.join(&description.name).join(&description.version);
+    if target.exists() {
+        return Err(HareError::BundleError(format!(
+            "bundle {} version {} is already installed", description.name, description.version
+        )));
+    }
+    fs::create_dir_all(bundle_dir.join(&description.name))?;
+    fs::rename(staging.path(), &target)?;
+    switch(bundle_dir, &description.name, &description.version)?;
+    Ok(description)
+}
+
+/// Activates an installed version of a bundle.
+///
+/// The `current` link is replaced atomically (a new link is created, then renamed).
+///
+/// # Errors
+///
+/// This function will return an error if the name or version is invalid, if the version is not installed,
+/// or if the link cannot be replaced.
+pub fn activate(bundle_dir: &Path, name: &str, version: &str) -> Result<(), HareError> {
+    let _lock = lock(bundle_dir)?;
+    switch(bundle_dir, name, version)
+}
+
+/// Replaces the `current` link of a bundle, with the lock of the bundle directory held.
+fn switch(bundle_dir: &Path, name: &str, version: &str) -> Result<(), HareError> {
+    if !is_valid_name(name) || !is_valid_version(version) {
+        return Err(HareError::BundleError("invalid bundle name or version".to_string()));
+    }
+
+    let dir = bundle_dir.join(name);
+    if !dir.join(version).is_dir() {
+        return Err(HareError::BundleError(format!("bundle {} version {} is not installed", name, version)));
+    }
+
+    let tmp = dir.join(format!(".{}.tmp", CURRENT_LINK));
+    if fs::symlink_metadata(&tmp).is_ok() {
+        fs::remove_file(&tmp)?;
+    }
+    std::os::unix::fs::symlink(version, &tmp)?;
+    fs::rename(&tmp, dir.join(CURRENT_LINK))?;
+    Ok(())
+}
+
+/// Lists the installed bundles, sorted by name.
+///
+/// @return Result<Vec<InstalledBundle>, HareError>
+///
+/// # Errors
+///
+/// This function will return an error if the bundle directory cannot be read.
+pub fn list(bundle_dir: &Path) -> Result<Vec<InstalledBundle>, HareError> {
+    let mut bundles = Vec::new();
+    if !bundle_dir.is_dir() {
+        return Ok(bundles);
+    }
+
+    for entry in fs::read_dir(bundle_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') || !entry.path().is_dir() {
+            continue;
+        }
+
+        let active = fs::read_link(entry.path().join(CURRENT_LINK)).ok()
+            .map(|target| target.to_string_lossy().to_string());
+        let mut versions: Vec<String> = fs::read_dir(entry.path())?
+            .filter_map(|version| version.ok())
+            .filter(|version| version.path().is_dir() && !version.path().is_symlink())
+            .map(|version| version.file_name().to_string_lossy().to_string())
+            .filter(|version| !version.starts_with('.'))
+            .collect();
+        versions.sort();
+
+        bundles.push(InstalledBundle { name, active, versions });
+    }
+
+    bundles.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(bundles)
+}
+
+/// Removes a bundle, or one of its versions.
+///
+/// Without version, the bundle is deactivated and all its versions are removed.
+/// The active version cannot be removed alone.
+///
+/// # Errors
+///
+/// This function will return an error if the bundle or the version is not installed,
+/// or if the version is the active one.
+pub fn remove(bundle_dir: &Path, name: &str, version: Option<&str>) -> Result<(), HareError> {
+    if !is_valid_name(name) || version.is_some_and(|version| !is_valid_version(version)) {
+        return Err(HareError::BundleError("invalid bundle name or version".to_string()));
+    }
+    let _lock = lock(bundle_dir)?;
+
+    let dir = bundle_dir.join(name);
+    if !dir.is_dir() {
+        return Err(HareError::BundleError(format!("bundle {} is not installed", name)));
+    }
+
+    match version {
+        None => {
+            let current = dir.join(CURRENT_LINK);
+            if fs::symlink_metadata(&current).is_ok() {
+                fs::remove_file(&current)?;
+            }
+            fs::remove_dir_all(&dir)?;
+        }
+        Some(version) => {
+            let active = fs::read_link(dir.join(CURRENT_LINK)).ok();
+            if active.as_deref() == Some(Path::new(version)) {
+                return Err(HareError::BundleError(format!("bundle {} version {} is active", name, version)));
+            }
+            if !dir.join(version).is_dir() {
+                return Err(HareError::BundleError(format!("bundle {} version {} is not installed", name, version)));
+            }
+            fs::remove_dir_all(dir.join(version))?;
+        }
+    }
+    Ok(())
+}
+
+/// Script roots of the active bundles, sorted by bundle name.
+///
+/// @return the `current` directory of each active bundle
+///
+pub fn active_roots(bundle_dir: &Path) -> Vec<String> {
+    let Ok(bundles) = list(bundle_dir) else { return Vec::new() };
+    bundles.into_iter()
+        .filter(|bundle| bundle.active.is_some())
+        .map(|bundle| bundle_dir.join(bundle.name).join(CURRENT_LINK).display().to_string())
+        .collect()
+}
+
+/// Locks the bundle directory, waiting for the other installs, activations and removals, of this
+/// process or of another one.
+///
+/// @return the lock file, unlocked when dropped
+///
+fn lock(bundle_dir: &Path) -> Result<fs::File, HareError> {
+    fs::create_dir_all(bundle_dir)?;
+    let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(bundle_dir.join(LOCK_FILE))?;
+    loop {
+        // Safety: the descriptor is valid while the file is open
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(file);
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error.into());
+        }
+    }
+}
+
+/// Unpacks a `.tar.zst` archive in a new directory.
+///
+/// Entries that would be written outside of the directory are rejected by `unpack_in`.
+fn unpack(archive: &Path, target: &Path) -> Result<(), HareError> {
+    fs::create_dir_all(target)?;
+    let decoder = zstd::Decoder::new(fs::File::open(archive)?)?;
+    let mut archive = tar::Archive::new(decoder);
+    archive.set_preserve_permissions(true);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.unpack_in(target)? {
+            return Err(HareError::BundleError(format!(
+                "archive entry {} is outside of the bundle", entry.path()?.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Validates an unpacked bundle.
+fn validate(dir: &Path) -> Result<BundleDescription, HareError> {
+    let content = fs::read_to_string(dir.join(BUNDLE_FILE))
+        .map_err(|error| HareError::BundleError(format!("cannot read {}: {}", BUNDLE_FILE, error)))?;
+    let description: BundleDescription = toml::from_str(&content)
+        .map_err(|error| HareError::BundleError(format!("invalid {}: {}", BUNDLE_FILE, error)))?;
+
+    if !is_valid_name(&description.name) {
+        return Err(HareError::BundleError(format!("invalid bundle name \"{}\"", description.name)));
+    }
+    if !is_valid_version(&description.version) {
+        return Err(HareError::BundleError(format!("invalid bundle version \"{}\"", description.version)));
+    }
+
+    for handler in &description.handlers {
+        if !is_valid_name(handler) {
+            return Err(HareError::BundleError(format!("invalid handler name \"{}\"", handler)));
+        }
+        let manifest = manifest::load(&dir.display().to_string(), handler)
+            .map_err(|error| HareError::BundleError(error.to_string()))?;
+        let script: PathBuf = dir.join(handler);
+        let executable = fs::metadata(&script).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0);
+        if !executable && manifest.render.is_none() && manifest.remote.is_none() {
+            return Err(HareError::BundleError(format!("handler {} is not an executable file", handler)));
+        }
+    }
+
+    Ok(description)
+}
+
+/// Bundle and handler names follow the script name rules : alphanumeric, '-' and '_'.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| matches!(c, '_' | '-' | 'a'..='z' | 'A'..='Z' | '0'..='9'))
+}
+
+/// Versions may also contain dots, but not start with one.
+fn is_valid_version(version: &str) -> bool {
+    !version.is_empty() && !version.starts_with('.') && version != CURRENT_LINK
+        && version.chars().all(|c| matches!(c, '.' | '_' | '-' | 'a'..='z' | 'A'..='Z' | '0'..='9'))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A fresh bundle directory.
+    pub(crate) fn bundle_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hare-bundle-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A `.tar.zst` bundle archive, with one executable handler.
+    pub(crate) fn archive(name: &str, version: &str) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |path: &str, mode: u32, content: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(mode);
+            header.set_cksum();
+            builder.append_data(&mut header, path, content).unwrap();
+        };
+        append(BUNDLE_FILE, 0o644, format!("name = \"{}\"\nversion = \"{}\"\nhandlers = [\"deploy\"]\n", name, version).as_bytes());
+        append("deploy", 0o755, b"#!/bin/sh\necho deployed\n");
+        zstd::encode_all(builder.into_inner().unwrap().as_slice(), 0).unwrap()
+    }
+
+    #[test]
+    fn concurrent_installs_do_not_collide() {
+        let dir = bundle_dir("concurrent");
+        let installs: Vec<_> = (0..8).map(|version| {
+            let dir = dir.clone();
+            std::thread::spawn(move || {
+                let archive_path = dir.join(format!("archive-{}.tar.zst", version));
+                fs::write(&archive_path, archive("web", &format!("1.{}", version))).unwrap();
+                install(&dir, &archive_path).unwrap().version
+            })
+        }).collect();
+        for install in installs {
+            install.join().unwrap();
+        }
+
+        let bundles = list(&dir).unwrap();
+        assert_eq!(bundles.len(), 1);
+        assert_eq!(bundles[0].versions.len(), 8);
+        assert!(bundles[0].active.is_some());
+        // no staging directory is left behind
+        assert!(fs::read_dir(&dir).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with(".staging-")));
+    }
+
+    #[test]
+    fn a_version_is_installed_once() {
+        let dir = bundle_dir("twice");
+        let archive_path = dir.join("archive.tar.zst");
+        fs::write(&archive_path, archive("web", "1.0")).unwrap();
+        install(&dir, &archive_path).unwrap();
+        assert!(matches!(install(&dir, &archive_path), Err(HareError::BundleError(_))));
+        assert_eq!(fs::read_link(dir.join("web").join(CURRENT_LINK)).unwrap(), Path::new("1.0"));
+    }
+}
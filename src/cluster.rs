@@ -0,0 +1,266 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use futures_lite::StreamExt;
+use lapin::message::Delivery;
+use lapin::options::*;
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::{BasicProperties, Channel, Connection, ExchangeKind};
+use tokio::sync::mpsc;
+use crate::conversion;
+use crate::harehandler::HareError;
+use crate::jobs::{self, RecentJobs};
+use crate::rollout::{self, Leases};
+
+/// Interval between two heartbeats of an instance.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An instance is considered gone after this delay without heartbeat.
+pub const MEMBER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Number of points of each instance on the hash ring.
+const VIRTUAL_NODES: u32 = 64;
+
+/// Configuration of the clustered coordination mode.
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    pub exchange: String,       // control exchange (template) shared by the instances
+    pub instance: String,       // id of this instance
+    pub partitions: u32,        // number of partitions of the queue
+    pub key_header: String,     // header holding the ordering key of a message
+}
+
+/// Default id of this instance : the host name and the process id.
+///
+/// @return the instance id
+///
+pub fn default_instance_id() -> String {
+    format!("{}-{}", hostname(), std::process::id())
+}
+
+/// Name of this host.
+///
+/// @return the host name, "hare" if it cannot be read
+///
+pub fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // Safety: the buffer is large enough for a host name, and gethostname nul terminates it
+    if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } == 0 {
+        let end = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+        String::from_utf8_lossy(&buffer[..end]).to_string()
+    } else {
+        "hare".to_string()
+    }
+}
+
+/// Name of the queue of a partition.
+///
+/// @return "<queue>.p<partition>"
+///
+pub fn partition_queue(queue: &str, partition: u32) -> String {
+    format!("{}.p{}", queue, partition)
+}
+
+/// Partition of a message, from its ordering key.
+///
+/// @return the partition queue the message is routed to, None if the message has no ordering key
+///
+pub fn route(config: &ClusterConfig, queue: &str, delivery: &Delivery) -> Option<String> {
+    let key = delivery.properties.headers().as_ref()?
+        .inner().get(config.key_header.as_str())
+        .and_then(conversion::header_value)?;
+    Some(partition_queue(queue, (hash(&key) % config.partitions as u64) as u32))
+}
+
+/// Joins the cluster, and consumes the partitions owned by this instance.
+///
+/// The instances announce themselves with heartbeats on the control exchange (fanout), and
+/// each instance keeps the list of the live instances. The partitions are spread across the
+/// live instances by consistent hashing : when an instance joins or leaves, only the partitions
+/// it gains or loses move. Each instance consumes the partitions it owns, and cancels its
+/// consumer on the partitions it no longer owns.
+///
+/// The partition queues are declared with `x-single-active-consumer` : while the instances
+/// disagree on the owner of a partition, the broker still delivers its messages to a single
+/// consumer, in order.
+///
+/// The heartbeats also carry the rollout leases of the instances (see `rollout::Leases`), and the
+/// instances answer the queries of their recent jobs sent on the control exchange (see `jobs::query`).
+///
+/// @return the deliveries of the owned partitions (or the error that stopped the coordination)
+///
+/// # Errors
+///
+/// This function will return an error if the control exchange or the queues cannot be declared.
+pub async fn join(connection: &Connection, exchange: &str, queue: &str, config: &ClusterConfig, leases: Arc<Leases>, jobs: Arc<RecentJobs>) -> Result<mpsc::Receiver<Result<Delivery, lapin::Error>>, HareError> {
+    let channel = connection.create_channel().await?;
+    channel.basic_qos(1, BasicQosOptions::default()).await?;
+    channel.exchange_declare(exchange, ExchangeKind::Fanout, ExchangeDeclareOptions { durable: true, ..ExchangeDeclareOptions::default() }, FieldTable::default()).await?;
+
+    let mut arguments = FieldTable::default();
+    arguments.insert("x-single-active-consumer".into(), AMQPValue::Boolean(true));
+    for partition in 0..config.partitions {
+        channel.queue_declare(&partition_queue(queue, partition), QueueDeclareOptions { durable: true, ..QueueDeclareOptions::default() }, arguments.clone()).await?;
+    }
+
+    let members = channel.queue_declare("", QueueDeclareOptions { exclusive: true, auto_delete: true, ..QueueDeclareOptions::default() }, FieldTable::default()).await?;
+    channel.queue_bind(members.name().as_str(), exchange, "", QueueBindOptions::default(), FieldTable::default()).await?;
+    let heartbeats = channel.basic_consume(members.name().as_str(), "", BasicConsumeOptions { no_ack: true, ..BasicConsumeOptions::default() }, FieldTable::default()).await?;
+
+    log::info!("Joining cluster on exchange {} as {}, {} partitions", exchange, config.instance, config.partitions);
+    leases.attach(channel.clone(), exchange);
+    jobs.attach(channel.clone(), exchange);
+    let (sender, receiver) = mpsc::channel(1);
+    let coordinator = Coordinator {
+        channel, exchange: exchange.to_string(), queue: queue.to_string(), config: config.clone(),
+        members: HashMap::new(), owned: HashSet::new(), deliveries: sender, leases, jobs,
+    };
+    tokio::spawn(coordinator.run(heartbeats));
+    Ok(receiver)
+}
+
+/// Keeps track of the cluster members, and of the partitions consumed by this instance.
+struct Coordinator {
+    channel: Channel,                   // channel of the heartbeats and of the partition consumers
+    exchange: String,                   // control exchange
+    queue: String,                      // partitioned queue
+    config: ClusterConfig,
+    members: HashMap<String, Instant>,  // live instances, with their last heartbeat
+    owned: HashSet<u32>,                // partitions consumed by this instance
+    deliveries: mpsc::Sender<Result<Delivery, lapin::Error>>, // deliveries of the owned partitions
+    leases: Arc<Leases>,                // rollout leases, published with the heartbeats
+    jobs: Arc<RecentJobs>,              // recent jobs of this instance, sent to the queries of the fleet
+}
+
+impl Coordinator {
+
+    /// Sends heartbeats, follows the heartbeats of the other instances, and rebalances the partitions.
+    async fn run(mut self, mut heartbeats: lapin::Consumer) {
+        let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            let result = tokio::select! {
+                _ = ticker.tick() => self.tick().await,
+                heartbeat = heartbeats.next() => match heartbeat {
+                    Some(Ok(heartbeat)) => self.heartbeat(&heartbeat).await,
+                    Some(Err(error)) => Err(error),
+                    None => return,
+                },
+            };
+            if let Err(error) = result {
+                log::error!("Cluster coordination stopped: {}", error);
+                let _ = self.deliveries.send(Err(error)).await;
+                return;
+            }
+        }
+    }
+
+    /// Publishes the heartbeat of this instance, and forgets the silent instances.
+    async fn tick(&mut self) -> Result<(), lapin::Error> {
+        let body = rollout::heartbeat(&self.config.instance, &self.leases.own());
+        let properties = BasicProperties::default()
+            .with_content_type("application/json".into())
+            .with_expiration(MEMBER_TIMEOUT.as_millis().to_string().into());
+        self.channel.basic_publish(&self.exchange, "", BasicPublishOptions::default(), body.as_bytes(), properties).await?;
+
+        let before = self.members.len();
+        self.members.retain(|instance, seen| {
+            let live = seen.elapsed() < MEMBER_TIMEOUT;
+            if !live {
+                log::info!("Cluster member {} left", instance);
+            }
+            live
+        });
+        if self.members.len() != before || self.members.is_empty() {
+            self.rebalance().await?;
+        }
+        Ok(())
+    }
+
+    /// Records the heartbeat of an instance, or answers a query of the recent jobs.
+    async fn heartbeat(&mut self, delivery: &Delivery) -> Result<(), lapin::Error> {
+        let heartbeat = serde_json::from_slice::<serde_json::Value>(&delivery.data).unwrap_or_default();
+        if heartbeat["query"] == jobs::JOBS_QUERY {
+            return self.jobs.answer(&self.channel, &heartbeat, &delivery.properties).await;
+        }
+        let Some(instance) = heartbeat["instance"].as_str().map(str::to_string) else {
+            log::warn!("Invalid heartbeat on the cluster exchange");
+            return Ok(());
+        };
+        let leases = serde_json::from_value(heartbeat["leases"].clone()).unwrap_or_default();
+        self.leases.observe(&instance, leases);
+
+        if self.members.insert(instance.clone(), Instant::now()).is_none() {
+            log::info!("Cluster member {} joined", instance);
+            self.rebalance().await?;
+        }
+        Ok(())
+    }
+
+    /// Consumes the partitions this instance owns, and cancels the consumers of the others.
+    async fn rebalance(&mut self) -> Result<(), lapin::Error> {
+        let mut instances: Vec<String> = self.members.keys().cloned().collect();
+        if !self.members.contains_key(&self.config.instance) {
+            instances.push(self.config.instance.clone());
+        }
+        let ring = Ring::new(&instances);
+
+        for partition in 0..self.config.partitions {
+            let owner = ring.owner(&partition.to_string()) == Some(self.config.instance.as_str());
+            let tag = format!("hare-{}-p{}", self.config.instance, partition);
+            let queue = partition_queue(&self.queue, partition);
+
+            if owner && self.owned.insert(partition) {
+                log::info!("Consuming partition {}", queue);
+                let mut consumer = self.channel.basic_consume(&queue, &tag, BasicConsumeOptions::default(), FieldTable::default()).await?;
+                let deliveries = self.deliveries.clone();
+                tokio::spawn(async move {
+                    while let Some(delivery) = consumer.next().await {
+                        if deliveries.send(delivery).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            } else if !owner && self.owned.remove(&partition) {
+                log::info!("Releasing partition {}", queue);
+                self.channel.basic_cancel(&tag, BasicCancelOptions::default()).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Consistent hash ring of the cluster instances.
+struct Ring {
+    points: Vec<(u64, String)>, // points of the instances, sorted by hash
+}
+
+impl Ring {
+
+    /// Places each instance on the ring, with its virtual nodes.
+    fn new(instances: &[String]) -> Self {
+        let mut points: Vec<(u64, String)> = instances.iter()
+            .flat_map(|instance| (0..VIRTUAL_NODES).map(move |node| (hash(&format!("{}#{}", instance, node)), instance.clone())))
+            .collect();
+        points.sort();
+        Ring { points }
+    }
+
+    /// Owner of a key : the first instance clockwise from the hash of the key.
+    fn owner(&self, key: &str) -> Option<&str> {
+        let hash = hash(key);
+        let index = self.points.partition_point(|(point, _)| *point < hash);
+        self.points.get(index).or(self.points.first()).map(|(_, instance)| instance.as_str())
+    }
+}
+
+/// Stable hash of a string (FNV-1a, with a final mix), identical across instances and versions.
+fn hash(value: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in value.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^ (hash >> 33)
+}
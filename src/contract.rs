@@ -0,0 +1,142 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use crate::runas::User;
+
+/// Prefix of the variables holding the message headers, e.g. HARE_VAR_APP for the `app` header.
+pub const VAR_PREFIX: &str = "HARE_VAR_";
+
+/// Variable holding the time spent by the message in the queue, in milliseconds.
+pub const QUEUE_LATENCY_MS: &str = "HARE_QUEUE_LATENCY_MS";
+
+/// Variable holding the id of the job.
+pub const JOB_ID: &str = "HARE_JOB_ID";
+
+/// Variable holding the id of the message : its AMQP message id or correlation id, or an id generated by hare.
+pub const MESSAGE_ID: &str = "HARE_MESSAGE_ID";
+
+/// Variable holding the path of the file with the message body.
+pub const BODY_FILE: &str = "HARE_BODY_FILE";
+
+/// Variable holding the size of the message body, in bytes, also given on the standard input of the script.
+pub const BODY_SIZE: &str = "HARE_BODY_SIZE";
+
+/// Variable holding the content type of the message body, if the message has one.
+pub const CONTENT_TYPE: &str = "HARE_CONTENT_TYPE";
+
+/// Variable holding the path of the file where the script may write its result, as JSON.
+pub const RESULT_FILE: &str = "HARE_RESULT_FILE";
+
+/// Variable set to "sandbox" for the scripts run by a shadow instance, replaying production messages.
+pub const SHADOW: &str = "HARE_SHADOW";
+
+/// Prefix of the variables holding the fields of a form-encoded body, e.g. HARE_FORM_APP for the `app` field.
+pub const FORM_PREFIX: &str = "HARE_FORM_";
+
+/// Variable holding the path of the file with the fields of a form-encoded body, as JSON.
+pub const FORM_FILE: &str = "HARE_FORM_FILE";
+
+/// Prefix of the output lines reporting the progress of a job, e.g. "::hare-progress:: 40 copying files".
+pub const PROGRESS_MARKER: &str = "::hare-progress::";
+
+/// Prefix of the output lines reporting a metric, e.g. `::hare-metric:: deploy_duration_seconds 12.4 labels{app="web"}`.
+pub const METRIC_MARKER: &str = "::hare-metric::";
+
+/// Name of the variable holding a header.
+///
+/// @return the variable name, e.g. HARE_VAR_APP for the `app` header
+///
+pub fn header_variable(header: &str) -> String {
+    format!("{}{}", VAR_PREFIX, header.to_ascii_uppercase())
+}
+
+/// Name of the variable holding a form field.
+///
+/// The characters that cannot appear in a variable name are replaced by `_`.
+///
+/// @return the variable name, e.g. HARE_FORM_REPO_NAME for the `repo-name` field
+///
+pub fn form_variable(field: &str) -> String {
+    let name: String = field.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("{}{}", FORM_PREFIX, name)
+}
+
+/// Files shared between hare and a script : the message body, and the result written by the script.
+///
+/// The files live in a private directory of the job, removed when the job files are dropped.
+pub struct JobFiles {
+    dir: PathBuf,   // directory of the job files
+}
+
+impl JobFiles {
+
+    /// Creates the job files, with the message body, and the fields of a form-encoded body.
+    ///
+    /// The directory is only accessible to the user the script runs as.
+    ///
+    /// @return JobFiles
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the directory or the body file cannot be written.
+    pub fn create(job: &str, body: &[u8], form: Option<&serde_json::Value>, user: Option<&User>) -> std::io::Result<Self> {
+        let files = JobFiles { dir: std::env::temp_dir().join(format!("hare-{}", job)) };
+        fs::create_dir_all(&files.dir)?;
+        fs::set_permissions(&files.dir, fs::Permissions::from_mode(0o700))?;
+        fs::write(files.body(), body)?;
+        if let Some(form) = form {
+            fs::write(files.form(), form.to_string())?;
+        }
+        if let Some(user) = user {
+            std::os::unix::fs::chown(&files.dir, Some(user.uid), Some(user.gid))?;
+            std::os::unix::fs::chown(files.body(), Some(user.uid), Some(user.gid))?;
+            if form.is_some() {
+                std::os::unix::fs::chown(files.form(), Some(user.uid), Some(user.gid))?;
+            }
+        }
+        Ok(files)
+    }
+
+    /// Path of the directory of the job files.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the body file.
+    pub fn body(&self) -> PathBuf {
+        self.dir.join("body")
+    }
+
+    /// Path of the form file.
+    pub fn form(&self) -> PathBuf {
+        self.dir.join("form.json")
+    }
+
+    /// Path of the result file.
+    pub fn result(&self) -> PathBuf {
+        self.dir.join("result.json")
+    }
+
+    /// Reads the result written by the script.
+    ///
+    /// @return the result, None if the script wrote no result or an invalid one
+    ///
+    pub fn read_result(&self) -> Option<serde_json::Value> {
+        let content = fs::read(self.result()).ok()?;
+        match serde_json::from_slice(&content) {
+            Ok(result) => Some(result),
+            Err(error) => {
+                log::warn!("Invalid result file {}: {}", self.result().display(), error);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for JobFiles {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
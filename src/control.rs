@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use crate::bundle::{self, BundleDescription};
+use crate::harehandler::HareError;
+
+/// Message type of the control message that installs a new handler bundle.
+pub const UPDATE_HANDLERS: &str = "_hare.update-handlers";
+
+/// Maximum size of a downloaded bundle.
+const MAX_BUNDLE_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Downloads, verifies and activates a handler bundle, as requested by a control message.
+///
+/// The message gives the URL of the bundle archive in the `url` header, and its Ed25519
+/// signature (hex encoded) in the `signature` header. The signature is verified with the
+/// configured public key before the bundle is installed and activated.
+///
+/// # Arguments
+///
+/// * `bundle_dir` - the directory of the installed bundles
+/// * `public_key` - the Ed25519 public key of the bundle publisher, hex encoded
+/// * `headers` - the control message headers
+///
+/// @return the description of the activated bundle
+///
+/// # Errors
+///
+/// This function will return an error if a header is missing, if the download fails,
+/// if the signature is invalid, or if the bundle cannot be installed.
+pub fn update_handlers(bundle_dir: &Path, public_key: &str, headers: &HashMap<String, String>) -> Result<BundleDescription, HareError> {
+    let url = headers.get("url")
+        .ok_or_else(|| HareError::BundleError("missing url header".to_string()))?;
+    let signature = headers.get("signature")
+        .and_then(|signature| hex::decode(signature).ok())
+        .ok_or_else(|| HareError::BundleError("missing or invalid signature header".to_string()))?;
+    let public_key = hex::decode(public_key)
+        .map_err(|_| HareError::ConfigError("invalid bundle public key".to_string()))?;
+
+    log::info!("Downloading handler bundle from {}", url);
+    let response = ureq::get(url).call()
+        .map_err(|error| HareError::BundleError(format!("cannot download {}: {}", url, error)))?;
+    let mut archive = Vec::new();
+    response.into_reader().take(MAX_BUNDLE_SIZE + 1).read_to_end(&mut archive)?;
+    if archive.len() as u64 > MAX_BUNDLE_SIZE {
+        return Err(HareError::BundleError(format!("bundle {} is larger than {} bytes", url, MAX_BUNDLE_SIZE)));
+    }
+
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, &public_key)
+        .verify(&archive, &signature)
+        .map_err(|_| HareError::BundleError(format!("invalid signature for bundle {}", url)))?;
+
+    // each download has its own file, removed when dropped
+    fs::create_dir_all(bundle_dir)?;
+    let mut download = tempfile::Builder::new().prefix(".download-").suffix(".tar.zst").tempfile_in(bundle_dir)?;
+    download.write_all(&archive)?;
+    bundle::install(bundle_dir, download.path())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use crate::bundle::tests::{archive, bundle_dir};
+
+    /// Serves a bundle archive over HTTP, for the given number of requests.
+    ///
+    /// @return the URL of the archive
+    ///
+    fn serve(archive: Vec<u8>, requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/bundle.tar.zst", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut head = Vec::new();
+                let mut byte = [0u8; 1];
+                while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                    head.push(byte[0]);
+                }
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", archive.len());
+                stream.write_all(response.as_bytes()).unwrap();
+                stream.write_all(&archive).unwrap();
+            }
+        });
+        url
+    }
+
+    /// A new Ed25519 key pair of a bundle publisher.
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn headers(url: &str, signature: &[u8]) -> HashMap<String, String> {
+        HashMap::from([("url".to_string(), url.to_string()), ("signature".to_string(), hex::encode(signature))])
+    }
+
+    #[test]
+    fn a_signed_bundle_is_installed() {
+        let dir = bundle_dir("signed");
+        let key = key_pair();
+        let archive = archive("web", "1.0");
+        let signature = key.sign(&archive);
+        let url = serve(archive, 1);
+
+        let bundle = update_handlers(&dir, &hex::encode(key.public_key()), &headers(&url, signature.as_ref())).unwrap();
+        assert_eq!((bundle.name.as_str(), bundle.version.as_str()), ("web", "1.0"));
+        assert_eq!(bundle::active_roots(&dir).len(), 1);
+        // the download is removed once installed
+        assert!(!fs::read_dir(&dir).unwrap().any(|entry| entry.unwrap().file_name().to_string_lossy().starts_with(".download-")));
+    }
+
+    #[test]
+    fn a_bundle_with_an_invalid_signature_is_not_installed() {
+        let dir = bundle_dir("invalid-signature");
+        let (key, other) = (key_pair(), key_pair());
+        let archive = archive("web", "1.0");
+        let public_key = hex::encode(key.public_key());
+
+        // signed by another key, or signed but altered
+        let signature = other.sign(&archive);
+        let url = serve(archive.clone(), 1);
+        let error = update_handlers(&dir, &public_key, &headers(&url, signature.as_ref())).unwrap_err();
+        assert!(error.to_string().contains("invalid signature"));
+
+        let signature = key.sign(&archive);
+        let mut altered = archive;
+        *altered.last_mut().unwrap() ^= 1;
+        let url = serve(altered, 1);
+        assert!(update_handlers(&dir, &public_key, &headers(&url, signature.as_ref())).is_err());
+        assert!(bundle::list(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_headers_and_the_key_are_checked_before_the_download() {
+        let dir = bundle_dir("headers");
+        let public_key = hex::encode(key_pair().public_key());
+        // nothing listens on the URL
+        let url = "http://127.0.0.1:1/bundle.tar.zst";
+
+        let missing_url = HashMap::from([("signature".to_string(), "00".to_string())]);
+        assert!(update_handlers(&dir, &public_key, &missing_url).unwrap_err().to_string().contains("missing url header"));
+        let missing_signature = HashMap::from([("url".to_string(), url.to_string())]);
+        assert!(update_handlers(&dir, &public_key, &missing_signature).unwrap_err().to_string().contains("signature header"));
+        let invalid_signature = HashMap::from([("url".to_string(), url.to_string()), ("signature".to_string(), "not hex".to_string())]);
+        assert!(update_handlers(&dir, &public_key, &invalid_signature).unwrap_err().to_string().contains("signature header"));
+        assert!(matches!(update_handlers(&dir, "not hex", &headers(url, b"signature")), Err(HareError::ConfigError(_))));
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use lapin::types::{AMQPValue, DecimalValue, FieldTable};
+
+/// Maximum size of a converted header value, well under the size of an environment string (128 KiB on Linux).
+pub const MAX_VALUE_SIZE: usize = 64 * 1024;
+
+/// Maximum nesting depth of the arrays and tables of a header value.
+const MAX_DEPTH: usize = 16;
+
+/// Converts the headers of a message to their string values, as given to the scripts.
+///
+/// Headers that cannot be given to a script are left out : empty names, names containing `=` or
+/// a NUL character, and values without string form (see `header_value`).
+///
+/// @return the string value of each header, per name
+///
+pub fn header_map(headers: &FieldTable) -> HashMap<String, String> {
+    headers.inner().iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            !name.is_empty() && !name.contains(['=', '\0'])
+        })
+        .filter_map(|(name, value)| header_value(value).map(|value| (name.to_string(), value)))
+        .collect()
+}
+
+/// Converts a header value to its string form.
+///
+/// Booleans and numbers are written as text, decimals with their scale (e.g. "1.25"), timestamps in
+/// seconds since epoch, and strings are decoded as UTF-8 (invalid sequences replaced). Arrays and
+/// tables are written as compact JSON, up to 16 levels of nesting.
+///
+/// @return the string value, None for byte arrays and void values, for values nested too deeply,
+/// values longer than 64 KiB, and values containing a NUL character, which cannot be passed in
+/// an environment variable
+///
+pub fn header_value(value: &AMQPValue) -> Option<String> {
+    let text = match value {
+        AMQPValue::FieldArray(_) | AMQPValue::FieldTable(_) => json(value, 0)?.to_string(),
+        value => scalar(value)?,
+    };
+    (text.len() <= MAX_VALUE_SIZE && !text.contains('\0')).then_some(text)
+}
+
+/// The string form of a value that is neither an array nor a table.
+fn scalar(value: &AMQPValue) -> Option<String> {
+    Some(match value {
+        AMQPValue::Boolean(value) => value.to_string(),
+        AMQPValue::ShortShortInt(value) => value.to_string(),
+        AMQPValue::ShortShortUInt(value) => value.to_string(),
+        AMQPValue::ShortInt(value) => value.to_string(),
+        AMQPValue::ShortUInt(value) => value.to_string(),
+        AMQPValue::LongInt(value) => value.to_string(),
+        AMQPValue::LongUInt(value) => value.to_string(),
+        AMQPValue::LongLongInt(value) => value.to_string(),
+        AMQPValue::Float(value) => value.to_string(),
+        AMQPValue::Double(value) => value.to_string(),
+        AMQPValue::DecimalValue(value) => decimal(value),
+        AMQPValue::ShortString(value) => value.to_string(),
+        AMQPValue::LongString(value) => value.to_string(),
+        AMQPValue::Timestamp(value) => value.to_string(),
+        AMQPValue::FieldArray(_) | AMQPValue::FieldTable(_) | AMQPValue::ByteArray(_) | AMQPValue::Void => return None,
+    })
+}
+
+/// Writes a decimal with its scale, e.g. "1.25" for the value 125 with scale 2.
+fn decimal(decimal: &DecimalValue) -> String {
+    let scale = decimal.scale as usize;
+    if scale == 0 {
+        return decimal.value.to_string();
+    }
+    let digits = format!("{:0>width$}", decimal.value, width = scale + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - scale);
+    format!("{}.{}", integer, fraction)
+}
+
+/// The JSON form of a value, None if it is nested too deeply.
+///
+/// Inside arrays and tables, byte arrays are written as hex strings and void values as null.
+fn json(value: &AMQPValue, depth: usize) -> Option<serde_json::Value> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    Some(match value {
+        AMQPValue::Boolean(value) => (*value).into(),
+        AMQPValue::ShortShortInt(value) => (*value).into(),
+        AMQPValue::ShortShortUInt(value) => (*value).into(),
+        AMQPValue::ShortInt(value) => (*value).into(),
+        AMQPValue::ShortUInt(value) => (*value).into(),
+        AMQPValue::LongInt(value) => (*value).into(),
+        AMQPValue::LongUInt(value) => (*value).into(),
+        AMQPValue::LongLongInt(value) => (*value).into(),
+        AMQPValue::Timestamp(value) => (*value).into(),
+        // NaN and infinities have no JSON number
+        AMQPValue::Float(value) => serde_json::Number::from_f64(f64::from(*value)).map(Into::into).unwrap_or_else(|| value.to_string().into()),
+        AMQPValue::Double(value) => serde_json::Number::from_f64(*value).map(Into::into).unwrap_or_else(|| value.to_string().into()),
+        AMQPValue::DecimalValue(value) => decimal(value).into(),
+        AMQPValue::ShortString(value) => value.to_string().into(),
+        AMQPValue::LongString(value) => value.to_string().into(),
+        AMQPValue::ByteArray(value) => hex::encode(value.as_slice()).into(),
+        AMQPValue::Void => serde_json::Value::Null,
+        AMQPValue::FieldArray(values) => values.as_slice().iter()
+            .map(|value| json(value, depth + 1))
+            .collect::<Option<Vec<_>>>()?
+            .into(),
+        AMQPValue::FieldTable(table) => table.inner().iter()
+            .map(|(name, value)| json(value, depth + 1).map(|value| (name.to_string(), value)))
+            .collect::<Option<serde_json::Map<_, _>>>()?
+            .into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::types::{ByteArray, FieldArray, LongString, ShortString};
+
+    /// Number of random values checked by each property.
+    const CASES: usize = 2000;
+
+    /// Random strings : ASCII, unicode, control characters (including NUL), and huge strings.
+    fn string(rng: &mut fastrand::Rng) -> String {
+        match rng.u8(0..10) {
+            0 => String::new(),
+            1 => "\0".repeat(rng.usize(1..4)),
+            2 => "x".repeat(rng.usize(MAX_VALUE_SIZE - 8..MAX_VALUE_SIZE + 8)),
+            3 => ["é", "日本", "🐇", "\u{202e}", "\u{feff}", "a=b", "\n", "\u{0}"].iter().take(rng.usize(1..9)).copied().collect(),
+            _ => (0..rng.usize(0..40)).map(|_| rng.char(..)).collect(),
+        }
+    }
+
+    /// Random values of every type, nested up to `depth` levels.
+    fn value(rng: &mut fastrand::Rng, depth: usize) -> AMQPValue {
+        match rng.u8(0..if depth == 0 { 16 } else { 18 }) {
+            0 => AMQPValue::Boolean(rng.bool()),
+            1 => AMQPValue::ShortShortInt(rng.i8(..)),
+            2 => AMQPValue::ShortShortUInt(rng.u8(..)),
+            3 => AMQPValue::ShortInt(rng.i16(..)),
+            4 => AMQPValue::ShortUInt(rng.u16(..)),
+            5 => AMQPValue::LongInt(rng.i32(..)),
+            6 => AMQPValue::LongUInt(rng.u32(..)),
+            7 => AMQPValue::LongLongInt(rng.i64(..)),
+            8 => AMQPValue::Float(f32::from_bits(rng.u32(..))),
+            9 => AMQPValue::Double(f64::from_bits(rng.u64(..))),
+            10 => AMQPValue::DecimalValue(DecimalValue { scale: rng.u8(..), value: rng.u32(..) }),
+            11 => AMQPValue::ShortString(ShortString::from(string(rng))),
+            12 => AMQPValue::LongString(LongString::from((0..rng.usize(0..64)).map(|_| rng.u8(..)).collect::<Vec<u8>>())),
+            13 => AMQPValue::Timestamp(rng.u64(..)),
+            14 => AMQPValue::ByteArray(ByteArray::from((0..rng.usize(0..16)).map(|_| rng.u8(..)).collect::<Vec<u8>>())),
+            15 => AMQPValue::Void,
+            16 => AMQPValue::FieldArray(FieldArray::from((0..rng.usize(0..4)).map(|_| value(rng, depth - 1)).collect::<Vec<_>>())),
+            _ => AMQPValue::FieldTable(table(rng, depth - 1)),
+        }
+    }
+
+    fn table(rng: &mut fastrand::Rng, depth: usize) -> FieldTable {
+        let mut table = FieldTable::default();
+        for _ in 0..rng.usize(0..5) {
+            table.insert(ShortString::from(string(rng)), value(rng, depth));
+        }
+        table
+    }
+
+    /// A value nested in `depth` arrays.
+    fn nested(depth: usize) -> AMQPValue {
+        (0..depth).fold(AMQPValue::Boolean(true), |value, _| AMQPValue::FieldArray(FieldArray::from(vec![value])))
+    }
+
+    #[test]
+    fn converted_values_can_be_passed_to_scripts() {
+        let mut rng = fastrand::Rng::with_seed(252);
+        for _ in 0..CASES {
+            let value = value(&mut rng, 6);
+            if let Some(text) = header_value(&value) {
+                assert!(text.len() <= MAX_VALUE_SIZE, "value of {} bytes", text.len());
+                assert!(!text.contains('\0'), "NUL in {:?}", text);
+                if matches!(value, AMQPValue::FieldArray(_) | AMQPValue::FieldTable(_)) {
+                    assert!(serde_json::from_str::<serde_json::Value>(&text).is_ok(), "invalid JSON {:?}", text);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn converted_headers_have_valid_names() {
+        let mut rng = fastrand::Rng::with_seed(253);
+        for _ in 0..CASES {
+            for (name, value) in header_map(&table(&mut rng, 4)) {
+                assert!(!name.is_empty() && !name.contains(['=', '\0']), "invalid name {:?}", name);
+                assert!(value.len() <= MAX_VALUE_SIZE && !value.contains('\0'));
+            }
+        }
+    }
+
+    #[test]
+    fn integers_are_written_exactly() {
+        let mut rng = fastrand::Rng::with_seed(254);
+        for _ in 0..CASES {
+            let number = rng.i64(..);
+            assert_eq!(header_value(&AMQPValue::LongLongInt(number)), Some(number.to_string()));
+            let number = rng.u32(..);
+            assert_eq!(header_value(&AMQPValue::LongUInt(number)), Some(number.to_string()));
+        }
+    }
+
+    #[test]
+    fn decimals_keep_their_value() {
+        let mut rng = fastrand::Rng::with_seed(255);
+        for _ in 0..CASES {
+            let (scale, value) = (rng.u8(0..10), rng.u32(..));
+            let text = header_value(&AMQPValue::DecimalValue(DecimalValue { scale, value })).unwrap();
+            assert_eq!(text.replace('.', "").parse::<u64>().unwrap(), value as u64, "{}", text);
+            assert_eq!(text.split('.').nth(1).map_or(0, str::len), scale as usize, "{}", text);
+        }
+        assert_eq!(header_value(&AMQPValue::DecimalValue(DecimalValue { scale: 2, value: 125 })).as_deref(), Some("1.25"));
+        assert_eq!(header_value(&AMQPValue::DecimalValue(DecimalValue { scale: 3, value: 5 })).as_deref(), Some("0.005"));
+    }
+
+    #[test]
+    fn deep_and_huge_values_are_left_out() {
+        assert!(header_value(&nested(MAX_DEPTH)).is_some());
+        assert!(header_value(&nested(MAX_DEPTH + 1)).is_none());
+        assert!(header_value(&nested(1_000)).is_none());
+        assert!(header_value(&AMQPValue::LongString(LongString::from("x".repeat(MAX_VALUE_SIZE)))).is_some());
+        assert!(header_value(&AMQPValue::LongString(LongString::from("x".repeat(MAX_VALUE_SIZE + 1)))).is_none());
+        assert!(header_value(&AMQPValue::LongString(LongString::from("a\0b"))).is_none());
+    }
+
+    #[test]
+    fn binary_and_void_values_are_left_out() {
+        assert_eq!(header_value(&AMQPValue::ByteArray(ByteArray::from(vec![1u8, 2]))), None);
+        assert_eq!(header_value(&AMQPValue::Void), None);
+        let array = AMQPValue::FieldArray(FieldArray::from(vec![AMQPValue::ByteArray(ByteArray::from(vec![0xffu8])), AMQPValue::Void]));
+        assert_eq!(header_value(&array).as_deref(), Some("[\"ff\",null]"));
+    }
+}
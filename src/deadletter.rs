@@ -0,0 +1,94 @@
+use std::time::SystemTime;
+use lapin::message::Delivery;
+use lapin::types::{AMQPValue, FieldTable, LongString};
+use crate::harehandler::Execution;
+use crate::publisher::OutgoingMessage;
+use crate::trace;
+
+/// Header giving why the message was dead-lettered : "exit-code", "killed", "script-missing" or "rejected".
+pub const REASON_HEADER: &str = "x-hare-failure-reason";
+
+/// Header giving the exit code of the failed script.
+pub const EXIT_CODE_HEADER: &str = "x-hare-exit-code";
+
+/// Header giving the end of the standard error of the failed script.
+pub const STDERR_HEADER: &str = "x-hare-stderr";
+
+/// Header giving when the message was dead-lettered (RFC 3339).
+pub const FAILED_AT_HEADER: &str = "x-hare-failed-at";
+
+/// Longest excerpt of the standard error kept in a dead-lettered message, in bytes.
+const MAX_STDERR: usize = 1024;
+
+/// Why a message is dead-lettered.
+pub enum Cause<'a> {
+    Failed(&'a Execution),  // its script failed, or was killed
+    Missing,                // there is no script for its message type
+    Rejected,               // it was rejected (quota exceeded, degraded handler...)
+}
+
+/// The end of the standard error of a script, for the dead-lettered messages and the logs.
+///
+/// @return the last 1 KiB of the output, decoded as UTF-8 (invalid sequences replaced)
+///
+pub fn excerpt(stderr: &[u8]) -> String {
+    let mut start = stderr.len().saturating_sub(MAX_STDERR);
+    // the excerpt does not start in the middle of a character
+    while start < stderr.len() && stderr[start] & 0xc0 == 0x80 {
+        start += 1;
+    }
+    let text = String::from_utf8_lossy(&stderr[start..]);
+    text.trim().to_string()
+}
+
+/// Builds the copy of a message dead-lettered by hare to HARE_DEAD_LETTER_EXCHANGE.
+///
+/// The copy keeps the body, the properties and the routing key of the message, so that it can be
+/// replayed as is, and gets the failure headers (reason, exit code, standard error excerpt, time)
+/// and an `x-hare-trace` entry : "rejected" for a rejected message, "failed" otherwise.
+///
+/// # Arguments
+///
+/// * `delivery` - the dead-lettered message
+/// * `exchange` - the dead letter exchange
+/// * `instance` - the id of this instance, for the trace
+/// * `handler` - the handler of the message, "unknown" if it has none
+/// * `cause` - why the message is dead-lettered
+///
+/// @return OutgoingMessage
+///
+pub fn message(delivery: &Delivery, exchange: &str, instance: &str, handler: &str, cause: &Cause) -> OutgoingMessage {
+    let (reason, outcome) = match cause {
+        Cause::Failed(execution) if execution.exit_code.is_some() => ("exit-code", "failed"),
+        Cause::Failed(_) => ("killed", "failed"),
+        Cause::Missing => ("script-missing", "failed"),
+        Cause::Rejected => ("rejected", "rejected"),
+    };
+    let properties = trace::append(delivery.properties.clone(), instance, handler, outcome);
+
+    // the headers of a previous failure are replaced
+    let mut headers = FieldTable::default();
+    for (key, value) in properties.headers().as_ref().map(FieldTable::inner).into_iter().flatten() {
+        if ![REASON_HEADER, EXIT_CODE_HEADER, STDERR_HEADER, FAILED_AT_HEADER].contains(&key.as_str()) {
+            headers.insert(key.clone(), value.clone());
+        }
+    }
+    let text = |value: &str| AMQPValue::LongString(LongString::from(value));
+    headers.insert(REASON_HEADER.into(), text(reason));
+    headers.insert(FAILED_AT_HEADER.into(), text(&humantime::format_rfc3339_seconds(SystemTime::now()).to_string()));
+    if let Cause::Failed(execution) = cause {
+        if let Some(exit_code) = execution.exit_code {
+            headers.insert(EXIT_CODE_HEADER.into(), AMQPValue::LongInt(exit_code));
+        }
+        if let Some(stderr) = execution.stderr.as_deref().filter(|stderr| !stderr.is_empty()) {
+            headers.insert(STDERR_HEADER.into(), text(&excerpt(stderr.as_bytes())));
+        }
+    }
+
+    OutgoingMessage {
+        exchange: exchange.to_string(),
+        routing_key: delivery.routing_key.to_string(),
+        body: delivery.data.clone(),
+        properties: properties.with_headers(headers),
+    }
+}
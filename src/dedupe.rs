@@ -0,0 +1,280 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use lapin::message::Delivery;
+use crate::config::HareConfig;
+use crate::harehandler::HareError;
+use crate::message::DEDUPLICATION_HEADER;
+
+
+/// Name of the journal of the handled keys, inside the state directory.
+pub const DEDUPE_FILE: &str = "dedupe.log";
+
+/// Default time a handled key is remembered.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// Delay before a message is tried again while a message with the same key runs.
+pub const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Number of handled keys remembered, the oldest ones are forgotten past it.
+const MAX_KEYS: usize = 100_000;
+
+/// Whether a message may run, per its deduplication key.
+pub enum Claim<'a> {
+    New(Pending<'a>),   // the key is unknown : the message runs, and its key is recorded once it succeeded
+    Done,               // a message with the key was handled already
+    Running,            // a message with the key is running
+}
+
+/// Local deduplication of the messages (HARE_DEDUPE) : the keys of the messages that were handled
+/// successfully are remembered for a while, and a message delivered again with one of them is
+/// acknowledged without running.
+///
+/// The key of a message is its `x-deduplication-header` header, the key of the deduplication plugin
+/// of RabbitMQ, or else its AMQP message id ; messages without either are not deduplicated. The broker
+/// plugin drops the duplicates published before the message is consumed, hare the ones delivered
+/// again after it ran, e.g. when hare stopped before acknowledging it.
+///
+/// A key is recorded once its handler succeeded, before the message is acknowledged. With a state
+/// directory, the keys are appended to the `dedupe.log` journal, compacted at startup and past the
+/// number of remembered keys, so that they are kept across restarts.
+pub struct Dedupe {
+    path: Option<PathBuf>,      // journal of the handled keys, None without state directory
+    ttl: Duration,              // how long a handled key is remembered
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    done: HashMap<String, u64>, // handled keys, with their expiry in seconds since epoch
+    order: VecDeque<String>,    // handled keys, oldest first
+    running: HashSet<String>,   // keys of the messages running
+    journal: Option<File>,      // journal, open for appending once loaded
+    appended: usize,            // lines appended to the journal since it was compacted
+}
+
+/// The claim of a running message on its key, released when dropped.
+pub struct Pending<'a> {
+    dedupe: &'a Dedupe,
+    key: String,
+}
+
+impl Dedupe {
+
+    /// Reads the settings of the deduplication : HARE_DEDUPE and HARE_DEDUPE_TTL.
+    ///
+    /// @return the deduplication, None if it is not enabled
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a setting is invalid.
+    pub fn load(config: &HareConfig) -> Result<Option<Self>, String> {
+        if config.get("HARE_DEDUPE").is_none_or(|v| v != "true") {
+            return Ok(None);
+        }
+        let ttl = match config.get("HARE_DEDUPE_TTL") {
+            Some(value) => humantime::parse_duration(&value).ok().filter(|ttl| !ttl.is_zero())
+                .ok_or_else(|| format!("invalid HARE_DEDUPE_TTL {:?}, expected a duration like \"24h\"", value))?,
+            None => DEFAULT_TTL,
+        };
+        Ok(Some(Dedupe::new(config.get("HARE_STATE_DIR").as_deref(), ttl)))
+    }
+
+    /// Creates the deduplication of a state directory, empty until it is started.
+    ///
+    /// @return Dedupe
+    ///
+    pub fn new(state_dir: Option<&str>, ttl: Duration) -> Self {
+        Dedupe { path: state_dir.map(|dir| Path::new(dir).join(DEDUPE_FILE)), ttl, state: Mutex::new(State::default()) }
+    }
+
+    /// How long a handled key is remembered.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Loads the keys of the journal that did not expire, and compacts it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the journal cannot be written.
+    pub fn start(&self) -> Result<(), HareError> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let now = now();
+        let mut state = self.state.lock().unwrap();
+        for line in fs::read_to_string(path).unwrap_or_default().lines() {
+            let Some((expiry, key)) = line.split_once('\t') else { continue };
+            match expiry.parse::<u64>() {
+                Ok(expiry) if expiry > now => state.insert(key, expiry),
+                _ => {}
+            }
+        }
+        state.compact(path)?;
+        log::info!("Deduplicating the messages, {} keys handled in the last {}", state.done.len(), humantime::format_duration(self.ttl));
+        Ok(())
+    }
+
+    /// Claims the key of a message before it runs.
+    ///
+    /// @return New with the claim if the message may run, or Done or Running for a duplicate
+    ///
+    pub fn claim(&self, key: &str) -> Claim<'_> {
+        let mut state = self.state.lock().unwrap();
+        if state.done.get(key).is_some_and(|expiry| *expiry > now()) {
+            return Claim::Done;
+        }
+        if !state.running.insert(key.to_string()) {
+            return Claim::Running;
+        }
+        Claim::New(Pending { dedupe: self, key: key.to_string() })
+    }
+
+    /// Records a handled key.
+    fn record(&self, key: &str) {
+        let expiry = now() + self.ttl.as_secs();
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.insert(key, expiry);
+        if let Some(journal) = state.journal.as_mut() {
+            if let Err(error) = writeln!(journal, "{}\t{}", expiry, key) {
+                log::error!("Could not record the deduplication key {}: {}", key, error);
+            }
+            state.appended += 1;
+        }
+        if let Some(path) = self.path.as_ref().filter(|_| state.appended > MAX_KEYS) {
+            if let Err(error) = state.compact(path) {
+                log::error!("Could not compact the deduplication journal: {}", error);
+            }
+        }
+    }
+}
+
+impl State {
+
+    /// Remembers a key, forgetting the expired keys and the oldest ones past the number of remembered keys.
+    fn insert(&mut self, key: &str, expiry: u64) {
+        if self.done.insert(key.to_string(), expiry).is_none() {
+            self.order.push_back(key.to_string());
+        }
+        let now = now();
+        while let Some(oldest) = self.order.front() {
+            if self.order.len() <= MAX_KEYS && self.done.get(oldest).is_some_and(|expiry| *expiry > now) {
+                break;
+            }
+            if let Some(oldest) = self.order.pop_front() {
+                self.done.remove(&oldest);
+            }
+        }
+    }
+
+    /// Writes the remembered keys to a new journal (write to a temporary file, then rename), and opens it for appending.
+    fn compact(&mut self, path: &Path) -> Result<(), HareError> {
+        let mut content = String::new();
+        for key in &self.order {
+            if let Some(expiry) = self.done.get(key) {
+                content.push_str(&format!("{}\t{}\n", expiry, key));
+            }
+        }
+        let tmp = path.with_extension("log.tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)?;
+        self.journal = Some(OpenOptions::new().append(true).open(path)?);
+        self.appended = 0;
+        Ok(())
+    }
+}
+
+impl Pending<'_> {
+
+    /// Records the key once the message was handled successfully, so that its duplicates do not run.
+    pub fn complete(self) {
+        self.dedupe.record(&self.key);
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.dedupe.state.lock().unwrap().running.remove(&self.key);
+    }
+}
+
+/// The deduplication key of a message : its `x-deduplication-header` header, or else its message id.
+///
+/// @return the key, None if the message has neither, or a key on several lines
+///
+pub fn key(delivery: &Delivery, headers: &HashMap<String, String>) -> Option<String> {
+    headers.get(DEDUPLICATION_HEADER).cloned()
+        .or_else(|| delivery.properties.message_id().as_ref().map(|id| id.to_string()))
+        .filter(|key| !key.is_empty() && !key.contains(['\n', '\r']))
+}
+
+/// The current time, in seconds since epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh state directory.
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hare-dedupe-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_handled_key_is_a_duplicate() {
+        let dedupe = Dedupe::new(None, DEFAULT_TTL);
+        dedupe.start().unwrap();
+        match dedupe.claim("deploy-42") {
+            Claim::New(pending) => pending.complete(),
+            _ => panic!("unknown key claimed as a duplicate"),
+        }
+        assert!(matches!(dedupe.claim("deploy-42"), Claim::Done));
+        assert!(matches!(dedupe.claim("deploy-43"), Claim::New(_)));
+    }
+
+    #[test]
+    fn a_running_key_is_deferred_then_released() {
+        let dedupe = Dedupe::new(None, DEFAULT_TTL);
+        let pending = dedupe.claim("deploy-42");
+        assert!(matches!(dedupe.claim("deploy-42"), Claim::Running));
+        // a failed execution drops its claim without recording the key : the message may run again
+        drop(pending);
+        assert!(matches!(dedupe.claim("deploy-42"), Claim::New(_)));
+    }
+
+    #[test]
+    fn handled_keys_are_kept_across_restarts() {
+        let dir = state_dir("restart");
+        let dedupe = Dedupe::new(dir.to_str(), DEFAULT_TTL);
+        dedupe.start().unwrap();
+        if let Claim::New(pending) = dedupe.claim("deploy-42") {
+            pending.complete();
+        }
+        drop(dedupe);
+
+        let dedupe = Dedupe::new(dir.to_str(), DEFAULT_TTL);
+        dedupe.start().unwrap();
+        assert!(matches!(dedupe.claim("deploy-42"), Claim::Done));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expired_keys_are_dropped_from_the_journal() {
+        let dir = state_dir("expiry");
+        fs::write(dir.join(DEDUPE_FILE), format!("1\told\n{}\tcurrent\ninvalid line\n", now() + 3600)).unwrap();
+        let dedupe = Dedupe::new(dir.to_str(), DEFAULT_TTL);
+        dedupe.start().unwrap();
+        assert!(matches!(dedupe.claim("old"), Claim::New(_)));
+        assert!(matches!(dedupe.claim("current"), Claim::Done));
+        assert!(!fs::read_to_string(dir.join(DEDUPE_FILE)).unwrap().contains("old"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::harehandler::HareError;
+use crate::manifest;
+use crate::selftest;
+
+/// Message type of the built-in handler that describes a handler, named by the `handler` header.
+pub const DESCRIBE: &str = "_hare.describe";
+
+/// Argument given to a script describing itself.
+pub const DESCRIBE_ARG: &str = "--hare-describe";
+
+/// Variable set for a script describing itself, for the scripts that do not parse their arguments.
+pub const DESCRIBE_VARIABLE: &str = "HARE_DESCRIBE";
+
+/// How long a script may take to describe itself.
+const DESCRIBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Contract of a handler, for the publishers : what its messages must hold.
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Description {
+    pub summary: Option<String>,            // what the handler does
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,  // documented headers, with their meaning
+    #[serde(default)]
+    pub required_headers: Vec<String>,      // headers a message must have
+    pub content_type: Option<String>,       // expected content type of the body
+    pub body: Option<String>,               // expected body format, e.g. an example or a schema
+}
+
+/// Describes a handler : the `[describe]` section of its manifest, or else the JSON printed by its
+/// script run with `--hare-describe` (and HARE_DESCRIBE=1), in the same format.
+///
+/// # Arguments
+///
+/// * `script_root` - the script root of the handler
+/// * `handler` - the name of the handler
+///
+/// @return the description, as a JSON object with the handler, its script root and where the description comes from
+///
+/// # Errors
+///
+/// This function will return an error if the manifest is invalid, or the handler has no manifest
+/// description and its script cannot describe itself.
+pub fn describe(script_root: &str, handler: &str) -> Result<serde_json::Value, HareError> {
+    let (description, source) = match manifest::load(script_root, handler)?.describe {
+        Some(description) => (description, "manifest"),
+        None => {
+            let script = Path::new(script_root).join(handler);
+            if !script.is_file() {
+                return Err(HareError::ConfigError(format!("handler {} has no description in its manifest, and no script", handler)));
+            }
+            let mut command = Command::new(&script);
+            command.arg(DESCRIBE_ARG).current_dir(script_root).env(DESCRIBE_VARIABLE, "1");
+            let output = selftest::run(&mut command, DESCRIBE_TIMEOUT)
+                .map_err(|reason| HareError::ConfigError(format!("handler {} cannot describe itself: {}", handler, reason)))?;
+            let description = serde_json::from_slice(&output)
+                .map_err(|error| HareError::ConfigError(format!("handler {} cannot describe itself: invalid description: {}", handler, error)))?;
+            (description, "script")
+        }
+    };
+    Ok(serde_json::json!({
+        "handler": handler,
+        "script_root": script_root,
+        "source": source,
+        "summary": description.summary,
+        "headers": description.headers,
+        "required_headers": description.required_headers,
+        "content_type": description.content_type,
+        "body": description.body,
+    }))
+}
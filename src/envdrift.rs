@@ -0,0 +1,127 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+use crate::contract;
+use crate::harehandler::HareError;
+use crate::metrics::{self, Metrics};
+
+/// Name of the file of the environment snapshots, inside the state directory.
+pub const ENVIRONMENTS_FILE: &str = "environments.json";
+
+/// Variables set by hare that change with every execution, left out of the snapshots.
+const DYNAMIC_VARIABLES: [&str; 7] = [
+    contract::JOB_ID, contract::MESSAGE_ID, contract::QUEUE_LATENCY_MS, contract::BODY_FILE, contract::BODY_SIZE,
+    contract::RESULT_FILE, contract::FORM_FILE,
+];
+
+/// Environment of the last execution of a handler.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Snapshot {
+    hash: String,                                   // hash of the variables
+    variables: BTreeMap<String, Option<String>>,    // digest of the value of each variable, None for the message variables
+    since: u64,                                     // first execution with this environment, in seconds since epoch
+}
+
+/// Environment snapshots of the handlers, to flag the changes of environment between their runs.
+///
+/// The environment of each execution is reduced to a hash, and compared with the one of the
+/// previous run of the handler. The variables of the message (headers, form fields, XML
+/// extractions) only count by their name, as their values change with every message : a header
+/// that appears or disappears is a change, a new value is not. The other variables (provided
+/// variables, locale, content type...) count by their value, kept as a digest so that no secret
+/// is written. With a state directory, the snapshots are kept in the `environments.json` file, so
+/// that the changes are detected across restarts.
+pub struct EnvSnapshots {
+    path: Option<PathBuf>,                      // file of the snapshots, None without state directory
+    ignored: Vec<String>,                       // additional variables left out of the snapshots
+    snapshots: Mutex<BTreeMap<String, Snapshot>>, // snapshot of the last execution of each handler
+}
+
+impl EnvSnapshots {
+
+    /// Creates the snapshots of a state directory, reading the saved ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `state_dir` - the state directory, if any
+    /// * `ignored` - additional variables left out of the snapshots, comma separated (HARE_ENV_DRIFT_IGNORE)
+    ///
+    /// @return EnvSnapshots
+    ///
+    pub fn new(state_dir: Option<&str>, ignored: &str) -> Self {
+        let path = state_dir.map(|dir| Path::new(dir).join(ENVIRONMENTS_FILE));
+        let snapshots = path.as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+        EnvSnapshots {
+            path,
+            ignored: ignored.split(',').map(str::trim).filter(|name| !name.is_empty()).map(str::to_string).collect(),
+            snapshots: Mutex::new(snapshots),
+        }
+    }
+
+    /// Records the environment of an execution, and logs the changes since the previous run of the handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `metrics` - the metrics, counting the changes
+    /// * `handler` - the handler
+    /// * `environment` - the variables set by hare for the script
+    /// * `message_variable` - whether a variable comes from the message, and only counts by its name
+    ///
+    /// @return the hash of the environment
+    ///
+    pub fn record(&self, metrics: &Metrics, handler: &str, environment: &HashMap<String, String>, message_variable: impl Fn(&str) -> bool) -> String {
+        let variables: BTreeMap<String, Option<String>> = environment.iter()
+            .filter(|(name, _)| !DYNAMIC_VARIABLES.contains(&name.as_str()) && !self.ignored.contains(name))
+            .map(|(name, value)| (name.clone(), (!message_variable(name)).then(|| digest(value.as_bytes())[..16].to_string())))
+            .collect();
+        let hash = digest(serde_json::to_string(&variables).unwrap_or_default().as_bytes())[..16].to_string();
+        let since = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        let mut snapshots = self.snapshots.lock().unwrap();
+        match snapshots.get(handler) {
+            Some(previous) if previous.hash == hash => return hash,
+            Some(previous) => {
+                let added: Vec<&str> = variables.keys().filter(|name| !previous.variables.contains_key(*name)).map(String::as_str).collect();
+                let removed: Vec<&str> = previous.variables.keys().filter(|name| !variables.contains_key(*name)).map(String::as_str).collect();
+                let changed: Vec<&str> = variables.iter()
+                    .filter(|(name, value)| previous.variables.get(*name).is_some_and(|previous| previous != *value))
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                let previous_since = humantime::format_rfc3339_seconds(SystemTime::UNIX_EPOCH + Duration::from_secs(previous.since));
+                log::warn!(handler = handler; "Environment of handler {} changed ({}, used since {}, -> {}): added {:?}, removed {:?}, changed {:?}",
+                           handler, previous.hash, previous_since, hash, added, removed, changed);
+                metrics.increment(&metrics::ENV_CHANGES, &[("handler", handler)]);
+            }
+            None => {}
+        }
+        snapshots.insert(handler.to_string(), Snapshot { hash: hash.clone(), variables, since });
+        if let Err(error) = self.save(&snapshots) {
+            log::error!("Could not save the environment snapshots: {}", error);
+        }
+        hash
+    }
+
+    /// Saves the snapshots atomically.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the snapshots file cannot be written.
+    fn save(&self, snapshots: &BTreeMap<String, Snapshot>) -> Result<(), HareError> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(snapshots).unwrap_or_default())?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// SHA-256 digest of a value, in hexadecimal.
+fn digest(value: &[u8]) -> String {
+    hex::encode(ring::digest::digest(&ring::digest::SHA256, value).as_ref())
+}
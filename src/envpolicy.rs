@@ -0,0 +1,110 @@
+use std::process::Command;
+use crate::contract;
+
+/// Variables of hare passed to the scripts with HARE_ENV_CLEAR, unless HARE_ENV_ALLOWLIST lists others.
+pub const DEFAULT_ALLOWLIST: &str = "PATH,HOME,USER,LOGNAME,LANG,LC_ALL,TZ";
+
+/// Clean environment of the scripts (HARE_ENV_CLEAR) : they do not inherit the environment of hare, with
+/// its credentials (HARE_AMQP_URL, cloud keys...), but only the variables of the allowlist and those set
+/// by hare.
+///
+/// The headers are passed with stricter names and values : the characters of the header names that
+/// cannot appear in a variable name are replaced by `_`, and the control characters of the values are
+/// escaped, so that a publisher cannot smuggle lines or terminal sequences into the script.
+pub struct EnvPolicy {
+    allowlist: Vec<String>,     // variables of hare passed to the scripts
+}
+
+impl EnvPolicy {
+
+    /// Creates the policy of a comma separated allowlist.
+    ///
+    /// @return EnvPolicy
+    ///
+    pub fn new(allowlist: &str) -> Self {
+        EnvPolicy { allowlist: allowlist.split(',').map(str::trim).filter(|name| !name.is_empty()).map(str::to_string).collect() }
+    }
+
+    /// The variables of hare passed to the scripts.
+    pub fn allowlist(&self) -> &[String] {
+        &self.allowlist
+    }
+
+    /// Clears the environment inherited by the command, but for the variables of the allowlist.
+    ///
+    /// The variables of the script are set after, and take precedence.
+    pub fn apply(&self, command: &mut Command) {
+        command.env_clear();
+        for name in &self.allowlist {
+            if let Some(value) = std::env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+    }
+
+    /// Name of the variable holding a header, its other characters than letters, digits and `_` replaced by `_`.
+    ///
+    /// @return the variable name, e.g. HARE_VAR_X_REQUEST_ID for the `x-request-id` header
+    ///
+    pub fn header_variable(header: &str) -> String {
+        let name: String = header.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect();
+        format!("{}{}", contract::VAR_PREFIX, name)
+    }
+
+    /// Escapes the control characters of a header value (but for tabs) as `\xNN`, and the backslashes as `\\`.
+    ///
+    /// @return the escaped value, e.g. `line 1\x0aline 2`
+    ///
+    pub fn escape(value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                c if c.is_control() && c != '\t' => escaped.push_str(&format!("\\x{:02x}", u32::from(c))),
+                c => escaped.push(c),
+            }
+        }
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_allowlist_is_split_on_commas() {
+        assert_eq!(EnvPolicy::new(" PATH, HOME,,LANG ").allowlist(), ["PATH", "HOME", "LANG"]);
+        assert!(EnvPolicy::new("").allowlist().is_empty());
+    }
+
+    #[test]
+    fn only_the_allowlist_is_inherited() {
+        let mut command = Command::new("env");
+        EnvPolicy::new("PATH,HARE_TEST_UNSET_VARIABLE").apply(&mut command);
+        command.env("HARE_VAR_TYPE", "deploy");
+        let output = command.output().unwrap();
+        let mut names: Vec<_> = String::from_utf8(output.stdout).unwrap().lines()
+            .filter_map(|line| line.split_once('=').map(|(name, _)| name.to_string()))
+            .collect();
+        names.sort();
+        assert_eq!(names, ["HARE_VAR_TYPE", "PATH"]);
+    }
+
+    #[test]
+    fn header_names_are_valid_variable_names() {
+        assert_eq!(EnvPolicy::header_variable("x-request-id"), "HARE_VAR_X_REQUEST_ID");
+        assert_eq!(EnvPolicy::header_variable("a.b c=d$(e)"), "HARE_VAR_A_B_C_D__E_");
+        assert_eq!(EnvPolicy::header_variable("type"), "HARE_VAR_TYPE");
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_eq!(EnvPolicy::escape("line 1\nline 2"), "line 1\\x0aline 2");
+        assert_eq!(EnvPolicy::escape("\u{1b}[31mred\r"), "\\x1b[31mred\\x0d");
+        assert_eq!(EnvPolicy::escape("a\tb \\n"), "a\tb \\\\n");
+        assert_eq!(EnvPolicy::escape("déploiement"), "déploiement");
+    }
+}
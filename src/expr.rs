@@ -0,0 +1,589 @@
+use std::collections::HashMap;
+use serde_json::{Map, Number, Value};
+
+/// Longest expression accepted.
+const MAX_LENGTH: usize = 4096;
+
+/// Maximum nesting depth of an expression.
+const MAX_DEPTH: usize = 64;
+
+/// The message an expression is evaluated against.
+pub struct Context<'a> {
+    pub headers: &'a HashMap<String, String>,   // string values of the message headers
+    pub body: &'a [u8],                         // message payload
+    pub content_type: Option<&'a str>,          // content type of the payload, if given
+    pub exchange: &'a str,                      // exchange the message was published to
+    pub routing_key: &'a str,                   // routing key of the message
+}
+
+/// A parsed expression, shared by the features that select messages.
+///
+/// The language is a small subset of CEL :
+///
+/// - literals : `"text"` or `'text'`, numbers, `true`, `false`, `null`, lists `[1, 2]` and maps
+///   `{"version": body.release.version, host: headers.host}` (keys are names or strings),
+/// - variables : `headers`, `body` (the payload decoded as JSON, or as a string), `content_type`,
+///   `exchange` and `routing_key`, with field access `body.release.version`, `headers["x-env"]`,
+///   and index access `body.hosts[0]` ; missing fields are null,
+/// - operators, by increasing precedence : `||`, `&&`, `==` `!=` `<` `<=` `>` `>=` `in`,
+///   `+` `-`, `*` `/` `%`, and the unary `!` `-`,
+/// - functions : `size(x)`, `has(x)` (x is not null), `int(x)`, `string(x)`, and the string methods
+///   `x.startsWith(s)`, `x.endsWith(s)`, `x.contains(s)`, `x.lower()`, `x.upper()`.
+///
+/// `&&` and `||` take booleans and only evaluate their right operand when needed, so that
+/// `has(body.release) && body.release.version > 2` is never an error.
+#[derive(Debug)]
+pub struct Expression {
+    root: Node,     // syntax tree of the expression
+}
+
+/// A node of the syntax tree.
+#[derive(Debug)]
+enum Node {
+    Literal(Value),
+    Variable(String),
+    Field(Box<Node>, String),
+    Index(Box<Node>, Box<Node>),
+    List(Vec<Node>),
+    Map(Vec<(String, Node)>),
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+    Call(String, Vec<Node>),            // function, or method with its receiver as first argument
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Or, And, Equal, NotEqual, Less, LessOrEqual, Greater, GreaterOrEqual, In, Add, Subtract, Multiply, Divide, Remainder,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    String(String),
+    Identifier(String),
+    Symbol(&'static str),
+}
+
+/// Symbols of the language, the longest ones first.
+const SYMBOLS: [&str; 23] = ["||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")", "[", "]", "{", "}", ":", ".", ","];
+
+impl Expression {
+
+    /// Parses an expression.
+    ///
+    /// @return Expression
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error, with its position, if the expression is invalid,
+    /// longer than 4096 characters or nested deeper than 64 levels.
+    pub fn parse(source: &str) -> Result<Expression, String> {
+        if source.len() > MAX_LENGTH {
+            return Err(format!("the expression is longer than {} characters", MAX_LENGTH));
+        }
+        let mut parser = Parser { tokens: tokenize(source)?, position: 0 };
+        let root = parser.expression(0)?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(Expression { root }),
+            Some((token, position)) => Err(format!("unexpected {} at position {}", describe(token), position)),
+        }
+    }
+
+    /// Evaluates the expression against a message.
+    ///
+    /// @return the value of the expression
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if an operator or a function is given values of the
+    /// wrong type, e.g. `"1" < 2`, or calls an unknown function.
+    pub fn evaluate(&self, context: &Context) -> Result<Value, String> {
+        evaluate(&self.root, context)
+    }
+}
+
+/// Splits an expression in tokens, with their position.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(position, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, quote)) if quote == c => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => text.push('\n'),
+                        Some((_, 't')) => text.push('\t'),
+                        Some((_, escaped)) => text.push(escaped),
+                        None => return Err(format!("unterminated string at position {}", position)),
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => return Err(format!("unterminated string at position {}", position)),
+                }
+            }
+            tokens.push((Token::String(text), position));
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if !c.is_ascii_digit() && c != '.' {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            let number = number.parse().map_err(|_| format!("invalid number {} at position {}", number, position))?;
+            tokens.push((Token::Number(number), position));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut identifier = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if !c.is_alphanumeric() && c != '_' {
+                    break;
+                }
+                identifier.push(c);
+                chars.next();
+            }
+            tokens.push((Token::Identifier(identifier), position));
+        } else {
+            let symbol = SYMBOLS.iter().find(|symbol| source[position..].starts_with(*symbol))
+                .ok_or_else(|| format!("unexpected character {:?} at position {}", c, position))?;
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push((Token::Symbol(symbol), position));
+        }
+    }
+    Ok(tokens)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Number(number) => format!("number {}", number),
+        Token::String(text) => format!("string {:?}", text),
+        Token::Identifier(identifier) => format!("identifier {}", identifier),
+        Token::Symbol(symbol) => format!("\"{}\"", symbol),
+    }
+}
+
+/// The binary operators, by increasing precedence.
+const PRECEDENCE: [&[(&str, Operator)]; 5] = [
+    &[("||", Operator::Or)],
+    &[("&&", Operator::And)],
+    &[("==", Operator::Equal), ("!=", Operator::NotEqual), ("<", Operator::Less), ("<=", Operator::LessOrEqual),
+      (">", Operator::Greater), (">=", Operator::GreaterOrEqual), ("in", Operator::In)],
+    &[("+", Operator::Add), ("-", Operator::Subtract)],
+    &[("*", Operator::Multiply), ("/", Operator::Divide), ("%", Operator::Remainder)],
+];
+
+/// A recursive descent parser over the tokens of an expression.
+struct Parser {
+    tokens: Vec<(Token, usize)>,    // tokens, with their position
+    position: usize,                // index of the next token
+}
+
+impl Parser {
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    /// Consumes the next token if it is the given symbol (or the `in` keyword).
+    fn accept(&mut self, symbol: &str) -> bool {
+        let found = match self.peek() {
+            Some(Token::Symbol(next)) => *next == symbol,
+            Some(Token::Identifier(next)) => symbol == "in" && next == "in",
+            _ => false,
+        };
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.accept(symbol) {
+            return Ok(());
+        }
+        Err(match self.tokens.get(self.position) {
+            Some((token, position)) => format!("expected \"{}\", found {} at position {}", symbol, describe(token), position),
+            None => format!("expected \"{}\" at the end of the expression", symbol),
+        })
+    }
+
+    /// Parses an expression, nested `depth` levels deep.
+    fn expression(&mut self, depth: usize) -> Result<Node, String> {
+        self.binary(0, depth)
+    }
+
+    /// Parses the binary operations from the given precedence level.
+    fn binary(&mut self, level: usize, depth: usize) -> Result<Node, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary(depth);
+        }
+        let mut left = self.binary(level + 1, depth)?;
+        'operators: loop {
+            for (symbol, operator) in PRECEDENCE[level] {
+                if self.accept(symbol) {
+                    let right = self.binary(level + 1, depth)?;
+                    left = Node::Binary(*operator, Box::new(left), Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Node, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("the expression is nested deeper than {} levels", MAX_DEPTH));
+        }
+        if self.accept("!") {
+            return Ok(Node::Not(Box::new(self.unary(depth + 1)?)));
+        }
+        if self.accept("-") {
+            return Ok(Node::Negate(Box::new(self.unary(depth + 1)?)));
+        }
+        let mut node = self.primary(depth)?;
+        loop {
+            if self.accept(".") {
+                let name = self.identifier()?;
+                if self.accept("(") {
+                    let mut arguments = vec![node];
+                    arguments.extend(self.arguments(")", depth)?);
+                    node = Node::Call(name, arguments);
+                } else {
+                    node = Node::Field(Box::new(node), name);
+                }
+            } else if self.accept("[") {
+                let index = self.expression(depth + 1)?;
+                self.expect("]")?;
+                node = Node::Index(Box::new(node), Box::new(index));
+            } else {
+                return Ok(node);
+            }
+        }
+    }
+
+    fn primary(&mut self, depth: usize) -> Result<Node, String> {
+        let Some((token, position)) = self.tokens.get(self.position).cloned() else {
+            return Err("unexpected end of the expression".to_string());
+        };
+        self.position += 1;
+        match token {
+            Token::Number(number) => Ok(Node::Literal(number_value(number))),
+            Token::String(text) => Ok(Node::Literal(Value::String(text))),
+            Token::Identifier(identifier) => match identifier.as_str() {
+                "true" => Ok(Node::Literal(Value::Bool(true))),
+                "false" => Ok(Node::Literal(Value::Bool(false))),
+                "null" => Ok(Node::Literal(Value::Null)),
+                _ if self.accept("(") => Ok(Node::Call(identifier, self.arguments(")", depth)?)),
+                _ => Ok(Node::Variable(identifier)),
+            },
+            Token::Symbol("(") => {
+                let node = self.expression(depth + 1)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Token::Symbol("[") => Ok(Node::List(self.arguments("]", depth)?)),
+            Token::Symbol("{") => Ok(Node::Map(self.entries(depth)?)),
+            token => Err(format!("unexpected {} at position {}", describe(&token), position)),
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, String> {
+        match self.tokens.get(self.position).cloned() {
+            Some((Token::Identifier(identifier), _)) => {
+                self.position += 1;
+                Ok(identifier)
+            }
+            Some((token, position)) => Err(format!("expected a name, found {} at position {}", describe(&token), position)),
+            None => Err("expected a name at the end of the expression".to_string()),
+        }
+    }
+
+    /// Parses the comma separated `key: expression` entries of a map, up to the closing brace.
+    fn entries(&mut self, depth: usize) -> Result<Vec<(String, Node)>, String> {
+        let mut entries = Vec::new();
+        if self.accept("}") {
+            return Ok(entries);
+        }
+        loop {
+            let key = match self.tokens.get(self.position).cloned() {
+                Some((Token::String(key), _)) => {
+                    self.position += 1;
+                    key
+                }
+                _ => self.identifier()?,
+            };
+            self.expect(":")?;
+            entries.push((key, self.expression(depth + 1)?));
+            if self.accept("}") {
+                return Ok(entries);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    /// Parses a comma separated list of expressions, up to the closing symbol.
+    fn arguments(&mut self, close: &str, depth: usize) -> Result<Vec<Node>, String> {
+        let mut arguments = Vec::new();
+        if self.accept(close) {
+            return Ok(arguments);
+        }
+        loop {
+            arguments.push(self.expression(depth + 1)?);
+            if self.accept(close) {
+                return Ok(arguments);
+            }
+            self.expect(",")?;
+        }
+    }
+}
+
+/// The JSON number of a float, written as an integer when it has no fraction.
+fn number_value(number: f64) -> Value {
+    if number.fract() == 0.0 && number.abs() < 9.0e15 {
+        Value::from(number as i64)
+    } else {
+        Number::from_f64(number).map(Value::Number).unwrap_or(Value::Null)
+    }
+}
+
+/// The value of a variable.
+fn variable(name: &str, context: &Context) -> Result<Value, String> {
+    Ok(match name {
+        "headers" => Value::Object(context.headers.iter().map(|(name, value)| (name.clone(), Value::String(value.clone()))).collect::<Map<_, _>>()),
+        "body" => serde_json::from_slice(context.body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(context.body).to_string())),
+        "content_type" => context.content_type.map(|content_type| Value::String(content_type.to_string())).unwrap_or(Value::Null),
+        "exchange" => Value::String(context.exchange.to_string()),
+        "routing_key" => Value::String(context.routing_key.to_string()),
+        name => return Err(format!("unknown variable {}", name)),
+    })
+}
+
+fn evaluate(node: &Node, context: &Context) -> Result<Value, String> {
+    match node {
+        Node::Literal(value) => Ok(value.clone()),
+        Node::Variable(name) => variable(name, context),
+        Node::Field(node, name) => Ok(evaluate(node, context)?.get(name).cloned().unwrap_or(Value::Null)),
+        Node::Index(node, index) => {
+            let value = evaluate(node, context)?;
+            Ok(match evaluate(index, context)? {
+                Value::String(name) => value.get(&name).cloned(),
+                Value::Number(index) => index.as_u64().and_then(|index| value.get(index as usize)).cloned(),
+                index => return Err(format!("cannot index with {}", type_name(&index))),
+            }.unwrap_or(Value::Null))
+        }
+        Node::List(nodes) => Ok(Value::Array(nodes.iter().map(|node| evaluate(node, context)).collect::<Result<_, _>>()?)),
+        Node::Map(entries) => Ok(Value::Object(entries.iter()
+            .map(|(key, node)| Ok((key.clone(), evaluate(node, context)?)))
+            .collect::<Result<Map<_, _>, String>>()?)),
+        Node::Not(node) => Ok(Value::Bool(!boolean(&evaluate(node, context)?, "!")?)),
+        Node::Negate(node) => Ok(number_value(-number(&evaluate(node, context)?, "-")?)),
+        Node::Binary(Operator::And, left, right) => Ok(Value::Bool(
+            boolean(&evaluate(left, context)?, "&&")? && boolean(&evaluate(right, context)?, "&&")?)),
+        Node::Binary(Operator::Or, left, right) => Ok(Value::Bool(
+            boolean(&evaluate(left, context)?, "||")? || boolean(&evaluate(right, context)?, "||")?)),
+        Node::Binary(operator, left, right) => binary(*operator, evaluate(left, context)?, evaluate(right, context)?),
+        Node::Call(name, arguments) => {
+            let arguments = arguments.iter().map(|node| evaluate(node, context)).collect::<Result<Vec<_>, _>>()?;
+            call(name, &arguments)
+        }
+    }
+}
+
+fn binary(operator: Operator, left: Value, right: Value) -> Result<Value, String> {
+    let symbol = PRECEDENCE.iter().flat_map(|level| level.iter())
+        .find(|(_, candidate)| *candidate == operator)
+        .map_or("", |(symbol, _)| symbol);
+    Ok(match operator {
+        Operator::Equal => Value::Bool(equal(&left, &right)),
+        Operator::NotEqual => Value::Bool(!equal(&left, &right)),
+        Operator::Less | Operator::LessOrEqual | Operator::Greater | Operator::GreaterOrEqual => {
+            let ordering = match (&left, &right) {
+                (Value::String(left), Value::String(right)) => left.partial_cmp(right),
+                (Value::Number(_), Value::Number(_)) => number(&left, symbol)?.partial_cmp(&number(&right, symbol)?),
+                _ => return Err(format!("cannot compare {} and {} with {}", type_name(&left), type_name(&right), symbol)),
+            };
+            Value::Bool(match operator {
+                Operator::Less => ordering.is_some_and(|ordering| ordering.is_lt()),
+                Operator::LessOrEqual => ordering.is_some_and(|ordering| ordering.is_le()),
+                Operator::Greater => ordering.is_some_and(|ordering| ordering.is_gt()),
+                _ => ordering.is_some_and(|ordering| ordering.is_ge()),
+            })
+        }
+        Operator::In => Value::Bool(match &right {
+            Value::Array(values) => values.iter().any(|value| equal(&left, value)),
+            Value::Object(map) => left.as_str().is_some_and(|key| map.contains_key(key)),
+            Value::String(text) => text.contains(string(&left, "in")?),
+            _ => return Err(format!("cannot search in {}", type_name(&right))),
+        }),
+        Operator::Add => match (&left, &right) {
+            (Value::String(left), Value::String(right)) => Value::String(format!("{}{}", left, right)),
+            (Value::Array(left), Value::Array(right)) => Value::Array(left.iter().chain(right).cloned().collect()),
+            _ => number_value(number(&left, symbol)? + number(&right, symbol)?),
+        },
+        Operator::Subtract => number_value(number(&left, symbol)? - number(&right, symbol)?),
+        Operator::Multiply => number_value(number(&left, symbol)? * number(&right, symbol)?),
+        Operator::Divide | Operator::Remainder => {
+            let (left, right) = (number(&left, symbol)?, number(&right, symbol)?);
+            if right == 0.0 {
+                return Err("division by zero".to_string());
+            }
+            number_value(if operator == Operator::Divide { left / right } else { left % right })
+        }
+        Operator::And | Operator::Or => unreachable!("evaluated with short-circuit"),
+    })
+}
+
+fn call(name: &str, arguments: &[Value]) -> Result<Value, String> {
+    Ok(match (name, arguments) {
+        ("size", [Value::String(text)]) => Value::from(text.chars().count()),
+        ("size", [Value::Array(values)]) => Value::from(values.len()),
+        ("size", [Value::Object(map)]) => Value::from(map.len()),
+        ("has", [value]) => Value::Bool(!value.is_null()),
+        ("int", [Value::Number(number)]) => Value::from(number.as_f64().unwrap_or_default().trunc() as i64),
+        ("int", [Value::String(text)]) => Value::from(text.trim().parse::<i64>().map_err(|_| format!("cannot convert {:?} to int", text))?),
+        ("string", [Value::String(text)]) => Value::String(text.clone()),
+        ("string", [value]) => Value::String(value.to_string()),
+        ("startsWith", [Value::String(text), prefix]) => Value::Bool(text.starts_with(string(prefix, name)?)),
+        ("endsWith", [Value::String(text), suffix]) => Value::Bool(text.ends_with(string(suffix, name)?)),
+        ("contains", [Value::String(text), part]) => Value::Bool(text.contains(string(part, name)?)),
+        ("contains", [Value::Array(values), value]) => Value::Bool(values.iter().any(|candidate| equal(candidate, value))),
+        ("lower", [Value::String(text)]) => Value::String(text.to_lowercase()),
+        ("upper", [Value::String(text)]) => Value::String(text.to_uppercase()),
+        ("size" | "has" | "int" | "string" | "startsWith" | "endsWith" | "contains" | "lower" | "upper", _) => {
+            let types: Vec<&str> = arguments.iter().map(type_name).collect();
+            return Err(format!("invalid arguments for {}: ({})", name, types.join(", ")));
+        }
+        _ => return Err(format!("unknown function {}", name)),
+    })
+}
+
+/// Compares two values, numbers by their value (`1 == 1.0`).
+fn equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left.as_f64() == right.as_f64(),
+        _ => left == right,
+    }
+}
+
+fn boolean(value: &Value, operator: &str) -> Result<bool, String> {
+    value.as_bool().ok_or_else(|| format!("{} expects booleans, found {}", operator, type_name(value)))
+}
+
+fn number(value: &Value, operator: &str) -> Result<f64, String> {
+    value.as_f64().ok_or_else(|| format!("{} expects numbers, found {}", operator, type_name(value)))
+}
+
+fn string<'a>(value: &'a Value, operator: &str) -> Result<&'a str, String> {
+    value.as_str().ok_or_else(|| format!("{} expects strings, found {}", operator, type_name(value)))
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "list",
+        Value::Object(_) => "map",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    /// Evaluates an expression against a message with the given JSON body.
+    fn eval(source: &str, body: &str) -> Result<Value, String> {
+        let headers = HashMap::from([("x-env".to_string(), "prod".to_string())]);
+        let context = Context { headers: &headers, body: body.as_bytes(), content_type: None, exchange: "events", routing_key: "deploy.web" };
+        Expression::parse(source)?.evaluate(&context)
+    }
+
+    #[test]
+    fn operators_follow_their_precedence() {
+        assert_eq!(eval("1 + 2 * 3", "{}"), Ok(json!(7)));
+        assert_eq!(eval("(1 + 2) * 3", "{}"), Ok(json!(9)));
+        assert_eq!(eval("10 - 4 - 3", "{}"), Ok(json!(3)));
+        assert_eq!(eval("true || false && false", "{}"), Ok(json!(true)));
+        assert_eq!(eval("body.a || body.b && body.c", r#"{"a": true, "b": false, "c": false}"#), Ok(json!(true)));
+        assert_eq!(eval("1 + 1 == 2 && -2 < 0", "{}"), Ok(json!(true)));
+        assert_eq!(eval("!false == true", "{}"), Ok(json!(true)));
+    }
+
+    #[test]
+    fn and_and_or_short_circuit() {
+        assert_eq!(eval("has(body.x) && body.x.y > 2", "{}"), Ok(json!(false)));
+        assert_eq!(eval("has(body.x) && body.x.y > 2", r#"{"x": {"y": 3}}"#), Ok(json!(true)));
+        assert_eq!(eval("true || 1 / 0 > 1", "{}"), Ok(json!(true)));
+        // without the short-circuit, the right operand is an error
+        assert!(eval("body.x.y > 2", "{}").is_err());
+        assert!(eval("true && 1", "{}").is_err());
+    }
+
+    #[test]
+    fn missing_fields_are_null() {
+        assert_eq!(eval("body.release.version", "{}"), Ok(Value::Null));
+        assert_eq!(eval("body.hosts[5]", r#"{"hosts": ["a"]}"#), Ok(Value::Null));
+        assert_eq!(eval(r#"headers["x-missing"] == null"#, "{}"), Ok(json!(true)));
+        assert_eq!(eval(r#"headers["x-env"]"#, "{}"), Ok(json!("prod")));
+        assert_eq!(eval("has(body.release)", "{}"), Ok(json!(false)));
+    }
+
+    #[test]
+    fn in_searches_lists_maps_and_strings() {
+        assert_eq!(eval(r#""web" in body.hosts"#, r#"{"hosts": ["db", "web"]}"#), Ok(json!(true)));
+        assert_eq!(eval("2 in [1, 2.0, 3]", "{}"), Ok(json!(true)));
+        assert_eq!(eval(r#""x-env" in headers"#, "{}"), Ok(json!(true)));
+        assert_eq!(eval(r#""version" in {"name": 1}"#, "{}"), Ok(json!(false)));
+        assert_eq!(eval(r#""web" in routing_key"#, "{}"), Ok(json!(true)));
+        assert!(eval("1 in routing_key", "{}").is_err());
+        assert!(eval("1 in 2", "{}").is_err());
+    }
+
+    #[test]
+    fn type_errors_are_reported() {
+        assert_eq!(eval(r#""1" < 2"#, "{}"), Err("cannot compare string and number with <".to_string()));
+        assert_eq!(eval(r#"1 - "a""#, "{}"), Err("- expects numbers, found string".to_string()));
+        assert_eq!(eval("!1", "{}"), Err("! expects booleans, found number".to_string()));
+        assert_eq!(eval("size(1)", "{}"), Err("invalid arguments for size: (number)".to_string()));
+        assert_eq!(eval("nope(1)", "{}"), Err("unknown function nope".to_string()));
+        assert_eq!(eval("nope", "{}"), Err("unknown variable nope".to_string()));
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert_eq!(eval("1 / 0", "{}"), Err("division by zero".to_string()));
+        assert_eq!(eval("1 % 0", "{}"), Err("division by zero".to_string()));
+        assert_eq!(eval("7 % 4 + 1 / 4", "{}"), Ok(json!(3.25)));
+    }
+
+    #[test]
+    fn oversized_expressions_are_rejected() {
+        let long = format!("1{}", " + 1".repeat(MAX_LENGTH / 4));
+        assert!(Expression::parse(&long).unwrap_err().contains("longer than"));
+        let deep = format!("{}1{}", "(".repeat(MAX_DEPTH + 1), ")".repeat(MAX_DEPTH + 1));
+        assert!(Expression::parse(&deep).unwrap_err().contains("nested deeper"));
+        let negated = format!("{}true", "!".repeat(MAX_DEPTH + 1));
+        assert!(Expression::parse(&negated).unwrap_err().contains("nested deeper"));
+        let nested = format!("{}1{}", "(".repeat(MAX_DEPTH / 2), ")".repeat(MAX_DEPTH / 2));
+        assert!(Expression::parse(&nested).is_ok());
+    }
+
+    #[test]
+    fn syntax_errors_have_their_position() {
+        assert_eq!(Expression::parse(r#"body.name == "web"#).unwrap_err(), "unterminated string at position 13");
+        assert_eq!(Expression::parse("'web").unwrap_err(), "unterminated string at position 0");
+        assert!(Expression::parse("1 +").is_err());
+        assert!(Expression::parse("1 2").unwrap_err().contains("position 2"));
+        assert!(Expression::parse("(1").is_err());
+    }
+}
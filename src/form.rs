@@ -0,0 +1,79 @@
+/// Content type of the form-encoded bodies.
+pub const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+/// Maximum size of a form-encoded body.
+const MAX_FORM_SIZE: usize = 64 * 1024;
+
+/// Maximum number of fields of a form.
+const MAX_FIELDS: usize = 100;
+
+/// Checks whether a content type is the form-encoded one, ignoring its parameters (e.g. the charset).
+pub fn is_form(content_type: Option<&str>) -> bool {
+    content_type.and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|content_type| content_type.trim().eq_ignore_ascii_case(FORM_CONTENT_TYPE))
+}
+
+/// Parses a form-encoded body, e.g. "app=web&version=1.2".
+///
+/// The names and values are percent-decoded, and `+` stands for a space. Fields without name are ignored.
+///
+/// @return the fields, in order
+///
+/// # Errors
+///
+/// This function will return an error if the body is larger than 64 KiB, or has more than 100 fields.
+pub fn parse(body: &[u8]) -> Result<Vec<(String, String)>, String> {
+    if body.len() > MAX_FORM_SIZE {
+        return Err(format!("form body of {} bytes, larger than {} bytes", body.len(), MAX_FORM_SIZE));
+    }
+
+    let fields: Vec<(String, String)> = body.split(|b| *b == b'&')
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let mut parts = field.splitn(2, |b| *b == b'=');
+            (decode(parts.next().unwrap_or_default()), decode(parts.next().unwrap_or_default()))
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect();
+    if fields.len() > MAX_FIELDS {
+        return Err(format!("form with {} fields, more than {}", fields.len(), MAX_FIELDS));
+    }
+    Ok(fields)
+}
+
+/// The fields of a form, as a JSON object : a repeated field is an array of its values.
+pub fn to_json(fields: &[(String, String)]) -> serde_json::Value {
+    let mut object = serde_json::Map::new();
+    for (name, value) in fields {
+        match object.get_mut(name) {
+            Some(serde_json::Value::Array(values)) => values.push(value.clone().into()),
+            Some(previous) => *previous = serde_json::Value::Array(vec![previous.clone(), value.clone().into()]),
+            None => { object.insert(name.clone(), value.clone().into()); }
+        }
+    }
+    serde_json::Value::Object(object)
+}
+
+/// Decodes a form-encoded name or value.
+fn decode(encoded: &[u8]) -> String {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.iter();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes.as_slice().get(..2).and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(value) => {
+                        decoded.push(value);
+                        bytes.nth(1);
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            _ => decoded.push(*byte),
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::harehandler::HareError;
+
+/// Name of the file of the disabled handlers, inside the state directory.
+pub const FROZEN_FILE: &str = "frozen.json";
+
+/// Message type of the control message that disables a handler.
+pub const DISABLE_HANDLER: &str = "_hare.disable";
+
+/// Message type of the control message that enables a handler again.
+pub const ENABLE_HANDLER: &str = "_hare.enable";
+
+/// Longest delay before a deferred message of a disabled handler is checked again.
+const RECHECK_DELAY: Duration = Duration::from_secs(30);
+
+/// Handlers disabled at runtime, with the end of their freeze (seconds since epoch, None until enabled again).
+type Frozen = BTreeMap<String, Option<u64>>;
+
+/// Handlers disabled by the operators.
+///
+/// With a state directory, the disabled handlers are kept in the `frozen.json` file : the
+/// `hare disable` and `hare enable` commands update it, and the running instance reads it
+/// for each message, so that a freeze applies at once and survives restarts. Without state
+/// directory, only the control messages can disable handlers, until the instance stops.
+pub struct Freezes {
+    path: Option<PathBuf>,  // file of the disabled handlers, None without state directory
+    frozen: Mutex<Frozen>,  // disabled handlers, without state directory
+}
+
+impl Freezes {
+
+    /// Creates the disabled handlers of a state directory.
+    ///
+    /// @return Freezes
+    ///
+    pub fn new(state_dir: Option<&Path>) -> Self {
+        Freezes { path: state_dir.map(|dir| dir.join(FROZEN_FILE)), frozen: Mutex::new(Frozen::new()) }
+    }
+
+    /// Checks whether a handler is disabled.
+    ///
+    /// @return how long to defer its messages, None if the handler is enabled
+    ///
+    pub fn check(&self, handler: &str) -> Option<Duration> {
+        let frozen = match self.load() {
+            Ok(frozen) => frozen,
+            Err(error) => {
+                log::error!("Could not read the disabled handlers: {}", error);
+                return None;
+            }
+        };
+        match frozen.get(handler)? {
+            None => Some(RECHECK_DELAY),
+            Some(until) => {
+                let remaining = Duration::from_secs(until.saturating_sub(now()));
+                (!remaining.is_zero()).then(|| remaining.min(RECHECK_DELAY))
+            }
+        }
+    }
+
+    /// Disables a handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - the handler to disable
+    /// * `until` - how long to disable it, None to disable it until it is enabled again
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the disabled handlers cannot be read or written.
+    pub fn disable(&self, handler: &str, until: Option<Duration>) -> Result<(), HareError> {
+        self.update(|frozen| {
+            frozen.insert(handler.to_string(), until.map(|until| now() + until.as_secs()));
+        })
+    }
+
+    /// Enables a disabled handler again.
+    ///
+    /// @return whether the handler was disabled
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the disabled handlers cannot be read or written.
+    pub fn enable(&self, handler: &str) -> Result<bool, HareError> {
+        self.update(|frozen| frozen.remove(handler).is_some())
+    }
+
+    /// Handles a disable or enable control message, the handler is given in the `handler` header,
+    /// and the duration of a freeze in the optional `until` header (e.g. "2h").
+    ///
+    /// @return the details of the execution of the control message
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a header is missing or invalid, or if the
+    /// disabled handlers cannot be updated.
+    pub fn control(&self, message_type: &str, headers: &HashMap<String, String>) -> Result<serde_json::Value, HareError> {
+        let handler = headers.get("handler")
+            .ok_or_else(|| HareError::ConfigError("missing handler header".to_string()))?;
+        if message_type == DISABLE_HANDLER {
+            let until = match headers.get("until") {
+                Some(until) => Some(humantime::parse_duration(until)
+                    .map_err(|error| HareError::ConfigError(format!("invalid until header {:?}: {}", until, error)))?),
+                None => None,
+            };
+            self.disable(handler, until)?;
+            log::warn!("Handler {} disabled{}", handler, until.map(|until| format!(" for {}", humantime::format_duration(until))).unwrap_or_default());
+            Ok(serde_json::json!({ "handler": handler, "disabled": true, "until": until.map(|until| humantime::format_duration(until).to_string()) }))
+        } else {
+            self.enable(handler)?;
+            log::warn!("Handler {} enabled", handler);
+            Ok(serde_json::json!({ "handler": handler, "disabled": false }))
+        }
+    }
+
+    /// The disabled handlers whose freeze has not ended.
+    ///
+    /// @return the disabled handlers, with the end of their freeze
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the disabled handlers cannot be read.
+    pub fn list(&self) -> Result<Vec<(String, Option<SystemTime>)>, HareError> {
+        Ok(self.load()?.into_iter()
+            .map(|(handler, until)| (handler, until.map(|until| UNIX_EPOCH + Duration::from_secs(until))))
+            .filter(|(_, until)| until.is_none_or(|until| until > SystemTime::now()))
+            .collect())
+    }
+
+    /// Reads the disabled handlers, from the state directory or from memory.
+    fn load(&self) -> Result<Frozen, HareError> {
+        let frozen = self.frozen.lock().unwrap();
+        let Some(path) = &self.path else { return Ok(frozen.clone()) };
+        match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|error| HareError::StateError(format!("invalid {}: {}", FROZEN_FILE, error))),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Frozen::new()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Updates the disabled handlers, and writes them atomically without the ended freezes.
+    fn update<R>(&self, change: impl FnOnce(&mut Frozen) -> R) -> Result<R, HareError> {
+        let mut frozen = self.load()?;
+        let result = change(&mut frozen);
+        let now = now();
+        frozen.retain(|_, until| until.is_none_or(|until| until > now));
+        match &self.path {
+            Some(path) => {
+                let tmp = path.with_extension("json.tmp");
+                fs::write(&tmp, serde_json::to_vec_pretty(&frozen).unwrap_or_default())?;
+                fs::rename(&tmp, path)?;
+            }
+            None => *self.frozen.lock().unwrap() = frozen,
+        }
+        Ok(result)
+    }
+}
+
+/// Current time, in seconds since epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
@@ -0,0 +1,122 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::harehandler::HareError;
+
+/// Name of the file of the pid of the consuming process, inside the state directory.
+pub const PID_FILE: &str = "hare.pid";
+
+/// How often a process taking over reads the running jobs of the previous one.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handover of the consumption from a running hare process to a new one, e.g. an upgraded binary.
+///
+/// The process consuming the queue is recorded in the `hare.pid` file of the state directory,
+/// and keeps the number of its running jobs in a `running-<pid>` file. A new process starts
+/// consuming first, then takes over : it records itself, and sends SIGTERM to the previous
+/// process, which stops consuming and finishes its running jobs (see `shutdown`). There is no
+/// gap in the consumption, and the broker delivers each message to one of the processes only.
+/// Until the previous process exits, the new one runs at most HARE_CONCURRENCY jobs minus the
+/// jobs still running in the previous one, so that the host never runs more jobs than configured.
+pub struct Handover {
+    dir: PathBuf,                       // state directory
+    previous: Mutex<Option<u32>>,       // previous process, while it drains
+    recorded: Mutex<Option<usize>>,     // running jobs last recorded for this process
+}
+
+impl Handover {
+
+    /// Creates the handover of a state directory.
+    ///
+    /// @return Handover
+    ///
+    pub fn new(state_dir: &Path) -> Self {
+        Handover { dir: state_dir.to_path_buf(), previous: Mutex::new(None), recorded: Mutex::new(None) }
+    }
+
+    /// Takes over from the previous process, once this one consumes : records this process, and
+    /// asks the previous one to drain and exit.
+    ///
+    /// @return the pid of the previous process, None if no other hare process was consuming
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the pid file cannot be written, or the previous process cannot be signalled.
+    pub fn take_over(&self) -> Result<Option<u32>, HareError> {
+        let own = std::process::id();
+        let previous = fs::read_to_string(self.dir.join(PID_FILE)).ok()
+            .and_then(|pid| pid.trim().parse::<u32>().ok())
+            .filter(|pid| *pid != own && is_running_hare(*pid));
+        write_atomically(&self.dir.join(PID_FILE), &own.to_string())?;
+
+        if let Some(pid) = previous {
+            log::info!("Taking over from hare process {}, which finishes its running jobs and exits", pid);
+            // Safety: kill has no memory effect, the pid was checked to be a hare process
+            if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+                return Err(HareError::StateError(format!("could not signal hare process {}: {}", pid, std::io::Error::last_os_error())));
+            }
+            *self.previous.lock().unwrap() = Some(pid);
+        }
+        Ok(previous)
+    }
+
+    /// The jobs still running in the previous process.
+    ///
+    /// @return the number of jobs, 0 once the previous process exited
+    ///
+    pub fn reserved(&self) -> usize {
+        let mut previous = self.previous.lock().unwrap();
+        let Some(pid) = *previous else { return 0 };
+        if !is_running_hare(pid) {
+            log::info!("Hare process {} exited, the handover is complete", pid);
+            *previous = None;
+            return 0;
+        }
+        fs::read_to_string(self.dir.join(running_file(pid))).ok()
+            .and_then(|running| running.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Records the number of jobs running in this process, for a process taking over.
+    pub fn record_running(&self, running: usize) {
+        let mut recorded = self.recorded.lock().unwrap();
+        if *recorded == Some(running) {
+            return;
+        }
+        match write_atomically(&self.dir.join(running_file(std::process::id())), &running.to_string()) {
+            Ok(()) => *recorded = Some(running),
+            Err(error) => log::error!("Could not record the running jobs for a handover: {}", error),
+        }
+    }
+
+    /// Removes the records of this process, when it stops consuming.
+    pub fn release(&self) {
+        let own = std::process::id();
+        let _ = fs::remove_file(self.dir.join(running_file(own)));
+        let pid_file = self.dir.join(PID_FILE);
+        if fs::read_to_string(&pid_file).is_ok_and(|pid| pid.trim() == own.to_string()) {
+            let _ = fs::remove_file(pid_file);
+        }
+    }
+}
+
+/// Whether a process is a running hare, rather than a process reusing the pid of a stopped one.
+pub fn is_running_hare(pid: u32) -> bool {
+    let comm = |pid: &str| fs::read_to_string(format!("/proc/{}/comm", pid)).ok();
+    // Safety: kill with signal 0 only checks that the process exists
+    let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    alive && comm(&pid.to_string()).is_some_and(|name| Some(name) == comm("self"))
+}
+
+/// Name of the file of the running jobs of a process, inside the state directory.
+fn running_file(pid: u32) -> String {
+    format!("running-{}", pid)
+}
+
+/// Writes a small file atomically.
+fn write_atomically(path: &Path, content: &str) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,130 @@
+use crate::harehandler::HareError;
+
+/// Normalization of the header names, for publishers that do not agree on them
+/// (e.g. "Type", "TYPE" and "x-type").
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeaderNormalization {
+    pub case: bool,         // match names case-insensitively (names are lowercased)
+    pub dashes: bool,       // make '_' and '-' equivalent (underscores become dashes)
+    pub x_prefix: bool,     // strip the "x-" prefix
+}
+
+impl HeaderNormalization {
+
+    /// Parses the normalization rules.
+    ///
+    /// The rules are separated by commas : `case`, `dashes` and `x-prefix`, or `all`.
+    ///
+    /// @return Result<HeaderNormalization, HareError>
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a rule is unknown.
+    pub fn parse(spec: &str) -> Result<Self, HareError> {
+        let mut normalization = HeaderNormalization::default();
+        for rule in spec.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            match rule {
+                "case" => normalization.case = true,
+                "dashes" => normalization.dashes = true,
+                "x-prefix" => normalization.x_prefix = true,
+                "all" => normalization = HeaderNormalization { case: true, dashes: true, x_prefix: true },
+                _ => return Err(HareError::ConfigError(format!("unknown header normalization \"{}\"", rule))),
+            }
+        }
+        Ok(normalization)
+    }
+
+    /// Normalizes a header name.
+    ///
+    /// The same normalization is applied to the names hare looks up (like the handler key),
+    /// so that "Type", "TYPE", "x_type" and "x-type" all match "type" with all the rules enabled.
+    ///
+    /// @return the normalized name
+    ///
+    pub fn apply(&self, name: &str) -> String {
+        let mut name = if self.case { name.to_ascii_lowercase() } else { name.to_string() };
+        if self.dashes {
+            name = name.replace('_', "-");
+        }
+        if self.x_prefix && name.len() > 2 && matches!(name.get(..2), Some("x-" | "X-" | "x_" | "X_")) {
+            name = name[2..].to_string();
+        }
+        name
+    }
+}
+
+/// Where the message type, selecting the handler, is read from (HARE_DISPATCH).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DispatchMode {
+    #[default]
+    Header,     // the header named by HARE_HANDLER_KEY
+    RoutingKey, // the routing key of the delivery
+    Both,       // the header, or the routing key for the messages without it
+    Body,       // a field of the JSON body, selected by HARE_BODY_TYPE_FIELD
+}
+
+impl DispatchMode {
+
+    /// Parses the dispatch mode : `header`, `routing_key`, `both` or `body`.
+    ///
+    /// @return Result<DispatchMode, HareError>
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the mode is unknown.
+    pub fn parse(value: &str) -> Result<Self, HareError> {
+        match value {
+            "header" => Ok(DispatchMode::Header),
+            "routing_key" => Ok(DispatchMode::RoutingKey),
+            "both" => Ok(DispatchMode::Both),
+            "body" => Ok(DispatchMode::Body),
+            _ => Err(HareError::ConfigError(format!("unknown dispatch mode \"{}\", expected header, routing_key, both or body", value))),
+        }
+    }
+
+    /// Whether the handler key header gives the message type of a message, rather than its routing key or body.
+    ///
+    /// # Arguments
+    ///
+    /// * `has_header` - whether the message has the handler key header
+    ///
+    pub fn uses_header(&self, has_header: bool) -> bool {
+        match self {
+            DispatchMode::Header => true,
+            DispatchMode::RoutingKey | DispatchMode::Body => false,
+            DispatchMode::Both => has_header,
+        }
+    }
+}
+
+/// The message type given by a routing key.
+///
+/// With `subdirectories`, the words of the key select a script in the subdirectories of the
+/// script roots : "deploy.web" runs `deploy/web`.
+///
+/// @return the message type
+///
+pub fn routing_key_type(routing_key: &str, subdirectories: bool) -> String {
+    match subdirectories {
+        true => routing_key.replace('.', "/"),
+        false => routing_key.to_string(),
+    }
+}
+
+/// The message type given by a field of a JSON body.
+///
+/// # Arguments
+///
+/// * `body` - the message body
+/// * `pointer` - the JSON pointer of the field, e.g. "/event/type"
+///
+/// @return the message type, None if the body is not JSON, or the field is missing or is not a string or a number
+///
+pub fn body_type(body: &[u8], pointer: &str) -> Option<String> {
+    let body: serde_json::Value = serde_json::from_slice(body).ok()?;
+    match body.pointer(pointer)? {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
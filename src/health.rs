@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often the consumer loop reports that it is alive.
+pub const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Default time without a turn of the consumer loop past which hare is considered wedged.
+pub const DEFAULT_LIVENESS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Health of the instance, served by the `/healthz` and `/readyz` endpoints.
+///
+/// The consumer loop (or the spool loop, in agent mode) updates it : the connection to the broker,
+/// whether the consumers are running, the last message taken, and the last turn of the loop. A loop
+/// that stops turning, e.g. blocked by a stuck future, leaves the last turn behind, and the instance
+/// is reported as not alive.
+pub struct Health {
+    broker: AtomicBool,         // whether the instance consumes from a broker, rather than a spool
+    connected: AtomicBool,      // whether the broker connection is open
+    consuming: AtomicBool,      // whether the consumers (or the spool loop) are running
+    stopping: AtomicBool,       // whether a shutdown was requested
+    last_message: AtomicU64,    // time of the last message taken, in ms since epoch, 0 if none
+    last_turn: Mutex<Instant>,  // last turn of the loop
+    started: Instant,           // start of the instance
+    liveness_timeout: Duration, // time without a turn of the loop past which the instance is not alive
+}
+
+impl Health {
+
+    /// Creates the health of an instance starting now : alive, but not ready.
+    ///
+    /// @return Health
+    ///
+    pub fn new(liveness_timeout: Duration) -> Self {
+        Health {
+            broker: AtomicBool::new(false),
+            connected: AtomicBool::new(false),
+            consuming: AtomicBool::new(false),
+            stopping: AtomicBool::new(false),
+            last_message: AtomicU64::new(0),
+            last_turn: Mutex::new(Instant::now()),
+            started: Instant::now(),
+            liveness_timeout,
+        }
+    }
+
+    /// Records the status of the broker connection.
+    pub fn set_connected(&self, connected: bool) {
+        self.broker.store(true, Ordering::SeqCst);
+        self.connected.store(connected, Ordering::SeqCst);
+    }
+
+    /// Records whether the consumers are running.
+    pub fn set_consuming(&self, consuming: bool) {
+        self.consuming.store(consuming, Ordering::SeqCst);
+    }
+
+    /// Records that a shutdown was requested : the instance is no longer ready.
+    pub fn set_stopping(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.consuming.store(false, Ordering::SeqCst);
+    }
+
+    /// Records that a message was taken.
+    pub fn message(&self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        self.last_message.store(now, Ordering::SeqCst);
+    }
+
+    /// Records a turn of the loop.
+    pub fn tick(&self) {
+        *self.last_turn.lock().unwrap() = Instant::now();
+    }
+
+    /// Whether the loop turned within the liveness timeout.
+    pub fn alive(&self) -> bool {
+        self.last_turn.lock().unwrap().elapsed() < self.liveness_timeout
+    }
+
+    /// Whether the instance takes messages : connected (with a broker), consuming, and not stopping.
+    pub fn ready(&self) -> bool {
+        let connected = !self.broker.load(Ordering::SeqCst) || self.connected.load(Ordering::SeqCst);
+        connected && self.consuming.load(Ordering::SeqCst) && !self.stopping.load(Ordering::SeqCst) && self.alive()
+    }
+
+    /// The health report, served by the endpoints.
+    ///
+    /// @return the JSON report, e.g. `{"alive": true, "ready": true, "connected": true, "consuming": true, ...}`
+    ///
+    pub fn report(&self) -> serde_json::Value {
+        let last_message = match self.last_message.load(Ordering::SeqCst) {
+            0 => serde_json::Value::Null,
+            ms => humantime::format_rfc3339_millis(UNIX_EPOCH + Duration::from_millis(ms)).to_string().into(),
+        };
+        let connected = match self.broker.load(Ordering::SeqCst) {
+            true => self.connected.load(Ordering::SeqCst).into(),
+            false => serde_json::Value::Null,
+        };
+        serde_json::json!({
+            "alive": self.alive(),
+            "ready": self.ready(),
+            "connected": connected,
+            "consuming": self.consuming.load(Ordering::SeqCst),
+            "stopping": self.stopping.load(Ordering::SeqCst),
+            "last_message": last_message,
+            "last_loop_secs": self.last_turn.lock().unwrap().elapsed().as_secs(),
+            "uptime_secs": self.started.elapsed().as_secs(),
+        })
+    }
+}
@@ -0,0 +1,375 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use crate::bundle;
+use crate::harehandler::HareError;
+use crate::health::Health;
+use crate::jobs::{self, RecentJobs};
+use crate::metrics::Metrics;
+use crate::receipt::Signer;
+use crate::spool::{JobStatus, Spool};
+
+/// Maximum size of a request head.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Maximum size of the body of a submitted job.
+const MAX_JOB_SIZE: usize = 1024 * 1024;
+
+/// Prefix of the request headers giving the message headers of a submitted job.
+const JOB_HEADER_PREFIX: &str = "x-hare-";
+
+/// Role of an API token.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Role {
+    Metrics,    // read-only access to the metrics
+    Control,    // access to the metrics and to the control operations
+}
+
+/// A static bearer token of the HTTP endpoints.
+pub struct ApiToken {
+    pub name: String,   // name of the token, written in the audit log
+    pub role: Role,     // what the token gives access to
+    pub secret: String, // value of the token
+}
+
+/// What the HTTP endpoints serve, and who may access them.
+pub struct Endpoints {
+    pub metrics: Arc<Metrics>,  // metrics registry
+    pub tokens: Vec<ApiToken>,  // accepted tokens, no authentication for the metrics if empty
+    pub bundle_dir: PathBuf,    // directory of the installed handler bundles
+    pub signer: Option<Arc<Signer>>, // signer of the audit records, if configured
+    pub spool: Option<Arc<Spool>>,  // spool of the submitted jobs, in agent mode
+    pub health: Arc<Health>,    // health of the instance, for the probes
+    pub jobs: Arc<RecentJobs>,  // latest executions of the instance, and queries of the fleet
+}
+
+/// Parses a list of API tokens.
+///
+/// The tokens are separated by commas, each token is written `name:role:secret`, where role
+/// is `metrics` (read-only) or `control`.
+///
+/// For instance : "prometheus:metrics:0c5e…,ops:control:9f1b…"
+///
+/// @return Result<Vec<ApiToken>, HareError>
+///
+/// # Errors
+///
+/// This function will return an error if a token is malformed or its role is unknown.
+pub fn parse_tokens(spec: &str) -> Result<Vec<ApiToken>, HareError> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .map(|token| {
+            let mut parts = token.splitn(3, ':');
+            let (Some(name), Some(role), Some(secret)) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(HareError::ConfigError("API tokens are written name:role:secret".to_string()));
+            };
+            let role = match role {
+                "metrics" => Role::Metrics,
+                "control" => Role::Control,
+                _ => return Err(HareError::ConfigError(format!("unknown role \"{}\" for API token {}", role, name))),
+            };
+            if secret.is_empty() {
+                return Err(HareError::ConfigError(format!("empty secret for API token {}", name)));
+            }
+            Ok(ApiToken { name: name.to_string(), role, secret: secret.to_string() })
+        })
+        .collect()
+}
+
+/// Serves the hare HTTP endpoints.
+///
+/// This is a minimal HTTP/1.1 server, answering one request per connection :
+///
+/// * `GET /metrics` returns the metrics in the Prometheus text format (role `metrics`),
+/// * `GET /healthz` and `GET /readyz` return the health report, with a 503 status when the instance is
+///   not alive, or not ready (no authentication, for the probes),
+/// * `POST /bundles/<name>/activate/<version>` activates an installed version of a bundle (role `control`),
+/// * `POST /jobs/<handler>` submits a job to the spool, in agent mode (role `control`),
+/// * `GET /jobs/<id>` returns the result of a submitted job, in agent mode (role `control`),
+/// * `GET /jobs` returns the latest executions of the instance (role `control`),
+/// * `GET /fleet/jobs` returns the latest executions of the instances of the cluster, merged (role `control`).
+///
+/// The job lists take the `handler` query parameter, listing the jobs of a handler only.
+///
+/// When tokens are configured, every request must carry one in an `Authorization: Bearer` header.
+/// Without tokens, the metrics are public and the control operations are disabled. Every control
+/// request is written to the audit log (`hare::audit` target), whether it is allowed or not.
+///
+/// @return Result<(), HareError>
+///
+/// # Errors
+///
+/// This function will return an error if the listening address cannot be bound.
+pub async fn serve(address: String, endpoints: Arc<Endpoints>) -> Result<(), HareError> {
+    let listener = TcpListener::bind(&address).await?;
+    log::info!("HTTP endpoints listening on {}", address);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let endpoints = endpoints.clone();
+        tokio::spawn(async move {
+            if let Err(error) = handle_connection(stream, peer, &endpoints).await {
+                log::debug!("HTTP connection error: {}", error);
+            }
+        });
+    }
+}
+
+/// Reads a request, and writes the response.
+async fn handle_connection(mut stream: TcpStream, peer: SocketAddr, endpoints: &Endpoints) -> std::io::Result<()> {
+    let mut buffer = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buffer.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 || buffer.len() + read > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    let head_end = buffer.windows(4).position(|w| w == b"\r\n\r\n").unwrap_or(buffer.len());
+    let head = String::from_utf8_lossy(&buffer[..head_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let parameter = |name: &str| query.split('&').filter_map(|pair| pair.split_once('=')).find(|(key, _)| *key == name).map(|(_, value)| value);
+    let headers: Vec<(&str, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    let header = |name: &str| headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| *value);
+    let bearer = header("authorization").and_then(|value| value.strip_prefix("Bearer ")).map(str::trim);
+
+    // read the request body, for the job submissions
+    let length: usize = header("content-length").and_then(|value| value.parse().ok()).unwrap_or(0);
+    if length > MAX_JOB_SIZE {
+        return respond(&mut stream, "413 Payload Too Large", "text/plain", "payload too large\n").await;
+    }
+    let mut body = buffer[(head_end + 4).min(buffer.len())..].to_vec();
+    while body.len() < length {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    body.truncate(length);
+
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let (status, content_type, body) = match (method, segments.as_slice()) {
+        ("GET", ["metrics"]) => match authorize(endpoints, bearer, Role::Metrics) {
+            Ok(_) => ("200 OK", "text/plain; version=0.0.4", endpoints.metrics.render()),
+            Err(status) => (status, "text/plain", format!("{}\n", status)),
+        },
+        ("GET", ["healthz"]) => health(endpoints.health.alive(), &endpoints.health),
+        ("GET", ["readyz"]) => health(endpoints.health.ready(), &endpoints.health),
+        ("POST", ["bundles", name, "activate", version]) => {
+            let action = format!("activate bundle {} version {}", name, version);
+            match authorize(endpoints, bearer, Role::Control) {
+                Ok(token) => match bundle::activate(&endpoints.bundle_dir, name, version) {
+                    Ok(()) => {
+                        log::info!(target: "hare::audit", "{}", audit_record(endpoints, format!("{} from {} with token {}: done", action, peer, token)));
+                        ("200 OK", "text/plain", format!("activated bundle {} version {}\n", name, version))
+                    }
+                    Err(error) => {
+                        log::info!(target: "hare::audit", "{}", audit_record(endpoints, format!("{} from {} with token {}: failed, {}", action, peer, token, error)));
+                        ("400 Bad Request", "text/plain", format!("{}\n", error))
+                    }
+                },
+                Err(status) => {
+                    log::warn!(target: "hare::audit", "{}", audit_record(endpoints, format!("{} from {}: denied, {}", action, peer, status)));
+                    (status, "text/plain", format!("{}\n", status))
+                }
+            }
+        }
+        ("POST", ["jobs", handler]) if endpoints.spool.is_some() => {
+            let action = format!("submit job for handler {}", handler);
+            match authorize(endpoints, bearer, Role::Control) {
+                Ok(token) => {
+                    // the message headers are given in the X-Hare-<name> request headers
+                    let job_headers = headers.iter()
+                        .filter_map(|(name, value)| {
+                            let name = name.to_ascii_lowercase();
+                            name.strip_prefix(JOB_HEADER_PREFIX).map(|name| (name.to_string(), value.to_string()))
+                        })
+                        .collect();
+                    let submitted = match endpoints.spool.as_ref() {
+                        Some(spool) => spool.submit(handler, job_headers, header("content-type"), &body),
+                        None => Err(HareError::ConfigError("no spool".to_string())),
+                    };
+                    match submitted {
+                        Ok(id) => {
+                            log::info!(target: "hare::audit", "{}", audit_record(endpoints, format!("{} from {} with token {}: job {}", action, peer, token, id)));
+                            ("202 Accepted", "application/json", format!("{}\n", serde_json::json!({ "job": id })))
+                        }
+                        Err(error) => {
+                            log::info!(target: "hare::audit", "{}", audit_record(endpoints, format!("{} from {} with token {}: failed, {}", action, peer, token, error)));
+                            ("500 Internal Server Error", "text/plain", format!("{}\n", error))
+                        }
+                    }
+                }
+                Err(status) => {
+                    log::warn!(target: "hare::audit", "{}", audit_record(endpoints, format!("{} from {}: denied, {}", action, peer, status)));
+                    (status, "text/plain", format!("{}\n", status))
+                }
+            }
+        }
+        ("GET", ["jobs", id]) if endpoints.spool.is_some() => match authorize(endpoints, bearer, Role::Control) {
+            Ok(_) => match endpoints.spool.as_ref().map(|spool| spool.status(id)) {
+                Some(JobStatus::Done(result)) => ("200 OK", "application/json", String::from_utf8_lossy(&result).to_string()),
+                Some(JobStatus::Pending) => ("202 Accepted", "application/json", format!("{}\n", serde_json::json!({ "job": id, "status": "pending" }))),
+                _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+            },
+            Err(status) => (status, "text/plain", format!("{}\n", status)),
+        },
+        ("GET", ["jobs"]) => match authorize(endpoints, bearer, Role::Control) {
+            Ok(_) => ("200 OK", "application/json", format!("{}\n", serde_json::json!({ "jobs": endpoints.jobs.list(parameter("handler")) }))),
+            Err(status) => (status, "text/plain", format!("{}\n", status)),
+        },
+        ("GET", ["fleet", "jobs"]) => match authorize(endpoints, bearer, Role::Control) {
+            Ok(_) => match endpoints.jobs.query_fleet(parameter("handler"), jobs::DEFAULT_QUERY_TIMEOUT).await {
+                Ok(fleet) => ("200 OK", "application/json", format!("{}\n", serde_json::to_string(&fleet).unwrap_or_default())),
+                Err(error) => ("503 Service Unavailable", "text/plain", format!("{}\n", error)),
+            },
+            Err(status) => (status, "text/plain", format!("{}\n", status)),
+        },
+        ("GET", _) | ("POST", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "method not allowed\n".to_string()),
+    };
+
+    respond(&mut stream, status, content_type, &body).await
+}
+
+/// The response of a probe : the health report, with a 503 status when the probe fails.
+fn health(ok: bool, health: &Health) -> (&'static str, &'static str, String) {
+    let status = if ok { "200 OK" } else { "503 Service Unavailable" };
+    (status, "application/json", format!("{}\n", health.report()))
+}
+
+/// Writes a response, and closes the connection.
+async fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> std::io::Result<()> {
+    let challenge = if status.starts_with("401") { "WWW-Authenticate: Bearer\r\n" } else { "" };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status, content_type, body.len(), challenge, body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Checks that a request may use an endpoint requiring a role.
+///
+/// @return the name of the token used, or the HTTP status of the refusal
+///
+fn authorize<'a>(endpoints: &'a Endpoints, bearer: Option<&str>, role: Role) -> Result<&'a str, &'static str> {
+    if endpoints.tokens.is_empty() {
+        // without tokens, only the read-only endpoints are available
+        return if role == Role::Metrics { Ok("anonymous") } else { Err("403 Forbidden") };
+    }
+
+    let bearer = bearer.ok_or("401 Unauthorized")?;
+    let token = endpoints.tokens.iter()
+        .find(|token| ring::constant_time::verify_slices_are_equal(token.secret.as_bytes(), bearer.as_bytes()).is_ok())
+        .ok_or("401 Unauthorized")?;
+    if token.role < role {
+        return Err("403 Forbidden");
+    }
+    Ok(&token.name)
+}
+
+/// An audit record, followed by its signature when a signing key is configured.
+fn audit_record(endpoints: &Endpoints, record: String) -> String {
+    match &endpoints.signer {
+        Some(signer) => signer.sign_line(&record),
+        None => record,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Endpoints accepting the given tokens.
+    fn endpoints(tokens: &str) -> Endpoints {
+        Endpoints {
+            metrics: Arc::new(Metrics::new()),
+            tokens: parse_tokens(tokens).unwrap(),
+            bundle_dir: PathBuf::from("/var/lib/hare/bundles"),
+            signer: None,
+            spool: None,
+            health: Arc::new(Health::new(Duration::from_secs(60))),
+            jobs: Arc::new(RecentJobs::new("test", None)),
+        }
+    }
+
+    #[test]
+    fn tokens_are_parsed() {
+        let tokens = parse_tokens(" prometheus:metrics:s3cr:et , ops:control:9f1b,").unwrap();
+        let tokens: Vec<_> = tokens.iter().map(|token| (token.name.as_str(), token.role, token.secret.as_str())).collect();
+        assert_eq!(tokens, [("prometheus", Role::Metrics, "s3cr:et"), ("ops", Role::Control, "9f1b")]);
+        assert!(parse_tokens("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_tokens_are_refused() {
+        assert!(parse_tokens("prometheus:metrics").is_err());
+        assert!(parse_tokens("ops:admin:9f1b").is_err());
+        assert!(parse_tokens("ops:control:").is_err());
+    }
+
+    #[test]
+    fn without_tokens_only_the_metrics_are_public() {
+        let endpoints = endpoints("");
+        assert_eq!(authorize(&endpoints, None, Role::Metrics), Ok("anonymous"));
+        assert_eq!(authorize(&endpoints, Some("anything"), Role::Control), Err("403 Forbidden"));
+    }
+
+    #[test]
+    fn the_token_must_grant_the_role() {
+        let endpoints = endpoints("prometheus:metrics:m3tr1cs,ops:control:c0ntr0l");
+        assert_eq!(authorize(&endpoints, None, Role::Metrics), Err("401 Unauthorized"));
+        assert_eq!(authorize(&endpoints, Some("wrong"), Role::Metrics), Err("401 Unauthorized"));
+        assert_eq!(authorize(&endpoints, Some("m3tr1c"), Role::Metrics), Err("401 Unauthorized"));
+        assert_eq!(authorize(&endpoints, Some("m3tr1cs"), Role::Metrics), Ok("prometheus"));
+        assert_eq!(authorize(&endpoints, Some("m3tr1cs"), Role::Control), Err("403 Forbidden"));
+        // the control role gives access to the metrics too
+        assert_eq!(authorize(&endpoints, Some("c0ntr0l"), Role::Metrics), Ok("ops"));
+        assert_eq!(authorize(&endpoints, Some("c0ntr0l"), Role::Control), Ok("ops"));
+    }
+
+    #[tokio::test]
+    async fn requests_carry_a_bearer_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let endpoints = endpoints("prometheus:metrics:m3tr1cs");
+        let server = async {
+            for _ in 0..3 {
+                let (stream, peer) = listener.accept().await.unwrap();
+                handle_connection(stream, peer, &endpoints).await.unwrap();
+            }
+        };
+        let request = |head: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(head.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let client = async {
+            let denied = request("GET /metrics HTTP/1.1\r\n\r\n").await;
+            assert!(denied.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+            assert!(denied.contains("WWW-Authenticate: Bearer\r\n"));
+            let allowed = request("GET /metrics HTTP/1.1\r\nAuthorization: Bearer m3tr1cs\r\n\r\n").await;
+            assert!(allowed.starts_with("HTTP/1.1 200 OK\r\n"));
+            let forbidden = request("POST /bundles/web/activate/1.0 HTTP/1.1\r\nauthorization: Bearer m3tr1cs\r\n\r\n").await;
+            assert!(forbidden.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        };
+        tokio::join!(server, client);
+    }
+}
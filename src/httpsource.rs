@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use base64::Engine;
+use serde::Deserialize;
+use crate::config::HareConfig;
+use crate::harehandler::HareError;
+use crate::spool::Spool;
+
+/// Name of the file of the cursor of the HTTP source, inside the state directory.
+pub const CURSOR_FILE: &str = "source-cursor";
+
+/// Default time the job API may hold a poll open, waiting for jobs.
+pub const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+
+/// Time allowed to the job API beyond the wait, for the network and its answer.
+const REQUEST_MARGIN: Duration = Duration::from_secs(10);
+
+/// Shortest time between two polls without jobs, for the APIs answering at once rather than holding the poll.
+const MIN_EMPTY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before polling again after a failed poll, doubled up to `MAX_RETRY_DELAY`.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between failed polls.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Header giving the id of a job in the job API, set on its message.
+pub const SOURCE_ID_HEADER: &str = "source_id";
+
+/// A job, as listed by the job API.
+#[derive(Debug, Deserialize)]
+struct SourceJob {
+    id: Option<String>,                                 // id of the job in the job API
+    #[serde(rename = "type")]
+    handler: String,                                    // message type, name of the handler
+    #[serde(default)]
+    headers: HashMap<String, String>,                   // message headers
+    content_type: Option<String>,                       // content type of the body, if given
+    #[serde(default)]
+    body: Option<String>,                               // body, as text
+    #[serde(default)]
+    body_base64: Option<String>,                        // body, encoded in base64, rather than text
+}
+
+/// An answer of the job API.
+#[derive(Debug, Deserialize)]
+struct Page {
+    #[serde(default)]
+    jobs: Vec<SourceJob>,                               // jobs after the cursor of the poll
+    cursor: Option<String>,                             // cursor after these jobs, the cursor is kept if missing
+}
+
+/// Settings of the HTTP source : a job API long-polled for jobs, in agent mode.
+///
+/// Each poll is a `GET <url>?wait=<seconds>&cursor=<cursor>` request, with a bearer token when
+/// configured. The API holds the request until jobs are available or the wait expires, and answers
+/// `{"jobs": [...], "cursor": "..."}`, or 204 without jobs. The jobs are stored in the spool, then
+/// the cursor is saved in the state directory : a job is run at least once, even across restarts.
+#[derive(Debug, Clone)]
+pub struct HttpSource {
+    url: String,                // URL of the job API
+    token: Option<String>,      // bearer token of the job API
+    wait: Duration,             // time the job API may hold a poll open
+}
+
+impl HttpSource {
+
+    /// Reads the settings of the HTTP source : HARE_HTTP_SOURCE_URL, HARE_HTTP_SOURCE_TOKEN and HARE_HTTP_SOURCE_WAIT.
+    ///
+    /// @return the settings, None when HARE_HTTP_SOURCE_URL is not set
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the URL is not an HTTP URL, or the wait is invalid.
+    pub fn load(config: &HareConfig) -> Result<Option<Self>, String> {
+        let Some(url) = config.get("HARE_HTTP_SOURCE_URL") else { return Ok(None) };
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("invalid HARE_HTTP_SOURCE_URL {:?}, expected an http or https URL", url));
+        }
+        let wait = match config.get("HARE_HTTP_SOURCE_WAIT") {
+            Some(value) => humantime::parse_duration(&value).map_err(|_| format!("invalid HARE_HTTP_SOURCE_WAIT {:?}", value))?,
+            None => DEFAULT_WAIT,
+        };
+        Ok(Some(HttpSource { url, token: config.get("HARE_HTTP_SOURCE_TOKEN"), wait }))
+    }
+
+    /// Polls the job API and stores its jobs in the spool, until the task is dropped.
+    ///
+    /// A poll that fails is logged and tried again, after a delay growing up to a minute.
+    ///
+    /// # Arguments
+    ///
+    /// * `spool` - the spool receiving the jobs
+    /// * `state_dir` - the state directory, keeping the cursor
+    ///
+    pub async fn run(self, spool: Arc<Spool>, state_dir: PathBuf) {
+        let cursor_path = state_dir.join(CURSOR_FILE);
+        let mut cursor = fs::read_to_string(&cursor_path).ok().map(|cursor| cursor.trim().to_string()).filter(|cursor| !cursor.is_empty());
+        let mut retry_delay = RETRY_DELAY;
+        log::info!("Polling jobs from {}", self.url);
+        loop {
+            let started = Instant::now();
+            let page = {
+                let source = self.clone();
+                let cursor = cursor.clone();
+                tokio::task::spawn_blocking(move || source.poll(cursor.as_deref())).await
+                    .map_err(|error| HareError::SourceError(error.to_string()))
+                    .and_then(|page| page)
+            };
+            let page = match page {
+                Ok(page) => page,
+                Err(error) => {
+                    log::error!("Could not poll the jobs from {}, retrying in {}: {}", self.url, humantime::format_duration(retry_delay), error);
+                    tokio::time::sleep(retry_delay).await;
+                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                    continue;
+                }
+            };
+            retry_delay = RETRY_DELAY;
+            let Some(page) = page.filter(|page| !page.jobs.is_empty() || page.cursor.is_some()) else {
+                tokio::time::sleep(MIN_EMPTY_POLL_INTERVAL.saturating_sub(started.elapsed())).await;
+                continue;
+            };
+
+            let empty = page.jobs.is_empty();
+            let mut stored = true;
+            for job in page.jobs {
+                if let Err(error) = Self::submit(&spool, job) {
+                    log::error!("Could not store a job from {}: {}", self.url, error);
+                    stored = false;
+                    break;
+                }
+            }
+            if !stored {
+                // the cursor is kept, the jobs are listed again by the next poll
+                tokio::time::sleep(retry_delay).await;
+                continue;
+            }
+            if let Some(next) = page.cursor.filter(|next| Some(next) != cursor.as_ref()) {
+                if let Err(error) = save_cursor(&cursor_path, &next) {
+                    log::error!("Could not save the cursor of the job source: {}", error);
+                }
+                cursor = Some(next);
+            }
+            if empty {
+                tokio::time::sleep(MIN_EMPTY_POLL_INTERVAL.saturating_sub(started.elapsed())).await;
+            }
+        }
+    }
+
+    /// Polls the job API once.
+    ///
+    /// @return the jobs after the cursor, None when the API answered 204
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails, or the answer is invalid.
+    fn poll(&self, cursor: Option<&str>) -> Result<Option<Page>, HareError> {
+        let agent = ureq::AgentBuilder::new().timeout(self.wait + REQUEST_MARGIN).build();
+        let mut request = agent.get(&self.url).query("wait", &self.wait.as_secs().to_string());
+        if let Some(cursor) = cursor {
+            request = request.query("cursor", cursor);
+        }
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+        let response = request.call().map_err(|error| HareError::SourceError(error.to_string()))?;
+        if response.status() == 204 {
+            return Ok(None);
+        }
+        let content = response.into_string()?;
+        let page = serde_json::from_str(&content).map_err(|error| HareError::SourceError(format!("invalid answer: {}", error)))?;
+        Ok(Some(page))
+    }
+
+    /// Stores a job of the job API in the spool.
+    ///
+    /// A job whose body is not valid base64 is logged and skipped, as it would be listed again forever.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the job cannot be written.
+    fn submit(spool: &Spool, job: SourceJob) -> Result<(), HareError> {
+        let body = match (job.body_base64, job.body) {
+            (Some(encoded), _) => match base64::engine::general_purpose::STANDARD.decode(encoded) {
+                Ok(body) => body,
+                Err(error) => {
+                    log::error!("Job {} of handler {} skipped, invalid body_base64: {}", job.id.as_deref().unwrap_or("without id"), job.handler, error);
+                    return Ok(());
+                }
+            },
+            (None, Some(body)) => body.into_bytes(),
+            (None, None) => Vec::new(),
+        };
+        let mut headers = job.headers;
+        if let Some(id) = &job.id {
+            headers.insert(SOURCE_ID_HEADER.to_string(), id.clone());
+        }
+        let id = spool.submit(&job.handler, headers, job.content_type.as_deref(), &body)?;
+        log::info!("Job {} of handler {} received from the job source{}", id, job.handler,
+                   job.id.map(|source_id| format!(" (id {})", source_id)).unwrap_or_default());
+        Ok(())
+    }
+}
+
+/// Saves the cursor of the job source atomically.
+fn save_cursor(path: &Path, cursor: &str) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, cursor)?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,98 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use crate::handover;
+use crate::harehandler::HareError;
+
+/// Name of the directory of the jobs acknowledged before their execution, inside the state directory.
+pub const INFLIGHT_DIR: &str = "inflight";
+
+/// A job whose message was acknowledged before its execution.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InflightJob {
+    pub job: String,        // job id
+    pub handler: String,    // message type, name of the handler
+    pub started_at: u64,    // start of the execution, in seconds since epoch
+    #[serde(default)]
+    pub pid: u32,           // process running the job, 0 if not recorded
+}
+
+/// Records the jobs of the handlers acknowledging their messages early, while they run.
+///
+/// A message acknowledged before its execution is gone from the broker : if hare stops during the
+/// execution, the job is lost (at-most-once delivery). Each such job is recorded in its own file of
+/// the inflight directory while it runs, so that the jobs lost this way are found, and reported,
+/// when hare starts again.
+pub struct Inflight {
+    dir: Option<PathBuf>,   // inflight directory, None without state directory
+}
+
+impl Inflight {
+
+    /// Creates the record, unavailable without state directory.
+    ///
+    /// @return Inflight
+    ///
+    pub fn new(state_dir: Option<&str>) -> Self {
+        Inflight { dir: state_dir.map(|dir| Path::new(dir).join(INFLIGHT_DIR)) }
+    }
+
+    /// Whether the jobs can be recorded, i.e. a state directory is configured.
+    pub fn available(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /// Records a job before its message is acknowledged.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if there is no state directory, or the job could not be written.
+    pub fn record(&self, job: &str, handler: &str) -> Result<(), HareError> {
+        let Some(dir) = &self.dir else {
+            return Err(HareError::StateError("no state directory".to_string()));
+        };
+        let started_at = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let entry = InflightJob { job: job.to_string(), handler: handler.to_string(), started_at, pid: std::process::id() };
+
+        fs::create_dir_all(dir)?;
+        let tmp = dir.join(format!(".{}.tmp", job));
+        fs::write(&tmp, serde_json::to_vec(&entry).unwrap_or_default())?;
+        fs::rename(&tmp, dir.join(format!("{}.json", job)))?;
+        Ok(())
+    }
+
+    /// Removes a job once it has run.
+    pub fn complete(&self, job: &str) {
+        let Some(dir) = &self.dir else { return };
+        if let Err(error) = fs::remove_file(dir.join(format!("{}.json", job))) {
+            log::error!("Could not remove the inflight record of job {}: {}", job, error);
+        }
+    }
+
+    /// The jobs left by a previous run, which stopped during their execution.
+    ///
+    /// Entries that cannot be parsed are logged and left in place. The jobs of a hare process still
+    /// running, during a handover, are not abandoned.
+    ///
+    /// @return the abandoned jobs
+    ///
+    pub fn abandoned(&self) -> Vec<InflightJob> {
+        let Some(dir) = &self.dir else { return vec![] };
+        let Ok(entries) = fs::read_dir(dir) else { return vec![] };
+        let mut jobs: Vec<InflightJob> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .filter_map(|path| match fs::read(&path).ok().and_then(|content| serde_json::from_slice(&content).ok()) {
+                Some(job) => Some(job),
+                None => {
+                    log::error!("Invalid inflight entry {}, ignored", path.display());
+                    None
+                }
+            })
+            .filter(|job: &InflightJob| job.pid == 0 || !handover::is_running_hare(job.pid))
+            .collect();
+        jobs.sort_by(|a: &InflightJob, b| a.job.cmp(&b.job));
+        jobs
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Script root reported for the executions of the in-process handlers, in the metrics.
+pub const IN_PROCESS_ROOT: &str = "in-process";
+
+/// A message given to an in-process handler.
+#[derive(Debug, Clone, Copy)]
+pub struct HandlerMessage<'a> {
+    pub handler: &'a str,                       // message type, name of the handler
+    pub headers: &'a HashMap<String, String>,   // message headers
+    pub body: &'a [u8],                         // message body
+    pub content_type: Option<&'a str>,          // content type of the body, if given
+}
+
+/// Future of the execution of an in-process handler.
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<serde_json::Value>, String>> + Send + 'a>>;
+
+/// A handler running inside the hare process, rather than as a script.
+///
+/// The programs embedding hare register their handlers with [`crate::HareHandler::register_handler`].
+/// A message whose type has an in-process handler runs it instead of a script of the same name,
+/// after the checks common to all handlers (disabled handlers, degraded handlers). The execution is
+/// reported like the one of a script : success or failure, and the details of the result.
+///
+/// ```no_run
+/// use hare::{HandlerFuture, HandlerMessage, HareConfig, HareHandler, MessageHandler};
+///
+/// struct Echo;
+///
+/// impl MessageHandler for Echo {
+///     fn handle<'a>(&'a self, message: HandlerMessage<'a>) -> HandlerFuture<'a> {
+///         Box::pin(async move {
+///             Ok(Some(serde_json::json!({ "received": message.body.len() })))
+///         })
+///     }
+/// }
+///
+/// # async fn example() -> Result<(), hare::HareError> {
+/// let mut hare = HareHandler::new(&HareConfig::load(None)?);
+/// hare.register_handler("echo", Echo);
+/// hare.start().await
+/// # }
+/// ```
+pub trait MessageHandler: Send + Sync {
+
+    /// Handles a message.
+    ///
+    /// @return the details of the result, if any
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the handling failed : the execution is reported as
+    /// failed, with the error in the details of its result.
+    fn handle<'a>(&'a self, message: HandlerMessage<'a>) -> HandlerFuture<'a>;
+}
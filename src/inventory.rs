@@ -0,0 +1,112 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use crate::bundle;
+use crate::harehandler::Execution;
+
+/// Message type of the built-in handler that reports the inventory of the instance.
+pub const INVENTORY: &str = "_hare.inventory";
+
+/// Number of failed executions kept for the inventory report.
+const RECENT_FAILURES: usize = 20;
+
+/// Latest failed executions of this instance, oldest first.
+pub struct RecentFailures {
+    failures: Mutex<VecDeque<serde_json::Value>>,
+}
+
+impl RecentFailures {
+
+    /// Creates an empty list of failures.
+    ///
+    /// @return RecentFailures
+    ///
+    pub fn new() -> Self {
+        RecentFailures { failures: Mutex::new(VecDeque::with_capacity(RECENT_FAILURES)) }
+    }
+
+    /// Records an execution, if it failed.
+    pub fn record(&self, execution: &Execution) {
+        if execution.exit_code == Some(0) {
+            return;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() == RECENT_FAILURES {
+            failures.pop_front();
+        }
+        failures.push_back(serde_json::json!({
+            "handler": execution.handler,
+            "exit_code": execution.exit_code,
+            "timestamp": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }));
+    }
+
+    /// The latest failures, oldest first.
+    pub fn list(&self) -> Vec<serde_json::Value> {
+        self.failures.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Lists the handlers available in the script roots.
+///
+/// A handler is an executable script, or a manifest of a handler without script (render or remote
+/// handler). The script roots are searched in order, the first one holding a handler wins, as for
+/// the messages. Each handler is reported with its script root, and the SHA-256 of its script and
+/// of its manifest, so that a controller can tell which version of a handler each instance runs.
+///
+/// @return the handlers, as JSON objects
+///
+pub fn handlers(script_roots: &[String]) -> Vec<serde_json::Value> {
+    let mut seen = HashSet::new();
+    let mut handlers = Vec::new();
+    for root in script_roots {
+        let Ok(entries) = fs::read_dir(root) else { continue };
+        let mut names: Vec<String> = entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let metadata = entry.metadata().ok()?;
+                if let Some(name) = name.strip_suffix(".toml") {
+                    Some(name.to_string())
+                } else if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+                    Some(name)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+
+        for name in names {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            handlers.push(serde_json::json!({
+                "name": name,
+                "script_root": root,
+                "script_sha256": digest(&Path::new(root).join(&name)),
+                "manifest_sha256": digest(&Path::new(root).join(format!("{}.toml", name))),
+            }));
+        }
+    }
+    handlers
+}
+
+/// Lists the installed handler bundles, with their active version.
+///
+/// @return the bundles, as JSON objects
+///
+pub fn bundles(bundle_dir: &Path) -> Vec<serde_json::Value> {
+    bundle::list(bundle_dir).unwrap_or_default().into_iter()
+        .map(|installed| serde_json::json!({ "name": installed.name, "active": installed.active, "versions": installed.versions }))
+        .collect()
+}
+
+/// SHA-256 of a file, hex encoded, None if the file cannot be read.
+fn digest(path: &Path) -> Option<String> {
+    let content = fs::read(path).ok()?;
+    Some(hex::encode(ring::digest::digest(&ring::digest::SHA256, &content).as_ref()))
+}
@@ -0,0 +1,229 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use futures_lite::StreamExt;
+use lapin::options::*;
+use lapin::types::FieldTable;
+use lapin::{BasicProperties, Channel};
+use serde::{Deserialize, Serialize};
+use crate::cluster;
+use crate::harehandler::{Execution, HareError};
+use crate::output;
+
+/// Number of executions kept by each instance.
+const RECENT_JOBS: usize = 200;
+
+/// Name of the file of the recent jobs, in the state directory.
+const JOBS_FILE: &str = "recent-jobs.json";
+
+/// Query of the recent jobs, sent on the control exchange of the cluster.
+pub const JOBS_QUERY: &str = "jobs";
+
+/// Default time given to the instances to answer a query.
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// An execution of a handler, as listed by `hare jobs`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobRecord {
+    pub handler: String,                    // handler of the job
+    pub message_id: String,                 // id of the message, or of the job in agent mode
+    pub correlation_id: Option<String>,     // correlation id of the message, if any
+    pub exit_code: Option<i32>,             // exit code, None if the script was killed by a signal or could not run
+    pub duration_ms: u64,                   // duration of the execution
+    pub timestamp: String,                  // end of the execution (RFC 3339)
+    pub host: String,                       // host of the instance
+    pub instance: String,                   // instance that ran the job
+}
+
+/// The jobs of the fleet, merged from the answers of the instances.
+#[derive(Serialize, Debug, Default)]
+pub struct FleetJobs {
+    pub jobs: Vec<JobRecord>,               // jobs of all the instances, latest first
+    pub instances: Vec<String>,             // instances that answered
+    pub silent: Vec<String>,                // instances seen in the heartbeats that did not answer in time
+}
+
+/// The latest executions of this instance, answering the queries of `hare jobs`.
+///
+/// With a state directory, the list is kept in `recent-jobs.json`, where `hare jobs` reads it
+/// without the broker, and which survives the restarts.
+pub struct RecentJobs {
+    instance: String,                       // id of this instance
+    path: Option<PathBuf>,                  // file of the list, in the state directory
+    jobs: Mutex<VecDeque<JobRecord>>,       // latest executions, oldest first
+    control: Mutex<Option<(Channel, String)>>, // channel and control exchange, once the cluster is joined
+}
+
+impl RecentJobs {
+
+    /// Creates the list of the recent jobs, reading it from the state directory if any.
+    ///
+    /// @return RecentJobs
+    ///
+    pub fn new(instance: &str, state_dir: Option<&Path>) -> Self {
+        let path = state_dir.map(|dir| dir.join(JOBS_FILE));
+        let jobs = path.as_deref().map(read).unwrap_or_default();
+        RecentJobs { instance: instance.to_string(), path, jobs: Mutex::new(jobs.into()), control: Mutex::new(None) }
+    }
+
+    /// Answers the queries of the fleet through the control exchange of the cluster, once joined.
+    pub fn attach(&self, channel: Channel, exchange: &str) {
+        *self.control.lock().unwrap() = Some((channel, exchange.to_string()));
+    }
+
+    /// Records an execution.
+    pub fn record(&self, message_id: &str, correlation_id: Option<&str>, execution: &Execution) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.len() == RECENT_JOBS {
+            jobs.pop_front();
+        }
+        jobs.push_back(JobRecord {
+            handler: execution.handler.clone(),
+            message_id: message_id.to_string(),
+            correlation_id: correlation_id.map(str::to_string),
+            exit_code: execution.exit_code,
+            duration_ms: execution.duration.as_millis() as u64,
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            host: cluster::hostname(),
+            instance: self.instance.clone(),
+        });
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("json.tmp");
+            let written = fs::write(&tmp, serde_json::to_vec(&*jobs).unwrap_or_default()).and_then(|_| fs::rename(&tmp, path));
+            if let Err(error) = written {
+                log::error!("Could not save the recent jobs to {}: {}", path.display(), error);
+            }
+        }
+    }
+
+    /// The latest jobs of this instance, latest first.
+    ///
+    /// @return the jobs, of a handler or of all of them
+    ///
+    pub fn list(&self, handler: Option<&str>) -> Vec<JobRecord> {
+        self.jobs.lock().unwrap().iter().rev()
+            .filter(|job| handler.is_none_or(|handler| job.handler == handler))
+            .cloned()
+            .collect()
+    }
+
+    /// Queries the jobs of the fleet, through the control exchange of the cluster.
+    ///
+    /// @return the jobs of the instances, including this one
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the cluster is not joined, or the broker cannot be used.
+    pub async fn query_fleet(&self, handler: Option<&str>, timeout: Duration) -> Result<FleetJobs, HareError> {
+        let control = self.control.lock().unwrap().clone();
+        let (channel, exchange) = control.ok_or_else(|| HareError::ConfigError("the cluster is not joined, HARE_CLUSTER_EXCHANGE is not set".to_string()))?;
+        query(&channel, &exchange, handler, timeout).await
+    }
+
+    /// Answers a query of the fleet received on the control exchange, with the jobs of this instance.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the answer cannot be published.
+    pub async fn answer(&self, channel: &Channel, query: &serde_json::Value, properties: &BasicProperties) -> Result<(), lapin::Error> {
+        let Some(reply_to) = properties.reply_to() else {
+            log::warn!("Query of the recent jobs without reply queue, ignored");
+            return Ok(());
+        };
+        let body = serde_json::json!({ "instance": self.instance, "jobs": self.list(query["handler"].as_str()) });
+        let mut answer = BasicProperties::default().with_content_type("application/json".into());
+        if let Some(correlation_id) = properties.correlation_id() {
+            answer = answer.with_correlation_id(correlation_id.clone());
+        }
+        channel.basic_publish("", reply_to.as_str(), BasicPublishOptions::default(), body.to_string().as_bytes(), answer).await?;
+        Ok(())
+    }
+}
+
+/// Reads the recent jobs kept in the state directory.
+///
+/// @return the jobs, oldest first, none if the file does not exist or cannot be read
+///
+fn read(path: &Path) -> Vec<JobRecord> {
+    fs::read(path).ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Reads the recent jobs of the instance using a state directory, for `hare jobs`.
+///
+/// @return the jobs, latest first, of a handler or of all of them
+///
+pub fn local(state_dir: &Path, handler: Option<&str>) -> Vec<JobRecord> {
+    read(&state_dir.join(JOBS_FILE)).into_iter().rev()
+        .filter(|job| handler.is_none_or(|handler| job.handler == handler))
+        .collect()
+}
+
+/// Queries the recent jobs of the instances of the cluster, and merges their answers.
+///
+/// The query is published to the control exchange, with a private reply queue, which also follows
+/// the heartbeats to learn the members of the cluster. The answers are collected until every
+/// member seen has answered, once a heartbeat interval went by, or until the timeout : the
+/// members that did not answer are reported as silent.
+///
+/// @return the jobs of the fleet, latest first
+///
+/// # Errors
+///
+/// This function will return an error if the reply queue cannot be declared, or the query cannot be published.
+pub async fn query(channel: &Channel, exchange: &str, handler: Option<&str>, timeout: Duration) -> Result<FleetJobs, HareError> {
+    let replies = channel.queue_declare("", QueueDeclareOptions { exclusive: true, auto_delete: true, ..QueueDeclareOptions::default() }, FieldTable::default()).await?;
+    channel.queue_bind(replies.name().as_str(), exchange, "", QueueBindOptions::default(), FieldTable::default()).await?;
+    let mut consumer = channel.basic_consume(replies.name().as_str(), "", BasicConsumeOptions { no_ack: true, ..BasicConsumeOptions::default() }, FieldTable::default()).await?;
+
+    let correlation_id = output::new_message_id();
+    let body = serde_json::json!({ "query": JOBS_QUERY, "handler": handler });
+    let properties = BasicProperties::default()
+        .with_content_type("application/json".into())
+        .with_reply_to(replies.name().clone())
+        .with_correlation_id(correlation_id.clone().into())
+        .with_expiration(timeout.as_millis().to_string().into());
+    channel.basic_publish(exchange, "", BasicPublishOptions::default(), body.to_string().as_bytes(), properties).await?;
+
+    let started = Instant::now();
+    let mut members = BTreeSet::new();
+    let mut fleet = FleetJobs::default();
+    loop {
+        let answered = fleet.instances.len() >= members.len() && members.iter().all(|member| fleet.instances.contains(member));
+        if answered && started.elapsed() >= cluster::HEARTBEAT_INTERVAL {
+            break;
+        }
+        let Some(remaining) = timeout.checked_sub(started.elapsed()) else { break };
+        let delivery = match tokio::time::timeout(remaining, consumer.next()).await {
+            Ok(Some(delivery)) => delivery?,
+            Ok(None) | Err(_) => break,
+        };
+        let message = serde_json::from_slice::<serde_json::Value>(&delivery.data).unwrap_or_default();
+        let Some(instance) = message["instance"].as_str().map(str::to_string) else { continue };
+        match delivery.properties.correlation_id() {
+            // an answer to the query
+            Some(id) if id.as_str() == correlation_id => {
+                members.insert(instance.clone());
+                if !fleet.instances.contains(&instance) {
+                    let jobs: Vec<JobRecord> = serde_json::from_value(message["jobs"].clone()).unwrap_or_default();
+                    fleet.jobs.extend(jobs);
+                    fleet.instances.push(instance);
+                }
+            }
+            // a heartbeat
+            _ => {
+                members.insert(instance);
+            }
+        }
+    }
+    let _ = channel.queue_delete(replies.name().as_str(), QueueDeleteOptions::default()).await;
+
+    fleet.silent = members.into_iter().filter(|member| !fleet.instances.contains(member)).collect();
+    fleet.instances.sort();
+    // the timestamps are all RFC 3339 in UTC, with milliseconds : they sort as strings
+    fleet.jobs.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    Ok(fleet)
+}
@@ -0,0 +1,95 @@
+//! hare runs handlers for the messages fetched from a RabbitMQ queue.
+//!
+//! The `hare` binary runs shell scripts as handlers. Rust programs can embed hare instead :
+//! [`HareHandler`] consumes the queue with the settings of a [`HareConfig`], and runs the
+//! [`MessageHandler`] registered for a message type in-process, or the script of the same name.
+//! An [`AckPolicy`] decides what to do with the message of each execution.
+//!
+//! The client API publishes messages to hare handlers : [`message::HareMessageBuilder`] builds
+//! messages following the hare header conventions, and [`message::HarePublisher`] publishes them
+//! with publisher confirms.
+
+pub mod message;
+pub mod ackpolicy;
+
+// commands of the hare binary
+pub mod sdk;
+pub mod stats;
+pub mod accounting;
+pub mod freeze;
+pub mod receipt;
+pub mod bench;
+pub mod bundle;
+pub mod expr;
+pub mod jobs;
+
+mod harehandler;
+mod config;
+mod conversion;
+mod headers;
+mod state;
+mod prefetch;
+mod builtins;
+mod naming;
+mod publisher;
+mod outbox;
+mod manifest;
+mod quota;
+mod breaker;
+mod metrics;
+mod http;
+mod logging;
+mod postmortem;
+mod limits;
+mod runas;
+mod render;
+mod remote;
+mod cluster;
+mod output;
+mod contract;
+mod scriptroot;
+mod inventory;
+mod shutdown;
+mod preflight;
+mod spool;
+mod selftest;
+mod reload;
+mod worker;
+mod trace;
+mod deadletter;
+mod transform;
+mod requires;
+mod archive;
+mod inflight;
+mod form;
+mod xml;
+mod control;
+mod providers;
+mod queues;
+mod sla;
+mod rollout;
+mod tls;
+mod scriptmetrics;
+mod after;
+mod health;
+mod shadow;
+mod envdrift;
+mod httpsource;
+mod handover;
+mod inprocess;
+mod describe;
+mod mirror;
+mod window;
+mod status;
+mod topology;
+mod netns;
+mod mountns;
+mod envpolicy;
+mod dedupe;
+mod retry;
+mod slots;
+
+pub use config::HareConfig;
+pub use ackpolicy::{AckDecision, AckPolicy, DeliveryInfo};
+pub use harehandler::{Execution, HareError, HareHandler};
+pub use inprocess::{HandlerFuture, HandlerMessage, MessageHandler};
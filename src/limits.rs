@@ -0,0 +1,50 @@
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use crate::manifest::Limits;
+
+/// Type of the resource argument of setrlimit, which depends on the libc.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type Resource = libc::c_int;
+
+/// Applies the resource limits of a handler to the command running its script.
+///
+/// The limits are set in the child process, just before the script is executed.
+/// The soft limit is set to the configured value; the hard limit is kept, unless it is
+/// below the configured value, in which case it is raised too (which requires privileges).
+pub fn apply(command: &mut Command, limits: &Limits) {
+    let resources: Vec<(Resource, u64)> = [
+        (libc::RLIMIT_NOFILE, limits.nofile),
+        (libc::RLIMIT_NPROC, limits.nproc),
+        (libc::RLIMIT_FSIZE, limits.fsize),
+        (libc::RLIMIT_CORE, limits.core),
+    ].into_iter()
+        .filter_map(|(resource, value)| value.map(|value| (resource, value)))
+        .collect();
+
+    if resources.is_empty() {
+        return;
+    }
+
+    // Safety: the closure only calls getrlimit and setrlimit, which are async-signal-safe,
+    // and does not allocate
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, value) in &resources {
+                let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+                if libc::getrlimit(*resource, &mut limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                limit.rlim_cur = *value as libc::rlim_t;
+                if limit.rlim_max < limit.rlim_cur {
+                    limit.rlim_max = limit.rlim_cur;
+                }
+                if libc::setrlimit(*resource, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,560 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use log::{Level, LevelFilter};
+use crate::harehandler::HareError;
+
+tokio::task_local! {
+    /// Id of the message handled by the current task, added to its log lines.
+    pub static MESSAGE_ID: String;
+}
+
+/// The id of the message handled by the current task, if any.
+pub fn message_id() -> Option<String> {
+    MESSAGE_ID.try_with(String::clone).ok()
+}
+
+/// Format of the log lines of a sink.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,   // [timestamp level target] message
+    Json,   // one JSON object per line
+}
+
+/// Destination of a log sink.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogTarget {
+    Stdout,
+    Stderr,
+    Syslog,
+    Journald,
+    File(String),
+}
+
+/// A log destination, with its own level and format.
+#[derive(Debug, Clone)]
+pub struct LogSink {
+    pub target: LogTarget,
+    pub level: LevelFilter,
+    pub format: LogFormat,
+}
+
+/// Socket of the native protocol of journald.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Journal fields of the key-values of the log records, the other keys are upper-cased.
+const JOURNAL_FIELDS: [(&str, &str); 5] = [
+    ("handler", "HARE_TYPE"),
+    ("job", "HARE_JOB_ID"),
+    ("stream", "HARE_STREAM"),
+    ("exit_code", "EXIT_CODE"),
+    ("message_id", "HARE_MESSAGE_ID"),
+];
+
+/// Default maximum size of a handler log file, before it is rotated.
+pub const DEFAULT_HANDLER_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Number of rotated files kept for each handler log.
+const HANDLER_LOG_KEEP: usize = 5;
+
+/// Default number of occurrences of a warning or error logged per interval, before it is summarized.
+pub const DEFAULT_SAMPLE_BURST: u64 = 10;
+
+/// Default interval of the log sampling.
+pub const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of distinct messages followed by the log sampling, the others are logged as is.
+const MAX_SAMPLED_MESSAGES: usize = 1000;
+
+/// Sampling of the repeated warnings and errors.
+#[derive(Debug, Clone, Copy)]
+pub struct LogSampling {
+    pub burst: u64,         // occurrences of a message logged per interval
+    pub interval: Duration, // interval after which the other occurrences are summarized
+}
+
+/// Parses a log format, `text` or `json` (HARE_LOG_FORMAT).
+///
+/// # Errors
+///
+/// This function will return an error if the format is unknown.
+pub fn parse_format(format: &str) -> Result<LogFormat, String> {
+    match format {
+        "text" => Ok(LogFormat::Text),
+        "json" => Ok(LogFormat::Json),
+        format => Err(format!("unknown log format \"{}\"", format)),
+    }
+}
+
+/// Parses a list of log sinks.
+///
+/// The sinks are separated by commas, each sink is written `destination[:level[:format]]`,
+/// where destination is `stdout`, `stderr`, `syslog`, `journald` or the path of a log file,
+/// level is one of `trace`, `debug` (default), `info`, `warn`, `error` or `off`,
+/// and format is `text` or `json` (default : `default_format`).
+///
+/// For instance : "stdout:info:json,/var/log/hare.log:debug,syslog:warn"
+///
+/// @return Result<Vec<LogSink>, HareError>
+///
+/// # Errors
+///
+/// This function will return an error if a level or a format is unknown.
+pub fn parse_sinks(spec: &str, default_format: LogFormat) -> Result<Vec<LogSink>, HareError> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|sink| !sink.is_empty())
+        .map(|sink| {
+            let mut parts = sink.split(':');
+            let target = match parts.next().unwrap_or_default() {
+                "stdout" => LogTarget::Stdout,
+                "stderr" => LogTarget::Stderr,
+                "syslog" => LogTarget::Syslog,
+                "journald" => LogTarget::Journald,
+                path => LogTarget::File(path.to_string()),
+            };
+            let level = match parts.next() {
+                Some(level) => level.parse::<LevelFilter>()
+                    .map_err(|_| HareError::ConfigError(format!("unknown log level \"{}\" in log sink \"{}\"", level, sink)))?,
+                None => LevelFilter::Debug,
+            };
+            let format = match parts.next() {
+                None => default_format,
+                Some(format) => parse_format(format).map_err(|error| HareError::ConfigError(format!("{} in log sink \"{}\"", error, sink)))?,
+            };
+            Ok(LogSink { target, level, format })
+        })
+        .collect()
+}
+
+/// Configures the logger to write to all the given sinks.
+///
+/// The execution logs of the handlers are also written to their own files, when `handler_logs`
+/// is given. With `sampling`, a warning or error repeated more than its burst within an interval is
+/// collapsed into a summary line, with the number of occurrences left out.
+///
+/// @return Result<(), HareError>
+///
+/// # Errors
+///
+/// This function will return an error if a log file, the syslog or journald cannot be opened,
+/// or if the logger was already configured.
+pub fn configure(sinks: &[LogSink], handler_logs: Option<HandlerLogs>, sampling: Option<LogSampling>) -> Result<(), HareError> {
+    let mut dispatch = fern::Dispatch::new();
+    if let Some(handler_logs) = handler_logs {
+        dispatch = dispatch.chain(Box::new(handler_logs) as Box<dyn log::Log>);
+    }
+
+    for sink in sinks {
+        let mut sink_dispatch = fern::Dispatch::new().level(sink.level);
+
+        // syslog adds its own timestamp and level, journald gets the records as is, their key-values becoming its fields
+        sink_dispatch = match (&sink.target, sink.format) {
+            (LogTarget::Journald, _) => sink_dispatch,
+            (LogTarget::Syslog, LogFormat::Text) => sink_dispatch.format(|out, message, record| {
+                out.finish(format_args!("{} {}", record.target(), message))
+            }),
+            (_, LogFormat::Text) => sink_dispatch.format(|out, message, record| {
+                out.finish(format_args!(
+                    "[{} {} {}{}] {}",
+                    humantime::format_rfc3339_millis(SystemTime::now()),
+                    record.level(),
+                    record.target(),
+                    message_id().map(|id| format!(" {}", id)).unwrap_or_default(),
+                    message
+                ))
+            }),
+            (_, LogFormat::Json) => sink_dispatch.format(|out, message, record| {
+                let mut line = serde_json::json!({
+                    "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": message.to_string(),
+                });
+                // structured fields of the record, e.g. the job and stream of script output lines
+                let mut fields = JsonFields(serde_json::Map::new());
+                if let Some(id) = message_id() {
+                    fields.0.insert("message_id".to_string(), serde_json::Value::String(id));
+                }
+                let _ = record.key_values().visit(&mut fields);
+                if let Some(line) = line.as_object_mut() {
+                    line.extend(fields.0);
+                }
+                out.finish(format_args!("{}", line))
+            }),
+        };
+
+        sink_dispatch = match &sink.target {
+            LogTarget::Stdout => sink_dispatch.chain(std::io::stdout()),
+            LogTarget::Stderr => sink_dispatch.chain(std::io::stderr()),
+            LogTarget::File(path) => sink_dispatch.chain(fern::log_file(path)?),
+            LogTarget::Syslog => {
+                let formatter = syslog::Formatter3164 {
+                    facility: syslog::Facility::LOG_DAEMON,
+                    hostname: None,
+                    process: "hare".to_string(),
+                    pid: std::process::id(),
+                };
+                let logger = syslog::unix(formatter)
+                    .map_err(|error| HareError::ConfigError(format!("cannot connect to syslog: {}", error)))?;
+                sink_dispatch.chain(logger)
+            }
+            LogTarget::Journald => sink_dispatch.chain(Box::new(Journal::connect()?) as Box<dyn log::Log>),
+        };
+
+        dispatch = dispatch.chain(sink_dispatch);
+    }
+
+    let Some(sampling) = sampling else {
+        dispatch.apply()?;
+        return Ok(());
+    };
+    let (level, logger) = dispatch.into_log();
+    let sampler = Arc::new(Sampler { inner: logger, sampling, seen: Mutex::new(HashMap::new()) });
+    log::set_boxed_logger(Box::new(SampledLogger(sampler.clone())))?;
+    log::set_max_level(level);
+
+    // the summaries are written once the interval of a message is over, even if it does not occur again
+    std::thread::spawn(move || loop {
+        std::thread::sleep(sampling.interval.min(Duration::from_secs(10)));
+        sampler.summarize(false);
+    });
+    Ok(())
+}
+
+/// Logger collapsing the repeated warnings and errors, in front of the sinks.
+///
+/// The occurrences of a message (same level, target and text) are counted per interval : the first
+/// ones (the burst) are logged, the others are left out, and a summary line gives their number once
+/// the interval is over. The records of the audit log and the structured records (the output of the
+/// scripts, the start and end of the jobs) are always logged.
+struct Sampler {
+    inner: Box<dyn log::Log>,   // the sinks
+    sampling: LogSampling,
+    seen: Mutex<HashMap<MessageKey, (Instant, u64)>>, // start of the interval and occurrences, per level, target and message
+}
+
+/// A sampled message : its level, target and text.
+type MessageKey = (Level, String, String);
+
+/// The logger installed, sharing the sampler with the summary thread.
+struct SampledLogger(Arc<Sampler>);
+
+impl Sampler {
+
+    /// Whether a record is logged, or left out and counted.
+    fn admit(&self, record: &log::Record) -> bool {
+        if record.level() > Level::Warn || record.target() == "hare::audit" || record.key_values().count() > 0 {
+            return true;
+        }
+        let key = (record.level(), record.target().to_string(), record.args().to_string());
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.contains_key(&key) && seen.len() >= MAX_SAMPLED_MESSAGES {
+            return true;
+        }
+        let (since, count) = seen.entry(key.clone()).or_insert((Instant::now(), 0));
+        if since.elapsed() >= self.sampling.interval {
+            // the interval is over : its summary is written before this occurrence, which starts another
+            let summary = (key, *since, *count);
+            *since = Instant::now();
+            *count = 1;
+            drop(seen);
+            self.write_summary(summary);
+            return true;
+        }
+        *count += 1;
+        *count <= self.sampling.burst
+    }
+
+    /// Writes the summaries of the messages whose interval is over, or of all of them.
+    fn summarize(&self, all: bool) {
+        let expired: Vec<_> = {
+            let mut seen = self.seen.lock().unwrap();
+            let keys: Vec<_> = seen.iter()
+                .filter(|(_, (since, _))| all || since.elapsed() >= self.sampling.interval)
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.into_iter()
+                .filter_map(|key| seen.remove(&key).map(|(since, count)| (key, since, count)))
+                .collect()
+        };
+        for summary in expired {
+            self.write_summary(summary);
+        }
+    }
+
+    /// Writes the summary of an interval, if occurrences were left out.
+    fn write_summary(&self, ((level, target, message), since, count): (MessageKey, Instant, u64)) {
+        if count <= self.sampling.burst {
+            return;
+        }
+        let elapsed = humantime::format_duration(Duration::from_secs(since.elapsed().as_secs()));
+        self.inner.log(&log::Record::builder()
+            .level(level)
+            .target(&target)
+            .args(format_args!("{} (repeated {} more times in the last {})", message, count - self.sampling.burst, elapsed))
+            .build());
+    }
+}
+
+impl log::Log for SampledLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.0.inner.enabled(record.metadata()) && self.0.admit(record) {
+            self.0.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.summarize(true);
+        self.0.inner.flush();
+    }
+}
+
+/// Log sink writing the execution logs of each handler to its own file, `<handler>.log` in a directory.
+///
+/// The execution logs are the records carrying a `handler` field : the start and end of the jobs,
+/// and the output lines of the scripts. A file larger than the maximum size is rotated, to
+/// `<handler>.log.1` and so on, keeping 5 rotated files.
+pub struct HandlerLogs {
+    dir: PathBuf,                               // directory of the handler log files
+    max_size: u64,                              // size over which a file is rotated
+    files: Mutex<HashMap<String, (File, u64)>>, // open files, with their size, per handler
+}
+
+impl HandlerLogs {
+
+    /// Creates the sink, and its directory.
+    ///
+    /// @return HandlerLogs
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the directory cannot be created.
+    pub fn new(dir: &str, max_size: u64) -> Result<Self, HareError> {
+        fs::create_dir_all(dir)?;
+        Ok(HandlerLogs { dir: PathBuf::from(dir), max_size, files: Mutex::new(HashMap::new()) })
+    }
+
+    /// Appends a line to the file of a handler, rotating it first if it would get too large.
+    fn write(&self, handler: &str, line: &str) -> std::io::Result<()> {
+        let path = self.dir.join(format!("{}.log", handler.replace('/', ".")));
+        let mut files = self.files.lock().unwrap();
+        if files.get(handler).is_some_and(|(_, size)| *size > 0 && size + line.len() as u64 > self.max_size) {
+            files.remove(handler);
+            for index in (1..HANDLER_LOG_KEEP).rev() {
+                let _ = fs::rename(path.with_extension(format!("log.{}", index)), path.with_extension(format!("log.{}", index + 1)));
+            }
+            fs::rename(&path, path.with_extension("log.1"))?;
+        }
+        if !files.contains_key(handler) {
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let size = file.metadata()?.len();
+            files.insert(handler.to_string(), (file, size));
+        }
+        if let Some((file, size)) = files.get_mut(handler) {
+            file.write_all(line.as_bytes())?;
+            *size += line.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+impl log::Log for HandlerLogs {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let Some(handler) = record.key_values().get(log::kv::Key::from_str("handler")) else { return };
+        let handler = handler.to_string();
+        // the handler names are script names, but the file name must not escape the directory
+        if handler.is_empty() || !handler.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') || handler.starts_with('.') {
+            return;
+        }
+        let line = format!(
+            "[{} {} {}{}] {}\n",
+            humantime::format_rfc3339_millis(SystemTime::now()),
+            record.level(),
+            record.target(),
+            message_id().map(|id| format!(" {}", id)).unwrap_or_default(),
+            record.args()
+        );
+        if let Err(error) = self.write(&handler, &line) {
+            eprintln!("Could not write the log of handler {}: {}", handler, error);
+        }
+    }
+
+    fn flush(&self) {
+        for (file, _) in self.files.lock().unwrap().values_mut() {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Log sink writing to journald with its native protocol, each record with its structured fields.
+///
+/// The key-values of the records become fields of the journal entries : the output lines of the
+/// scripts get HARE_TYPE (the handler), HARE_JOB_ID and HARE_STREAM, and the end of a job gets
+/// EXIT_CODE, so that `journalctl -t hare HARE_TYPE=deploy` shows the activity of a handler. The
+/// entries have the `hare` identifier, and their priority comes from the level of the record, or
+/// from the stream of an output line : error for the standard error, info for the standard output.
+pub struct Journal {
+    socket: UnixDatagram,   // socket connected to journald
+}
+
+impl Journal {
+
+    /// Connects to journald.
+    ///
+    /// @return Journal
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the socket of journald cannot be reached.
+    pub fn connect() -> Result<Self, HareError> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)
+            .map_err(|error| HareError::ConfigError(format!("cannot connect to journald: {}", error)))?;
+        Ok(Journal { socket })
+    }
+
+    /// Encodes a record as a journal entry.
+    fn entry(record: &log::Record) -> Vec<u8> {
+        let mut fields = JournalFields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        let stream = fields.0.iter().find(|(name, _)| name == "HARE_STREAM").map(|(_, value)| value.as_str());
+        let priority = match (stream, record.level()) {
+            (Some("stderr"), Level::Info | Level::Debug | Level::Trace) => 3,
+            (_, Level::Error) => 3,
+            (_, Level::Warn) => 4,
+            (_, Level::Info) => 6,
+            (_, Level::Debug | Level::Trace) => 7,
+        };
+
+        let mut entry = Vec::new();
+        let mut field = |name: &str, value: &str| {
+            // the values holding a line break are written with their length
+            if value.contains('\n') {
+                entry.extend_from_slice(name.as_bytes());
+                entry.push(b'\n');
+                entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                entry.extend_from_slice(name.as_bytes());
+                entry.push(b'=');
+            }
+            entry.extend_from_slice(value.as_bytes());
+            entry.push(b'\n');
+        };
+        field("MESSAGE", &record.args().to_string());
+        field("PRIORITY", &priority.to_string());
+        field("SYSLOG_IDENTIFIER", "hare");
+        field("HARE_TARGET", record.target());
+        if let Some(file) = record.file() {
+            field("CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            field("CODE_LINE", &line.to_string());
+        }
+        if let Some(id) = message_id() {
+            field("HARE_MESSAGE_ID", &id);
+        }
+        for (name, value) in &fields.0 {
+            field(name, value);
+        }
+        entry
+    }
+}
+
+impl log::Log for Journal {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Err(error) = self.socket.send(&Self::entry(record)) {
+            eprintln!("Could not write to journald: {}", error);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Collects the key-values of a log record as journal fields.
+struct JournalFields(Vec<(String, String)>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JournalFields {
+    fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+        let name = match JOURNAL_FIELDS.iter().find(|(key_name, _)| *key_name == key.as_str()) {
+            Some((_, name)) => name.to_string(),
+            // journal field names are made of upper-case letters, digits and underscores
+            None => key.as_str().chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect(),
+        };
+        let mut field = JsonValue(serde_json::Value::Null);
+        value.visit(&mut field)?;
+        let value = match field.0 {
+            // a missing value, e.g. the exit code of a script killed by a signal, has no field
+            serde_json::Value::Null => return Ok(()),
+            serde_json::Value::String(value) => value,
+            value => value.to_string(),
+        };
+        if !name.starts_with('_') && !name.is_empty() {
+            self.0.push((name, value));
+        }
+        Ok(())
+    }
+}
+
+/// Collects the key-values of a log record as JSON fields.
+struct JsonFields(serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+        let mut field = JsonValue(serde_json::Value::Null);
+        value.visit(&mut field)?;
+        self.0.insert(key.to_string(), field.0);
+        Ok(())
+    }
+}
+
+/// Converts the value of a key-value to JSON : numbers and booleans are kept, missing values are null.
+struct JsonValue(serde_json::Value);
+
+impl<'v> log::kv::VisitValue<'v> for JsonValue {
+    fn visit_any(&mut self, value: log::kv::Value) -> Result<(), log::kv::Error> {
+        self.0 = serde_json::Value::String(value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), log::kv::Error> {
+        self.0 = serde_json::Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), log::kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), log::kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), log::kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), log::kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+}
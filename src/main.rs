@@ -1,13 +1,457 @@
-use crate::harehandler::{HareError, HareHandler};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use clap::{Parser, Subcommand};
+use hare::{accounting, bench, bundle, expr, freeze, jobs, receipt, sdk, stats};
+use hare::{HareConfig, HareError, HareHandler};
+use hare::message::HareMessageBuilder;
 
-mod harehandler;
-mod amqputils;
+/// Runs scripts for the messages fetched from a RabbitMQ queue.
+///
+/// The configuration is read from the HARE_* environment variables, and from the configuration
+/// file (/etc/hare/hare.toml by default), the variables overriding the file. The settings given with
+/// `--set` override both.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Configuration file, instead of /etc/hare/hare.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Setting overriding the environment and the configuration file, written name=value, e.g. amqp_queue=deploy
+    #[arg(long = "set", global = true, value_name = "NAME=VALUE", value_parser = parse_setting)]
+    settings: Vec<(String, String)>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Consume messages and run their handlers (default command)
+    Run,
+
+    /// Run the handlers for the jobs submitted over HTTP, without broker
+    Agent,
+
+    /// Check the configuration, and print the main settings
+    CheckConfig,
+
+    /// List the handlers available in the script roots and the active bundles
+    ListHandlers,
+
+    /// Print the contract of a handler : its documented headers and expected body
+    Describe {
+        handler: String,
+    },
+
+    /// Publish a message to the queue of hare, or to an exchange
+    Publish {
+        /// Message type, the handler of the message
+        #[arg(long = "type")]
+        handler: String,
+
+        /// Header of the message, written name=value
+        #[arg(long = "header", value_parser = parse_header)]
+        headers: Vec<(String, String)>,
+
+        /// File holding the body of the message, - for the standard input
+        #[arg(long)]
+        body: Option<PathBuf>,
+
+        /// Content type of the message
+        #[arg(long)]
+        content_type: Option<String>,
+
+        /// Correlation id of the message, copied to its result
+        #[arg(long)]
+        correlation_id: Option<String>,
+
+        /// Deduplication key of the message, so that publishing it again does not run it twice
+        #[arg(long)]
+        deduplication_id: Option<String>,
+
+        /// Exchange to publish to, instead of the queue of hare
+        #[arg(long)]
+        exchange: Option<String>,
+
+        /// Routing key of the message, with an exchange
+        #[arg(long, default_value = "", requires = "exchange")]
+        routing_key: String,
+    },
+
+    /// Print helpers that scripts can source or import to use the hare environment
+    Sdk {
+        language: sdk::Language,
+    },
+
+    /// Publish synthetic messages, and measure the latency and throughput of the hare instances
+    Bench {
+        /// Message type of the synthetic messages
+        #[arg(long = "type")]
+        handler: String,
+
+        /// Publication rate, e.g. 50/s or 300/m
+        #[arg(long, value_parser = bench::parse_rate, default_value = "10/s")]
+        rate: f64,
+
+        /// How long messages are published, e.g. 60s
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+        duration: Duration,
+
+        /// Additional header of the messages, written name=value
+        #[arg(long = "header", value_parser = parse_header)]
+        headers: Vec<(String, String)>,
+
+        /// File holding the body of the messages
+        #[arg(long)]
+        body: Option<PathBuf>,
+
+        /// Size of a synthetic body of the messages, rather than a file, e.g. 64KiB
+        #[arg(long, value_parser = bench::parse_size, conflicts_with = "body")]
+        size: Option<usize>,
+    },
+
+    /// Print the statistics kept in the state directory
+    Stats,
+
+    /// List the latest jobs of this instance, or of all the instances of the cluster
+    Jobs {
+        /// Query the instances of the cluster through its control exchange, rather than the state directory
+        #[arg(long)]
+        fleet: bool,
+
+        /// List the jobs of this handler only
+        #[arg(long = "type")]
+        handler: Option<String>,
+
+        /// How long the instances have to answer, with --fleet
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "6s", requires = "fleet")]
+        timeout: Duration,
+
+        /// Print the jobs as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Export the resources used by the handlers, per day
+    Accounting {
+        /// First day to export, e.g. 2024-12-01
+        #[arg(long)]
+        from: Option<String>,
+
+        /// Last day to export
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Export format
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+    },
+
+    /// Disable a handler : its messages are deferred until it is enabled again
+    Disable {
+        handler: String,
+
+        /// Enable the handler again after this delay, e.g. 2h
+        #[arg(long, value_parser = humantime::parse_duration)]
+        until: Option<Duration>,
+    },
+
+    /// Enable a disabled handler again
+    Enable {
+        handler: String,
+    },
+
+    /// List the disabled handlers
+    Disabled,
+
+    /// Generate the signing key of the results and audit records, and print its public key
+    Keygen {
+        path: PathBuf,
+    },
+
+    /// Manage the handler bundles
+    Bundle {
+        #[command(subcommand)]
+        command: BundleCommand,
+    },
+
+    /// Work with the expressions used to select messages
+    Expr {
+        #[command(subcommand)]
+        command: ExprCommand,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum ExportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum BundleCommand {
+    /// Install and activate a handler bundle (.tar.zst archive)
+    Install {
+        archive: PathBuf,
+    },
+
+    /// List the installed bundles
+    List,
+
+    /// Remove a bundle, or one of its versions
+    Remove {
+        name: String,
+        version: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ExprCommand {
+    /// Evaluate an expression against a sample message, and print its value
+    Test {
+        expression: String,
+
+        /// Header of the message, written name=value
+        #[arg(long = "header", value_parser = parse_header)]
+        headers: Vec<(String, String)>,
+
+        /// File holding the body of the message, - for the standard input
+        #[arg(long)]
+        body: Option<PathBuf>,
+
+        /// Content type of the message
+        #[arg(long)]
+        content_type: Option<String>,
+
+        /// Exchange the message was published to
+        #[arg(long, default_value = "")]
+        exchange: String,
+
+        /// Routing key of the message
+        #[arg(long, default_value = "")]
+        routing_key: String,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<(), HareError> {
 
-    let hare = HareHandler::new();
-    hare.start().await?;
+    let cli = Cli::parse();
+    let mut config = HareConfig::load(cli.config.as_deref())?;
+    for (name, value) in &cli.settings {
+        config.set(name, value);
+    }
+    let hare = HareHandler::new(&config);
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => hare.start().await?,
+        Command::Agent => hare.agent().await?,
+        Command::CheckConfig => {
+            let settings = hare.check_config()?;
+            match config.path() {
+                Some(path) => println!("configuration is valid (file {})", path.display()),
+                None => println!("configuration is valid (no configuration file)"),
+            }
+            println!("{}", serde_json::to_string_pretty(&settings).unwrap_or_default());
+        }
+        Command::ListHandlers => {
+            for handler in hare.handlers() {
+                let digest = |field: &str| handler[field].as_str().map(|sha| sha[..12].to_string()).unwrap_or_else(|| "-".to_string());
+                println!("{}\tscript root: {}\tscript: {}\tmanifest: {}",
+                         handler["name"].as_str().unwrap_or_default(), handler["script_root"].as_str().unwrap_or_default(),
+                         digest("script_sha256"), digest("manifest_sha256"));
+            }
+        }
+        Command::Describe { handler } => println!("{}", serde_json::to_string_pretty(&hare.describe(&handler)?).unwrap_or_default()),
+        Command::Publish { handler, headers, body, content_type, correlation_id, deduplication_id, exchange, routing_key } => {
+            let mut message = headers.iter()
+                .fold(HareMessageBuilder::new(&handler), |builder, (name, value)| builder.header(name, value));
+            match body {
+                Some(path) if path.as_os_str() == "-" => {
+                    let mut body = Vec::new();
+                    std::io::stdin().read_to_end(&mut body)?;
+                    message = message.body(body);
+                }
+                Some(path) => message = message.body(std::fs::read(path)?),
+                None => {}
+            }
+            if let Some(content_type) = content_type {
+                message = message.content_type(&content_type);
+            }
+            if let Some(correlation_id) = correlation_id {
+                message = message.correlation_id(&correlation_id);
+            }
+            if let Some(deduplication_id) = deduplication_id {
+                message = message.deduplication_id(&deduplication_id);
+            }
+            let destination = hare.publish(message, exchange.as_deref(), &routing_key).await?;
+            println!("published a message of type {} to {}", handler, destination);
+        }
+        Command::Sdk { language } => print!("{}", sdk::helpers(language)),
+        Command::Bench { handler, rate, duration, headers, body, size } => {
+            let body = match (body, size) {
+                (Some(path), _) => std::fs::read(path)?,
+                (None, Some(size)) => vec![b'x'; size],
+                (None, None) => Vec::new(),
+            };
+            hare.bench(&bench::BenchOptions { handler, rate, duration, headers, body }).await?.print();
+        }
+        Command::Stats => stats_command(hare.state_dir())?,
+        Command::Jobs { fleet: true, handler, timeout, json } => {
+            let fleet = hare.fleet_jobs(handler.as_deref(), timeout).await?;
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&fleet).unwrap_or_default()),
+                false => {
+                    print_jobs(&fleet.jobs);
+                    println!("{} instances answered", fleet.instances.len());
+                    if !fleet.silent.is_empty() {
+                        println!("no answer from: {}", fleet.silent.join(", "));
+                    }
+                }
+            }
+        }
+        Command::Jobs { fleet: false, handler, json, .. } => {
+            let state_dir = hare.state_dir().ok_or_else(|| HareError::ConfigError("HARE_STATE_DIR is not set".to_string()))?;
+            let jobs = jobs::local(state_dir, handler.as_deref());
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&jobs).unwrap_or_default()),
+                false => print_jobs(&jobs),
+            }
+        }
+        Command::Accounting { from, to, format } => accounting_command(hare.state_dir(), from, to, format)?,
+        Command::Disable { handler, until } => {
+            freezes(hare.state_dir())?.disable(&handler, until)?;
+            match until {
+                Some(until) => println!("disabled handler {} for {}", handler, humantime::format_duration(until)),
+                None => println!("disabled handler {}", handler),
+            }
+        }
+        Command::Enable { handler } => match freezes(hare.state_dir())?.enable(&handler)? {
+            true => println!("enabled handler {}", handler),
+            false => println!("handler {} was not disabled", handler),
+        },
+        Command::Disabled => {
+            for (handler, until) in freezes(hare.state_dir())?.list()? {
+                let until = until.map(|until| humantime::format_rfc3339_seconds(until).to_string());
+                println!("{}	until: {}", handler, until.as_deref().unwrap_or("-"));
+            }
+        }
+        Command::Keygen { path } => println!("{}", receipt::generate(&path)?),
+        Command::Bundle { command } => bundle_command(hare.bundle_dir(), command)?,
+        Command::Expr { command } => expr_command(command)?,
+    }
+
+    Ok(())
+}
+
+/// Parses a setting given on the command line, written name=value.
+fn parse_setting(value: &str) -> Result<(String, String), String> {
+    value.split_once('=')
+        .filter(|(name, _)| !name.is_empty())
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("invalid setting {:?}, expected name=value", value))
+}
+
+/// Parses a header given on the command line, written name=value.
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    value.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("invalid header {:?}, expected name=value", value))
+}
+
+/// Prints the statistics kept in the state directory.
+fn stats_command(state_dir: Option<&Path>) -> Result<(), HareError> {
+    let state_dir = state_dir.ok_or_else(|| HareError::ConfigError("HARE_STATE_DIR is not set".to_string()))?;
+    let stats = stats::read(state_dir)?;
+    let date = |secs: Option<u64>| secs
+        .map(|secs| humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string())
+        .unwrap_or_else(|| "-".to_string());
+
+    println!("starts: {}\tfirst start: {}\tlast start: {}\tuptime: {}",
+             stats.starts, date(stats.first_start), date(stats.last_start),
+             humantime::format_duration(Duration::from_secs(stats.uptime_secs)));
+    for (handler, handler_stats) in &stats.handlers {
+        println!("{}\texecutions: {}\tfailures: {}\tlast success: {}\tlast failure: {}",
+                 handler, handler_stats.executions, handler_stats.failures,
+                 date(handler_stats.last_success), date(handler_stats.last_failure));
+    }
+    Ok(())
+}
+
+/// Prints a list of jobs, one per line.
+fn print_jobs(jobs: &[jobs::JobRecord]) {
+    for job in jobs {
+        let exit_code = job.exit_code.map(|code| code.to_string());
+        println!("{}\t{}\thost: {}\tinstance: {}\tmessage: {}\tcorrelation: {}\texit code: {}\tduration: {}",
+                 job.timestamp, job.handler, job.host, job.instance, job.message_id, job.correlation_id.as_deref().unwrap_or("-"),
+                 exit_code.as_deref().unwrap_or("-"), humantime::format_duration(Duration::from_millis(job.duration_ms)));
+    }
+}
+
+/// Exports the resources used by the handlers.
+fn accounting_command(state_dir: Option<&Path>, from: Option<String>, to: Option<String>, format: ExportFormat) -> Result<(), HareError> {
+    let state_dir = state_dir.ok_or_else(|| HareError::ConfigError("HARE_STATE_DIR is not set".to_string()))?;
+    let records = accounting::export(state_dir, from.as_deref(), to.as_deref())?;
+    match format {
+        ExportFormat::Csv => {
+            println!("date,handler,executions,wall_seconds,cpu_seconds,output_bytes");
+            for record in records {
+                println!("{},{},{},{:.3},{:.3},{}", record.date, record.handler, record.usage.executions,
+                         record.usage.wall_ms as f64 / 1000.0, record.usage.cpu_ms as f64 / 1000.0, record.usage.output_bytes);
+            }
+        }
+        ExportFormat::Json => println!("{}", serde_json::to_string_pretty(&records).unwrap_or_default()),
+    }
+    Ok(())
+}
+
+/// The handlers disabled in the state directory, read by the running instances.
+fn freezes(state_dir: Option<&Path>) -> Result<freeze::Freezes, HareError> {
+    let state_dir = state_dir.ok_or_else(|| HareError::ConfigError("HARE_STATE_DIR is not set".to_string()))?;
+    std::fs::create_dir_all(state_dir)?;
+    Ok(freeze::Freezes::new(Some(state_dir)))
+}
+
+/// Runs a `hare bundle` command.
+fn bundle_command(bundle_dir: &Path, command: BundleCommand) -> Result<(), HareError> {
+    match command {
+        BundleCommand::Install { archive } => {
+            let description = bundle::install(bundle_dir, &archive)?;
+            println!("installed and activated bundle {} version {} ({})",
+                     description.name, description.version, description.handlers.join(", "));
+        }
+        BundleCommand::List => {
+            for installed in bundle::list(bundle_dir)? {
+                println!("{}\tactive: {}\tinstalled: {}",
+                         installed.name, installed.active.as_deref().unwrap_or("-"), installed.versions.join(", "));
+            }
+        }
+        BundleCommand::Remove { name, version } => {
+            bundle::remove(bundle_dir, &name, version.as_deref())?;
+            match version {
+                Some(version) => println!("removed bundle {} version {}", name, version),
+                None => println!("removed bundle {}", name),
+            }
+        }
+    }
+    Ok(())
+}
 
+/// Runs a `hare expr` command.
+fn expr_command(command: ExprCommand) -> Result<(), HareError> {
+    match command {
+        ExprCommand::Test { expression, headers, body, content_type, exchange, routing_key } => {
+            let expression = expr::Expression::parse(&expression).map_err(HareError::ExpressionError)?;
+            let body = match body {
+                Some(path) => std::fs::read(path)?,
+                None => Vec::new(),
+            };
+            let headers = headers.into_iter().collect();
+            let context = expr::Context { headers: &headers, body: &body, content_type: content_type.as_deref(), exchange: &exchange, routing_key: &routing_key };
+            println!("{}", expression.evaluate(&context).map_err(HareError::ExpressionError)?);
+        }
+    }
     Ok(())
 }
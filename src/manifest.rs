@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use serde::{Deserialize, Deserializer};
+use crate::describe::Description;
+use crate::harehandler::HareError;
+use crate::mountns::FilesystemPolicy;
+use crate::netns::NetworkPolicy;
+use crate::transform::Transform;
+use crate::window::WindowPolicy;
+use crate::xml::{self, XPath};
+
+/// Per-handler settings, read from the optional `<script>.toml` file next to the script.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub quota: Option<QuotaPolicy>,     // usage quota of the handler
+    pub limits: Option<Limits>,         // resource limits of the script
+    pub locale: Option<String>,         // locale of the script (LANG and LC_ALL)
+    pub circuit_breaker: Option<BreakerPolicy>, // circuit breaker of the handler
+    #[serde(default)]
+    pub run_as: Vec<String>,            // users a publisher may ask the script to run as
+    pub render: Option<RenderPolicy>,   // renders a file instead of running a script
+    pub remote: Option<RemoteHost>,     // runs a command on a remote host instead of a local script
+    #[serde(default)]
+    pub requires: Vec<Requirement>,     // binaries and files the handler needs
+    #[serde(default)]
+    pub ack: AckMode,                   // when the message is acknowledged
+    #[serde(default, deserialize_with = "deserialize_xml_rules")]
+    pub xml: BTreeMap<String, XPath>,   // variables extracted from an XML body, e.g. HARE_XML_VERSION = "/release/version/text()"
+    pub selftest: Option<SelfTest>,     // verification run at startup
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub timeout: Option<Duration>,      // how long the script may run, instead of HARE_SCRIPT_TIMEOUT
+    #[serde(default)]
+    pub on_timeout: TimeoutAction,      // what to do with the message of a script that timed out
+    #[serde(default)]
+    pub transform: Vec<Transform>,      // steps transforming the body before the handler gets it
+    pub sla: Option<SlaPolicy>,         // how long after their arrival the messages must start
+    pub rollout: Option<RolloutPolicy>, // how many instances of the cluster may run the handler at the same time
+    pub describe: Option<Description>,  // contract of the handler, for the publishers
+    pub windows: Option<WindowPolicy>,  // when the handler may run, e.g. deploys on weekdays only
+    pub network: Option<NetworkPolicy>, // private network namespace of the script, with its allowed destinations
+    pub filesystem: Option<FilesystemPolicy>, // private mount namespace of the script, with its bind mounts and root
+    #[serde(default)]
+    pub required_headers: Vec<String>,  // headers a message must have, rejected otherwise
+    pub allowed_headers: Option<Vec<String>>, // headers passed to the script, all of them if not set
+    #[serde(default, deserialize_with = "deserialize_concurrency")]
+    pub concurrency: Option<usize>,     // executions of the handler at the same time on this instance
+    pub user: Option<String>,           // user the script runs as, unless the publisher asks for one of `run_as`, instead of HARE_RUN_AS_USER
+    pub group: Option<String>,          // group the script runs as when it switches user, instead of HARE_RUN_AS_GROUP
+    pub stdin: Option<bool>,            // whether the script gets the body on its standard input (default : true)
+}
+
+/// Fleet-wide cap of a handler in cluster mode : the share of the instances running it at the same time.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RolloutPolicy {
+    #[serde(default, deserialize_with = "deserialize_fraction")]
+    pub max_fraction: Option<f64>,      // share of the live instances, e.g. 0.2 for 20%
+    pub max_instances: Option<usize>,   // number of instances
+
+    #[serde(default = "default_defer_delay", deserialize_with = "deserialize_duration")]
+    pub defer_delay: Duration,          // delay before a message waiting for a slot is requeued
+}
+
+/// Service level of a handler : how long after their arrival its messages must start.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SlaPolicy {
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub start_within: Duration,         // e.g. "2m"
+}
+
+/// Verification of a handler, run once at startup.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SelfTest {
+    #[serde(default)]
+    pub command: Vec<String>,           // command to run, defaults to the script with `--hare-selftest`
+
+    #[serde(default = "default_selftest_timeout", deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,              // how long the verification may run
+}
+
+/// A dependency of a handler : a binary (with an optional minimum version) or a file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Requirement {
+    pub binary: Option<String>,         // name of a binary in the PATH, or its path
+    pub file: Option<String>,           // path of a file that must exist
+    pub min_version: Option<String>,    // minimum version of the binary, e.g. "24" or "3.1.2"
+    #[serde(default)]
+    pub version_command: Vec<String>,   // command printing the version, defaults to `<binary> --version`
+}
+
+/// Remote handler : runs a command on a remote host over SSH, instead of a local script.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteHost {
+    pub host: String,                   // remote host name or address
+    pub user: Option<String>,           // remote user, defaults to the ssh configuration
+    pub port: Option<u16>,              // SSH port, defaults to the ssh configuration
+    pub identity: Option<String>,       // path of the private key
+    pub known_hosts: Option<String>,    // path of the known hosts file
+    pub command: Vec<String>,           // command to run, e.g. ["/opt/deploy/run.sh", "--quiet"]
+}
+
+/// Render handler : renders a template to a file with the message values, instead of running a script.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RenderPolicy {
+    pub template: String,               // path of the template, relative to the script root
+    pub destination: String,            // path of the rendered file
+    pub owner: Option<String>,          // owner of the rendered file
+    pub group: Option<String>,          // group of the rendered file
+
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub mode: Option<u32>,              // mode of the rendered file, in octal, e.g. "0640"
+
+    #[serde(default)]
+    pub reload: Vec<String>,            // command run once the file is written, e.g. ["systemctl", "reload", "nginx"]
+}
+
+/// Circuit breaker of a handler, opened after consecutive failures.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BreakerPolicy {
+    pub failures: u32,                  // number of consecutive failures opening the circuit
+
+    #[serde(default = "default_cooldown", deserialize_with = "deserialize_duration")]
+    pub cooldown: Duration,             // how long the circuit stays open before a probe execution
+}
+
+/// Resource limits (ulimits) of a script.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub nofile: Option<u64>,    // maximum number of open files
+    pub nproc: Option<u64>,     // maximum number of processes of the user
+    pub fsize: Option<u64>,     // maximum size of a written file, in bytes
+    pub core: Option<u64>,      // maximum size of a core dump, in bytes (0 disables core dumps)
+}
+
+/// When the message of a handler is acknowledged.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AckMode {
+    #[default]
+    Late,           // after the execution : the message is redelivered if hare stops meanwhile (at-least-once)
+    Early,          // before the execution, for handlers outlasting the consumer ack timeout (at-most-once)
+}
+
+/// Usage quota of a handler over a rolling window.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaPolicy {
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub window: Duration,               // length of the rolling window, e.g. "24h"
+
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub max_runtime: Option<Duration>,  // maximum cumulated runtime over the window, e.g. "30m"
+
+    pub max_executions: Option<usize>,  // maximum number of executions over the window
+
+    #[serde(default)]
+    pub on_exceeded: QuotaAction,       // what to do with messages once the quota is exceeded
+
+    #[serde(default = "default_defer_delay", deserialize_with = "deserialize_duration")]
+    pub defer_delay: Duration,          // delay before a deferred message is requeued
+}
+
+/// What to do with a message when its handler exceeded its quota.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuotaAction {
+    #[default]
+    Defer,          // requeue the message after a delay
+    DeadLetter,     // reject the message, the broker dead-letters it if the queue has a dead letter exchange
+}
+
+/// What to do with the message of a script killed on timeout.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimeoutAction {
+    #[default]
+    DeadLetter,     // reject the message, the broker dead-letters it if the queue has a dead letter exchange
+    Requeue,        // return the message to the queue, to run it again
+}
+
+fn default_defer_delay() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_cooldown() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_selftest_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Loads the manifest of a handler.
+///
+/// @return the manifest, or a default manifest if the handler has no manifest file
+///
+/// # Errors
+///
+/// This function will return an error if the manifest file cannot be read or is invalid.
+pub fn load(script_root: &str, handler: &str) -> Result<Manifest, HareError> {
+    let path = Path::new(script_root).join(format!("{}.toml", handler));
+    if !path.exists() {
+        return Ok(Manifest::default());
+    }
+
+    let content = std::fs::read_to_string(&path)?;
+    let manifest: Manifest = toml::from_str(&content).map_err(|error| HareError::ConfigError(format!("invalid manifest {}: {}", path.display(), error)))?;
+    if manifest.network.is_some() && manifest.remote.is_some() {
+        return Err(HareError::ConfigError(format!("invalid manifest {}: the network of a remote handler cannot be isolated", path.display())));
+    }
+    if let Some(filesystem) = &manifest.filesystem {
+        if manifest.remote.is_some() {
+            return Err(HareError::ConfigError(format!("invalid manifest {}: the filesystem of a remote handler cannot be isolated", path.display())));
+        }
+        filesystem.check().map_err(|error| HareError::ConfigError(format!("invalid manifest {}: {}", path.display(), error)))?;
+    }
+    Ok(manifest)
+}
+
+/// Deserializes a human readable duration, like "90s" or "1h 30m".
+pub fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value).map_err(serde::de::Error::custom)
+}
+
+/// Deserializes an optional human readable duration.
+pub fn deserialize_optional_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    deserialize_duration(deserializer).map(Some)
+}
+
+/// Deserializes a fraction, between 0 (excluded) and 1.
+fn deserialize_fraction<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let value = f64::deserialize(deserializer)?;
+    match value > 0.0 && value <= 1.0 {
+        true => Ok(Some(value)),
+        false => Err(serde::de::Error::custom(format!("{} is not a fraction between 0 and 1", value))),
+    }
+}
+
+/// Deserializes the XPath extraction rules, checking the variable names and the expressions.
+fn deserialize_xml_rules<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, XPath>, D::Error> {
+    let rules = BTreeMap::<String, String>::deserialize(deserializer)?;
+    rules.into_iter()
+        .map(|(variable, expression)| {
+            xml::check_variable(&variable).map_err(serde::de::Error::custom)?;
+            Ok((variable, XPath::parse(&expression).map_err(serde::de::Error::custom)?))
+        })
+        .collect()
+}
+
+/// Deserializes a concurrency limit, at least 1.
+fn deserialize_concurrency<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    match usize::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("the concurrency of a handler is at least 1")),
+        value => Ok(Some(value)),
+    }
+}
+
+/// Deserializes an octal file mode, like "0640".
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    u32::from_str_radix(&value, 8).map(Some).map_err(serde::de::Error::custom)
+}
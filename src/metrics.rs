@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// Definition of a counter metric.
+pub struct Counter {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+/// Definition of a counter or gauge metric whose value is set, rather than incremented.
+pub struct Gauge {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: &'static str, // "counter" or "gauge"
+}
+
+/// Definition of a histogram metric.
+pub struct Histogram {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub buckets: &'static [f64],
+}
+
+/// Time spent by messages in the queue, from publication to consumption.
+pub const QUEUE_LATENCY: Histogram = Histogram {
+    name: "hare_queue_latency_seconds",
+    help: "Time spent by messages in the queue, from publication to consumption.",
+    buckets: &[0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0],
+};
+
+/// Script executions, per handler and script root.
+pub const EXECUTIONS: Counter = Counter {
+    name: "hare_executions_total",
+    help: "Script executions, per handler and script root.",
+};
+
+/// Messages that did not result in an execution, per reason.
+pub const DROPPED: Counter = Counter {
+    name: "hare_messages_dropped_total",
+    help: "Messages that did not result in an execution, per reason.",
+};
+
+/// Messages that matched no binding, caught by the alternate exchange.
+pub const UNROUTABLE: Counter = Counter {
+    name: "hare_unroutable_messages_total",
+    help: "Messages that matched no binding, caught by the alternate exchange.",
+};
+
+/// Messages that could not be archived, and were deferred.
+pub const ARCHIVE_FAILURES: Counter = Counter {
+    name: "hare_archive_failures_total",
+    help: "Messages that could not be archived, and were deferred.",
+};
+
+/// Scripts killed because they outlasted their timeout, per handler.
+pub const SCRIPT_TIMEOUTS: Counter = Counter {
+    name: "hare_script_timeouts_total",
+    help: "Scripts killed because they outlasted their timeout, per handler.",
+};
+
+/// Scripts whose output was truncated past HARE_OUTPUT_MAX_SIZE, per handler.
+pub const SCRIPT_OUTPUT_TRUNCATED: Counter = Counter {
+    name: "hare_script_output_truncated_total",
+    help: "Scripts whose output was truncated past HARE_OUTPUT_MAX_SIZE, per handler.",
+};
+
+/// Failed messages republished to a delay queue, to run again, per handler (HARE_ON_FAILURE retry).
+pub const RETRIED: Counter = Counter {
+    name: "hare_messages_retried_total",
+    help: "Failed messages republished to a delay queue, to run again, per handler.",
+};
+
+/// Failed messages parked in the parking lot queue after their last attempt, per handler.
+pub const PARKED: Counter = Counter {
+    name: "hare_messages_parked_total",
+    help: "Failed messages parked in the parking lot queue after their last attempt, per handler.",
+};
+
+/// Messages emitted by hare and dropped unpublished, past the caps of the outbox or of the pending buffer, per overflow policy.
+pub const PUBLICATIONS_DROPPED: Counter = Counter {
+    name: "hare_publications_dropped_total",
+    help: "Messages emitted by hare and dropped unpublished, past the caps of the outbox or of the pending buffer.",
+};
+
+/// Messages delivered again with the deduplication key of a message handled already, or running, per outcome (HARE_DEDUPE).
+pub const DUPLICATES: Counter = Counter {
+    name: "hare_duplicates_total",
+    help: "Messages delivered again with the deduplication key of a message handled already, or running, per outcome.",
+};
+
+/// Messages started later after their arrival than the SLA of their handler, per handler.
+pub const SLA_BREACHES: Counter = Counter {
+    name: "hare_sla_breaches_total",
+    help: "Messages started later after their arrival than the SLA of their handler, per handler.",
+};
+
+/// Messages handled by a shadow instance, per outcome.
+pub const SHADOW_OUTCOMES: Counter = Counter {
+    name: "hare_shadow_outcomes_total",
+    help: "Messages handled by a shadow instance, per outcome.",
+};
+
+/// Changes of the environment of the handlers between their runs.
+pub const ENV_CHANGES: Counter = Counter {
+    name: "hare_env_changes_total",
+    help: "Changes of the environment of the handlers between their runs.",
+};
+
+/// Executions per handler, cumulated across restarts.
+pub const HANDLER_EXECUTIONS: Gauge = Gauge {
+    name: "hare_handler_executions_total",
+    help: "Executions per handler, cumulated across restarts.",
+    kind: "counter",
+};
+
+/// Failed executions per handler, cumulated across restarts.
+pub const HANDLER_FAILURES: Gauge = Gauge {
+    name: "hare_handler_failures_total",
+    help: "Failed executions per handler, cumulated across restarts.",
+    kind: "counter",
+};
+
+/// Time of the last successful execution per handler, in seconds since epoch.
+pub const LAST_SUCCESS: Gauge = Gauge {
+    name: "hare_handler_last_success_timestamp_seconds",
+    help: "Time of the last successful execution per handler, in seconds since epoch.",
+    kind: "gauge",
+};
+
+/// Whether a script root is available (1) or not (0), e.g. a network mount that does not respond.
+pub const SCRIPT_ROOT_AVAILABLE: Gauge = Gauge {
+    name: "hare_script_root_available",
+    help: "Whether a script root is available (1) or not (0).",
+    kind: "gauge",
+};
+
+/// Whether the self-test of a handler failed at startup (1) or passed (0).
+pub const HANDLER_DEGRADED: Gauge = Gauge {
+    name: "hare_handler_degraded",
+    help: "Whether the self-test of a handler failed at startup (1) or passed (0).",
+    kind: "gauge",
+};
+
+/// Messages emitted by hare and not confirmed by the broker yet.
+pub const PUBLICATIONS_PENDING: Gauge = Gauge {
+    name: "hare_publications_pending",
+    help: "Messages emitted by hare and not confirmed by the broker yet.",
+    kind: "gauge",
+};
+
+/// Maximum number of series of the metrics reported by the scripts, so that a script cannot exhaust the memory.
+const MAX_SCRIPT_SERIES: usize = 1000;
+
+/// Labels of a metric sample, sorted by name.
+type Labels = Vec<(String, String)>;
+
+/// Observations of a histogram, for a set of labels.
+#[derive(Default)]
+struct HistogramValues {
+    counts: Vec<u64>,   // cumulative count per bucket
+    sum: f64,
+    count: u64,
+}
+
+/// A metric and its values, per set of labels.
+enum Family {
+    Counter { help: &'static str, values: BTreeMap<Labels, f64> },
+    Gauge { help: &'static str, kind: &'static str, values: BTreeMap<Labels, f64> },
+    Histogram { help: &'static str, buckets: &'static [f64], values: BTreeMap<Labels, HistogramValues> },
+}
+
+/// Registry of the hare metrics, rendered in the Prometheus text format.
+pub struct Metrics {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+    script: Mutex<BTreeMap<String, BTreeMap<Labels, f64>>>, // metrics reported by the scripts, per name
+}
+
+impl Metrics {
+
+    /// Creates an empty registry.
+    ///
+    /// @return Metrics
+    ///
+    pub fn new() -> Self {
+        Metrics { families: Mutex::new(BTreeMap::new()), script: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// Increments a counter.
+    pub fn increment(&self, counter: &Counter, labels: &[(&str, &str)]) {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(counter.name).or_insert_with(|| Family::Counter { help: counter.help, values: BTreeMap::new() });
+        if let Family::Counter { values, .. } = family {
+            *values.entry(Self::labels(labels)).or_default() += 1.0;
+        }
+    }
+
+    /// Sets the value of a gauge.
+    pub fn set(&self, gauge: &Gauge, labels: &[(&str, &str)], value: f64) {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(gauge.name).or_insert_with(|| Family::Gauge { help: gauge.help, kind: gauge.kind, values: BTreeMap::new() });
+        if let Family::Gauge { values, .. } = family {
+            values.insert(Self::labels(labels), value);
+        }
+    }
+
+    /// Records an observation in a histogram.
+    pub fn observe(&self, histogram: &Histogram, labels: &[(&str, &str)], value: f64) {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(histogram.name).or_insert_with(|| Family::Histogram {
+            help: histogram.help, buckets: histogram.buckets, values: BTreeMap::new()
+        });
+        if let Family::Histogram { buckets, values, .. } = family {
+            let entry = values.entry(Self::labels(labels)).or_default();
+            entry.counts.resize(buckets.len(), 0);
+            for (index, bound) in buckets.iter().enumerate() {
+                if value <= *bound {
+                    entry.counts[index] += 1;
+                }
+            }
+            entry.sum += value;
+            entry.count += 1;
+        }
+    }
+
+    /// Records a metric reported by a script : a counter, incremented by the value, when its name ends
+    /// in `_total`, a gauge, set to the value, otherwise.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the metric would add a series past the maximum number of
+    /// series of the script metrics.
+    pub fn record_script(&self, name: &str, labels: &[(&str, &str)], value: f64) -> Result<(), String> {
+        let mut script = self.script.lock().unwrap();
+        let labels = Self::labels(labels);
+        let known = script.get(name).is_some_and(|values| values.contains_key(&labels));
+        if !known && script.values().map(BTreeMap::len).sum::<usize>() >= MAX_SCRIPT_SERIES {
+            return Err(format!("more than {} series reported by the scripts, {} dropped", MAX_SCRIPT_SERIES, name));
+        }
+        let entry = script.entry(name.to_string()).or_default().entry(labels).or_default();
+        match name.ends_with("_total") {
+            true => *entry += value,
+            false => *entry = value,
+        }
+        Ok(())
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    ///
+    /// @return String
+    ///
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+
+        for (name, family) in families.iter() {
+            match family {
+                Family::Counter { help, values } => {
+                    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+                    for (labels, value) in values {
+                        let _ = writeln!(out, "{}{} {}", name, Self::format_labels(labels, None), value);
+                    }
+                }
+                Family::Gauge { help, kind, values } => {
+                    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+                    for (labels, value) in values {
+                        let _ = writeln!(out, "{}{} {}", name, Self::format_labels(labels, None), value);
+                    }
+                }
+                Family::Histogram { help, buckets, values } => {
+                    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+                    for (labels, value) in values {
+                        for (bound, count) in buckets.iter().zip(value.counts.iter()) {
+                            let _ = writeln!(out, "{}_bucket{} {}", name, Self::format_labels(labels, Some(&bound.to_string())), count);
+                        }
+                        let _ = writeln!(out, "{}_bucket{} {}", name, Self::format_labels(labels, Some("+Inf")), value.count);
+                        let _ = writeln!(out, "{}_sum{} {}", name, Self::format_labels(labels, None), value.sum);
+                        let _ = writeln!(out, "{}_count{} {}", name, Self::format_labels(labels, None), value.count);
+                    }
+                }
+            }
+        }
+        for (name, values) in self.script.lock().unwrap().iter() {
+            let kind = if name.ends_with("_total") { "counter" } else { "gauge" };
+            let _ = writeln!(out, "# HELP {} Reported by the scripts.\n# TYPE {} {}", name, name, kind);
+            for (labels, value) in values {
+                let _ = writeln!(out, "{}{} {}", name, Self::format_labels(labels, None), value);
+            }
+        }
+        out
+    }
+
+    /// Converts labels to their sorted, owned form.
+    fn labels(labels: &[(&str, &str)]) -> Labels {
+        let mut labels: Labels = labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        labels.sort();
+        labels
+    }
+
+    /// Formats labels as `{name="value",...}`, with the optional `le` bucket label.
+    fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+        let mut parts: Vec<String> = labels.iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+            .collect();
+        if let Some(le) = le {
+            parts.push(format!("le=\"{}\"", le));
+        }
+        if parts.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", parts.join(","))
+        }
+    }
+}
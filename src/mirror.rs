@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use lapin::message::Delivery;
+use lapin::types::{AMQPValue, FieldTable, LongString};
+use crate::config::HareConfig;
+use crate::deadletter;
+use crate::harehandler::Outcome;
+use crate::postmortem;
+use crate::publisher::OutgoingMessage;
+
+/// Header giving what hare did with the message : "succeeded", "failed", "skipped", "rejected" or "missing".
+pub const OUTCOME_HEADER: &str = "x-hare-outcome";
+
+/// Header giving the handler of the message.
+pub const HANDLER_HEADER: &str = "x-hare-handler";
+
+/// Header giving the duration of the execution, in milliseconds.
+pub const DURATION_HEADER: &str = "x-hare-duration-ms";
+
+/// Header giving the instance that consumed the message.
+pub const INSTANCE_HEADER: &str = "x-hare-instance";
+
+/// Header giving when the message was handled (RFC 3339).
+pub const HANDLED_AT_HEADER: &str = "x-hare-handled-at";
+
+/// Value replacing the redacted headers and body fields.
+pub const REDACTED: &str = "[redacted]";
+
+/// Copy of the consumed messages to an analytics exchange, with the outcome of their handling.
+///
+/// Each message, once handled, is republished to the mirror exchange with its routing key, its
+/// properties and the outcome headers. A share of the messages is mirrored with HARE_MIRROR_SAMPLE,
+/// evenly spread over the stream of messages. The headers named in HARE_MIRROR_REDACT and those whose
+/// name looks like a secret get a redacted value ; the entries of HARE_MIRROR_REDACT starting with a '/'
+/// are JSON pointers, redacting the fields of a JSON body.
+pub struct Mirror {
+    pub exchange: String,           // mirror exchange (template)
+    sample: f64,                    // share of the messages mirrored, from 0 to 1
+    headers: Vec<String>,           // redacted headers, lowercase
+    pointers: Vec<String>,          // redacted fields of the JSON bodies
+    handled: AtomicU64,             // messages handled, for the sampling
+}
+
+impl Mirror {
+
+    /// Reads the settings of the mirror : HARE_MIRROR_EXCHANGE, HARE_MIRROR_SAMPLE and HARE_MIRROR_REDACT.
+    ///
+    /// @return the mirror, None when HARE_MIRROR_EXCHANGE is not set
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the sample is not a number from 0 to 1.
+    pub fn load(config: &HareConfig) -> Result<Option<Self>, String> {
+        let Some(exchange) = config.get("HARE_MIRROR_EXCHANGE") else { return Ok(None) };
+        let sample = match config.get("HARE_MIRROR_SAMPLE") {
+            Some(value) => value.parse::<f64>().ok().filter(|sample| (0.0..=1.0).contains(sample))
+                .ok_or_else(|| format!("invalid HARE_MIRROR_SAMPLE {:?}, expected a number from 0 to 1", value))?,
+            None => 1.0,
+        };
+        let rules = config.get("HARE_MIRROR_REDACT").unwrap_or_default();
+        let rules: Vec<&str> = rules.split(',').map(str::trim).filter(|rule| !rule.is_empty()).collect();
+        Ok(Some(Mirror {
+            exchange,
+            sample,
+            headers: rules.iter().filter(|rule| !rule.starts_with('/')).map(|rule| rule.to_ascii_lowercase()).collect(),
+            pointers: rules.iter().filter(|rule| rule.starts_with('/')).map(|rule| rule.to_string()).collect(),
+            handled: AtomicU64::new(0),
+        }))
+    }
+
+    /// Whether the next handled message is mirrored, per the sample.
+    pub fn sampled(&self) -> bool {
+        let handled = self.handled.fetch_add(1, Ordering::Relaxed) as f64;
+        ((handled + 1.0) * self.sample).floor() > (handled * self.sample).floor()
+    }
+
+    /// Builds the copy of a handled message.
+    ///
+    /// # Arguments
+    ///
+    /// * `delivery` - the handled message
+    /// * `exchange` - the mirror exchange
+    /// * `instance` - the id of this instance
+    /// * `handler` - the handler of the message, "unknown" if it has none
+    /// * `outcome` - what happened to the message
+    ///
+    /// @return OutgoingMessage
+    ///
+    pub fn message(&self, delivery: &Delivery, exchange: &str, instance: &str, handler: &str, outcome: &Outcome) -> OutgoingMessage {
+        let text = |value: &str| AMQPValue::LongString(LongString::from(value));
+        let mut headers = FieldTable::default();
+        for (key, value) in delivery.properties.headers().as_ref().map(FieldTable::inner).into_iter().flatten() {
+            let redacted = self.headers.contains(&key.as_str().to_ascii_lowercase()) || postmortem::is_secret(key.as_str());
+            headers.insert(key.clone(), if redacted { text(REDACTED) } else { value.clone() });
+        }
+        let status = match outcome {
+            Outcome::Executed(execution) if execution.exit_code == Some(0) => "succeeded",
+            Outcome::Executed(_) => "failed",
+            Outcome::Skipped => "skipped",
+            Outcome::Deferred(_) => "deferred",
+            Outcome::Rejected => "rejected",
+            Outcome::Missing => "missing",
+        };
+        headers.insert(OUTCOME_HEADER.into(), text(status));
+        headers.insert(HANDLER_HEADER.into(), text(handler));
+        headers.insert(INSTANCE_HEADER.into(), text(instance));
+        headers.insert(HANDLED_AT_HEADER.into(), text(&humantime::format_rfc3339_millis(SystemTime::now()).to_string()));
+        if let Outcome::Executed(execution) = outcome {
+            headers.insert(DURATION_HEADER.into(), AMQPValue::LongLongInt(execution.duration.as_millis() as i64));
+            if let Some(exit_code) = execution.exit_code {
+                headers.insert(deadletter::EXIT_CODE_HEADER.into(), AMQPValue::LongInt(exit_code));
+            }
+        }
+
+        OutgoingMessage {
+            exchange: exchange.to_string(),
+            routing_key: delivery.routing_key.to_string(),
+            body: self.redact_body(&delivery.data),
+            properties: delivery.properties.clone().with_headers(headers),
+        }
+    }
+
+    /// Redacts the fields of a JSON body, the other bodies are copied as is.
+    fn redact_body(&self, body: &[u8]) -> Vec<u8> {
+        if self.pointers.is_empty() {
+            return body.to_vec();
+        }
+        let Ok(mut document) = serde_json::from_slice::<serde_json::Value>(body) else { return body.to_vec() };
+        for pointer in &self.pointers {
+            if let Some(field) = document.pointer_mut(pointer) {
+                *field = serde_json::Value::String(REDACTED.to_string());
+            }
+        }
+        serde_json::to_vec(&document).unwrap_or_else(|_| body.to_vec())
+    }
+}
@@ -0,0 +1,261 @@
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use serde::Deserialize;
+use crate::runas::User;
+
+/// Filesystem view of a handler : its script runs in a private mount namespace, with bind mounts of
+/// the directories it may use, and optionally chrooted in a directory.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FilesystemPolicy {
+    pub root: Option<String>,   // directory the script is chrooted in, e.g. "/srv/hare/roots/deploy"
+    #[serde(default)]
+    pub mounts: Vec<Mount>,     // directories and files bound in the view of the script
+}
+
+/// A bind mount of the view of a script.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Mount {
+    pub source: String,         // path on the host
+    pub target: Option<String>, // path in the view, the source path by default
+    #[serde(default)]
+    pub writable: bool,         // whether the script may write to it, read-only by default
+}
+
+/// A bind mount, ready to be done in the child process.
+struct Bind {
+    source: CString,
+    target: CString,            // path of the mount point, under the root directory if any
+    writable: bool,
+}
+
+/// The view of a job, ready to be applied to the command running its script.
+///
+/// The mount point of the job directory is created under the root directory for the job only, and
+/// removed once the view is dropped, with the command.
+pub struct View {
+    root: Option<CString>,
+    binds: Vec<Bind>,
+    job_mount_point: Option<PathBuf>,   // mount point of the job directory, created for the job
+}
+
+impl FilesystemPolicy {
+
+    /// Checks the policy : absolute paths, and an existing root directory.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error with the invalid path.
+    pub fn check(&self) -> Result<(), String> {
+        if let Some(root) = &self.root {
+            if !Path::new(root).is_absolute() || !Path::new(root).is_dir() {
+                return Err(format!("the filesystem root {} is not an absolute path to a directory", root));
+            }
+        }
+        for mount in &self.mounts {
+            for path in std::iter::once(&mount.source).chain(&mount.target) {
+                if !Path::new(path).is_absolute() {
+                    return Err(format!("the mount path {} is not absolute", path));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prepares the view of a job : its bind mounts, and their mount points under the root directory.
+    ///
+    /// With a root directory, the script root is bound read-only and the job directory read-write,
+    /// at the same paths, so that the script and its job files are found in the view.
+    ///
+    /// @return the view of the job
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a source does not exist, or a mount point cannot be created.
+    pub fn prepare(&self, script_root: &str, job_dir: Option<&Path>) -> Result<View, String> {
+        let mut mounts = self.mounts.clone();
+        if self.root.is_some() {
+            mounts.insert(0, Mount { source: script_root.to_string(), target: None, writable: false });
+        }
+
+        let mut view = View { root: None, binds: vec![], job_mount_point: None };
+        for mount in &mounts {
+            view.binds.push(self.bind(mount)?);
+        }
+        if let (Some(root), Some(job_dir)) = (&self.root, job_dir) {
+            let mount = Mount { source: job_dir.display().to_string(), target: None, writable: true };
+            view.binds.push(self.bind(&mount)?);
+            view.job_mount_point = Some(Path::new(root).join(job_dir.strip_prefix("/").unwrap_or(job_dir)));
+        }
+        view.root = match &self.root {
+            Some(root) => Some(c_path(Path::new(root))?),
+            None => None,
+        };
+        Ok(view)
+    }
+
+    /// Prepares a bind mount, creating its mount point under the root directory if needed.
+    fn bind(&self, mount: &Mount) -> Result<Bind, String> {
+        let source = Path::new(&mount.source);
+        let target = Path::new(mount.target.as_deref().unwrap_or(&mount.source));
+        let metadata = fs::metadata(source).map_err(|error| format!("cannot bind {}: {}", source.display(), error))?;
+        let target = match &self.root {
+            Some(root) => {
+                let target = Path::new(root).join(target.strip_prefix("/").unwrap_or(target));
+                let created = match metadata.is_dir() {
+                    true => fs::create_dir_all(&target),
+                    false => target.parent().map_or(Ok(()), fs::create_dir_all)
+                        .and_then(|_| fs::OpenOptions::new().create(true).append(true).open(&target).map(|_| ())),
+                };
+                created.map_err(|error| format!("cannot create the mount point {}: {}", target.display(), error))?;
+                target
+            }
+            None => target.to_path_buf(),
+        };
+        Ok(Bind { source: c_path(source)?, target: c_path(&target)?, writable: mount.writable })
+    }
+}
+
+impl Drop for View {
+    fn drop(&mut self) {
+        if let Some(mount_point) = &self.job_mount_point {
+            let _ = fs::remove_dir(mount_point);
+        }
+    }
+}
+
+/// Makes the command run its script in the view : a private mount namespace with the bind mounts,
+/// chrooted in the root directory if any, then as the user if any.
+///
+/// The namespace and the mounts require the privileges of hare : the user is switched last, instead
+/// of with `runas::apply`. The mounts do not propagate to the host, and go with the script.
+pub fn apply(command: &mut Command, view: View, user: Option<&User>) {
+    let ids = user.map(|user| (user.uid, user.gid));
+    // Safety: the closure only calls unshare, mount, chroot, chdir, setgroups, setgid and setuid,
+    // which are async-signal-safe, and does not allocate
+    unsafe {
+        command.pre_exec(move || {
+            let view = &view;
+            if libc::unshare(libc::CLONE_NEWNS) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // the mounts of the namespace would propagate to the host through shared mounts
+            if libc::mount(std::ptr::null(), c"/".as_ptr(), std::ptr::null(), libc::MS_REC | libc::MS_PRIVATE, std::ptr::null()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            for bind in &view.binds {
+                if libc::mount(bind.source.as_ptr(), bind.target.as_ptr(), std::ptr::null(), libc::MS_BIND | libc::MS_REC, std::ptr::null()) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                // a bind mount is made read-only by remounting it
+                if !bind.writable && libc::mount(std::ptr::null(), bind.target.as_ptr(), std::ptr::null(),
+                                                 libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY, std::ptr::null()) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(root) = &view.root {
+                if libc::chroot(root.as_ptr()) != 0 || libc::chdir(c"/".as_ptr()) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some((uid, gid)) = ids {
+                if libc::setgroups(0, std::ptr::null()) != 0 || libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// A path as a C string.
+fn c_path(path: &Path) -> Result<CString, String> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| format!("invalid path {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hare-mountns-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_policy_is_read_from_the_manifest() {
+        let policy: FilesystemPolicy = toml::from_str("root = \"/srv/root\"\n[[mounts]]\nsource = \"/var/cache\"\nwritable = true\n").unwrap();
+        assert_eq!(policy.root.as_deref(), Some("/srv/root"));
+        assert_eq!(policy.mounts, vec![Mount { source: "/var/cache".to_string(), target: None, writable: true }]);
+        assert!(toml::from_str::<FilesystemPolicy>("chroot = \"/srv/root\"").is_err());
+    }
+
+    #[test]
+    fn the_paths_must_be_absolute() {
+        let dir = temp_dir("check");
+        let mount = |source: &str, target: Option<&str>| Mount { source: source.to_string(), target: target.map(str::to_string), writable: false };
+        let policy = FilesystemPolicy { root: Some(dir.display().to_string()), mounts: vec![mount("/etc", Some("/etc/host"))] };
+        assert_eq!(policy.check(), Ok(()));
+
+        let relative = FilesystemPolicy { root: None, mounts: vec![mount("etc", None)] };
+        assert!(relative.check().unwrap_err().contains("etc"));
+        let relative_target = FilesystemPolicy { root: None, mounts: vec![mount("/etc", Some("etc"))] };
+        assert!(relative_target.check().is_err());
+        let missing_root = FilesystemPolicy { root: Some(dir.join("missing").display().to_string()), mounts: vec![] };
+        assert!(missing_root.check().is_err());
+    }
+
+    #[test]
+    fn the_mount_points_are_created_under_the_root() {
+        let dir = temp_dir("prepare");
+        let (root, scripts, job_dir) = (dir.join("root"), dir.join("scripts"), dir.join("job"));
+        for path in [&root, &scripts, &job_dir] {
+            fs::create_dir_all(path).unwrap();
+        }
+        fs::write(dir.join("hosts"), "").unwrap();
+        let policy = FilesystemPolicy {
+            root: Some(root.display().to_string()),
+            mounts: vec![Mount { source: dir.join("hosts").display().to_string(), target: Some("/etc/hosts".to_string()), writable: false }],
+        };
+
+        let view = policy.prepare(&scripts.display().to_string(), Some(&job_dir)).unwrap();
+        // the script root read-only, the mounts, then the job directory read-write
+        let targets: Vec<_> = view.binds.iter().map(|bind| (bind.target.to_str().unwrap().to_string(), bind.writable)).collect();
+        let under_root = |path: &Path| root.join(path.strip_prefix("/").unwrap()).display().to_string();
+        assert_eq!(targets, vec![
+            (under_root(&scripts), false),
+            (root.join("etc/hosts").display().to_string(), false),
+            (under_root(&job_dir), true),
+        ]);
+        assert!(root.join("etc/hosts").is_file());
+        assert_eq!(view.root.as_deref(), Some(c_path(&root).unwrap().as_c_str()));
+
+        // the mount point of the job directory goes with the view
+        let job_mount_point = PathBuf::from(under_root(&job_dir));
+        assert!(job_mount_point.is_dir());
+        drop(view);
+        assert!(!job_mount_point.exists());
+    }
+
+    #[test]
+    fn without_root_the_mounts_are_bound_in_place() {
+        let dir = temp_dir("in-place");
+        let policy = FilesystemPolicy { root: None, mounts: vec![Mount { source: dir.display().to_string(), target: None, writable: true }] };
+        let view = policy.prepare("/etc/hare/scripts", Some(&dir)).unwrap();
+        assert!(view.root.is_none());
+        assert_eq!(view.binds.len(), 1);
+        assert_eq!(view.binds[0].target.to_str().unwrap(), dir.display().to_string());
+        assert!(view.job_mount_point.is_none());
+
+        let missing = FilesystemPolicy { root: None, mounts: vec![Mount { source: dir.join("missing").display().to_string(), target: None, writable: false }] };
+        assert!(matches!(missing.prepare("/etc/hare/scripts", None), Err(error) if error.contains("cannot bind")));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::harehandler::HareError;
+
+/// Renders a queue or exchange name template.
+///
+/// The template may contain the `{env}` placeholder, replaced by the configured
+/// environment name (`HARE_ENV`), so that the same configuration can be used
+/// against the dev, staging and prod brokers, e.g. "{env}.deploy" gives "staging.deploy".
+/// Use `{{` and `}}` for literal braces.
+///
+/// @return Result<String, HareError>
+///
+/// # Errors
+///
+/// This function will return an error if the template uses an unknown placeholder,
+/// or uses `{env}` while no environment name is configured.
+pub fn render(template: &str, environment: Option<&str>) -> Result<String, HareError> {
+    render_with_tag(template, environment, None)
+}
+
+/// Renders the binding keys of the queue in topic mode.
+///
+/// A binding key template using the `{tag}` placeholder gives one binding key per host tag,
+/// e.g. "deploy.{tag}.#" with the tags "web" and "eu1" gives "deploy.web.#" and "deploy.eu1.#",
+/// so that messages published to a subset of hosts are routed by the broker.
+/// Templates without `{tag}` give a single binding key. Duplicate keys are removed.
+///
+/// @return the binding keys, in template order
+///
+/// # Errors
+///
+/// This function will return an error if a template is invalid (see `render`).
+pub fn binding_keys(templates: &[String], tags: &[String], environment: Option<&str>) -> Result<Vec<String>, HareError> {
+    let mut keys: Vec<String> = Vec::new();
+    for template in templates {
+        let rendered = if template.contains("{tag}") {
+            tags.iter()
+                .map(|tag| render_with_tag(template, environment, Some(tag)))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            vec![render(template, environment)?]
+        };
+        for key in rendered {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// Renders a template, with an optional value for the `{tag}` placeholder.
+fn render_with_tag(template: &str, environment: Option<&str>, tag: Option<&str>) -> Result<String, HareError> {
+    let mut name = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                name.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                name.push('}');
+            }
+            '{' => {
+                let placeholder: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                match (placeholder.as_str(), tag) {
+                    ("env", _) => match environment {
+                        Some(environment) => name.push_str(environment),
+                        None => return Err(HareError::ConfigError(format!(
+                            "name template \"{}\" uses {{env}} but HARE_ENV is not set", template
+                        ))),
+                    },
+                    ("tag", Some(tag)) => name.push_str(tag),
+                    _ => return Err(HareError::ConfigError(format!(
+                        "unknown placeholder {{{}}} in name template \"{}\"", placeholder, template
+                    ))),
+                }
+            }
+            _ => name.push(c),
+        }
+    }
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn the_environment_is_rendered() {
+        assert_eq!(render("{env}.deploy", Some("staging")).unwrap(), "staging.deploy");
+        assert_eq!(render("deploy-{env}-{env}", Some("prod")).unwrap(), "deploy-prod-prod");
+        assert_eq!(render("deploy", None).unwrap(), "deploy");
+        assert_eq!(render("", Some("prod")).unwrap(), "");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(render("{{env}}.deploy", Some("prod")).unwrap(), "{env}.deploy");
+        assert_eq!(render("{{{env}}}", Some("prod")).unwrap(), "{prod}");
+        assert_eq!(render("a}b", None).unwrap(), "a}b");
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        assert!(matches!(render("{env}.deploy", None), Err(HareError::ConfigError(error)) if error.contains("HARE_ENV is not set")));
+        assert!(matches!(render("{region}.deploy", Some("prod")), Err(HareError::ConfigError(error)) if error.contains("unknown placeholder {region}")));
+        // the tags only apply to the binding keys
+        assert!(matches!(render("deploy.{tag}", Some("prod")), Err(HareError::ConfigError(error)) if error.contains("unknown placeholder {tag}")));
+    }
+
+    #[test]
+    fn a_binding_key_is_rendered_per_tag() {
+        let keys = binding_keys(&strings(&["{env}.deploy.{tag}.#", "{env}.all"]), &strings(&["web", "eu1"]), Some("prod")).unwrap();
+        assert_eq!(keys, ["prod.deploy.web.#", "prod.deploy.eu1.#", "prod.all"]);
+        // without tags, the templates using them give no key
+        assert_eq!(binding_keys(&strings(&["deploy.{tag}", "#"]), &[], None).unwrap(), ["#"]);
+    }
+
+    #[test]
+    fn duplicate_binding_keys_are_removed() {
+        let keys = binding_keys(&strings(&["deploy.{tag}", "deploy.web", "#", "#"]), &strings(&["web", "db", "web"]), None).unwrap();
+        assert_eq!(keys, ["deploy.web", "deploy.db", "#"]);
+        assert!(binding_keys(&strings(&["{env}.{tag}"]), &strings(&["web"]), None).is_err());
+    }
+}
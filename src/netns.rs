@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::net::{Ipv4Addr, ToSocketAddrs};
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use serde::{Deserialize, Deserializer};
+use crate::runas::User;
+
+/// Default subnet of the links between the host and the network namespaces of the handlers.
+pub const DEFAULT_SUBNET: &str = "10.213.0.0/16";
+
+/// Host-wide IPv4 forwarding switch, required to route the packets of the namespaces.
+const IP_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+
+/// Network policy of a handler : its script runs in a private network namespace, and only reaches
+/// the allowed destinations.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkPolicy {
+    #[serde(default, deserialize_with = "deserialize_egress")]
+    pub egress: Vec<Egress>,    // allowed destinations, e.g. "10.0.0.0/8", "git.internal:22", "10.0.0.2:53/udp"
+}
+
+impl NetworkPolicy {
+
+    /// The nftables rules accepting the allowed destinations, their host names resolved.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a host name has no IPv4 address.
+    fn rules(&self) -> Result<Vec<String>, String> {
+        let mut allowed = Vec::new();
+        for egress in &self.egress {
+            allowed.extend(egress.rules()?);
+        }
+        Ok(allowed)
+    }
+}
+
+/// A destination the script of a handler may reach.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Egress {
+    host: String,               // IPv4 address, network or host name, resolved when the rules are set
+    prefix: Option<u8>,         // prefix length of a network
+    port: Option<u16>,          // destination port, any if None
+    protocol: &'static str,     // protocol of the port, "tcp" or "udp"
+}
+
+impl Egress {
+
+    /// Parses a destination : `host`, `address`, or `network/prefix`, then an optional `:port`,
+    /// and an optional `/udp` after the port (tcp by default).
+    ///
+    /// @return the destination
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the address, the prefix, the port or the protocol is invalid.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("invalid egress {:?}: {}", value, reason);
+        let (destination, port) = match value.trim().split_once(':') {
+            Some((destination, port)) => (destination, Some(port)),
+            None => (value.trim(), None),
+        };
+        let (port, protocol) = match port.map(|port| port.split_once('/').unwrap_or((port, "tcp"))) {
+            Some((port, protocol)) => {
+                let port = port.parse::<u16>().ok().filter(|port| *port > 0).ok_or_else(|| invalid("expected a port from 1 to 65535"))?;
+                match protocol {
+                    "tcp" => (Some(port), "tcp"),
+                    "udp" => (Some(port), "udp"),
+                    _ => return Err(invalid("expected the protocol tcp or udp")),
+                }
+            }
+            None => (None, "tcp"),
+        };
+        let (host, prefix) = match destination.split_once('/') {
+            Some((address, prefix)) => {
+                address.parse::<Ipv4Addr>().map_err(|_| invalid("a network is written with an IPv4 address, e.g. 10.0.0.0/8"))?;
+                let prefix = prefix.parse::<u8>().ok().filter(|prefix| *prefix <= 32).ok_or_else(|| invalid("expected a prefix length from 0 to 32"))?;
+                (address, Some(prefix))
+            }
+            None => (destination, None),
+        };
+        if host.is_empty() {
+            return Err(invalid("no destination"));
+        }
+        Ok(Egress { host: host.to_string(), prefix, port, protocol })
+    }
+
+    /// The nftables rules accepting the destination, its host name resolved.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the host name has no IPv4 address.
+    fn rules(&self) -> Result<Vec<String>, String> {
+        let addresses = match (self.prefix, self.host.parse::<Ipv4Addr>()) {
+            (Some(prefix), _) => vec![format!("{}/{}", self.host, prefix)],
+            (None, Ok(address)) => vec![address.to_string()],
+            (None, Err(_)) => {
+                let mut addresses: Vec<String> = (self.host.as_str(), 0).to_socket_addrs()
+                    .map_err(|error| format!("cannot resolve {}: {}", self.host, error))?
+                    .filter(|address| address.is_ipv4())
+                    .map(|address| address.ip().to_string())
+                    .collect();
+                addresses.sort();
+                addresses.dedup();
+                if addresses.is_empty() {
+                    return Err(format!("{} has no IPv4 address", self.host));
+                }
+                addresses
+            }
+        };
+        Ok(addresses.into_iter().map(|address| match self.port {
+            Some(port) => format!("ip daddr {} {} dport {} accept", address, self.protocol, port),
+            None => format!("ip daddr {} accept", address),
+        }).collect())
+    }
+}
+
+/// Network namespaces of the handlers, each linked to the host by a pair of virtual interfaces.
+///
+/// Each handler with a network policy gets a namespace (`hare-net<n>`) the first time it runs, with a
+/// link to the host (`hare<n>h` on the host, `hare<n>n` in the namespace) taking a /30 of
+/// HARE_NETNS_SUBNET, its default route going through the host. The egress policy is enforced on the
+/// host, by the nftables table `inet hare_net<n>`, out of reach of the script even if it runs as root :
+/// the packets coming from the link are accepted when they answer a connection, or go to an allowed
+/// destination, and dropped otherwise ; the allowed ones are masqueraded. The rules are set again when
+/// the policy changes, or when the host names it lists resolve to other addresses.
+///
+/// IPv4 forwarding is a host-wide setting : when it is disabled, hare enables it with a warning
+/// when it sets the first namespace up, and disables it again when it stops.
+///
+/// Setting the namespaces up requires hare to run as root, with the `ip` and `nft` commands.
+pub struct Namespaces {
+    subnet: Result<(u32, u32), String>, // first address and size of the subnet of the links, or the configuration error
+    slots: tokio::sync::Mutex<HashMap<String, Slot>>, // namespaces of the handlers
+    forwarding: AtomicBool,             // whether hare enabled IPv4 forwarding, and disables it when it stops
+}
+
+/// The namespace of a handler.
+struct Slot {
+    index: u32,                 // number of the namespace, its link takes the index-th /30 of the subnet
+    namespace: Arc<File>,       // the namespace, entered by the scripts
+    rules: String,              // egress rules set on the host
+}
+
+impl Namespaces {
+
+    /// Reads the subnet of the links, HARE_NETNS_SUBNET.
+    ///
+    /// @return Namespaces
+    ///
+    pub fn new(subnet: Option<&str>) -> Self {
+        let subnet = subnet.unwrap_or(DEFAULT_SUBNET);
+        let invalid = || format!("invalid HARE_NETNS_SUBNET {:?}, expected an IPv4 network up to /30, e.g. {}", subnet, DEFAULT_SUBNET);
+        let parsed = subnet.split_once('/')
+            .and_then(|(address, prefix)| Some((address.parse::<Ipv4Addr>().ok()?, prefix.parse::<u32>().ok().filter(|prefix| *prefix <= 30)?)))
+            .map(|(address, prefix)| {
+                let size = 1u64 << (32 - prefix);
+                (u32::from(address) & !((size - 1) as u32), (size / 4).min(u32::MAX as u64) as u32)
+            })
+            .ok_or_else(invalid);
+        Namespaces { subnet: parsed, slots: tokio::sync::Mutex::new(HashMap::new()), forwarding: AtomicBool::new(false) }
+    }
+
+    /// Checks the subnet of the links.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if HARE_NETNS_SUBNET is invalid.
+    pub fn validate(&self) -> Result<(), String> {
+        self.subnet.as_ref().map(|_| ()).map_err(String::clone)
+    }
+
+    /// The namespace of a handler, set up on its first run, its rules updated to the policy.
+    ///
+    /// @return the namespace, entered by the script (see `apply`)
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a host name cannot be resolved, or the namespace or its
+    /// rules cannot be set up.
+    pub async fn enter(&self, handler: &str, policy: &NetworkPolicy) -> Result<Arc<File>, String> {
+        let (base, size) = self.subnet.clone()?;
+        // the host names are resolved before taking the lock, so that a slow resolver does not hold up
+        // the namespaces of the other handlers
+        let policy = policy.clone();
+        let allowed = tokio::task::spawn_blocking(move || policy.rules()).await.map_err(|error| error.to_string())??;
+        let mut slots = self.slots.lock().await;
+        let index = match slots.get(handler) {
+            Some(slot) => slot.index,
+            None => u32::try_from(slots.len()).ok().filter(|index| *index < size)
+                .ok_or_else(|| format!("HARE_NETNS_SUBNET has no room for the namespace of handler {}", handler))?,
+        };
+        let link = Link::new(index, base);
+        let known = slots.get(handler).map(|slot| (slot.namespace.clone(), slot.rules.clone()));
+        let slot = tokio::task::spawn_blocking(move || -> Result<(Slot, bool), String> {
+            let rules = link.ruleset(&allowed);
+            let (namespace, enabled) = match known {
+                Some((namespace, current)) => {
+                    if current != rules {
+                        link.set_rules(&rules)?;
+                    }
+                    (namespace, false)
+                }
+                None => {
+                    let namespace = link.create()?;
+                    link.set_rules(&rules)?;
+                    (namespace, enable_forwarding()?)
+                }
+            };
+            Ok((Slot { index, namespace, rules }, enabled))
+        }).await.map_err(|error| error.to_string())??;
+        let (slot, enabled) = slot;
+        if enabled {
+            log::warn!("IPv4 forwarding was disabled on the host, hare enabled it for the network namespaces until it stops");
+            self.forwarding.store(true, Ordering::Relaxed);
+        }
+        let namespace = slot.namespace.clone();
+        if !slots.contains_key(handler) {
+            log::info!(handler = handler; "Network namespace hare-net{} set up for handler {}", index, handler);
+        }
+        slots.insert(handler.to_string(), slot);
+        Ok(namespace)
+    }
+
+    /// Removes the namespaces and their rules, when hare stops, once the namespaces being set up
+    /// are, and disables IPv4 forwarding again if hare enabled it.
+    pub async fn teardown(&self) {
+        let Ok((base, _)) = self.subnet else { return };
+        let mut slots = self.slots.lock().await;
+        let links: Vec<Link> = slots.drain().map(|(_, slot)| Link::new(slot.index, base)).collect();
+        let forwarding = self.forwarding.swap(false, Ordering::Relaxed);
+        let removed = tokio::task::spawn_blocking(move || {
+            for link in links {
+                link.remove();
+            }
+            if forwarding {
+                if let Err(error) = std::fs::write(IP_FORWARD, "0") {
+                    log::error!("Cannot disable IPv4 forwarding again: {}", error);
+                }
+            }
+        });
+        let _ = removed.await;
+    }
+}
+
+/// The link between the host and the namespace of a handler.
+struct Link {
+    index: u32,
+    host: Ipv4Addr,             // address of the host side
+    namespace: Ipv4Addr,        // address of the namespace side
+}
+
+impl Link {
+
+    fn new(index: u32, base: u32) -> Self {
+        let first = base.wrapping_add(index * 4);
+        Link { index, host: Ipv4Addr::from(first + 1), namespace: Ipv4Addr::from(first + 2) }
+    }
+
+    fn name(&self) -> String {
+        format!("hare-net{}", self.index)
+    }
+
+    /// Creates the namespace and its link, replacing those left by a previous run.
+    ///
+    /// @return the namespace
+    ///
+    fn create(&self) -> Result<Arc<File>, String> {
+        self.remove();
+        let (name, host, peer) = (self.name(), format!("hare{}h", self.index), format!("hare{}n", self.index));
+        let (host_address, namespace_address) = (format!("{}/30", self.host), format!("{}/30", self.namespace));
+        let steps: [&[&str]; 9] = [
+            &["netns", "add", &name],
+            &["link", "add", &host, "type", "veth", "peer", "name", &peer],
+            &["link", "set", &peer, "netns", &name],
+            &["addr", "add", &host_address, "dev", &host],
+            &["link", "set", &host, "up"],
+            &["-n", &name, "addr", "add", &namespace_address, "dev", &peer],
+            &["-n", &name, "link", "set", &peer, "up"],
+            &["-n", &name, "link", "set", "lo", "up"],
+            &["-n", &name, "route", "add", "default", "via", &self.host.to_string()],
+        ];
+        for step in steps {
+            run("ip", step, None)?;
+        }
+        let namespace = File::open(format!("/run/netns/{}", name)).map_err(|error| format!("cannot open network namespace {}: {}", name, error))?;
+        Ok(Arc::new(namespace))
+    }
+
+    /// The nftables table of the link, enforcing the egress policy, given by its rules (see `NetworkPolicy::rules`).
+    fn ruleset(&self, allowed: &[String]) -> String {
+        let table = format!("inet hare_net{}", self.index);
+        let interface = format!("hare{}h", self.index);
+        let mut ruleset = format!("table {table}\ndelete table {table}\ntable {table} {{\n");
+        for hook in ["input", "forward"] {
+            ruleset.push_str(&format!("  chain {hook} {{\n    type filter hook {hook} priority 0; policy accept;\n    iifname \"{interface}\" jump egress\n  }}\n"));
+        }
+        ruleset.push_str("  chain egress {\n    ct state established,related accept\n");
+        for rule in allowed {
+            ruleset.push_str(&format!("    {}\n", rule));
+        }
+        ruleset.push_str("    drop\n  }\n");
+        ruleset.push_str(&format!("  chain postrouting {{\n    type nat hook postrouting priority 100; policy accept;\n    ip saddr {} masquerade\n  }}\n}}\n", self.namespace));
+        ruleset
+    }
+
+    /// Replaces the rules of the link, in a single transaction.
+    fn set_rules(&self, ruleset: &str) -> Result<(), String> {
+        run("nft", &["-f", "-"], Some(ruleset))
+    }
+
+    /// Removes the namespace, its link and its rules, if they exist.
+    fn remove(&self) {
+        let table = format!("inet hare_net{}", self.index);
+        let _ = run("nft", &["-f", "-"], Some(&format!("table {table}\ndelete table {table}\n")));
+        // the host side of the link goes with the namespace side
+        let _ = run("ip", &["netns", "delete", &self.name()], None);
+        let _ = run("ip", &["link", "delete", &format!("hare{}h", self.index)], None);
+    }
+}
+
+/// Enables IPv4 forwarding on the host, the packets of the namespaces are routed by the host.
+///
+/// @return whether forwarding was disabled, and was enabled
+///
+/// # Errors
+///
+/// This function will return an error if the forwarding switch cannot be read or written.
+fn enable_forwarding() -> Result<bool, String> {
+    let current = std::fs::read_to_string(IP_FORWARD).map_err(|error| format!("cannot read {}: {}", IP_FORWARD, error))?;
+    if current.trim() == "1" {
+        return Ok(false);
+    }
+    std::fs::write(IP_FORWARD, "1").map_err(|error| format!("cannot enable IPv4 forwarding: {}", error))?;
+    Ok(true)
+}
+
+/// Runs a setup command.
+///
+/// # Errors
+///
+/// This function will return an error with the standard error of the command, if it fails.
+fn run(program: &str, args: &[&str], input: Option<&str>) -> Result<(), String> {
+    let failed = |error: String| format!("{} {} failed: {}", program, args.join(" "), error);
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| failed(error.to_string()))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes()).map_err(|error| failed(error.to_string()))?;
+    }
+    let output = child.wait_with_output().map_err(|error| failed(error.to_string()))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(failed(String::from_utf8_lossy(&output.stderr).trim().to_string())),
+    }
+}
+
+/// Makes the command run in a network namespace.
+///
+/// Entering a namespace requires the privileges of hare : a script running as another user (see
+/// `runas`) switches user once in the namespace, rather than with `Command::uid`, which applies before.
+pub fn apply(command: &mut Command, namespace: Arc<File>, user: Option<&User>) {
+    let ids = user.map(|user| (user.uid, user.gid));
+    // Safety: the closure only calls setns, setgroups, setgid and setuid, which are async-signal-safe,
+    // and does not allocate
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if let Some((uid, gid)) = ids {
+                if libc::setgroups(0, std::ptr::null()) != 0 || libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Deserializes the allowed destinations of a handler.
+fn deserialize_egress<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Egress>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?.iter()
+        .map(|egress| Egress::parse(egress).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn egress_destinations_are_parsed() {
+        let network = Egress::parse("10.20.0.0/16:443").unwrap();
+        assert_eq!((network.host.as_str(), network.prefix, network.port, network.protocol), ("10.20.0.0", Some(16), Some(443), "tcp"));
+        let host = Egress::parse(" git.internal:22 ").unwrap();
+        assert_eq!((host.host.as_str(), host.prefix, host.port, host.protocol), ("git.internal", None, Some(22), "tcp"));
+        let udp = Egress::parse("10.0.0.2:53/udp").unwrap();
+        assert_eq!((udp.port, udp.protocol), (Some(53), "udp"));
+        let address = Egress::parse("10.30.1.5").unwrap();
+        assert_eq!((address.prefix, address.port), (None, None));
+    }
+
+    #[test]
+    fn invalid_egress_destinations_are_rejected() {
+        for invalid in ["", ":443", "10.0.0.0/33", "10.0.0.0/x", "git.internal/8", "10.0.0.1:0", "10.0.0.1:65536", "10.0.0.1:http", "10.0.0.1:53/sctp"] {
+            assert!(Egress::parse(invalid).is_err(), "{:?} accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn the_ruleset_accepts_the_egress_and_drops_the_rest() {
+        let policy = NetworkPolicy { egress: vec![Egress::parse("10.20.0.0/16:443").unwrap(), Egress::parse("10.0.0.2:53/udp").unwrap(), Egress::parse("10.30.1.5").unwrap()] };
+        let (base, _) = Namespaces::new(None).subnet.unwrap();
+        let ruleset = Link::new(2, base).ruleset(&policy.rules().unwrap());
+        assert_eq!(ruleset, "\
+table inet hare_net2
+delete table inet hare_net2
+table inet hare_net2 {
+  chain input {
+    type filter hook input priority 0; policy accept;
+    iifname \"hare2h\" jump egress
+  }
+  chain forward {
+    type filter hook forward priority 0; policy accept;
+    iifname \"hare2h\" jump egress
+  }
+  chain egress {
+    ct state established,related accept
+    ip daddr 10.20.0.0/16 tcp dport 443 accept
+    ip daddr 10.0.0.2 udp dport 53 accept
+    ip daddr 10.30.1.5 accept
+    drop
+  }
+  chain postrouting {
+    type nat hook postrouting priority 100; policy accept;
+    ip saddr 10.213.0.10 masquerade
+  }
+}
+");
+    }
+
+    #[test]
+    fn the_links_take_consecutive_slices_of_the_subnet() {
+        let namespaces = Namespaces::new(Some("10.1.2.0/29"));
+        let (base, size) = namespaces.subnet.clone().unwrap();
+        assert_eq!(size, 2);
+        let link = Link::new(1, base);
+        assert_eq!((link.host, link.namespace), (Ipv4Addr::new(10, 1, 2, 5), Ipv4Addr::new(10, 1, 2, 6)));
+        assert!(Namespaces::new(Some("10.1.2.0/31")).validate().is_err());
+    }
+}
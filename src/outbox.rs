@@ -0,0 +1,385 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use lapin::BasicProperties;
+use crate::config::HareConfig;
+use crate::harehandler::HareError;
+use crate::publisher::OutgoingMessage;
+
+/// Name of the outbox directory, inside the state directory.
+pub const OUTBOX_DIR: &str = "outbox";
+
+/// Default size of the outbox, past which the messages are dropped (256 MiB).
+pub const DEFAULT_MAX_SIZE: u64 = 256 << 20;
+
+/// Default number of messages in the outbox, past which the messages are dropped.
+pub const DEFAULT_MAX_MESSAGES: usize = 100_000;
+
+/// Which messages are dropped when the outbox, or the pending buffer in memory, is full.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Overflow {
+    #[default]
+    DropOldest, // the oldest messages make room for the new one, as in a ring buffer
+    DropNewest, // the new message is dropped, the buffered ones are kept
+}
+
+impl Overflow {
+
+    /// Parses a policy, as set in HARE_OUTBOX_OVERFLOW.
+    ///
+    /// @return Overflow
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the policy is unknown.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "drop-newest" => Ok(Overflow::DropNewest),
+            other => Err(format!("invalid HARE_OUTBOX_OVERFLOW {:?}, expected drop-oldest or drop-newest", other)),
+        }
+    }
+
+    /// The name of the policy, as set in HARE_OUTBOX_OVERFLOW.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Overflow::DropOldest => "drop-oldest",
+            Overflow::DropNewest => "drop-newest",
+        }
+    }
+}
+
+/// Size caps of the outbox, and what to drop past them.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_size: u64,          // total size of the entries, in bytes
+    pub max_messages: usize,    // number of entries
+    pub overflow: Overflow,     // messages dropped past the caps
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits { max_size: DEFAULT_MAX_SIZE, max_messages: DEFAULT_MAX_MESSAGES, overflow: Overflow::default() }
+    }
+}
+
+impl Limits {
+
+    /// Reads the caps of the outbox : HARE_OUTBOX_MAX_SIZE, HARE_OUTBOX_MAX_MESSAGES and HARE_OUTBOX_OVERFLOW.
+    ///
+    /// @return Limits
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a setting is invalid.
+    pub fn load(config: &HareConfig) -> Result<Self, String> {
+        let max_size = match config.get("HARE_OUTBOX_MAX_SIZE") {
+            Some(value) => value.parse().ok().filter(|size| *size > 0)
+                .ok_or_else(|| format!("invalid HARE_OUTBOX_MAX_SIZE {:?}, expected a size in bytes", value))?,
+            None => DEFAULT_MAX_SIZE,
+        };
+        let max_messages = match config.get("HARE_OUTBOX_MAX_MESSAGES") {
+            Some(value) => value.parse().ok().filter(|messages| *messages > 0)
+                .ok_or_else(|| format!("invalid HARE_OUTBOX_MAX_MESSAGES {:?}, expected a number of messages", value))?,
+            None => DEFAULT_MAX_MESSAGES,
+        };
+        let overflow = config.get("HARE_OUTBOX_OVERFLOW").map_or(Ok(Overflow::default()), |value| Overflow::parse(&value))?;
+        Ok(Limits { max_size, max_messages, overflow })
+    }
+}
+
+/// Durable storage of the messages emitted by hare, until the broker confirms them.
+///
+/// Each message is stored in its own file, named after its creation time so that the
+/// messages are published in order. The file holds a JSON line with the message metadata
+/// (exchange, routing key, properties) followed by the raw message body.
+/// Files are written atomically (write to a temporary file, then rename).
+///
+/// The outbox is capped in size and in number of messages (see `Limits`) : during a long outage
+/// of the broker, it acts as a ring buffer, the publisher dropping the oldest messages, or the
+/// new ones, past the caps. Only the index of the entries is kept in memory.
+pub struct Outbox {
+    dir: PathBuf,       // outbox directory
+    sequence: u64,      // disambiguates messages stored during the same nanosecond
+    limits: Limits,     // size caps
+    entries: VecDeque<(PathBuf, u64)>, // entries and their size, oldest first
+    size: u64,          // total size of the entries
+}
+
+impl Outbox {
+
+    /// Opens the outbox of a state directory, indexing the entries left by a previous run.
+    ///
+    /// @return Outbox
+    ///
+    pub fn open(state_dir: &Path, limits: Limits) -> Self {
+        let dir = state_dir.join(OUTBOX_DIR);
+        let mut entries: Vec<(PathBuf, u64)> = fs::read_dir(&dir).into_iter().flatten()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .map(|entry| (entry.path(), entry.metadata().map(|metadata| metadata.len()).unwrap_or_default()))
+            .collect();
+        entries.sort();
+        let size = entries.iter().map(|(_, size)| size).sum();
+        Outbox { dir, sequence: 0, limits, entries: entries.into(), size }
+    }
+
+    /// The caps of the outbox.
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Number of messages in the outbox.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the outbox is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether a message does not fit in the outbox, without dropping others.
+    pub fn is_full(&self, message: &OutgoingMessage) -> bool {
+        self.entries.len() >= self.limits.max_messages || self.size + Self::encode(message).len() as u64 > self.limits.max_size
+    }
+
+    /// Stores a message in the outbox, whatever its caps.
+    ///
+    /// @return the path of the outbox entry
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the message could not be written.
+    pub fn store(&mut self, message: &OutgoingMessage) -> Result<PathBuf, HareError> {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos();
+        self.sequence += 1;
+        let name = format!("{:020}-{:06}", timestamp, self.sequence % 1_000_000);
+
+        let content = Self::encode(message);
+        let tmp = self.dir.join(format!(".{}.tmp", name));
+        let path = self.dir.join(&name);
+        fs::write(&tmp, &content)?;
+        fs::rename(&tmp, &path)?;
+        self.entries.push_back((path.clone(), content.len() as u64));
+        self.size += content.len() as u64;
+        Ok(path)
+    }
+
+    /// Loads the oldest messages stored in the outbox.
+    ///
+    /// Entries that cannot be parsed are logged and left in place, out of the index.
+    ///
+    /// @return the path and the message of each entry, up to `limit` entries
+    ///
+    pub fn load(&mut self, limit: usize) -> Vec<(PathBuf, OutgoingMessage)> {
+        let mut messages = Vec::with_capacity(limit.min(self.entries.len()));
+        let mut index = 0;
+        while messages.len() < limit && index < self.entries.len() {
+            let path = self.entries[index].0.clone();
+            match Self::read_entry(&path) {
+                Some(message) => {
+                    messages.push((path, message));
+                    index += 1;
+                }
+                None => {
+                    log::error!("Invalid outbox entry {}, ignored", path.display());
+                    if let Some((_, size)) = self.entries.remove(index) {
+                        self.size -= size;
+                    }
+                }
+            }
+        }
+        messages
+    }
+
+    /// Removes an entry from the outbox, once the message has been confirmed.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the entry could not be removed.
+    pub fn remove(&mut self, path: &Path) -> Result<(), HareError> {
+        if let Some(index) = self.entries.iter().position(|(entry, _)| entry == path) {
+            if let Some((_, size)) = self.entries.remove(index) {
+                self.size -= size;
+            }
+        }
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// Drops the oldest entry of the outbox, to make room for a new one.
+    ///
+    /// @return the path of the dropped entry, None if the outbox is empty
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the entry could not be removed.
+    pub fn evict(&mut self) -> Result<Option<PathBuf>, HareError> {
+        let Some((path, size)) = self.entries.pop_front() else { return Ok(None) };
+        self.size -= size;
+        match fs::remove_file(&path) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(Some(path)),
+        }
+    }
+
+    /// The content of the entry of a message : its metadata on a JSON line, then its body.
+    fn encode(message: &OutgoingMessage) -> Vec<u8> {
+        let metadata = serde_json::json!({
+            "exchange": message.exchange,
+            "routing_key": message.routing_key,
+            "properties": message.properties,
+        });
+        let mut content = metadata.to_string().into_bytes();
+        content.push(b'\n');
+        content.extend_from_slice(&message.body);
+        content
+    }
+
+    /// Parses an outbox entry.
+    fn read_entry(path: &Path) -> Option<OutgoingMessage> {
+        let content = fs::read(path).ok()?;
+        let newline = content.iter().position(|b| *b == b'\n')?;
+        let metadata: serde_json::Value = serde_json::from_slice(&content[..newline]).ok()?;
+
+        let properties = match serde_json::from_value(metadata["properties"].clone()) {
+            Ok(properties) => properties,
+            // the entries of the previous versions only kept some of the properties
+            Err(_) => {
+                let mut properties = BasicProperties::default().with_delivery_mode(2);
+                if let Some(content_type) = metadata["content_type"].as_str() {
+                    properties = properties.with_content_type(content_type.into());
+                }
+                if let Ok(headers) = serde_json::from_value(metadata["headers"].clone()) {
+                    properties = properties.with_headers(headers);
+                }
+                if let Some(timestamp) = metadata["timestamp"].as_u64() {
+                    properties = properties.with_timestamp(timestamp);
+                }
+                properties
+            }
+        };
+
+        Some(OutgoingMessage {
+            exchange: metadata["exchange"].as_str()?.to_string(),
+            routing_key: metadata["routing_key"].as_str()?.to_string(),
+            body: content[newline + 1..].to_vec(),
+            properties,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::types::{AMQPValue, FieldTable};
+
+    /// A fresh state directory, with its outbox directory.
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hare-outbox-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(OUTBOX_DIR)).unwrap();
+        dir
+    }
+
+    fn message(routing_key: &str, body: &[u8]) -> OutgoingMessage {
+        let mut headers = FieldTable::default();
+        headers.insert("type".into(), AMQPValue::LongString("deploy".into()));
+        OutgoingMessage {
+            exchange: "results".to_string(),
+            routing_key: routing_key.to_string(),
+            body: body.to_vec(),
+            properties: BasicProperties::default().with_content_type("application/json".into()).with_headers(headers),
+        }
+    }
+
+    #[test]
+    fn messages_are_kept_in_order_until_removed() {
+        let dir = state_dir("order");
+        let mut outbox = Outbox::open(&dir, Limits::default());
+        let first = outbox.store(&message("first", b"{\"n\":1}\n")).unwrap();
+        outbox.store(&message("second", b"")).unwrap();
+        assert!(!dir.join(OUTBOX_DIR).read_dir().unwrap().any(|entry| entry.unwrap().file_name().to_string_lossy().starts_with('.')));
+
+        // the entries are found again by the next run
+        let mut outbox = Outbox::open(&dir, Limits::default());
+        assert_eq!(outbox.len(), 2);
+        let messages = outbox.load(10);
+        let keys: Vec<_> = messages.iter().map(|(_, message)| message.routing_key.as_str()).collect();
+        assert_eq!(keys, ["first", "second"]);
+        let (path, loaded) = &messages[0];
+        assert_eq!(path, &first);
+        assert_eq!(loaded.exchange, "results");
+        assert_eq!(loaded.body, b"{\"n\":1}\n");
+        assert_eq!(loaded.properties, message("first", b"").properties);
+        assert_eq!(outbox.load(1).len(), 1);
+
+        outbox.remove(&first).unwrap();
+        assert_eq!(outbox.len(), 1);
+        assert!(!first.exists());
+        assert_eq!(Outbox::open(&dir, Limits::default()).len(), 1);
+    }
+
+    #[test]
+    fn the_outbox_is_capped() {
+        let dir = state_dir("caps");
+        let limits = Limits { max_size: u64::MAX, max_messages: 2, overflow: Overflow::DropOldest };
+        let mut outbox = Outbox::open(&dir, limits);
+        let oldest = outbox.store(&message("first", b"")).unwrap();
+        assert!(!outbox.is_full(&message("second", b"")));
+        outbox.store(&message("second", b"")).unwrap();
+        assert!(outbox.is_full(&message("third", b"")));
+
+        assert_eq!(outbox.evict().unwrap(), Some(oldest.clone()));
+        assert!(!oldest.exists());
+        assert!(!outbox.is_full(&message("third", b"")));
+
+        // the size counts the metadata with the body
+        let entry = Outbox::encode(&message("big", b"0123456789")).len() as u64;
+        let limits = Limits { max_size: entry, max_messages: 10, overflow: Overflow::DropNewest };
+        let mut outbox = Outbox::open(&state_dir("size"), limits);
+        assert!(!outbox.is_full(&message("big", b"0123456789")));
+        assert!(outbox.is_full(&message("big", b"0123456789a")));
+        outbox.store(&message("big", b"0123456789")).unwrap();
+        assert!(outbox.is_full(&message("", b"")));
+        outbox.evict().unwrap();
+        assert!(outbox.is_empty());
+        assert_eq!(outbox.evict().unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_entries_are_left_out() {
+        let dir = state_dir("invalid");
+        fs::write(dir.join(OUTBOX_DIR).join("00000000000000000001-000001"), b"not json\n").unwrap();
+        // an entry of a previous version, with some of the properties only
+        fs::write(dir.join(OUTBOX_DIR).join("00000000000000000002-000001"),
+                  b"{\"exchange\":\"results\",\"routing_key\":\"old\",\"content_type\":\"text/plain\",\"timestamp\":42}\nbody").unwrap();
+        let mut outbox = Outbox::open(&dir, Limits::default());
+        assert_eq!(outbox.len(), 2);
+
+        let messages = outbox.load(10);
+        assert_eq!(messages.len(), 1);
+        let (_, message) = &messages[0];
+        assert_eq!((message.routing_key.as_str(), message.body.as_slice()), ("old", b"body".as_slice()));
+        assert_eq!(message.properties.content_type().as_ref().map(|content_type| content_type.as_str()), Some("text/plain"));
+        assert_eq!(*message.properties.timestamp(), Some(42));
+        assert_eq!(*message.properties.delivery_mode(), Some(2));
+        assert_eq!(outbox.len(), 1);
+    }
+
+    #[test]
+    fn the_caps_are_read_from_the_configuration() {
+        let mut config = HareConfig::default();
+        config.set("HARE_OUTBOX_MAX_SIZE", "1024");
+        config.set("HARE_OUTBOX_OVERFLOW", "drop-newest");
+        let limits = Limits::load(&config).unwrap();
+        assert_eq!((limits.max_size, limits.max_messages, limits.overflow), (1024, DEFAULT_MAX_MESSAGES, Overflow::DropNewest));
+        assert_eq!(Overflow::parse(Overflow::DropOldest.name()), Ok(Overflow::DropOldest));
+
+        config.set("HARE_OUTBOX_MAX_MESSAGES", "0");
+        assert!(Limits::load(&config).is_err());
+        assert!(Overflow::parse("drop-all").is_err());
+    }
+}
@@ -0,0 +1,325 @@
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use bytes::Bytes;
+use ring::rand::SecureRandom;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, Interest};
+use tokio::process::ChildStdin;
+use crate::contract::PROGRESS_MARKER;
+
+/// Default delay between the SIGTERM and the SIGKILL of a script that timed out.
+pub const DEFAULT_TIMEOUT_GRACE: Duration = Duration::from_secs(10);
+
+/// Default size of each output stream of a script, past which it is truncated (1 MiB).
+pub const DEFAULT_MAX_SIZE: usize = 1 << 20;
+
+/// Size past which a line of output is split, for the scripts writing without line breaks.
+const MAX_LINE: usize = 64 << 10;
+
+/// How long a script may run.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    pub limit: Duration,    // how long the script may run before it is sent SIGTERM
+    pub grace: Duration,    // how long it may take to exit after SIGTERM, before it is sent SIGKILL
+}
+
+/// Output of a script, once it exited.
+#[derive(Debug)]
+pub struct ScriptOutput {
+    pub status: ExitStatus, // exit status of the script
+    pub stdout: Vec<u8>,    // end of the standard output, at most the max size
+    pub stderr: Vec<u8>,    // end of the standard error, at most the max size
+    pub size: usize,        // bytes written by the script on both streams, truncated or not
+    pub truncated: bool,    // whether a stream was larger than the max size
+}
+
+/// A stream of the script, as read while it runs.
+#[derive(Default)]
+struct Stream {
+    kept: Vec<u8>,          // end of the stream
+    size: usize,            // bytes read
+}
+
+/// Sequence number of the jobs started by this process.
+static JOB_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Creates a new job id, unique across restarts : the start time in milliseconds and a sequence number.
+///
+/// @return the job id
+///
+pub fn next_job_id() -> String {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    format!("{}-{}", now.as_millis(), JOB_SEQUENCE.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Creates a message id, for the messages published without one : a random UUID (version 4).
+///
+/// @return the message id
+///
+pub fn new_message_id() -> String {
+    let mut bytes = [0u8; 16];
+    if ring::rand::SystemRandom::new().fill(&mut bytes).is_err() {
+        // without randomness, the id is still unique within the instance
+        return next_job_id();
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Runs a command, logging its output line by line while it runs.
+///
+/// Each line is logged when it is read, tagged with its stream (`[stdout]` or `[stderr]`) and the
+/// job id, which are also attached to the log record as the `job` and `stream` fields, so that
+/// the JSON log sinks emit one structured event per line. The records also carry the `handler`
+/// field, routing them to the log file of the handler. Progress lines (starting with
+/// `::hare-progress::`) are logged as the progress of the job.
+///
+/// Each stream is truncated past the max size, so that a verbose script cannot exhaust the memory
+/// or flood the logs : its first lines are logged, up to the max size, then a single line tells
+/// that the rest is left out (the progress lines are still logged), and its end is kept in the
+/// returned output, where the errors of a failed script are.
+///
+/// The input is written to the standard input of the command, which is then closed. A command
+/// that does not read its input is not an error.
+///
+/// The command runs through tokio::process, its input written and its output read by the task
+/// awaiting it, so that a long script does not tie up a thread of the runtime. The command is
+/// killed if the job is dropped, e.g. when hare stops. Its exit is awaited without reaping it
+/// (on a pidfd), so that its CPU time can be read before it is reaped.
+///
+/// With a timeout, the command runs in its own process group ; past the timeout, the group is sent
+/// SIGTERM, then SIGKILL after the grace period, so that the processes started by the script do
+/// not outlive it.
+///
+/// @return the exit status and the output of the command, the CPU time it used, and whether it timed out
+///
+/// # Errors
+///
+/// This function will return an error if the command cannot be started.
+pub async fn run(command: Command, input: Bytes, handler: &str, job: &str, timeout: Option<Timeout>, max_size: usize)
+                 -> std::io::Result<(ScriptOutput, Duration, bool)> {
+    let mut command = tokio::process::Command::from(command);
+    if timeout.is_some() {
+        command.process_group(0);
+    }
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true).spawn()?;
+    let pid = child.id().ok_or_else(|| std::io::Error::other("the command exited before it was started"))?;
+
+    // the input is written while the output is read, the command may fill its output pipes before reading its input
+    let (stdin, stdout, stderr) = (child.stdin.take(), child.stdout.take(), child.stderr.take());
+    let (timed_out, (), stdout, stderr) = tokio::join!(
+        watch(pid, timeout, handler, job),
+        feed(stdin, input, job),
+        stream(stdout, "stdout", handler, job, max_size),
+        stream(stderr, "stderr", handler, job, max_size),
+    );
+    let timed_out = timed_out?;
+
+    // the command is reaped once its CPU time is read : until then, its process group id cannot be reused
+    let cpu_time = cpu_time(pid).unwrap_or_else(|error| {
+        log::warn!(handler = handler, job = job; "Could not read the CPU time of job {}: {}", job, error);
+        Duration::ZERO
+    });
+    let status = child.wait().await?;
+
+    let output = ScriptOutput {
+        status,
+        size: stdout.size + stderr.size,
+        truncated: stdout.size > max_size || stderr.size > max_size,
+        stdout: stdout.kept,
+        stderr: stderr.kept,
+    };
+    Ok((output, cpu_time, timed_out))
+}
+
+/// Waits for a command to exit, killing its process group if it outlasts its timeout.
+///
+/// The group is sent SIGTERM once the timeout expired, then SIGKILL once the command exited or
+/// the grace period expired, for the processes it left behind.
+///
+/// @return whether the command timed out
+///
+async fn watch(pid: u32, timeout: Option<Timeout>, handler: &str, job: &str) -> std::io::Result<bool> {
+    let Some(timeout) = timeout else {
+        exited(pid).await?;
+        return Ok(false);
+    };
+    // Safety: the command is not reaped yet, its process group id cannot be reused
+    let signal = |signal: libc::c_int| unsafe { libc::kill(-(pid as libc::pid_t), signal) };
+    let exit = exited(pid);
+    tokio::pin!(exit);
+    if let Ok(exit) = tokio::time::timeout(timeout.limit, &mut exit).await {
+        return exit.map(|()| false);
+    }
+    log::warn!(handler = handler, job = job; "Job {} timed out after {}, terminating it", job, humantime::format_duration(timeout.limit));
+    signal(libc::SIGTERM);
+    match tokio::time::timeout(timeout.grace, &mut exit).await {
+        Ok(exit) => exit?,
+        Err(_) => {
+            log::warn!(handler = handler, job = job; "Job {} still running {} after SIGTERM, killing it", job, humantime::format_duration(timeout.grace));
+            signal(libc::SIGKILL);
+            exit.await?;
+        }
+    }
+    signal(libc::SIGKILL);
+    Ok(true)
+}
+
+/// Waits for a child process to exit, without reaping it.
+///
+/// The exit is awaited on a pidfd ; on the kernels without them (before 5.3), it is polled.
+async fn exited(pid: u32) -> std::io::Result<()> {
+    // Safety: pidfd_open takes a pid and flags, and returns a new file descriptor
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        while !has_exited(pid)? {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        return Ok(());
+    }
+    // Safety: the descriptor was just opened, and is owned here
+    let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+    // a pidfd becomes readable once its process exited
+    let fd = AsyncFd::with_interest(fd, Interest::READABLE)?;
+    let _ready = fd.readable().await?;
+    Ok(())
+}
+
+/// Tells whether a child process exited, without reaping it.
+fn has_exited(pid: u32) -> std::io::Result<bool> {
+    // Safety: siginfo_t is a plain C struct, filled by waitid
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    loop {
+        // Safety: the pointer is valid for the duration of the call
+        if unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, libc::WEXITED | libc::WNOWAIT | libc::WNOHANG) } == 0 {
+            // Safety: si_pid is set by waitid, zero when the process is still running
+            return Ok(unsafe { info.si_pid() } != 0);
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Reads the CPU time of a child process that exited, before it is reaped.
+///
+/// @return the user and system CPU time of the process and of its waited-for children
+///
+fn cpu_time(pid: u32) -> std::io::Result<Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "unexpected format of /proc/<pid>/stat");
+    // the fields follow the command name, in parentheses, which may hold spaces ; utime, stime,
+    // cutime and cstime are the fields 14 to 17, counted from the pid
+    let fields = stat.rsplit_once(')').ok_or_else(invalid)?.1.split_whitespace().skip(11).take(4);
+    let mut ticks = 0u64;
+    for field in fields {
+        ticks += field.parse::<u64>().map_err(|_| invalid())?;
+    }
+    // Safety: sysconf has no precondition
+    let per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    let per_second = u64::try_from(per_second).ok().filter(|&ticks| ticks > 0).ok_or_else(invalid)?;
+    Ok(Duration::from_secs(ticks / per_second) + Duration::from_nanos((ticks % per_second) * 1_000_000_000 / per_second))
+}
+
+/// Writes the input of the script, closing its standard input once written.
+async fn feed(stdin: Option<ChildStdin>, input: Bytes, job: &str) {
+    let Some(mut stdin) = stdin else {
+        return;
+    };
+    if let Err(error) = stdin.write_all(&input).await {
+        // the script exited, or closed its input, without reading it all
+        if error.kind() != std::io::ErrorKind::BrokenPipe {
+            log::error!("Could not write the standard input of job {}: {}", job, error);
+        }
+    }
+}
+
+/// Reads a stream of the script, logging each line up to the max size, and keeping its end.
+async fn stream<R: AsyncRead + Unpin>(reader: Option<R>, name: &'static str, handler: &str, job: &str, max_size: usize) -> Stream {
+    let mut stream = Stream::default();
+    let Some(reader) = reader else {
+        return stream;
+    };
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    while let Ok(read) = (&mut reader).take(MAX_LINE as u64).read_until(b'\n', &mut line).await {
+        if read == 0 {
+            break;
+        }
+        let logged = stream.size < max_size;
+        stream.size += read;
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end_matches(['\n', '\r']);
+        match text.strip_prefix(PROGRESS_MARKER) {
+            Some(progress) => log::info!(handler = handler, job = job, stream = name, progress = progress.trim(); "{} progress: {}", job, progress.trim()),
+            None if logged => log::info!(handler = handler, job = job, stream = name; "{} [{}] {}", job, name, text),
+            None => {}
+        }
+        if logged && stream.size >= max_size {
+            log::warn!(handler = handler, job = job, stream = name; "{} [{}] output larger than {} bytes, the next lines are not logged", job, name, max_size);
+        }
+        // the stream is trimmed once it holds twice the max size, rather than on every line
+        stream.kept.extend_from_slice(&line);
+        if stream.kept.len() > 2 * max_size {
+            stream.kept.drain(..stream.kept.len() - max_size);
+        }
+        line.clear();
+    }
+    if stream.kept.len() > max_size {
+        stream.kept.drain(..stream.kept.len() - max_size);
+    }
+    stream
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+    use super::*;
+
+    /// A command running a shell script.
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[tokio::test]
+    async fn the_input_is_fed_and_the_output_collected() {
+        let (output, _, timed_out) = run(shell("cat; echo done >&2; exit 3"), Bytes::from_static(b"hello\n"), "test", "job-1", None, DEFAULT_MAX_SIZE).await.unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"hello\n");
+        assert_eq!(output.stderr, b"done\n");
+        assert_eq!(output.size, 11);
+        assert!(!output.truncated && !timed_out);
+    }
+
+    #[tokio::test]
+    async fn the_end_of_a_large_output_is_kept() {
+        let (output, _, _) = run(shell("seq 1 1000"), Bytes::new(), "test", "job-2", None, 16).await.unwrap();
+        assert!(output.truncated);
+        assert_eq!(output.stdout, b"97\n998\n999\n1000\n");
+    }
+
+    #[tokio::test]
+    async fn a_script_past_its_timeout_is_killed_with_its_group() {
+        let timeout = Timeout { limit: Duration::from_millis(200), grace: Duration::from_millis(200) };
+        let started = std::time::Instant::now();
+        // the script ignores SIGTERM, and leaves a process behind holding its output
+        let (output, _, timed_out) = run(shell("trap '' TERM; sleep 30 & sleep 30"), Bytes::new(), "test", "job-3", Some(timeout), DEFAULT_MAX_SIZE).await.unwrap();
+        assert!(timed_out);
+        assert_eq!(output.status.signal(), Some(libc::SIGKILL));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn the_cpu_time_is_reported() {
+        let (output, cpu_time, _) = run(shell("i=0; while [ $i -lt 200000 ]; do i=$((i+1)); done"), Bytes::new(), "test", "job-4", None, DEFAULT_MAX_SIZE).await.unwrap();
+        assert!(output.status.success());
+        assert!(cpu_time > Duration::ZERO);
+    }
+}
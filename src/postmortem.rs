@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, SystemTime};
+use crate::harehandler::HareError;
+
+/// Number of journal lines collected in a bundle.
+const JOURNAL_LINES: &str = "200";
+
+/// How long journalctl may run, before the bundle is written without the journal.
+const JOURNAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fragments of variable names whose values are redacted from the environment snapshot.
+const SECRET_MARKERS: [&str; 6] = ["PASSWORD", "PASSWD", "SECRET", "TOKEN", "KEY", "CREDENTIAL"];
+
+/// What is known about a failed execution.
+pub struct Failure<'a> {
+    pub handler: &'a str,                           // message type
+    pub script_path: &'a str,                       // path of the script
+    pub status: ExitStatus,                         // exit status of the script
+    pub started: SystemTime,                        // start of the execution
+    pub duration: Duration,                         // duration of the execution
+    pub environment: &'a HashMap<String, String>,   // variables set by hare for the script
+    pub stdout: &'a [u8],                           // standard output of the script
+    pub stderr: &'a [u8],                           // standard error of the script
+}
+
+/// Collects a post-mortem bundle for a failed execution.
+///
+/// The bundle is a directory created under `root`, holding :
+///
+/// * `context.json` : handler, script, exit status, timing, core dump information,
+/// * `environment` : the environment of the script, with secret values redacted,
+/// * `stdout` and `stderr` : the output of the script,
+/// * `journal` : the system journal since the start of the execution, when journalctl is available
+///   and answers within JOURNAL_TIMEOUT.
+///
+/// @return the path of the bundle directory
+///
+/// # Errors
+///
+/// This function will return an error if the bundle directory or its files cannot be written.
+pub async fn collect(root: &Path, failure: &Failure<'_>) -> Result<PathBuf, HareError> {
+    let started = failure.started.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let dir = root.join(format!("{}-{:09}-{}", started.as_secs(), started.subsec_nanos(), failure.handler.replace('/', ".")));
+    fs::create_dir_all(&dir)?;
+
+    let core_pattern = fs::read_to_string("/proc/sys/kernel/core_pattern").ok().map(|v| v.trim().to_string());
+    let context = serde_json::json!({
+        "handler": failure.handler,
+        "script": failure.script_path,
+        "exit_code": failure.status.code(),
+        "signal": failure.status.signal(),
+        "core_dumped": failure.status.core_dumped(),
+        "core_pattern": core_pattern,
+        "started": humantime::format_rfc3339_millis(failure.started).to_string(),
+        "duration_ms": failure.duration.as_millis() as u64,
+        "pid": std::process::id(),
+    });
+    fs::write(dir.join("context.json"), serde_json::to_string_pretty(&context).unwrap_or_default())?;
+
+    // the script inherits the hare environment, plus the variables set by hare
+    let mut environment: BTreeMap<String, String> = std::env::vars().collect();
+    environment.extend(failure.environment.iter().map(|(k, v)| (k.clone(), v.clone())));
+    let snapshot: String = environment.iter()
+        .map(|(k, v)| format!("{}={}\n", k, if is_secret(k) { "<redacted>" } else { v }))
+        .collect();
+    fs::write(dir.join("environment"), snapshot)?;
+
+    fs::write(dir.join("stdout"), failure.stdout)?;
+    fs::write(dir.join("stderr"), failure.stderr)?;
+
+    let journal = tokio::process::Command::new("journalctl")
+        .args(["--no-pager", "-n", JOURNAL_LINES, "--since"])
+        .arg(format!("@{}", started.as_secs()))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(JOURNAL_TIMEOUT, journal).await {
+        Ok(Ok(journal)) if journal.status.success() => fs::write(dir.join("journal"), journal.stdout)?,
+        _ => log::debug!("No journal excerpt for the post-mortem bundle {}", dir.display()),
+    }
+
+    Ok(dir)
+}
+
+/// Whether a variable is likely to hold a secret.
+pub fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    SECRET_MARKERS.iter().any(|marker| name.contains(marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_bundle_holds_the_context_environment_and_output() {
+        let root = std::env::temp_dir().join(format!("hare-postmortem-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let environment = HashMap::from([
+            ("HARE_VAR_TYPE".to_string(), "deploy".to_string()),
+            ("HARE_VAR_API_TOKEN".to_string(), "s3cr3t".to_string()),
+        ]);
+        let started = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 42);
+        let failure = Failure {
+            handler: "deploy/web",
+            script_path: "/etc/hare/scripts/deploy/web",
+            status: ExitStatus::from_raw(3 << 8),
+            started,
+            duration: Duration::from_millis(1500),
+            environment: &environment,
+            stdout: b"deploying\n",
+            stderr: b"error: no image\n",
+        };
+
+        let dir = collect(&root, &failure).await.unwrap();
+        assert_eq!(dir, root.join("1700000000-000000042-deploy.web"));
+        let context: serde_json::Value = serde_json::from_slice(&fs::read(dir.join("context.json")).unwrap()).unwrap();
+        assert_eq!(context["handler"], "deploy/web");
+        assert_eq!(context["script"], "/etc/hare/scripts/deploy/web");
+        assert_eq!(context["exit_code"], 3);
+        assert_eq!(context["signal"], serde_json::Value::Null);
+        assert_eq!(context["core_dumped"], false);
+        assert_eq!(context["duration_ms"], 1500);
+        assert_eq!(context["started"], "2023-11-14T22:13:20.000Z");
+
+        let snapshot = fs::read_to_string(dir.join("environment")).unwrap();
+        assert!(snapshot.lines().any(|line| line == "HARE_VAR_TYPE=deploy"));
+        assert!(snapshot.lines().any(|line| line == "HARE_VAR_API_TOKEN=<redacted>"));
+        assert!(!snapshot.contains("s3cr3t"));
+        assert_eq!(fs::read(dir.join("stdout")).unwrap(), b"deploying\n");
+        assert_eq!(fs::read(dir.join("stderr")).unwrap(), b"error: no image\n");
+    }
+
+    #[test]
+    fn secrets_are_told_by_their_name() {
+        assert!(is_secret("AWS_SECRET_ACCESS_KEY"));
+        assert!(is_secret("db_password"));
+        assert!(is_secret("HARE_VAR_X_AUTH_TOKEN"));
+        assert!(!is_secret("HARE_VAR_TYPE"));
+        assert!(!is_secret("PATH"));
+    }
+}
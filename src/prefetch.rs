@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+/// Smoothing factor of the moving average of script durations.
+const SMOOTHING: f64 = 0.2;
+
+/// Number of observations between two prefetch adjustments.
+const ADJUST_EVERY: u32 = 5;
+
+/// Bounds of the adaptive prefetch mode.
+#[derive(Clone, Debug)]
+pub struct PrefetchBounds {
+    pub min: u16,                   // lowest prefetch count
+    pub max: u16,                   // highest prefetch count
+    pub target_window: Duration,    // how much work the unacked backlog should represent
+}
+
+/// Adjusts the AMQP prefetch count from the observed script durations.
+///
+/// The tuner keeps an exponential moving average of the processing time of a message,
+/// and sizes the prefetch so that the messages buffered on the client side represent
+/// about `target_window` of work for the busy workers : fast handlers get a large prefetch
+/// (throughput), slow handlers a small one (short unacked backlog, messages stay
+/// available for other consumers).
+pub struct PrefetchTuner {
+    bounds: PrefetchBounds,
+    average: Option<f64>,   // average processing duration, in seconds
+    current: u16,           // prefetch count currently applied
+    observations: u32,      // observations since last adjustment
+}
+
+impl PrefetchTuner {
+
+    /// Creates a new tuner, starting at the lowest prefetch count.
+    ///
+    /// @return PrefetchTuner
+    ///
+    pub fn new(mut bounds: PrefetchBounds) -> Self {
+        bounds.min = bounds.min.max(1);
+        bounds.max = bounds.max.max(bounds.min);
+        let current = bounds.min;
+        PrefetchTuner { bounds, average: None, current, observations: 0 }
+    }
+
+    /// The prefetch count currently applied.
+    pub fn current(&self) -> u16 {
+        self.current
+    }
+
+    /// Records the processing duration of a message.
+    ///
+    /// `busy` is the number of workers that were busy when the message completed,
+    /// out of `concurrency` workers : a pool that is not fully used does not need
+    /// a larger prefetch.
+    ///
+    /// @return the new prefetch count, if it must be changed
+    ///
+    pub fn observe(&mut self, duration: Duration, busy: usize, concurrency: usize) -> Option<u16> {
+        let seconds = duration.as_secs_f64();
+        self.average = Some(match self.average {
+            None => seconds,
+            Some(average) => average + SMOOTHING * (seconds - average),
+        });
+
+        self.observations += 1;
+        if self.observations < ADJUST_EVERY {
+            return None;
+        }
+        self.observations = 0;
+
+        let target = self.target(busy, concurrency);
+        if target != self.current {
+            self.current = target;
+            Some(target)
+        } else {
+            None
+        }
+    }
+
+    /// Computes the prefetch count from the average duration and the worker utilization.
+    fn target(&self, busy: usize, concurrency: usize) -> u16 {
+        let average = self.average.unwrap_or_default().max(0.001);
+        let workers = busy.clamp(1, concurrency.max(1)) as f64;
+        let per_worker = (self.bounds.target_window.as_secs_f64() / average).max(1.0);
+        let target = (workers * per_worker).ceil();
+
+        (target.min(u16::MAX as f64) as u16).clamp(self.bounds.min, self.bounds.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tuner(min: u16, max: u16) -> PrefetchTuner {
+        PrefetchTuner::new(PrefetchBounds { min, max, target_window: Duration::from_secs(2) })
+    }
+
+    /// Observes the same duration until the tuner adjusts the prefetch.
+    fn observe(tuner: &mut PrefetchTuner, millis: u64, busy: usize, concurrency: usize) -> Option<u16> {
+        (0..ADJUST_EVERY).map(|_| tuner.observe(Duration::from_millis(millis), busy, concurrency)).last().flatten()
+    }
+
+    #[test]
+    fn the_bounds_are_sane() {
+        let tuner = tuner(0, 0);
+        assert_eq!((tuner.bounds.min, tuner.bounds.max, tuner.current()), (1, 1, 1));
+        let tuner = PrefetchTuner::new(PrefetchBounds { min: 10, max: 5, target_window: Duration::from_secs(2) });
+        assert_eq!((tuner.bounds.min, tuner.bounds.max, tuner.current()), (10, 10, 10));
+    }
+
+    #[test]
+    fn the_prefetch_is_adjusted_every_few_observations() {
+        let mut tuner = tuner(1, 50);
+        for _ in 1..ADJUST_EVERY {
+            assert_eq!(tuner.observe(Duration::from_millis(100), 1, 1), None);
+            assert_eq!(tuner.current(), 1);
+        }
+        // 2s of work is 20 messages of 100ms
+        assert_eq!(tuner.observe(Duration::from_millis(100), 1, 1), Some(20));
+        assert_eq!(tuner.current(), 20);
+        // unchanged, nothing to apply
+        assert_eq!(observe(&mut tuner, 100, 1, 1), None);
+    }
+
+    #[test]
+    fn fast_handlers_get_a_large_prefetch_and_slow_ones_a_small_one() {
+        let mut fast = tuner(1, 50);
+        assert_eq!(observe(&mut fast, 1, 1, 1), Some(50));
+        let mut slow = tuner(2, 50);
+        assert_eq!(observe(&mut slow, 10_000, 1, 1), None);
+        assert_eq!(slow.current(), 2);
+    }
+
+    #[test]
+    fn the_prefetch_grows_with_the_busy_workers() {
+        let mut tuner = tuner(1, 100);
+        tuner.average = Some(0.5);
+        // 4 messages of 500ms per busy worker
+        assert_eq!(tuner.target(1, 4), 4);
+        assert_eq!(tuner.target(3, 4), 12);
+        assert_eq!(tuner.target(0, 4), 4);
+        assert_eq!(tuner.target(8, 4), 16);
+        assert_eq!(tuner.target(2, 0), 4);
+        // at least a message per busy worker
+        tuner.average = Some(60.0);
+        assert_eq!(tuner.target(3, 4), 3);
+    }
+
+    #[test]
+    fn the_average_follows_the_durations() {
+        let mut tuner = tuner(1, 1000);
+        assert_eq!(observe(&mut tuner, 100, 1, 1), Some(20));
+        // the average moves toward 1s by a fifth of the gap on each observation
+        assert_eq!(observe(&mut tuner, 1000, 1, 1), Some(3));
+        let average = tuner.average.unwrap();
+        assert!((average - (1.0 - 0.9 * 0.8f64.powi(5))).abs() < 1e-9, "average {}", average);
+    }
+}
@@ -0,0 +1,58 @@
+use lapin::options::*;
+use lapin::protocol::{AMQPErrorKind, AMQPSoftError};
+use lapin::types::FieldTable;
+use lapin::{Connection, ExchangeKind};
+use crate::harehandler::HareError;
+
+/// Checks that the configured credentials can use the queue and the exchanges of hare.
+///
+/// The queue is declared passively (it must exist), and a message is fetched with `basic.get` and
+/// requeued at once, to check the read permission needed to consume. The exchanges hare publishes
+/// to are declared passively : this checks that they exist and that the user may access them, the
+/// write permission itself is only checked by the first publication.
+///
+/// Each check runs on its own channel, since the broker closes the channel on the first error.
+///
+/// # Arguments
+///
+/// * `connection` - the broker connection
+/// * `queue` - the queue hare consumes
+/// * `exchanges` - the exchanges hare publishes to, with their role (e.g. "result")
+///
+/// # Errors
+///
+/// This function will return an error naming the missing queue, exchange or permission.
+pub async fn check(connection: &Connection, queue: &str, exchanges: &[(&str, &str)]) -> Result<(), HareError> {
+    let channel = connection.create_channel().await?;
+    channel.queue_declare(queue, QueueDeclareOptions { passive: true, ..QueueDeclareOptions::default() }, FieldTable::default()).await
+        .map_err(|error| explain(error, &format!("queue {}", queue), "cannot access the queue"))?;
+    let message = channel.basic_get(queue, BasicGetOptions::default()).await
+        .map_err(|error| explain(error, &format!("queue {}", queue), "missing read permission, needed to consume the queue"))?;
+    if let Some(message) = message {
+        message.delivery.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await?;
+    }
+    let _ = channel.close(200, "pre-flight check").await;
+
+    for (role, exchange) in exchanges {
+        let channel = connection.create_channel().await?;
+        channel.exchange_declare(exchange, ExchangeKind::Topic, ExchangeDeclareOptions { passive: true, ..ExchangeDeclareOptions::default() }, FieldTable::default()).await
+            .map_err(|error| explain(error, &format!("{} exchange {}", role, exchange), "cannot access the exchange"))?;
+        let _ = channel.close(200, "pre-flight check").await;
+    }
+
+    log::info!("Pre-flight check passed for queue {}", queue);
+    Ok(())
+}
+
+/// Turns a broker error into a pre-flight error naming the resource and the problem.
+fn explain(error: lapin::Error, resource: &str, refused: &str) -> HareError {
+    let reason = match &error {
+        lapin::Error::ProtocolError(amqp) => match amqp.kind() {
+            AMQPErrorKind::Soft(AMQPSoftError::NOTFOUND) => format!("{} does not exist", resource),
+            AMQPErrorKind::Soft(AMQPSoftError::ACCESSREFUSED) => format!("{}: {} ({})", resource, refused, amqp.get_message()),
+            _ => format!("{}: {}", resource, error),
+        },
+        _ => format!("{}: {}", resource, error),
+    };
+    HareError::PreflightError(reason)
+}
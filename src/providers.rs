@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::conversion::MAX_VALUE_SIZE;
+
+/// Default time a provided value is kept before it is fetched again.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// How long fetching a value may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Name server queried for the TXT records, when /etc/resolv.conf gives none.
+const DEFAULT_NAME_SERVER: &str = "127.0.0.1";
+
+/// Where the value of a provided variable comes from.
+#[derive(Debug)]
+enum Source {
+    Http(String),       // body of a GET request, e.g. the region from a metadata service
+    DnsTxt(String),     // TXT records of a DNS name, joined with commas
+    File(PathBuf),      // content of a file
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Http(url) => write!(f, "http:{}", url),
+            Source::DnsTxt(name) => write!(f, "dns-txt:{}", name),
+            Source::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// A variable given to every execution, with the source of its value.
+#[derive(Debug)]
+struct Provider {
+    variable: String,   // name of the variable
+    source: Source,     // where its value is fetched
+    ttl: Duration,      // how long the value is kept before it is fetched again
+}
+
+/// Variables given to every execution, whose values are fetched at dispatch time (region, feature
+/// flags...), rather than baked into the scripts.
+///
+/// The providers are declared with HARE_ENV_PROVIDERS, a comma separated list of `NAME=kind:target`
+/// entries, the kind being `http`, `dns-txt` or `file`, with an optional `;ttl=<duration>` suffix :
+///
+/// ```text
+/// REGION=http:http://169.254.169.254/latest/meta-data/placement/region;ttl=1h,FLAGS=dns-txt:flags.example.com
+/// ```
+///
+/// The values are cached for their TTL. A value that cannot be fetched is logged ; the previous
+/// value is kept if there is one, otherwise the variable is left out.
+#[derive(Debug)]
+pub struct EnvProviders {
+    providers: Vec<Provider>,                           // declared providers
+    cache: Mutex<HashMap<String, (String, Instant)>>,   // fetched values, with their fetch time, per variable
+}
+
+impl EnvProviders {
+
+    /// Parses the declaration of the providers.
+    ///
+    /// # Arguments
+    ///
+    /// * `declaration` - the value of HARE_ENV_PROVIDERS
+    /// * `ttl` - the TTL of the providers without their own
+    ///
+    /// @return EnvProviders
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if an entry is invalid, gives an invalid variable name or a
+    /// name starting with HARE_ (reserved for the environment contract), or declares a variable twice.
+    pub fn parse(declaration: &str, ttl: Duration) -> Result<EnvProviders, String> {
+        let mut providers: Vec<Provider> = Vec::new();
+        for entry in declaration.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let invalid = |reason: &str| format!("invalid environment provider {:?}: {}", entry, reason);
+            let (variable, source) = entry.split_once('=').ok_or_else(|| invalid("expected NAME=kind:target"))?;
+            let variable = variable.trim();
+            if variable.is_empty() || variable.starts_with(|c: char| c.is_ascii_digit())
+                || !variable.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(invalid("invalid variable name"));
+            }
+            if variable.starts_with("HARE_") {
+                return Err(invalid("the HARE_ variables are reserved"));
+            }
+            if providers.iter().any(|provider| provider.variable == variable) {
+                return Err(invalid("variable declared twice"));
+            }
+
+            let (source, provider_ttl) = match source.rsplit_once(";ttl=") {
+                Some((source, provider_ttl)) => (source, humantime::parse_duration(provider_ttl.trim()).map_err(|error| invalid(&error.to_string()))?),
+                None => (source, ttl),
+            };
+            let source = match source.trim().split_once(':') {
+                Some(("http", url)) if url.starts_with("http://") || url.starts_with("https://") => Source::Http(url.to_string()),
+                Some(("dns-txt", name)) if !name.is_empty() => Source::DnsTxt(name.trim_end_matches('.').to_string()),
+                Some(("file", path)) if !path.is_empty() => Source::File(PathBuf::from(path)),
+                _ => return Err(invalid("expected http:<url>, dns-txt:<name> or file:<path>")),
+            };
+            providers.push(Provider { variable: variable.to_string(), source, ttl: provider_ttl });
+        }
+        Ok(EnvProviders { providers, cache: Mutex::new(HashMap::new()) })
+    }
+
+    /// The provided variables, fetching the values that are not cached or are expired.
+    ///
+    /// @return the name and the value of each variable that has a value
+    ///
+    pub async fn resolve(&self) -> Vec<(String, String)> {
+        let mut variables = Vec::with_capacity(self.providers.len());
+        for provider in &self.providers {
+            let cached = self.cache.lock().unwrap().get(&provider.variable).cloned();
+            if let Some((value, fetched)) = &cached {
+                if fetched.elapsed() < provider.ttl {
+                    variables.push((provider.variable.clone(), value.clone()));
+                    continue;
+                }
+            }
+
+            match fetch(&provider.source).await {
+                Ok(value) => {
+                    self.cache.lock().unwrap().insert(provider.variable.clone(), (value.clone(), Instant::now()));
+                    variables.push((provider.variable.clone(), value));
+                }
+                Err(error) => match cached {
+                    // the previous value is kept, and fetched again for the next execution
+                    Some((value, _)) => {
+                        log::warn!("Could not fetch {} from {}, keeping its previous value: {}", provider.variable, provider.source, error);
+                        variables.push((provider.variable.clone(), value));
+                    }
+                    None => log::error!("Could not fetch {} from {}, variable not set: {}", provider.variable, provider.source, error),
+                },
+            }
+        }
+        variables
+    }
+}
+
+/// Fetches the value of a source, with a timeout.
+async fn fetch(source: &Source) -> Result<String, String> {
+    let value = match source {
+        Source::Http(url) => {
+            let url = url.clone();
+            tokio::task::spawn_blocking(move || {
+                let response = ureq::AgentBuilder::new().timeout(FETCH_TIMEOUT).build()
+                    .get(&url).call().map_err(|error| error.to_string())?;
+                response.into_string().map_err(|error| error.to_string())
+            }).await.map_err(|error| error.to_string())??
+        }
+        Source::File(path) => {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || std::fs::read_to_string(path))
+                .await.map_err(|error| error.to_string())?
+                .map_err(|error| error.to_string())?
+        }
+        Source::DnsTxt(name) => tokio::time::timeout(FETCH_TIMEOUT, dns_txt(name)).await
+            .map_err(|_| format!("no answer in {}", humantime::format_duration(FETCH_TIMEOUT)))??,
+    };
+    let value = value.trim();
+    if value.len() > MAX_VALUE_SIZE || value.contains('\0') {
+        return Err("the value is too large, or contains a NUL character".to_string());
+    }
+    Ok(value.to_string())
+}
+
+/// Queries the TXT records of a name, from the first name server of /etc/resolv.conf.
+///
+/// @return the records, joined with commas, each record being the concatenation of its strings
+///
+async fn dns_txt(name: &str) -> Result<String, String> {
+    let server = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default().lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .map(|server| server.trim().to_string())
+        .next()
+        .unwrap_or_else(|| DEFAULT_NAME_SERVER.to_string());
+    let server = match server.parse::<std::net::IpAddr>() {
+        Ok(address) => std::net::SocketAddr::new(address, 53),
+        Err(_) => return Err(format!("invalid name server {}", server)),
+    };
+
+    // a random id, so that an off-path host cannot guess it to forge an answer
+    let mut id = [0u8; 2];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut id).map_err(|_| "no random DNS query id".to_string())?;
+    let query = dns_query(u16::from_be_bytes(id), name)?;
+    let socket = tokio::net::UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await.map_err(|error| error.to_string())?;
+    socket.connect(server).await.map_err(|error| error.to_string())?;
+    socket.send(&query).await.map_err(|error| error.to_string())?;
+
+    let mut response = vec![0u8; 4096];
+    loop {
+        let length = socket.recv(&mut response).await.map_err(|error| error.to_string())?;
+        // answers to other queries are ignored
+        if is_answer(&query, &response[..length]) {
+            return dns_answer(&response[..length]);
+        }
+    }
+}
+
+/// Whether a response answers a query : same id, and same question.
+fn is_answer(query: &[u8], response: &[u8]) -> bool {
+    let Some(question) = response.get(12..query.len()) else { return false };
+    response[..2] == query[..2]
+        && response[2] & 0x80 != 0                  // a response
+        && response[4..6] == [0, 1]                 // with a single question
+        && question.eq_ignore_ascii_case(&query[12..])
+}
+
+/// Builds a recursive query for the TXT records of a name.
+fn dns_query(id: u16, name: &str) -> Result<Vec<u8>, String> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]); // recursion desired, one question
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid DNS name {}", name));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0, 0, 16, 0, 1]); // end of name, type TXT, class IN
+    Ok(query)
+}
+
+/// Reads the TXT records of a response.
+///
+/// # Errors
+///
+/// This function will return an error if the response is truncated or malformed, is an error, or
+/// holds no TXT record.
+fn dns_answer(response: &[u8]) -> Result<String, String> {
+    let truncated = || "truncated DNS response".to_string();
+    if response.len() < 12 {
+        return Err(truncated());
+    }
+    if response[2] & 0x02 != 0 {
+        return Err("DNS response too large for UDP".to_string());
+    }
+    match response[3] & 0x0f {
+        0 => {}
+        3 => return Err("no such DNS name".to_string()),
+        code => return Err(format!("DNS error {}", code)),
+    }
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+
+    let mut position = 12;
+    for _ in 0..questions {
+        position = skip_name(response, position).ok_or_else(truncated)? + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        position = skip_name(response, position).ok_or_else(truncated)?;
+        let header = response.get(position..position + 10).ok_or_else(truncated)?;
+        let kind = u16::from_be_bytes([header[0], header[1]]);
+        let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let data = response.get(position + 10..position + 10 + length).ok_or_else(truncated)?;
+        position += 10 + length;
+        // CNAME records may precede the TXT records
+        if kind != 16 {
+            continue;
+        }
+        let mut record = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let size = data[offset] as usize;
+            record.extend_from_slice(data.get(offset + 1..offset + 1 + size).ok_or_else(truncated)?);
+            offset += 1 + size;
+        }
+        records.push(String::from_utf8_lossy(&record).to_string());
+    }
+    if records.is_empty() {
+        return Err("no TXT record".to_string());
+    }
+    Ok(records.join(","))
+}
+
+/// Skips a name, made of labels or ending with a compression pointer.
+///
+/// @return the position after the name, None if the response is truncated or the name is invalid
+///
+fn skip_name(response: &[u8], mut position: usize) -> Option<usize> {
+    loop {
+        let length = *response.get(position)? as usize;
+        match length {
+            0 => return Some(position + 1),
+            length if length & 0xc0 == 0xc0 => return response.get(position + 1).map(|_| position + 2),
+            // 0x40 and 0x80 are reserved label types
+            length if length & 0xc0 != 0 => return None,
+            length => position += 1 + length,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response to a query, with the given answers : (type, data), their name a pointer to the question.
+    fn response(query: &[u8], answers: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2] |= 0x80;
+        response[7] = answers.len() as u8;
+        for (kind, data) in answers {
+            response.extend_from_slice(&[0xc0, 12]);
+            response.extend_from_slice(&kind.to_be_bytes());
+            response.extend_from_slice(&[0, 1, 0, 0, 0x0e, 0x10]); // class IN, TTL 3600
+            response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            response.extend_from_slice(data);
+        }
+        response
+    }
+
+    #[test]
+    fn a_query_asks_for_the_txt_records() {
+        let query = dns_query(0x1234, "config.example.com").unwrap();
+        assert_eq!(&query[..12], &[0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&query[12..], b"\x06config\x07example\x03com\x00\x00\x10\x00\x01");
+        assert!(dns_query(1, "config..com").is_err());
+        assert!(dns_query(1, &"a".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn the_txt_records_are_read() {
+        let query = dns_query(7, "config.example.com").unwrap();
+        let answer = response(&query, &[(16, b"\x05eu-we\x04st-1"), (16, b"\x04prod")]);
+        assert!(is_answer(&query, &answer));
+        assert_eq!(dns_answer(&answer), Ok("eu-west-1,prod".to_string()));
+
+        // a CNAME, its name compressed, precedes the TXT record
+        let answer = response(&query, &[(5, b"\x03txt\xc0\x13"), (16, b"\x04blue")]);
+        assert_eq!(dns_answer(&answer), Ok("blue".to_string()));
+        assert_eq!(dns_answer(&response(&query, &[(5, b"\x03txt\xc0\x13")])), Err("no TXT record".to_string()));
+    }
+
+    #[test]
+    fn the_errors_are_reported() {
+        let query = dns_query(7, "config.example.com").unwrap();
+        let mut answer = response(&query, &[]);
+        answer[3] = 3;
+        assert_eq!(dns_answer(&answer), Err("no such DNS name".to_string()));
+        answer[3] = 2;
+        assert_eq!(dns_answer(&answer), Err("DNS error 2".to_string()));
+        answer[3] = 0;
+        answer[2] |= 0x02;
+        assert_eq!(dns_answer(&answer), Err("DNS response too large for UDP".to_string()));
+    }
+
+    #[test]
+    fn the_answers_to_other_queries_are_ignored() {
+        let query = dns_query(7, "config.example.com").unwrap();
+        let answer = response(&query, &[(16, b"\x04blue")]);
+        assert!(is_answer(&query, &answer));
+        // the case of the name may change
+        assert!(is_answer(&query, &response(&dns_query(7, "CONFIG.example.com").unwrap(), &[(16, b"\x04blue")])));
+
+        assert!(!is_answer(&dns_query(8, "config.example.com").unwrap(), &answer));
+        assert!(!is_answer(&dns_query(7, "config.example.org").unwrap(), &answer));
+        assert!(!is_answer(&query, &query));
+        let mut two_questions = answer.clone();
+        two_questions[5] = 2;
+        assert!(!is_answer(&query, &two_questions));
+        assert!(!is_answer(&query, &answer[..query.len() - 1]));
+        assert!(!is_answer(&query, &[]));
+    }
+
+    #[test]
+    fn a_truncated_or_malformed_response_is_an_error() {
+        let query = dns_query(7, "config.example.com").unwrap();
+        let answer = response(&query, &[(5, b"\x03txt\xc0\x13"), (16, b"\x05eu-we\x04st-1")]);
+        for length in 0..answer.len() {
+            assert!(dns_answer(&answer[..length]).is_err(), "{} bytes", length);
+            is_answer(&query, &answer[..length]);
+        }
+
+        // a string of a record longer than the record
+        assert!(dns_answer(&response(&query, &[(16, b"\x09short")])).is_err());
+        // more answers than the response holds
+        let mut answers = answer.clone();
+        answers[7] = 200;
+        assert!(dns_answer(&answers).is_err());
+        // a reserved label type
+        let mut reserved = answer.clone();
+        reserved[12] = 0x46;
+        assert!(dns_answer(&reserved).is_err());
+        assert_eq!(skip_name(&[0xc0], 0), None);
+        assert_eq!(skip_name(&[0xc0, 12], 0), Some(2));
+        assert_eq!(skip_name(&[3, b'c', b'o', b'm', 0], 0), Some(5));
+        assert_eq!(skip_name(&[3, b'c', b'o'], 0), None);
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
+use lapin::publisher_confirm::Confirmation;
+use lapin::{BasicProperties, Channel, Connection};
+use crate::harehandler::HareError;
+use crate::metrics::{self, Metrics};
+use crate::outbox::{Outbox, Overflow};
+
+/// Number of attempts to publish a message before keeping it in the pending buffer.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry, doubled at each attempt.
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// How long to wait for the broker to confirm a message.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of messages kept in memory while the broker does not confirm them.
+const MAX_PENDING: usize = 1000;
+
+/// Delay before publishing again after the broker did not confirm a message, doubled at each failure.
+const BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay between two attempts to publish the pending messages.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Interval at which the pending messages are published again, once their backoff is over.
+pub const RESUME_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A message emitted by hare.
+#[derive(Clone, Debug)]
+pub struct OutgoingMessage {
+    pub exchange: String,
+    pub routing_key: String,
+    pub body: Vec<u8>,
+    pub properties: BasicProperties,
+}
+
+/// Publishes the messages emitted by hare, with publisher confirms.
+///
+/// Every message is published on a dedicated channel in confirm mode, and is only
+/// considered sent once the broker acknowledged it. Messages that are nacked, not
+/// confirmed in time, or that fail because the channel was closed are retried with
+/// an exponential backoff (on a new channel if needed), then kept in a bounded pending
+/// buffer that is flushed before the next publication. Once a message could not be
+/// confirmed, the following ones are only buffered until a backoff delay is over (from 1s,
+/// doubled up to 1 minute), so that an unavailable broker does not slow down the handling
+/// of the messages.
+///
+/// With an outbox, messages are stored on disk before being published, and removed once
+/// confirmed : messages that could not be published before hare stopped are loaded from
+/// the outbox and published after the next connection. Only the oldest pending messages
+/// are kept in memory, the others are read from the outbox once they are sent.
+///
+/// Past the caps of the outbox (or 1000 messages in memory without outbox), the oldest
+/// messages are dropped, or the new ones, per the overflow policy.
+pub struct Publisher {
+    channel: Option<Channel>,           // confirm mode channel, re-created when closed
+    pending: VecDeque<(OutgoingMessage, Option<PathBuf>)>, // oldest messages not confirmed yet and their outbox entry, oldest first
+    outbox: Option<Outbox>,             // durable storage of the pending messages
+    overflow: Overflow,                 // messages dropped when the buffer is full
+    retry_at: Option<Instant>,          // end of the backoff, after a message could not be confirmed
+    backoff: Duration,                  // next backoff delay
+    metrics: Option<Arc<Metrics>>,      // registry of the dropped and pending messages
+}
+
+impl Publisher {
+
+    /// Creates a new publisher, the channel is opened on first use.
+    ///
+    /// The messages left in the outbox are pending messages, the overflow policy is the one of
+    /// the outbox if any.
+    ///
+    /// @return Result<Publisher, HareError>
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the outbox cannot be read.
+    pub fn new(outbox: Option<Outbox>, overflow: Overflow) -> Result<Self, HareError> {
+        let overflow = outbox.as_ref().map_or(overflow, |outbox| outbox.limits().overflow);
+        let mut publisher = Publisher { channel: None, pending: VecDeque::new(), outbox, overflow, retry_at: None, backoff: BACKOFF, metrics: None };
+        publisher.refill();
+        Ok(publisher)
+    }
+
+    /// Counts the dropped and pending messages in a metrics registry.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Publishes a message, and waits for the broker confirmation.
+    ///
+    /// Pending messages are flushed first, to keep the publication order.
+    /// If the message cannot be confirmed, it is kept in the pending buffer.
+    ///
+    /// @return Result<(), HareError>
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the message, or an older pending message,
+    /// could not be confirmed by the broker.
+    pub async fn publish(&mut self, connection: &Connection, message: OutgoingMessage) -> Result<(), HareError> {
+        self.buffer(message)?;
+        self.send(connection).await
+    }
+
+    /// Publishes the copy of a consumed message (forwarded, retried, dead-lettered...), and waits
+    /// for the broker confirmation, before the message is acked.
+    ///
+    /// Unlike `publish`, a copy that could not be confirmed is only kept when the outbox stored it :
+    /// without outbox, it is withdrawn from the pending buffer, as the message is requeued rather
+    /// than acked, and would be copied twice otherwise.
+    ///
+    /// @return Result<(), HareError>
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the copy was neither confirmed by the broker, nor stored
+    /// in the outbox.
+    pub async fn publish_copy(&mut self, connection: &Connection, message: OutgoingMessage) -> Result<(), HareError> {
+        if !self.buffer(message)? {
+            return Err(HareError::PublishError("the copy was dropped, the buffer is full".to_string()));
+        }
+        match self.send(connection).await {
+            Err(error) if self.outbox.is_none() => {
+                // the copy is the newest pending message
+                self.pending.pop_back();
+                self.observe();
+                Err(error)
+            }
+            Err(error) => {
+                log::warn!("Copy kept in the outbox, until the broker confirms it: {}", error);
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Publishes the pending messages, unless their backoff is running.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error during the backoff, or if a message could not be confirmed by the broker.
+    async fn send(&mut self, connection: &Connection) -> Result<(), HareError> {
+        if let Some(retry_at) = self.retry_at.filter(|retry_at| *retry_at > Instant::now()) {
+            return Err(HareError::PublishError(format!("{} messages not confirmed by the broker, publishing again in {}s",
+                self.pending(), retry_at.saturating_duration_since(Instant::now()).as_secs() + 1)));
+        }
+        self.flush(connection).await
+    }
+
+    /// Publishes the pending messages once their backoff is over, e.g. on a timer.
+    ///
+    /// @return Result<(), HareError>
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if a message could not be confirmed by the broker.
+    pub async fn resume(&mut self, connection: &Connection) -> Result<(), HareError> {
+        match self.retry_at {
+            Some(retry_at) if retry_at <= Instant::now() && self.pending() > 0 => self.flush(connection).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Publishes the pending messages, oldest first.
+    ///
+    /// @return Result<(), HareError>
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if a message could not be confirmed by the broker,
+    /// the message and the following ones stay in the pending buffer.
+    pub async fn flush(&mut self, connection: &Connection) -> Result<(), HareError> {
+        loop {
+            if self.pending.is_empty() {
+                self.refill();
+            }
+            let Some((message, _)) = self.pending.front() else { break };
+            let message = message.clone();
+            if let Err(error) = self.publish_with_retry(connection, &message).await {
+                self.retry_at = Some(Instant::now() + self.backoff);
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                self.observe();
+                return Err(error);
+            }
+            self.retry_at = None;
+            self.backoff = BACKOFF;
+
+            if let Some((_, Some(path))) = self.pending.pop_front() {
+                if let Some(outbox) = self.outbox.as_mut() {
+                    outbox.remove(&path)?;
+                }
+            }
+        }
+        self.observe();
+        Ok(())
+    }
+
+    /// Number of messages waiting for a broker confirmation, in the outbox or in memory.
+    pub fn pending(&self) -> usize {
+        match &self.outbox {
+            Some(outbox) => outbox.len(),
+            None => self.pending.len(),
+        }
+    }
+
+    /// Adds a message to the pending messages, dropping the oldest ones or the message past the
+    /// caps of the outbox, or of the buffer in memory.
+    ///
+    /// @return whether the message is kept, false if it was dropped
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the message could not be written to the outbox.
+    fn buffer(&mut self, message: OutgoingMessage) -> Result<bool, HareError> {
+        let Some(outbox) = self.outbox.as_mut() else {
+            if self.pending.len() >= MAX_PENDING {
+                match self.overflow {
+                    Overflow::DropOldest => if let Some((dropped, _)) = self.pending.pop_front() {
+                        self.dropped(&format!("the message to {} ({})", dropped.exchange, dropped.routing_key));
+                    },
+                    Overflow::DropNewest => {
+                        self.dropped(&format!("the message to {} ({})", message.exchange, message.routing_key));
+                        return Ok(false);
+                    }
+                }
+            }
+            self.pending.push_back((message, None));
+            return Ok(true);
+        };
+
+        let mut evicted = vec![];
+        if self.overflow == Overflow::DropOldest {
+            while outbox.is_full(&message) {
+                let Some(path) = outbox.evict()? else { break };
+                // the oldest messages of the outbox are the ones in memory
+                match self.pending.front() {
+                    Some((dropped, Some(front))) if *front == path => {
+                        evicted.push(format!("the message to {} ({})", dropped.exchange, dropped.routing_key));
+                        self.pending.pop_front();
+                    }
+                    _ => evicted.push(format!("the message {}", path.display())),
+                }
+            }
+        }
+        let stored = match outbox.is_full(&message) {
+            true => None,
+            false => Some(outbox.store(&message)?),
+        };
+        // the messages in memory are the oldest of the outbox
+        let in_memory = self.pending.len() + 1 == outbox.len() && self.pending.len() < MAX_PENDING;
+        for dropped in evicted {
+            self.dropped(&dropped);
+        }
+        match stored {
+            Some(path) if in_memory => self.pending.push_back((message, Some(path))),
+            Some(_) => {}
+            None => {
+                self.dropped(&format!("the message to {} ({})", message.exchange, message.routing_key));
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Loads the oldest messages of the outbox in memory, once the previous ones were sent.
+    fn refill(&mut self) {
+        if let Some(outbox) = self.outbox.as_mut().filter(|outbox| !outbox.is_empty()) {
+            self.pending.extend(outbox.load(MAX_PENDING).into_iter().map(|(path, message)| (message, Some(path))));
+        }
+    }
+
+    /// Logs and counts a dropped message.
+    fn dropped(&self, message: &str) {
+        let buffer = if self.outbox.is_some() { "Outbox" } else { "Publisher buffer" };
+        log::error!("{} full, dropping {}", buffer, message);
+        if let Some(metrics) = &self.metrics {
+            metrics.increment(&metrics::PUBLICATIONS_DROPPED, &[("overflow", self.overflow.name())]);
+        }
+    }
+
+    /// Reports the number of pending messages.
+    fn observe(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set(&metrics::PUBLICATIONS_PENDING, &[], self.pending() as f64);
+        }
+    }
+
+    /// Publishes a message, retrying with an exponential backoff.
+    async fn publish_with_retry(&mut self, connection: &Connection, message: &OutgoingMessage) -> Result<(), HareError> {
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 1;
+
+        loop {
+            match self.publish_confirmed(connection, message).await {
+                Ok(()) => return Ok(()),
+                Err(error) if attempt < MAX_ATTEMPTS => {
+                    log::warn!("Publication to {} failed (attempt {}/{}): {}", message.exchange, attempt, MAX_ATTEMPTS, error);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Publishes a message once, and waits for the broker confirmation.
+    async fn publish_confirmed(&mut self, connection: &Connection, message: &OutgoingMessage) -> Result<(), HareError> {
+        let channel = self.confirm_channel(connection).await?;
+
+        let confirm = channel.basic_publish(
+            &message.exchange,
+            &message.routing_key,
+            BasicPublishOptions { mandatory: true, ..BasicPublishOptions::default() },
+            &message.body,
+            message.properties.clone(),
+        ).await;
+
+        let confirmation = match confirm {
+            Ok(confirm) => tokio::time::timeout(CONFIRM_TIMEOUT, confirm).await
+                .map_err(|_| HareError::PublishError("broker confirmation timed out".to_string()))?,
+            Err(error) => Err(error),
+        };
+
+        match confirmation {
+            Ok(Confirmation::Ack(None)) | Ok(Confirmation::NotRequested) => Ok(()),
+            Ok(Confirmation::Ack(Some(_))) => {
+                // the broker accepted the message but could not route it : retrying would not help
+                log::error!("Message to exchange {} with routing key {} was not routed to any queue", message.exchange, message.routing_key);
+                Ok(())
+            }
+            Ok(Confirmation::Nack(_)) => Err(HareError::PublishError("message nacked by the broker".to_string())),
+            Err(error) => {
+                // the channel is closed by the broker on errors, open a new one on next attempt
+                self.channel = None;
+                Err(HareError::AmqpConnectionError(error))
+            }
+        }
+    }
+
+    /// Returns the confirm mode channel, opening it if needed.
+    async fn confirm_channel(&mut self, connection: &Connection) -> Result<&Channel, HareError> {
+        if !self.channel.as_ref().is_some_and(|channel| channel.status().connected()) {
+            let channel = connection.create_channel().await?;
+            channel.confirm_select(ConfirmSelectOptions::default()).await?;
+            self.channel = Some(channel);
+        }
+        Ok(self.channel.as_ref().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(routing_key: &str) -> OutgoingMessage {
+        OutgoingMessage { exchange: "results".to_string(), routing_key: routing_key.to_string(), body: vec![], properties: BasicProperties::default() }
+    }
+
+    #[test]
+    fn the_buffer_tells_whether_a_message_is_kept() {
+        let mut publisher = Publisher::new(None, Overflow::DropNewest).unwrap();
+        for index in 0..MAX_PENDING {
+            assert!(publisher.buffer(message(&index.to_string())).unwrap());
+        }
+        assert!(!publisher.buffer(message("newest")).unwrap());
+        assert_eq!(publisher.pending(), MAX_PENDING);
+
+        // the oldest message makes room for the new one
+        let mut publisher = Publisher::new(None, Overflow::DropOldest).unwrap();
+        for index in 0..=MAX_PENDING {
+            assert!(publisher.buffer(message(&index.to_string())).unwrap());
+        }
+        assert_eq!(publisher.pending(), MAX_PENDING);
+        assert_eq!(publisher.pending.front().unwrap().0.routing_key, "1");
+    }
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+/// A queue consumed by hare, with the settings of its handlers.
+///
+/// HARE_AMQP_QUEUE is consumed with the global settings (HARE_SCRIPT_ROOT, HARE_HANDLER_KEY,
+/// HARE_CONCURRENCY) ; the `[[queues]]` tables of the configuration file add queues consumed along
+/// with it, each with its own settings :
+///
+/// ```toml
+/// [[queues]]
+/// name = "billing-{env}"
+/// script_root = ["/opt/billing/scripts"]
+/// handler_key = "event"
+/// concurrency = 4
+/// ```
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueConfig {
+    pub name: String,               // queue name (template)
+    pub script_roots: Vec<String>,  // script roots of its handlers, in search order
+    pub handler_key: String,        // header giving the handler of its messages
+    pub concurrency: usize,         // number of its messages handled concurrently
+}
+
+/// A `[[queues]]` table of the configuration file, the settings left out taking the global values.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    name: String,
+    script_root: Option<ScriptRoots>,
+    handler_key: Option<String>,
+    concurrency: Option<usize>,
+}
+
+/// Script roots of a table, as an array or as a colon separated string like HARE_SCRIPT_ROOT.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScriptRoots {
+    List(Vec<String>),
+    Path(String),
+}
+
+/// Reads the additional queues of the configuration file.
+///
+/// # Arguments
+///
+/// * `tables` - the `[[queues]]` tables
+/// * `main` - the settings of HARE_AMQP_QUEUE, giving the defaults
+///
+/// @return the additional queues
+///
+/// # Errors
+///
+/// This function will return an error if a table is invalid, has a zero concurrency, or names
+/// a queue already consumed.
+pub fn parse(tables: &[toml::Table], main: &QueueConfig) -> Result<Vec<QueueConfig>, String> {
+    let mut queues: Vec<QueueConfig> = Vec::with_capacity(tables.len());
+    for table in tables {
+        let entry = Entry::deserialize(toml::Value::Table(table.clone()))
+            .map_err(|error| format!("invalid [[queues]] table: {}", error))?;
+        let invalid = |reason: &str| format!("invalid [[queues]] table for {}: {}", entry.name, reason);
+        if entry.name.trim().is_empty() {
+            return Err("invalid [[queues]] table: empty name".to_string());
+        }
+        if entry.name == main.name || queues.iter().any(|queue| queue.name == entry.name) {
+            return Err(invalid("the queue is already consumed"));
+        }
+        let script_roots = match &entry.script_root {
+            Some(ScriptRoots::List(roots)) => roots.clone(),
+            Some(ScriptRoots::Path(path)) => path.split(':').filter(|root| !root.is_empty()).map(str::to_string).collect(),
+            None => main.script_roots.clone(),
+        };
+        if script_roots.is_empty() {
+            return Err(invalid("no script root"));
+        }
+        queues.push(QueueConfig {
+            script_roots,
+            handler_key: entry.handler_key.clone().unwrap_or_else(|| main.handler_key.clone()),
+            concurrency: match entry.concurrency {
+                Some(0) => return Err(invalid("the concurrency must be at least 1")),
+                Some(concurrency) => concurrency,
+                None => main.concurrency,
+            },
+            name: entry.name,
+        });
+    }
+    Ok(queues)
+}
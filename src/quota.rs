@@ -0,0 +1,62 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::manifest::QuotaPolicy;
+
+/// Tracks the usage of each handler, to enforce their quotas.
+///
+/// The executions of each handler are kept in memory with their start time and duration;
+/// executions older than the quota window are forgotten.
+pub struct QuotaTracker {
+    usage: Mutex<HashMap<String, VecDeque<(Instant, Duration)>>>,  // executions per handler, oldest first
+    exceeded: Mutex<HashSet<String>>,                               // handlers currently over quota
+}
+
+impl QuotaTracker {
+
+    /// Creates a new tracker, without any recorded usage.
+    ///
+    /// @return QuotaTracker
+    ///
+    pub fn new() -> Self {
+        QuotaTracker { usage: Mutex::new(HashMap::new()), exceeded: Mutex::new(HashSet::new()) }
+    }
+
+    /// Checks whether a handler may run another execution.
+    ///
+    /// An alert is logged when the handler exceeds its quota, and when it is back under quota.
+    ///
+    /// @return true if the handler is within its quota
+    ///
+    pub fn allows(&self, handler: &str, policy: &QuotaPolicy) -> bool {
+        let mut usage = self.usage.lock().unwrap();
+        let executions = usage.entry(handler.to_string()).or_default();
+
+        let now = Instant::now();
+        while executions.front().is_some_and(|(started, _)| now.duration_since(*started) > policy.window) {
+            executions.pop_front();
+        }
+
+        let runtime: Duration = executions.iter().map(|(_, duration)| *duration).sum();
+        let within = policy.max_executions.is_none_or(|max| executions.len() < max)
+            && policy.max_runtime.is_none_or(|max| runtime < max);
+
+        let mut exceeded = self.exceeded.lock().unwrap();
+        if !within && exceeded.insert(handler.to_string()) {
+            log::error!(
+                "ALERT handler {} exceeded its quota: {} executions, {} of runtime over the last {}",
+                handler, executions.len(), humantime::format_duration(runtime), humantime::format_duration(policy.window)
+            );
+        } else if within && exceeded.remove(handler) {
+            log::info!("Handler {} is back within its quota", handler);
+        }
+
+        within
+    }
+
+    /// Records an execution of a handler.
+    pub fn record(&self, handler: &str, started: Instant, duration: Duration) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.entry(handler.to_string()).or_default().push_back((started, duration));
+    }
+}
@@ -0,0 +1,79 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use ring::rand::SecureRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use crate::harehandler::HareError;
+
+/// Header of the signature of a result message (Ed25519, hex encoded).
+pub const SIGNATURE_HEADER: &str = "x-hare-signature";
+
+/// Header of the public key that verifies the signature of a result message (hex encoded).
+pub const SIGNER_HEADER: &str = "x-hare-signer";
+
+/// Signs the records emitted by this instance, with its Ed25519 private key.
+///
+/// The receipts let downstream consumers check which host emitted a record, and that
+/// the record was not modified since : the signature covers the exact bytes of the record.
+pub struct Signer {
+    key_pair: Ed25519KeyPair,   // private key of this instance
+}
+
+impl Signer {
+
+    /// Loads the private key of this instance.
+    ///
+    /// The key file holds the 32 bytes seed of the Ed25519 key, hex encoded (see `generate`).
+    ///
+    /// @return Signer
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the key file cannot be read or is invalid.
+    pub fn load(path: &Path) -> Result<Self, HareError> {
+        let seed = hex::decode(fs::read_to_string(path)?.trim())
+            .map_err(|_| HareError::ConfigError(format!("signing key {} is not hex encoded", path.display())))?;
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&seed)
+            .map_err(|_| HareError::ConfigError(format!("invalid signing key {}", path.display())))?;
+        Ok(Signer { key_pair })
+    }
+
+    /// The public key that verifies the signatures, hex encoded.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key_pair.public_key().as_ref())
+    }
+
+    /// Signs a record.
+    ///
+    /// @return the signature, hex encoded
+    ///
+    pub fn sign(&self, record: &[u8]) -> String {
+        hex::encode(self.key_pair.sign(record).as_ref())
+    }
+
+    /// Signs a log line.
+    ///
+    /// @return the line followed by its signature, e.g. "<line> signature=<hex>"
+    ///
+    pub fn sign_line(&self, line: &str) -> String {
+        format!("{} signature={}", line, self.sign(line.as_bytes()))
+    }
+}
+
+/// Generates a new signing key, readable by its owner only.
+///
+/// @return the public key of the new signing key, hex encoded
+///
+/// # Errors
+///
+/// This function will return an error if the key file already exists or cannot be written.
+pub fn generate(path: &Path) -> Result<String, HareError> {
+    let mut seed = [0u8; 32];
+    ring::rand::SystemRandom::new().fill(&mut seed)
+        .map_err(|_| HareError::ConfigError("cannot generate a random key".to_string()))?;
+
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    writeln!(file, "{}", hex::encode(seed))?;
+    Ok(Signer::load(path)?.public_key())
+}
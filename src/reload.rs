@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Set when a configuration reload is requested (SIGHUP), cleared once it is handled.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often the reload flag is checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a queue left by a reload is checked, until it is drained.
+pub const DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Installs the SIGHUP handler, requesting a reload of the configuration.
+pub fn install() {
+    // Safety: the handler only touches an atomic
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as usize;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut());
+    }
+}
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Waits until a reload is requested, and clears the request.
+pub async fn wait() {
+    while !REQUESTED.swap(false, Ordering::SeqCst) {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Where hare gets its messages from : its queue, and the bindings of the queue in topic mode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topology {
+    pub queue: String,                      // name of the queue
+    pub bindings: Vec<(String, String)>,    // exchange and binding key of each binding, empty without topic exchange
+}
+
+/// Changes between two topologies, applied in order so that no message is lost.
+#[derive(Debug, Default)]
+pub struct TopologyChange {
+    pub queue: Option<String>,              // new queue, consumed before the previous one is left
+    pub bind: Vec<(String, String)>,        // bindings to create, on the new queue if it changes
+    pub unbind: Vec<(String, String)>,      // bindings to remove, from the previous queue if it changes
+}
+
+impl Topology {
+
+    /// The changes turning this topology into another one.
+    ///
+    /// When the queue changes, all the bindings move to the new queue ; otherwise, only the added
+    /// and removed bindings are changed.
+    ///
+    /// @return TopologyChange, empty if the topologies are the same
+    ///
+    pub fn diff(&self, next: &Topology) -> TopologyChange {
+        if self.queue != next.queue {
+            return TopologyChange { queue: Some(next.queue.clone()), bind: next.bindings.clone(), unbind: self.bindings.clone() };
+        }
+        TopologyChange {
+            queue: None,
+            bind: next.bindings.iter().filter(|binding| !self.bindings.contains(binding)).cloned().collect(),
+            unbind: self.bindings.iter().filter(|binding| !next.bindings.contains(binding)).cloned().collect(),
+        }
+    }
+}
+
+impl TopologyChange {
+
+    /// Whether there is nothing to change.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_none() && self.bind.is_empty() && self.unbind.is_empty()
+    }
+}
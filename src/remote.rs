@@ -0,0 +1,127 @@
+use std::collections::{BTreeMap, HashMap};
+use std::process::Command;
+use bytes::Bytes;
+use crate::manifest::RemoteHost;
+use crate::output;
+
+/// Script run by the remote shell : it reads the variables from its standard input, each followed
+/// by a line holding the marker, then runs the command with them, its standard input left to it.
+///
+/// Its arguments are the number of variables, the number of words of the command, the marker,
+/// then the command. The variables are appended to the arguments as they are read, then the
+/// command is rotated after them, for `env`.
+const REMOTE_SCRIPT: &str = r#"n=$1 c=$2 m=$3; shift 3
+while [ "$n" -gt 0 ]; do
+  v= s=
+  while IFS= read -r l && [ "$l" != "$m" ]; do v="$v$s$l"; s='
+'; done
+  set -- "$@" "$v"; n=$((n - 1))
+done
+while [ "$c" -gt 0 ]; do set -- "$@" "$1"; shift; c=$((c - 1)); done
+exec env "$@""#;
+
+/// Builds the command running a remote handler over SSH.
+///
+/// The system `ssh` client runs the command of the manifest on the remote host, in batch mode
+/// (key-based authentication only, no prompt) and with strict host key checking. The output and
+/// the exit code of the remote command are those of `ssh`, which exits with 255 when the connection
+/// itself fails.
+///
+/// The variables hare sets for scripts are sent on the standard input of `ssh`, ahead of the input
+/// of the command, as the SSH server usually does not accept them as environment, and the command
+/// line of a process can be read by every user of both hosts. A small shell script reads them,
+/// up to a random marker, before running the command.
+///
+/// # Arguments
+///
+/// * `remote` - the remote section of the handler manifest
+/// * `environment` - the variables set by hare for the handler
+///
+/// @return the `ssh` command, and the variables to write to its standard input before the input of the command
+///
+pub fn command(remote: &RemoteHost, environment: &HashMap<String, String>) -> (Command, Bytes) {
+    let mut command = Command::new("ssh");
+    command.args(["-o", "BatchMode=yes", "-o", "StrictHostKeyChecking=yes"]);
+    if let Some(port) = remote.port {
+        command.args(["-p", &port.to_string()]);
+    }
+    if let Some(identity) = &remote.identity {
+        command.args(["-i", identity, "-o", "IdentitiesOnly=yes"]);
+    }
+    if let Some(known_hosts) = &remote.known_hosts {
+        command.args(["-o", &format!("UserKnownHostsFile={}", known_hosts)]);
+    }
+    match &remote.user {
+        Some(user) => command.arg(format!("{}@{}", user, remote.host)),
+        None => command.arg(&remote.host),
+    };
+
+    // the remote shell parses the command line : every word is quoted
+    let marker = format!("hare-env-{}", output::new_message_id());
+    let mut remote_command = vec!["sh".to_string(), "-c".to_string(), quote(REMOTE_SCRIPT), "hare".to_string()];
+    remote_command.extend([environment.len().to_string(), remote.command.len().to_string(), marker.clone()]);
+    remote_command.extend(remote.command.iter().map(|word| quote(word)));
+    command.arg("--").arg(remote_command.join(" "));
+
+    let environment: BTreeMap<&String, &String> = environment.iter().collect();
+    let mut variables = String::new();
+    for (name, value) in environment {
+        variables.push_str(&format!("{}={}\n{}\n", name, value, marker));
+    }
+    (command, Bytes::from(variables))
+}
+
+/// Quotes a word for a POSIX shell.
+fn quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::process::Stdio;
+    use super::*;
+
+    fn remote(command: &[&str]) -> RemoteHost {
+        RemoteHost {
+            host: "db1.internal".to_string(),
+            user: Some("deploy".to_string()),
+            port: Some(2222),
+            identity: None,
+            known_hosts: None,
+            command: command.iter().map(|word| word.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn the_variables_are_not_on_the_command_line() {
+        let environment = HashMap::from([("HARE_VAR_TOKEN".to_string(), "s3cr3t".to_string())]);
+        let (command, preamble) = command(&remote(&["/opt/deploy/run.sh"]), &environment);
+        let args: Vec<String> = command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect();
+        assert!(args.contains(&"deploy@db1.internal".to_string()));
+        assert!(args.iter().all(|arg| !arg.contains("s3cr3t")));
+        assert!(String::from_utf8_lossy(&preamble).starts_with("HARE_VAR_TOKEN=s3cr3t\nhare-env-"));
+    }
+
+    #[test]
+    fn the_remote_shell_gets_the_variables_then_the_input() {
+        let environment = HashMap::from([
+            ("HARE_VAR_TOKEN".to_string(), "it's $HOME".to_string()),
+            ("HARE_VAR_NOTES".to_string(), "two\nlines\n".to_string()),
+            ("HARE_VAR_EMPTY".to_string(), String::new()),
+        ]);
+        let script = r#"printf '%s|' "$HARE_VAR_TOKEN" "$HARE_VAR_NOTES" "$HARE_VAR_EMPTY" "$1"; cat"#;
+        let (command, preamble) = command(&remote(&["sh", "-c", script, "script", "first arg"]), &environment);
+
+        // the remote shell runs the last argument of ssh
+        let remote_command = command.get_args().last().unwrap().to_string_lossy().into_owned();
+        let mut shell = Command::new("sh").arg("-c").arg(remote_command).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        let mut stdin = shell.stdin.take().unwrap();
+        stdin.write_all(&preamble).unwrap();
+        stdin.write_all(b"{\"app\": \"web\"}").unwrap();
+        drop(stdin);
+        let output = shell.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "it's $HOME|two\nlines\n||first arg|{\"app\": \"web\"}");
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use bytes::Bytes;
+use crate::harehandler::HareError;
+use crate::manifest::RenderPolicy;
+use crate::output::{self, Timeout};
+use crate::runas;
+
+/// Sequence number of the temporary files, unique within the process.
+static TMP_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Runs a render handler : renders its template to its destination file, then runs its reload command.
+///
+/// The template is rendered with the message values (see `render_template`). The file is written
+/// atomically : the content is written to a new temporary file next to the destination, created
+/// with the configured mode and ownership before anything is written to it, and is then renamed
+/// over the destination. The temporary file has a unique name, so that the concurrent renders of
+/// a destination do not write to the same file.
+///
+/// The reload command runs like a script, with the timeout of the handler.
+///
+/// # Arguments
+///
+/// * `script_root` - the script root of the handler, relative template paths are resolved from it
+/// * `policy` - the render section of the handler manifest
+/// * `headers` - the message headers
+/// * `body` - the message body
+/// * `handler` - the handler, for the log lines of the reload command
+/// * `timeout` - how long the reload command may run
+///
+/// @return the exit code of the reload command, Some(0) if there is no reload command
+///
+/// # Errors
+///
+/// This function will return an error if the template cannot be read or rendered, if the file
+/// cannot be written, or if the reload command cannot be started or times out.
+pub async fn run(script_root: &str, policy: &RenderPolicy, headers: &HashMap<String, String>, body: &[u8], handler: &str, timeout: Option<Timeout>)
+                 -> Result<Option<i32>, HareError> {
+    let template = fs::read_to_string(Path::new(script_root).join(&policy.template))
+        .map_err(|error| HareError::RenderError(format!("cannot read template {}: {}", policy.template, error)))?;
+    let content = render_template(&template, headers, body)?;
+
+    let destination = Path::new(&policy.destination);
+    let file_name = destination.file_name()
+        .ok_or_else(|| HareError::RenderError(format!("invalid destination {}", policy.destination)))?;
+    let tmp = destination.with_file_name(format!(".{}.hare-{}-{}", file_name.to_string_lossy(), std::process::id(), TMP_SEQUENCE.fetch_add(1, Ordering::Relaxed)));
+
+    let written = write(&tmp, &content, policy).and_then(|_| Ok(fs::rename(&tmp, destination)?));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written?;
+    log::info!("Rendered {} to {}", policy.template, policy.destination);
+
+    let Some((program, args)) = policy.reload.split_first() else { return Ok(Some(0)) };
+    let mut command = Command::new(program);
+    command.args(args);
+    let job = output::next_job_id();
+    let (output, _, timed_out) = output::run(command, Bytes::new(), handler, &job, timeout, output::DEFAULT_MAX_SIZE).await
+        .map_err(|error| HareError::RenderError(format!("cannot run reload command {}: {}", program, error)))?;
+    if timed_out {
+        return Err(HareError::RenderError(format!("reload command {} timed out", program)));
+    }
+    log::info!("Reload command exited with {}", output.status);
+    if !output.status.success() {
+        log::warn!("Reload command output: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    Ok(output.status.code())
+}
+
+/// Renders a template with the message values.
+///
+/// `{{ name }}` is replaced by the value of the `name` header, and `{{ body }}` by the message body.
+///
+/// @return the rendered template
+///
+/// # Errors
+///
+/// This function will return an error if the template uses a value the message does not have,
+/// or if a `{{` is not closed.
+pub fn render_template(template: &str, headers: &HashMap<String, String>, body: &[u8]) -> Result<String, HareError> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..].find("}}")
+            .ok_or_else(|| HareError::RenderError("unclosed {{ in template".to_string()))?;
+        let name = rest[start + 2..start + end].trim();
+        match (name, headers.get(name)) {
+            ("body", _) => rendered.push_str(&String::from_utf8_lossy(body)),
+            (_, Some(value)) => rendered.push_str(value),
+            (_, None) => return Err(HareError::RenderError(format!("the message has no {} header", name))),
+        }
+        rest = &rest[start + end + 2..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Writes the rendered content to the temporary file, created with its mode and ownership.
+///
+/// The file is created with the configured mode (narrowed by the umask), so that it is never
+/// readable more widely than the mode allows, then gets its exact mode and its ownership before
+/// the content is written.
+fn write(path: &Path, content: &str, policy: &RenderPolicy) -> Result<(), HareError> {
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(policy.mode.unwrap_or(0o666)).open(path)?;
+    if let Some(mode) = policy.mode {
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+
+    let uid = match &policy.owner {
+        Some(owner) => Some(runas::lookup(owner).ok_or_else(|| HareError::RenderError(format!("unknown user {}", owner)))?.uid),
+        None => None,
+    };
+    let gid = match &policy.group {
+        Some(group) => Some(runas::lookup_group(group).ok_or_else(|| HareError::RenderError(format!("unknown group {}", group)))?),
+        None => None,
+    };
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::fchown(&file, uid, gid)?;
+    }
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    /// A render policy writing to a fresh directory, with a template holding the given text.
+    fn policy(name: &str, template: &str, reload: &[&str]) -> (String, RenderPolicy) {
+        let dir = std::env::temp_dir().join(format!("hare-render-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("template"), template).unwrap();
+        let policy = RenderPolicy {
+            template: "template".to_string(),
+            destination: dir.join("rendered").to_string_lossy().into_owned(),
+            owner: None,
+            group: None,
+            mode: Some(0o600),
+            reload: reload.iter().map(|arg| arg.to_string()).collect(),
+        };
+        (dir.to_string_lossy().into_owned(), policy)
+    }
+
+    #[tokio::test]
+    async fn the_file_is_rendered_with_its_mode() {
+        let (root, policy) = policy("mode", "upstream {{ host }};\n", &["true"]);
+        let headers = HashMap::from([("host".to_string(), "10.0.0.1".to_string())]);
+        assert_eq!(run(&root, &policy, &headers, b"", "test", None).await.unwrap(), Some(0));
+        assert_eq!(fs::read_to_string(&policy.destination).unwrap(), "upstream 10.0.0.1;\n");
+        assert_eq!(fs::metadata(&policy.destination).unwrap().permissions().mode() & 0o777, 0o600);
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_reload_command_past_the_timeout_fails() {
+        let (root, policy) = policy("timeout", "static\n", &["sleep", "30"]);
+        let timeout = Timeout { limit: Duration::from_millis(100), grace: Duration::from_millis(100) };
+        let result = run(&root, &policy, &HashMap::new(), b"", "test", Some(timeout)).await;
+        assert!(matches!(result, Err(HareError::RenderError(_))));
+    }
+}
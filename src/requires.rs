@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::manifest::Requirement;
+
+/// How long a version command may run.
+const VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the result of a check is kept, before the dependencies are checked again.
+pub const CHECK_TTL: Duration = Duration::from_secs(60);
+
+/// The results of the dependency checks, per manifest.
+///
+/// The dependencies of a handler are checked at startup and before its runs, but the version
+/// commands only run once per CHECK_TTL, or when its manifest changes ; a reload checks them
+/// again at once.
+pub struct Checks {
+    results: Mutex<HashMap<String, Checked>>,  // result of the last check, per handler (script root and name)
+}
+
+/// The result of the check of the dependencies of a manifest.
+struct Checked {
+    requirements: Vec<Requirement>, // dependencies checked
+    at: Instant,                    // time of the check
+    result: Result<(), String>,
+}
+
+impl Checks {
+
+    /// Creates the checks, none done.
+    ///
+    /// @return Checks
+    ///
+    pub fn new() -> Self {
+        Checks { results: Mutex::new(HashMap::new()) }
+    }
+
+    /// Checks the dependencies of a handler, unless they were checked recently.
+    ///
+    /// @return Ok if every dependency is satisfied
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error describing the first unsatisfied dependency (see `check`).
+    pub async fn check(&self, script_root: &str, handler: &str, requirements: &[Requirement]) -> Result<(), String> {
+        if requirements.is_empty() {
+            return Ok(());
+        }
+        let key = format!("{}/{}", script_root, handler);
+        if let Some(checked) = self.results.lock().unwrap().get(&key) {
+            if checked.requirements == requirements && checked.at.elapsed() < CHECK_TTL {
+                return checked.result.clone();
+            }
+        }
+        let result = check(requirements).await;
+        let checked = Checked { requirements: requirements.to_vec(), at: Instant::now(), result: result.clone() };
+        self.results.lock().unwrap().insert(key, checked);
+        result
+    }
+
+    /// Forgets the results, so that the dependencies are checked again (on reload).
+    pub fn clear(&self) {
+        self.results.lock().unwrap().clear();
+    }
+}
+
+impl Default for Checks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks the dependencies declared by a handler manifest.
+///
+/// @return Ok if every dependency is satisfied
+///
+/// # Errors
+///
+/// This function will return an error describing the first unsatisfied dependency, e.g.
+/// "docker: version 23.0.1 is older than 24".
+pub async fn check(requirements: &[Requirement]) -> Result<(), String> {
+    for requirement in requirements {
+        if let Some(file) = &requirement.file {
+            if !Path::new(file).exists() {
+                return Err(format!("{}: no such file", file));
+            }
+        }
+
+        let Some(binary) = &requirement.binary else { continue };
+        let path = find_binary(binary).ok_or_else(|| format!("{}: not found in PATH", binary))?;
+
+        if let Some(min_version) = &requirement.min_version {
+            let command = match requirement.version_command.split_first() {
+                Some((program, args)) => (PathBuf::from(program), args.to_vec()),
+                None => (path, vec!["--version".to_string()]),
+            };
+            let output = run(&command.0, &command.1).await.map_err(|error| format!("{}: cannot get its version, {}", binary, error))?;
+            let version = parse_version(&output).ok_or_else(|| format!("{}: no version in {:?}", binary, output.trim()))?;
+            let minimum = parse_version(min_version).ok_or_else(|| format!("{}: invalid minimum version {:?}", binary, min_version))?;
+            if is_older(&version, &minimum) {
+                return Err(format!("{}: version {} is older than {}", binary, join(&version), min_version));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finds an executable, by path or in the PATH directories.
+fn find_binary(binary: &str) -> Option<PathBuf> {
+    let executable = |path: &Path| path.metadata().is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0);
+    if binary.contains('/') {
+        return Some(PathBuf::from(binary)).filter(|path| executable(path));
+    }
+    std::env::var("PATH").unwrap_or_default().split(':')
+        .map(|dir| Path::new(dir).join(binary))
+        .find(|path| executable(path))
+}
+
+/// Runs a version command, and returns its output (stdout and stderr).
+///
+/// The command is killed if it runs longer than VERSION_TIMEOUT.
+async fn run(program: &Path, args: &[String]) -> Result<String, String> {
+    let command = tokio::process::Command::new(program).args(args)
+        .stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(VERSION_TIMEOUT, command).await
+        .map_err(|_| format!("timed out after {}", humantime::format_duration(VERSION_TIMEOUT)))?
+        .map_err(|error| error.to_string())?;
+
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok(text)
+}
+
+/// Extracts the first version number of a text, e.g. [24, 0, 7] from "Docker version 24.0.7, build afdd53b".
+fn parse_version(text: &str) -> Option<Vec<u64>> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let version: Vec<u64> = text[start..]
+        .split(|c: char| !c.is_ascii_digit() && c != '.').next()?
+        .split('.').take_while(|part| !part.is_empty())
+        .map_while(|part| part.parse().ok())
+        .collect();
+    Some(version).filter(|version| !version.is_empty())
+}
+
+/// Whether a version is older than a minimum, the missing parts counting as 0 (24 is not older than 24.0.0).
+fn is_older(version: &[u64], minimum: &[u64]) -> bool {
+    let length = version.len().max(minimum.len());
+    let part = |version: &[u64], index: usize| version.get(index).copied().unwrap_or(0);
+    (0..length).map(|index| (part(version, index), part(minimum, index)))
+        .find(|(version, minimum)| version != minimum)
+        .is_some_and(|(version, minimum)| version < minimum)
+}
+
+fn join(version: &[u64]) -> String {
+    version.iter().map(u64::to_string).collect::<Vec<_>>().join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A dependency on `sh`, whose version command prints the given text and counts its runs in a file.
+    fn sh(min_version: &str, output: &str, runs: &Path) -> Requirement {
+        Requirement {
+            binary: Some("sh".to_string()),
+            file: None,
+            min_version: Some(min_version.to_string()),
+            version_command: vec!["sh".to_string(), "-c".to_string(), format!("echo run >> {}; echo '{}'", runs.display(), output)],
+        }
+    }
+
+    fn runs_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hare-requires-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn runs(path: &Path) -> usize {
+        std::fs::read_to_string(path).map(|runs| runs.lines().count()).unwrap_or(0)
+    }
+
+    #[test]
+    fn versions_are_parsed() {
+        assert_eq!(parse_version("Docker version 24.0.7, build afdd53b"), Some(vec![24, 0, 7]));
+        assert_eq!(parse_version("git version 2.39.2\n"), Some(vec![2, 39, 2]));
+        assert_eq!(parse_version("v1.28"), Some(vec![1, 28]));
+        assert_eq!(parse_version("24"), Some(vec![24]));
+        assert_eq!(parse_version("OpenSSL 3.0.11 19 Sep 2023"), Some(vec![3, 0, 11]));
+        assert_eq!(parse_version("1..2"), Some(vec![1]));
+        assert_eq!(parse_version("no version"), None);
+        assert_eq!(parse_version(""), None);
+    }
+
+    #[test]
+    fn versions_are_compared_part_by_part() {
+        assert!(is_older(&[23, 0, 1], &[24]));
+        assert!(is_older(&[2, 9], &[2, 10]));
+        assert!(is_older(&[1], &[1, 0, 1]));
+        assert!(!is_older(&[24], &[24, 0, 0]));
+        assert!(!is_older(&[24, 0, 7], &[24]));
+        assert!(!is_older(&[2, 10], &[2, 9, 9]));
+        assert!(!is_older(&[3, 1, 2], &[3, 1, 2]));
+    }
+
+    #[tokio::test]
+    async fn the_dependencies_are_checked() {
+        let runs = runs_file("check");
+        assert_eq!(check(&[sh("1.2", "version 1.2.3", &runs)]).await, Ok(()));
+        assert_eq!(check(&[sh("1.3", "version 1.2.3", &runs)]).await, Err("sh: version 1.2.3 is older than 1.3".to_string()));
+        assert!(check(&[sh("1", "unknown", &runs)]).await.unwrap_err().contains("no version"));
+
+        let missing = Requirement { binary: Some("hare-no-such-binary".to_string()), file: None, min_version: None, version_command: vec![] };
+        assert_eq!(check(&[missing]).await, Err("hare-no-such-binary: not found in PATH".to_string()));
+        let file = Requirement { binary: None, file: Some("/hare/no/such/file".to_string()), min_version: None, version_command: vec![] };
+        assert_eq!(check(&[file]).await, Err("/hare/no/such/file: no such file".to_string()));
+    }
+
+    #[tokio::test]
+    async fn the_results_are_kept_per_manifest() {
+        let file = runs_file("cache");
+        let checks = Checks::new();
+        let requirements = [sh("1.2", "1.2.3", &file)];
+        assert_eq!(checks.check("/scripts", "deploy", &requirements).await, Ok(()));
+        assert_eq!(checks.check("/scripts", "deploy", &requirements).await, Ok(()));
+        assert_eq!(runs(&file), 1);
+
+        // another handler, or another manifest, is checked on its own
+        assert_eq!(checks.check("/scripts", "build", &requirements).await, Ok(()));
+        assert_eq!(runs(&file), 2);
+        assert!(checks.check("/scripts", "deploy", &[sh("2", "1.2.3", &file)]).await.is_err());
+        assert_eq!(runs(&file), 3);
+
+        // a reload checks them again
+        checks.clear();
+        assert!(checks.check("/scripts", "deploy", &[sh("2", "1.2.3", &file)]).await.is_err());
+        assert_eq!(runs(&file), 4);
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+use lapin::message::Delivery;
+use lapin::options::QueueDeclareOptions;
+use lapin::types::{AMQPValue, FieldTable, LongString};
+use lapin::Connection;
+use crate::config::HareConfig;
+use crate::deadletter::{self, Cause};
+use crate::harehandler::HareError;
+use crate::publisher::OutgoingMessage;
+
+/// Header giving the attempt of a retried message : 2 for its first retry.
+pub const ATTEMPT_HEADER: &str = "x-hare-attempt";
+
+/// Default delays before each retry.
+pub const DEFAULT_DELAYS: &str = "10s,1m,5m";
+
+/// Default number of attempts of a message, before it is parked.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 4;
+
+/// Default name of the parking lot queue, where `{queue}` is replaced by the name of the queue.
+pub const DEFAULT_PARKING_QUEUE: &str = "{queue}.parking-lot";
+
+/// Delays of the deferral queues, a deferred message waits for the longest one not over its delay.
+const DEFER_DELAYS: [Duration; 7] = [
+    Duration::from_secs(1), Duration::from_secs(5), Duration::from_secs(30), Duration::from_secs(60),
+    Duration::from_secs(300), Duration::from_secs(900), Duration::from_secs(3600),
+];
+
+/// Retries of the failed messages, with a delay growing with the attempts (HARE_ON_FAILURE retry).
+///
+/// A failed message is republished to a delay queue, `<queue>.retry.<delay>`, with a per-message TTL :
+/// once it expires, the broker dead-letters it back to its queue, through the default exchange. Each
+/// delay has its own queue, so that a message waiting for a long delay does not hold back the shorter
+/// ones behind it. The `x-hare-attempt` header counts the attempts ; once a message failed
+/// HARE_RETRY_MAX_ATTEMPTS times, it is parked in the parking lot queue, with the failure headers of
+/// the dead-lettered messages, for an operator to look at.
+pub struct Retries {
+    delays: Vec<Duration>,          // delay before each retry, the last one repeating
+    max_attempts: u32,              // attempts of a message before it is parked
+    parking_queue: String,          // parking lot queue (template)
+    declared: Mutex<HashSet<String>>, // delay and parking queues declared by this instance
+}
+
+impl Retries {
+
+    /// Reads the settings of the retries : HARE_RETRY_DELAYS, HARE_RETRY_MAX_ATTEMPTS and HARE_RETRY_PARKING_QUEUE.
+    ///
+    /// @return Retries
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a delay is invalid, or the number of attempts is zero.
+    pub fn load(config: &HareConfig) -> Result<Self, String> {
+        let delays = config.get("HARE_RETRY_DELAYS").unwrap_or_else(|| DEFAULT_DELAYS.to_string());
+        let delays = delays.split(',').map(str::trim).filter(|delay| !delay.is_empty())
+            .map(|delay| humantime::parse_duration(delay).ok().filter(|delay| !delay.is_zero())
+                .ok_or_else(|| format!("invalid HARE_RETRY_DELAYS {:?}, expected durations like \"10s,1m,5m\"", delays)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if delays.is_empty() {
+            return Err("HARE_RETRY_DELAYS is empty".to_string());
+        }
+        let max_attempts = match config.get("HARE_RETRY_MAX_ATTEMPTS") {
+            Some(value) => value.parse().ok().filter(|attempts| *attempts > 0)
+                .ok_or_else(|| format!("invalid HARE_RETRY_MAX_ATTEMPTS {:?}, expected a number of attempts from 1", value))?,
+            None => DEFAULT_MAX_ATTEMPTS,
+        };
+        Ok(Retries {
+            delays,
+            max_attempts,
+            parking_queue: config.get("HARE_RETRY_PARKING_QUEUE").unwrap_or_else(|| DEFAULT_PARKING_QUEUE.to_string()),
+            declared: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// The attempt of a delivery, from its `x-hare-attempt` header.
+    ///
+    /// @return the attempt, 1 for a message never retried
+    ///
+    pub fn attempt(delivery: &Delivery) -> u32 {
+        delivery.properties.headers().as_ref()
+            .and_then(|headers| headers.inner().get(ATTEMPT_HEADER))
+            .and_then(|value| match value {
+                AMQPValue::LongInt(attempt) => u32::try_from(*attempt).ok(),
+                AMQPValue::LongLongInt(attempt) => u32::try_from(*attempt).ok(),
+                value => crate::conversion::header_value(value).and_then(|attempt| attempt.parse().ok()),
+            })
+            .unwrap_or(1)
+            .max(1)
+    }
+
+    /// The copy of a failed message, to its delay queue or to the parking lot queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - the broker connection, declaring the queues on their first use
+    /// * `delivery` - the failed message
+    /// * `queue` - the queue the message came from, where it returns after its delay
+    /// * `handler` - the handler of the message
+    /// * `type_header` - the handler key header setting the handler on the copy, with HARE_DISPATCH both
+    /// * `instance` - the id of this instance
+    /// * `cause` - the failure
+    ///
+    /// @return the copy, and whether it is parked
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the delay queue or the parking lot queue cannot be declared.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn message(&self, connection: &Connection, delivery: &Delivery, queue: &str, handler: &str, type_header: Option<&str>, instance: &str, cause: &Cause<'_>)
+                         -> Result<(OutgoingMessage, bool), HareError> {
+        let attempt = Self::attempt(delivery);
+        if attempt >= self.max_attempts {
+            let parking_queue = self.parking_queue.replace("{queue}", queue);
+            self.declare(connection, &parking_queue, FieldTable::default()).await?;
+            let mut message = deadletter::message(delivery, "", instance, handler, cause);
+            message.routing_key = parking_queue;
+            return Ok((message, true));
+        }
+
+        let delay = self.delays[(attempt as usize - 1).min(self.delays.len() - 1)];
+        let delay_queue = format!("{}.retry.{}", queue, humantime::format_duration(delay));
+        let text = |value: &str| AMQPValue::LongString(LongString::from(value));
+        self.declare(connection, &delay_queue, returning_to(queue)).await?;
+
+        // the failure headers tell why the message is retried ; it comes back with the name of the
+        // queue as routing key, its type is kept in a header
+        let mut message = deadletter::message(delivery, "", instance, handler, cause);
+        let mut headers = message.properties.headers().clone().unwrap_or_default();
+        headers.insert(ATTEMPT_HEADER.into(), AMQPValue::LongInt(attempt as i32 + 1));
+        if let Some(type_header) = type_header {
+            headers.insert(type_header.into(), text(handler));
+        }
+        message.routing_key = delay_queue;
+        message.properties = message.properties.with_headers(headers).with_expiration(delay.as_millis().to_string().into());
+        Ok((message, false))
+    }
+
+    /// The copy of a deferred message, to a deferral queue of its queue, `<queue>.defer.<delay>`.
+    ///
+    /// Like the delay queues of the retries, the deferral queues have no consumer, and dead-letter
+    /// the messages back to their queue once their delay expired. The delay is rounded down to one
+    /// of a few fixed delays (1s, 5s, 30s, 1m, 5m, 15m, 1h), so that a message is checked again on
+    /// time, through a few queues.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - the broker connection, declaring the queue on its first use
+    /// * `delivery` - the deferred message
+    /// * `queue` - the queue the message came from, where it returns after its delay
+    /// * `handler` - the handler of the message
+    /// * `type_header` - the handler key header setting the handler on the copy, with HARE_DISPATCH both
+    /// * `delay` - how long the message is deferred
+    ///
+    /// @return the copy
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the deferral queue cannot be declared.
+    pub async fn deferral(&self, connection: &Connection, delivery: &Delivery, queue: &str, handler: &str, type_header: Option<&str>, delay: Duration)
+                          -> Result<OutgoingMessage, HareError> {
+        let delay = defer_delay(delay);
+        let deferral_queue = format!("{}.defer.{}", queue, humantime::format_duration(delay));
+        self.declare(connection, &deferral_queue, returning_to(queue)).await?;
+        Ok(deferred_message(delivery, &deferral_queue, handler, type_header, delay))
+    }
+
+    /// The deferral queues of a queue declared by this instance, where deferred messages may wait.
+    pub fn deferral_queues(&self, queue: &str) -> Vec<String> {
+        let prefix = format!("{}.defer.", queue);
+        self.declared.lock().unwrap().iter().filter(|declared| declared.starts_with(&prefix)).cloned().collect()
+    }
+
+    /// Declares a delay or parking lot queue, once per instance.
+    async fn declare(&self, connection: &Connection, queue: &str, arguments: FieldTable) -> Result<(), HareError> {
+        if self.declared.lock().unwrap().contains(queue) {
+            return Ok(());
+        }
+        // a declaration contradicting an existing queue closes its channel
+        let channel = connection.create_channel().await?;
+        let declared = channel.queue_declare(queue, QueueDeclareOptions { durable: true, ..QueueDeclareOptions::default() }, arguments).await;
+        let _ = channel.close(200, "declared").await;
+        declared.map_err(|error| HareError::PublishError(format!("cannot declare queue {}: {}", queue, error)))?;
+        self.declared.lock().unwrap().insert(queue.to_string());
+        Ok(())
+    }
+}
+
+/// The arguments of a delay queue, dead-lettering the expired messages back to their queue through the default exchange.
+fn returning_to(queue: &str) -> FieldTable {
+    let mut arguments = FieldTable::default();
+    arguments.insert("x-dead-letter-exchange".into(), AMQPValue::LongString(LongString::from("")));
+    arguments.insert("x-dead-letter-routing-key".into(), AMQPValue::LongString(LongString::from(queue)));
+    arguments
+}
+
+/// The delay of the deferral queue of a deferred message : the longest one not over its delay.
+fn defer_delay(delay: Duration) -> Duration {
+    DEFER_DELAYS.into_iter().rev().find(|defer_delay| *defer_delay <= delay).unwrap_or(DEFER_DELAYS[0])
+}
+
+/// The copy of a deferred message to its deferral queue : the message unchanged, with the delay as
+/// per-message TTL, and the handler key header with HARE_DISPATCH both.
+fn deferred_message(delivery: &Delivery, deferral_queue: &str, handler: &str, type_header: Option<&str>, delay: Duration) -> OutgoingMessage {
+    let mut properties = delivery.properties.clone();
+    if let Some(type_header) = type_header {
+        let mut headers = properties.headers().clone().unwrap_or_default();
+        headers.insert(type_header.into(), AMQPValue::LongString(LongString::from(handler)));
+        properties = properties.with_headers(headers);
+    }
+    OutgoingMessage {
+        exchange: String::new(),
+        routing_key: deferral_queue.to_string(),
+        body: delivery.data.clone(),
+        properties: properties.with_expiration(delay.as_millis().to_string().into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::acker::Acker;
+    use lapin::BasicProperties;
+
+    #[allow(deprecated)]
+    fn delivery(headers: FieldTable) -> Delivery {
+        Delivery {
+            delivery_tag: 1,
+            exchange: "events".into(),
+            routing_key: "deploy.web".into(),
+            redelivered: false,
+            properties: BasicProperties::default().with_headers(headers).with_delivery_mode(2),
+            data: b"{}".to_vec(),
+            acker: Acker::default(),
+        }
+    }
+
+    #[test]
+    fn a_deferral_waits_for_the_longest_delay_not_over_its_own() {
+        assert_eq!(defer_delay(Duration::ZERO), Duration::from_secs(1));
+        assert_eq!(defer_delay(Duration::from_millis(1500)), Duration::from_secs(1));
+        assert_eq!(defer_delay(Duration::from_secs(5)), Duration::from_secs(5));
+        assert_eq!(defer_delay(Duration::from_secs(29)), Duration::from_secs(5));
+        assert_eq!(defer_delay(Duration::from_secs(30)), Duration::from_secs(30));
+        assert_eq!(defer_delay(Duration::from_secs(7200)), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn a_deferred_message_is_copied_unchanged() {
+        let mut headers = FieldTable::default();
+        headers.insert("app".into(), AMQPValue::LongString("web".into()));
+        let delivery = delivery(headers);
+
+        let copy = deferred_message(&delivery, "deploy.defer.30s", "deploy", None, Duration::from_secs(30));
+        assert_eq!((copy.exchange.as_str(), copy.routing_key.as_str()), ("", "deploy.defer.30s"));
+        assert_eq!(copy.body, b"{}");
+        assert_eq!(copy.properties.expiration().as_ref().map(|expiration| expiration.as_str()), Some("30000"));
+        assert_eq!(copy.properties.delivery_mode(), &Some(2));
+        assert_eq!(copy.properties.headers(), delivery.properties.headers());
+
+        // with HARE_DISPATCH both, the handler is kept in a header
+        let copy = deferred_message(&delivery, "deploy.defer.30s", "deploy.web", Some("type"), Duration::from_secs(30));
+        let headers = copy.properties.headers().clone().unwrap();
+        assert_eq!(headers.inner().get("type"), Some(&AMQPValue::LongString("deploy.web".into())));
+        assert_eq!(headers.inner().get("app"), Some(&AMQPValue::LongString("web".into())));
+    }
+
+    #[test]
+    fn the_deferral_queues_are_those_of_the_queue() {
+        let retries = Retries::load(&HareConfig::default()).unwrap();
+        retries.declared.lock().unwrap().extend(["deploy.defer.1s", "deploy.defer.1m", "deploy.retry.1m", "deploy.web.defer.1s"].map(String::from));
+        let mut queues = retries.deferral_queues("deploy");
+        queues.sort();
+        assert_eq!(queues, ["deploy.defer.1m", "deploy.defer.1s"]);
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use lapin::options::BasicPublishOptions;
+use lapin::{BasicProperties, Channel};
+use crate::cluster::MEMBER_TIMEOUT;
+use crate::manifest::RolloutPolicy;
+
+/// How long a claim waits for the claims of the other instances before it is settled.
+const CLAIM_SETTLE: Duration = Duration::from_secs(1);
+
+/// Fleet-wide slots of the handlers with a rollout policy, leased over the cluster control exchange.
+///
+/// Each instance publishes its leases (the handlers it runs, with the time it claimed them) with its
+/// heartbeats, and on every change. Before running a handler, an instance counts the other instances
+/// holding a lease on it : under the cap, it claims a lease, waits for the concurrent claims, and keeps
+/// its lease if the holders and the earlier claims leave it a slot. The leases of a gone instance
+/// lapse with its heartbeats.
+pub struct Leases {
+    instance: String,                   // id of this instance
+    state: Mutex<State>,
+    control: Mutex<Option<(Channel, String)>>, // channel and control exchange, once the cluster is joined
+}
+
+#[derive(Default)]
+struct State {
+    own: HashMap<String, (u64, usize)>, // leases of this instance : claim time (ms since epoch) and number of running jobs, per handler
+    others: HashMap<String, (HashMap<String, u64>, Instant)>, // leases of the other instances, with their last heartbeat
+}
+
+/// A slot of a handler, released when dropped.
+pub struct Slot {
+    leases: Option<Arc<Leases>>,        // leases holding the slot, None outside cluster mode
+    handler: String,
+}
+
+impl Leases {
+
+    /// Creates the leases of an instance, holding none.
+    ///
+    /// @return Leases
+    ///
+    pub fn new(instance: &str) -> Self {
+        Leases { instance: instance.to_string(), state: Mutex::new(State::default()), control: Mutex::new(None) }
+    }
+
+    /// Publishes the leases through the control exchange of the cluster, once joined.
+    pub fn attach(&self, channel: Channel, exchange: &str) {
+        *self.control.lock().unwrap() = Some((channel, exchange.to_string()));
+    }
+
+    /// The leases of this instance, published with its heartbeats.
+    ///
+    /// @return the claim time (ms since epoch) per handler
+    ///
+    pub fn own(&self) -> HashMap<String, u64> {
+        self.state.lock().unwrap().own.iter().map(|(handler, (claimed, _))| (handler.clone(), *claimed)).collect()
+    }
+
+    /// Records the leases published by an instance.
+    pub fn observe(&self, instance: &str, leases: HashMap<String, u64>) {
+        if instance != self.instance {
+            self.state.lock().unwrap().others.insert(instance.to_string(), (leases, Instant::now()));
+        }
+    }
+
+    /// Takes a slot of a handler, if the fleet-wide cap of its rollout policy leaves one.
+    ///
+    /// Outside cluster mode, the instance knows no other instance, and always gets a slot. An
+    /// instance already running the handler gets another slot at once : the cap counts instances.
+    ///
+    /// @return the slot, None if the cap is reached
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the claim cannot be published.
+    pub async fn acquire(self: &Arc<Self>, handler: &str, policy: &RolloutPolicy) -> Result<Option<Slot>, lapin::Error> {
+        if self.control.lock().unwrap().is_none() {
+            return Ok(Some(Slot { leases: None, handler: handler.to_string() }));
+        }
+
+        let holders = {
+            let mut state = self.state.lock().unwrap();
+            state.others.retain(|_, (_, seen)| seen.elapsed() < MEMBER_TIMEOUT);
+            if let Some((_, running)) = state.own.get_mut(handler) {
+                *running += 1;
+                return Ok(Some(Slot { leases: Some(self.clone()), handler: handler.to_string() }));
+            }
+            let cap = policy.cap(state.others.len() + 1);
+            let holders: HashSet<String> = state.others.iter()
+                .filter(|(_, (leases, _))| leases.contains_key(handler))
+                .map(|(instance, _)| instance.clone())
+                .collect();
+            if holders.len() >= cap {
+                log::info!("Handler {} runs on {} instances, its cap of {} is reached", handler, holders.len(), cap);
+                return Ok(None);
+            }
+            let claimed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+            state.own.insert(handler.to_string(), (claimed, 1));
+            holders
+        };
+        let slot = Slot { leases: Some(self.clone()), handler: handler.to_string() };
+        self.publish().await?;
+        tokio::time::sleep(CLAIM_SETTLE).await;
+
+        // the holders seen before the claim keep their slots, the concurrent claims are ranked by time
+        let state = self.state.lock().unwrap();
+        let cap = policy.cap(state.others.len() + 1);
+        let claimed = state.own.get(handler).map(|(claimed, _)| *claimed).unwrap_or_default();
+        let ahead = state.others.iter()
+            .filter_map(|(instance, (leases, _))| leases.get(handler).map(|other| (instance, *other)))
+            .filter(|(instance, other)| holders.contains(*instance) || (*other, instance.as_str()) < (claimed, self.instance.as_str()))
+            .count();
+        if ahead >= cap {
+            log::info!("Handler {} claimed concurrently by other instances, its cap of {} is reached", handler, cap);
+            // dropping the slot withdraws the claim
+            drop(state);
+            drop(slot);
+            return Ok(None);
+        }
+        Ok(Some(slot))
+    }
+
+    /// Publishes the leases of this instance to the other instances.
+    async fn publish(&self) -> Result<(), lapin::Error> {
+        let Some((channel, exchange)) = self.control.lock().unwrap().clone() else { return Ok(()) };
+        let body = heartbeat(&self.instance, &self.own());
+        channel.basic_publish(&exchange, "", BasicPublishOptions::default(), body.as_bytes(), BasicProperties::default()
+            .with_content_type("application/json".into())
+            .with_expiration(MEMBER_TIMEOUT.as_millis().to_string().into())).await?;
+        Ok(())
+    }
+
+    /// Releases a slot of a handler, and the lease once the last job of the handler finished.
+    fn release(self: &Arc<Self>, handler: &str) {
+        let mut state = self.state.lock().unwrap();
+        let Some((_, running)) = state.own.get_mut(handler) else { return };
+        *running -= 1;
+        if *running > 0 {
+            return;
+        }
+        state.own.remove(handler);
+        let leases = self.clone();
+        tokio::spawn(async move {
+            if let Err(error) = leases.publish().await {
+                log::error!("Could not publish the released leases, they lapse with the heartbeats: {}", error);
+            }
+        });
+    }
+}
+
+impl RolloutPolicy {
+
+    /// Number of instances that may run the handler at the same time.
+    ///
+    /// The fraction is rounded down, but at least one instance runs the handler ; with both a
+    /// fraction and a number of instances, the lowest cap applies.
+    ///
+    /// @return the cap, for a cluster of `instances` live instances
+    ///
+    pub fn cap(&self, instances: usize) -> usize {
+        let fraction = self.max_fraction.map(|fraction| ((instances as f64 * fraction).floor() as usize).max(1));
+        match (fraction, self.max_instances) {
+            (Some(fraction), Some(max)) => fraction.min(max),
+            (Some(cap), None) | (None, Some(cap)) => cap,
+            (None, None) => instances,
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(leases) = &self.leases {
+            leases.release(&self.handler);
+        }
+    }
+}
+
+/// The heartbeat of an instance : its id and its leases.
+///
+/// @return the JSON body, e.g. `{"instance": "web-01-4242", "leases": {"deploy": 1733393521000}}`
+///
+pub fn heartbeat(instance: &str, leases: &HashMap<String, u64>) -> String {
+    serde_json::json!({ "instance": instance, "leases": leases }).to_string()
+}
@@ -0,0 +1,108 @@
+use std::ffi::{CStr, CString};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// Header in which a publisher requests the user a script runs as.
+pub const RUN_AS_HEADER: &str = "run-as";
+
+/// A system user, as found in the password database.
+pub struct User {
+    pub name: String,   // user name
+    pub uid: u32,       // user id
+    pub gid: u32,       // primary group id
+    pub home: String,   // home directory
+}
+
+/// Looks up a user in the password database.
+///
+/// @return the user, None if there is no such user
+///
+pub fn lookup(name: &str) -> Option<User> {
+    let c_name = CString::new(name).ok()?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+    // Safety: the buffers outlive the call, and the result points into them
+    let status = unsafe {
+        libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result)
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+
+    let home = if passwd.pw_dir.is_null() {
+        "/".to_string()
+    } else {
+        // Safety: pw_dir is a nul terminated string in the buffer
+        unsafe { CStr::from_ptr(passwd.pw_dir) }.to_string_lossy().to_string()
+    };
+    Some(User { name: name.to_string(), uid: passwd.pw_uid, gid: passwd.pw_gid, home })
+}
+
+/// Looks up a group in the group database.
+///
+/// @return the group id, None if there is no such group
+///
+pub fn lookup_group(name: &str) -> Option<u32> {
+    let c_name = CString::new(name).ok()?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::group = std::ptr::null_mut();
+
+    // Safety: the buffers outlive the call, and the result points into them
+    let status = unsafe {
+        libc::getgrnam_r(c_name.as_ptr(), &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result)
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    Some(group.gr_gid)
+}
+
+/// Makes the command run as a user : its user and group ids, and its USER, LOGNAME and HOME variables.
+///
+/// Switching user requires hare to run as root.
+pub fn apply(command: &mut Command, user: &User) {
+    command.uid(user.uid).gid(user.gid);
+    environment(command, user);
+}
+
+/// Sets the USER, LOGNAME and HOME variables of a user, for a command switching user by itself (see `netns::apply`).
+pub fn environment(command: &mut Command, user: &User) {
+    command.env("USER", &user.name)
+        .env("LOGNAME", &user.name)
+        .env("HOME", &user.home);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_are_looked_up() {
+        let root = lookup("root").unwrap();
+        assert_eq!((root.name.as_str(), root.uid, root.gid), ("root", 0, 0));
+        assert!(!root.home.is_empty());
+        assert!(lookup("hare-no-such-user").is_none());
+        assert!(lookup("root\0").is_none());
+    }
+
+    #[test]
+    fn groups_are_looked_up() {
+        assert_eq!(lookup_group("root"), Some(0));
+        assert_eq!(lookup_group("hare-no-such-group"), None);
+    }
+
+    #[test]
+    fn the_variables_of_the_user_are_set() {
+        let user = User { name: "deploy".to_string(), uid: 1000, gid: 1000, home: "/home/deploy".to_string() };
+        let mut command = Command::new("true");
+        environment(&mut command, &user);
+        let mut variables: Vec<_> = command.get_envs()
+            .map(|(name, value)| (name.to_str().unwrap(), value.and_then(|value| value.to_str())))
+            .collect();
+        variables.sort();
+        assert_eq!(variables, [("HOME", Some("/home/deploy")), ("LOGNAME", Some("deploy")), ("USER", Some("deploy"))]);
+    }
+}
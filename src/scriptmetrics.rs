@@ -0,0 +1,135 @@
+use crate::contract::METRIC_MARKER;
+use crate::metrics::Metrics;
+
+/// Maximum number of labels of a metric reported by a script, the `handler` label excluded.
+const MAX_LABELS: usize = 16;
+
+/// A metric value reported by a script.
+#[derive(Debug, PartialEq)]
+pub struct Sample {
+    pub name: String,                   // metric name, e.g. "deploy_duration_seconds"
+    pub labels: Vec<(String, String)>,  // labels, in the order of the line
+    pub value: f64,
+}
+
+/// Records the metrics reported by a script on its output.
+///
+/// A script reports a metric by writing a line like `::hare-metric:: deploy_duration_seconds 12.4
+/// labels{app="web"}` on its standard output or error. The metric gets the `handler` label, and is
+/// exposed by the metrics endpoint : a name ending in `_total` is a counter, incremented by the value,
+/// any other name a gauge, set to the value. Invalid lines are logged and ignored.
+///
+/// # Arguments
+///
+/// * `metrics` - the metrics registry
+/// * `handler` - the handler of the script
+/// * `output` - the output of the script
+///
+pub fn collect(metrics: &Metrics, handler: &str, output: &[u8]) {
+    for line in String::from_utf8_lossy(output).lines() {
+        let Some(sample) = line.trim_start().strip_prefix(METRIC_MARKER) else { continue };
+        let recorded = parse(sample).and_then(|sample| {
+            let mut labels: Vec<(&str, &str)> = sample.labels.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+            labels.push(("handler", handler));
+            metrics.record_script(&sample.name, &labels, sample.value)
+        });
+        if let Err(error) = recorded {
+            log::warn!(handler = handler; "Invalid metric line from handler {}: {}", handler, error);
+        }
+    }
+}
+
+/// Parses a metric line, without its marker : `<name> <value> [labels]{<label>="<value>",...}`.
+///
+/// @return the sample
+///
+/// # Errors
+///
+/// This function will return an error if the name, the value or the labels are invalid, or if the
+/// name is reserved to hare (`hare_` prefix).
+pub fn parse(line: &str) -> Result<Sample, String> {
+    let line = line.trim();
+    let (name, rest) = line.split_once(char::is_whitespace).ok_or_else(|| format!("no value in {:?}", line))?;
+    if !valid_name(name, true) {
+        return Err(format!("invalid metric name {:?}", name));
+    }
+    if name.starts_with("hare_") {
+        return Err(format!("the metric name {} is reserved to hare", name));
+    }
+    let rest = rest.trim_start();
+    let (value, labels) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let value: f64 = value.parse().map_err(|_| format!("invalid value {:?} for metric {}", value, name))?;
+    if value.is_nan() {
+        return Err(format!("invalid value NaN for metric {}", name));
+    }
+    if name.ends_with("_total") && value < 0.0 {
+        return Err(format!("the counter {} cannot be incremented by a negative value", name));
+    }
+
+    let labels = labels.trim();
+    let labels = match labels.strip_prefix("labels").unwrap_or(labels) {
+        "" => vec![],
+        labels => parse_labels(labels).map_err(|error| format!("invalid labels for metric {}: {}", name, error))?,
+    };
+    Ok(Sample { name: name.to_string(), labels, value })
+}
+
+/// Parses the labels of a metric line : `{<label>="<value>",...}`, the values being quoted strings
+/// with `\"`, `\\` and `\n` escapes.
+fn parse_labels(text: &str) -> Result<Vec<(String, String)>, String> {
+    let inner = text.strip_prefix('{').and_then(|text| text.strip_suffix('}'))
+        .ok_or_else(|| "the labels are not enclosed in braces".to_string())?;
+    let mut labels: Vec<(String, String)> = vec![];
+    let mut chars = inner.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+        let name: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=')).collect();
+        let name = name.trim().to_string();
+        if chars.next() != Some('=') || chars.next() != Some('"') {
+            return Err(format!("expected {}=\"value\"", name));
+        }
+        if !valid_name(&name, false) || name == "handler" || name.starts_with("__") {
+            return Err(format!("invalid label name {:?}", name));
+        }
+        if labels.iter().any(|(other, _)| *other == name) {
+            return Err(format!("duplicate label {}", name));
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some(c @ ('"' | '\\')) => value.push(c),
+                    _ => return Err(format!("invalid escape in the value of {}", name)),
+                },
+                Some(c) => value.push(c),
+                None => return Err(format!("unterminated value for {}", name)),
+            }
+        }
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        match chars.next() {
+            Some(',') | None => {}
+            Some(c) => return Err(format!("unexpected {:?} after the value of {}", c, name)),
+        }
+        labels.push((name, value));
+        if labels.len() > MAX_LABELS {
+            return Err(format!("more than {} labels", MAX_LABELS));
+        }
+    }
+    Ok(labels)
+}
+
+/// Whether a name is a valid Prometheus metric name (with colons) or label name.
+fn valid_name(name: &str, metric: bool) -> bool {
+    let valid = |c: char, first: bool| c.is_ascii_alphabetic() || c == '_' || (metric && c == ':') || (!first && c.is_ascii_digit());
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| valid(c, true)) && chars.all(|c| valid(c, false))
+}
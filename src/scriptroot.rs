@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use crate::metrics::{self, Metrics};
+
+/// Delay before a message deferred because of an unavailable script root is retried.
+pub const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// What to do with a message when a script root is unavailable.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnavailableAction {
+    Defer,  // requeue the message, until the script root is available again
+    Fail,   // reject the message, the broker dead-letters it if the queue has a dead letter exchange
+}
+
+/// Looks up the scripts in the script roots, without hanging on an unavailable network mount.
+///
+/// An NFS or CIFS mount whose server is gone may block any access for minutes, or fail with
+/// a stale file handle. Each lookup runs on the blocking threads of the runtime, and is given up
+/// after a timeout : the script root is then unavailable, until the blocked lookup returns. No
+/// lookup starts on a script root with a blocked one, so that a hung mount does not pile up threads.
+pub struct ScriptRoots {
+    timeout: Duration,                  // how long a lookup may take
+    hung: Arc<Mutex<HashMap<String, u64>>>, // script roots with a blocked lookup, and its number
+    lookups: AtomicU64,                 // number of the last lookup
+}
+
+impl ScriptRoots {
+
+    /// Creates the lookup of the script roots.
+    ///
+    /// @return ScriptRoots
+    ///
+    pub fn new(timeout: Duration) -> Self {
+        ScriptRoots { timeout, hung: Arc::new(Mutex::new(HashMap::new())), lookups: AtomicU64::new(0) }
+    }
+
+    /// Checks whether a script root holds a handler (its script, or its manifest).
+    ///
+    /// The availability of the script root is exposed in the `hare_script_root_available` metric.
+    ///
+    /// @return whether the script root holds the handler
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error describing why the script root is unavailable :
+    /// the lookup timed out or is still blocked, or the mount is stale or failing.
+    pub async fn contains(&self, metrics: &Metrics, root: &str, name: &str) -> Result<bool, String> {
+        let result = self.lookup(root, name).await;
+        metrics.set(&metrics::SCRIPT_ROOT_AVAILABLE, &[("script_root", root)], if result.is_ok() { 1.0 } else { 0.0 });
+        result
+    }
+
+    async fn lookup(&self, root: &str, name: &str) -> Result<bool, String> {
+        self.lookup_with(root, name, stat).await
+    }
+
+    /// Looks up a handler with a function of the script root and the handler name, given up after the timeout.
+    async fn lookup_with(&self, root: &str, name: &str, stat: fn(&str, &str) -> Result<bool, String>) -> Result<bool, String> {
+        if self.hung.lock().unwrap().contains_key(root) {
+            return Err("a previous lookup is still blocked".to_string());
+        }
+
+        // the lookup finishing and the lookup timing out are decided under the lock of the blocked
+        // lookups, so that a lookup returning late always clears its script root
+        let lookup = self.lookups.fetch_add(1, Ordering::SeqCst) + 1;
+        let finished = Arc::new(AtomicBool::new(false));
+        let task = {
+            let (hung, finished) = (self.hung.clone(), finished.clone());
+            let (root, name) = (root.to_string(), name.to_string());
+            tokio::task::spawn_blocking(move || {
+                let result = stat(&root, &name);
+                let mut hung = hung.lock().unwrap();
+                finished.store(true, Ordering::SeqCst);
+                if hung.get(&root) == Some(&lookup) {
+                    hung.remove(&root);
+                }
+                result
+            })
+        };
+
+        match tokio::time::timeout(self.timeout, task).await {
+            Ok(result) => result.map_err(|error| error.to_string())?,
+            Err(_) => {
+                let mut hung = self.hung.lock().unwrap();
+                if !finished.load(Ordering::SeqCst) {
+                    hung.insert(root.to_string(), lookup);
+                }
+                Err(format!("lookup timed out after {}", humantime::format_duration(self.timeout)))
+            }
+        }
+    }
+}
+
+/// Looks up a handler in a script root.
+///
+/// A missing script root holds no handler; any other error (stale file handle, I/O error,
+/// disconnected transport...) makes the script root unavailable.
+fn stat(root: &str, name: &str) -> Result<bool, String> {
+    match std::fs::metadata(root) {
+        Ok(_) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(error) if error.raw_os_error() == Some(libc::ESTALE) => return Err("stale mount".to_string()),
+        Err(error) => return Err(error.to_string()),
+    }
+    for path in [Path::new(root).join(name), Path::new(root).join(format!("{}.toml", name))] {
+        match path.try_exists() {
+            Ok(true) => return Ok(true),
+            Ok(false) => {}
+            Err(error) if error.raw_os_error() == Some(libc::ESTALE) => return Err("stale mount".to_string()),
+            Err(error) => return Err(error.to_string()),
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A script root holding a `deploy` script, and the manifest of a `build` handler.
+    fn script_root(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("hare-scriptroot-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("deploy"), "#!/bin/sh\n").unwrap();
+        std::fs::write(dir.join("build.toml"), "").unwrap();
+        dir.display().to_string()
+    }
+
+    #[tokio::test]
+    async fn handlers_are_found() {
+        let (roots, metrics) = (ScriptRoots::new(Duration::from_secs(2)), Metrics::new());
+        let root = script_root("found");
+        assert_eq!(roots.contains(&metrics, &root, "deploy").await, Ok(true));
+        assert_eq!(roots.contains(&metrics, &root, "build").await, Ok(true));
+        assert_eq!(roots.contains(&metrics, &root, "missing").await, Ok(false));
+        // a missing script root holds no handler
+        assert_eq!(roots.contains(&metrics, "/hare/no/such/root", "deploy").await, Ok(false));
+    }
+
+    #[tokio::test]
+    async fn a_blocked_lookup_makes_the_root_unavailable_until_it_returns() {
+        let roots = ScriptRoots::new(Duration::from_millis(50));
+        let root = script_root("blocked");
+
+        // a lookup blocked on the mount, standing for the first lookup that timed out
+        roots.hung.lock().unwrap().insert(root.clone(), 0);
+        assert_eq!(roots.lookup(&root, "deploy").await, Err("a previous lookup is still blocked".to_string()));
+        roots.hung.lock().unwrap().remove(&root);
+        assert_eq!(roots.lookup(&root, "deploy").await, Ok(true));
+    }
+
+    #[tokio::test]
+    async fn a_lookup_timing_out_is_cleared_when_it_returns() {
+        let roots = ScriptRoots::new(Duration::from_millis(50));
+        let root = script_root("timeout");
+        let hanging = |root: &str, name: &str| {
+            std::thread::sleep(Duration::from_millis(200));
+            stat(root, name)
+        };
+        assert!(roots.lookup_with(&root, "deploy", hanging).await.unwrap_err().contains("timed out"));
+        assert!(roots.lookup(&root, "deploy").await.unwrap_err().contains("still blocked"));
+
+        // the blocked lookup returns : the script root is available again, whatever the order of the
+        // timeout and of the return
+        let started = std::time::Instant::now();
+        while roots.hung.lock().unwrap().contains_key(&root) {
+            assert!(started.elapsed() < Duration::from_secs(5), "the script root stays unavailable");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(roots.lookup(&root, "deploy").await, Ok(true));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::contract::{BODY_FILE, JOB_ID, METRIC_MARKER, PROGRESS_MARKER, QUEUE_LATENCY_MS, RESULT_FILE, VAR_PREFIX};
+
+/// Languages of the generated helpers.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Language {
+    Bash,
+    Python,
+}
+
+/// Generates the helpers a script can source or import to use the hare environment contract.
+///
+/// The helpers are generated from the variable names used by hare, so that they stay in sync
+/// with the contract : headers, body file, result file, job id, queue latency, progress and metric reporting.
+///
+/// @return the source of the helpers
+///
+pub fn helpers(language: Language) -> String {
+    let version = env!("CARGO_PKG_VERSION");
+    match language {
+        Language::Bash => format!(r#"# hare helpers for bash, generated by hare {version}
+# usage : . hare.sh
+
+# value of a message header : hare_header app (with HARE_ENV_CLEAR, the name is sanitized)
+hare_header() {{ local name; name=$(printf '%s' "$1" | tr '[:lower:]' '[:upper:]'); printenv "{VAR_PREFIX}$name" || printenv "{VAR_PREFIX}$(printf '%s' "$name" | tr -c 'A-Z0-9_' '_')"; }}
+
+# message body
+hare_body() {{ cat "${BODY_FILE}"; }}
+
+# id of the job
+hare_job_id() {{ printf '%s\n' "${JOB_ID}"; }}
+
+# time spent by the message in the queue, in milliseconds (empty if unknown)
+hare_queue_latency_ms() {{ printf '%s\n' "${{{QUEUE_LATENCY_MS}:-}}"; }}
+
+# result of the job, a JSON document added to the result message : hare_result '{{"version": "1.2"}}'
+hare_result() {{ printf '%s\n' "$1" > "${RESULT_FILE}"; }}
+
+# progress of the job : hare_progress 40 "copying files"
+hare_progress() {{ printf '{PROGRESS_MARKER} %s %s\n' "$1" "$2"; }}
+
+# metric exposed by hare, a counter for a name ending in _total, a gauge otherwise : hare_metric deploy_duration_seconds 12.4 'app="web"'
+hare_metric() {{ if [ -n "${{3:-}}" ]; then printf '{METRIC_MARKER} %s %s labels{{%s}}\n' "$1" "$2" "$3"; else printf '{METRIC_MARKER} %s %s\n' "$1" "$2"; fi; }}
+"#),
+        Language::Python => format!(r#""""hare helpers for python, generated by hare {version}.
+
+usage : import hare
+"""
+import json
+import os
+import re
+
+
+def header(name, default=None):
+    """Value of a message header (with HARE_ENV_CLEAR, the name is sanitized)."""
+    name = name.upper()
+    return os.environ.get("{VAR_PREFIX}" + name, os.environ.get("{VAR_PREFIX}" + re.sub(r"[^A-Z0-9_]", "_", name), default))
+
+
+def headers():
+    """All the message headers, with lower case names."""
+    prefix = "{VAR_PREFIX}"
+    return {{k[len(prefix):].lower(): v for k, v in os.environ.items() if k.startswith(prefix)}}
+
+
+def body():
+    """Message body, as bytes."""
+    with open(os.environ["{BODY_FILE}"], "rb") as f:
+        return f.read()
+
+
+def job_id():
+    """Id of the job."""
+    return os.environ.get("{JOB_ID}")
+
+
+def queue_latency_ms():
+    """Time spent by the message in the queue, in milliseconds (None if unknown)."""
+    value = os.environ.get("{QUEUE_LATENCY_MS}")
+    return int(value) if value else None
+
+
+def set_result(result):
+    """Result of the job, a JSON serializable value added to the result message."""
+    with open(os.environ["{RESULT_FILE}"], "w") as f:
+        json.dump(result, f)
+
+
+def progress(percent, message=""):
+    """Reports the progress of the job."""
+    print("{PROGRESS_MARKER} %s %s" % (percent, message), flush=True)
+
+
+def metric(name, value, **labels):
+    """Reports a metric exposed by hare, a counter for a name ending in _total, a gauge otherwise."""
+    line = "{METRIC_MARKER} %s %s" % (name, value)
+    if labels:
+        escape = lambda v: str(v).replace("\\", "\\\\").replace('"', '\\"').replace("\n", "\\n")
+        line += " labels{{%s}}" % ",".join('%s="%s"' % (k, escape(v)) for k, v in labels.items())
+    print(line, flush=True)
+"#),
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::limits;
+use crate::manifest::{Limits, SelfTest};
+use crate::metrics::{self, Metrics};
+
+/// Argument given to a script to run its verification, when its manifest gives no command.
+pub const SELFTEST_ARG: &str = "--hare-selftest";
+
+/// Variable set for the verification runs, so that a script can tell them from the executions.
+pub const SELFTEST_VARIABLE: &str = "HARE_SELFTEST";
+
+/// Longest output of a failed verification kept in its reason.
+const MAX_REASON: usize = 500;
+
+/// What to do with the messages of a degraded handler.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DegradedAction {
+    Warn,   // run the handler anyway, the failure is only logged and exposed in the metrics
+    Reject, // reject the message, the broker dead-letters it if the queue has a dead letter exchange
+}
+
+/// Handlers whose verification failed at startup.
+///
+/// The handlers declaring a `[selftest]` section in their manifest are verified once when hare
+/// starts, so that a broken handler (missing credentials, unreachable dependency...) is caught
+/// when it is deployed rather than on its first message. A handler whose verification fails is
+/// degraded until hare restarts.
+pub struct SelfTests {
+    degraded: Mutex<BTreeMap<String, String>>,  // degraded handlers, with the reason of the failure
+}
+
+impl SelfTests {
+
+    /// Creates an empty list of degraded handlers.
+    ///
+    /// @return SelfTests
+    ///
+    pub fn new() -> Self {
+        SelfTests { degraded: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// Runs the verification of a handler, and records the handler as degraded if it fails.
+    ///
+    /// The command runs in the script root, with the `HARE_SELFTEST` variable set and the resource
+    /// limits of the handler, and is killed past its timeout.
+    ///
+    /// # Arguments
+    ///
+    /// * `script_root` - the script root of the handler
+    /// * `handler` - the name of the handler
+    /// * `selftest` - the verification of the handler, from its manifest
+    /// * `script_limits` - the resource limits of the handler, if any
+    pub fn verify(&self, metrics: &Metrics, script_root: &str, handler: &str, selftest: &SelfTest, script_limits: Option<&Limits>) {
+        let mut command = match selftest.command.split_first() {
+            Some((program, args)) => {
+                let mut command = Command::new(program);
+                command.args(args);
+                command
+            }
+            None => {
+                let mut command = Command::new(Path::new(script_root).join(handler));
+                command.arg(SELFTEST_ARG);
+                command
+            }
+        };
+        command.current_dir(script_root).env(SELFTEST_VARIABLE, "1");
+        if let Some(script_limits) = script_limits {
+            limits::apply(&mut command, script_limits);
+        }
+
+        let result = run(&mut command, selftest.timeout);
+        let mut degraded = self.degraded.lock().unwrap();
+        metrics.set(&metrics::HANDLER_DEGRADED, &[("handler", handler)], if result.is_ok() { 0.0 } else { 1.0 });
+        match result {
+            Ok(_) => {
+                log::info!(handler = handler; "Self-test of handler {} passed", handler);
+                degraded.remove(handler);
+            }
+            Err(reason) => {
+                log::error!(handler = handler; "Self-test of handler {} failed, handler degraded: {}", handler, reason);
+                degraded.insert(handler.to_string(), reason);
+            }
+        }
+    }
+
+    /// Checks whether a handler is degraded.
+    ///
+    /// @return the reason of the failure of its verification, None if the handler is not degraded
+    ///
+    pub fn degraded(&self, handler: &str) -> Option<String> {
+        self.degraded.lock().unwrap().get(handler).cloned()
+    }
+
+    /// The degraded handlers, with the reason of their failure, for the inventory report.
+    pub fn list(&self) -> serde_json::Value {
+        serde_json::json!(*self.degraded.lock().unwrap())
+    }
+}
+
+/// Runs a verification command, with a timeout.
+///
+/// @return the standard output if the command exited with 0, otherwise the reason of the failure with the end of its output
+///
+pub fn run(command: &mut Command, timeout: Duration) -> Result<Vec<u8>, String> {
+    let mut child = command
+        .stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().map_err(|error| format!("cannot start {:?}: {}", command.get_program(), error))?;
+
+    // read while the command runs, it may fill its output pipes before exiting
+    let stdout = collect(child.stdout.take());
+    let stderr = collect(child.stderr.take());
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|error| error.to_string())? {
+            break status;
+        }
+        if started.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("timed out after {}", humantime::format_duration(timeout)));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    if status.success() {
+        return Ok(stdout.join().unwrap_or_default());
+    }
+
+    let mut output = stderr.join().unwrap_or_default();
+    output.extend(stdout.join().unwrap_or_default());
+    let output = String::from_utf8_lossy(&output);
+    let output = output.trim();
+    let tail = match output.char_indices().nth_back(MAX_REASON - 1) {
+        Some((start, _)) if start > 0 => &output[start..],
+        _ => output,
+    };
+    Err(match tail {
+        "" => status.to_string(),
+        tail => format!("{}: {}", status, tail),
+    })
+}
+
+/// Reads an output stream of the command in a thread.
+fn collect<R: Read + Send + 'static>(stream: Option<R>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut stream) = stream {
+            let _ = stream.read_to_end(&mut output);
+        }
+        output
+    })
+}
@@ -0,0 +1,80 @@
+use lapin::message::Delivery;
+use crate::config::HareConfig;
+use crate::harehandler::Outcome;
+use crate::metrics::{self, Metrics};
+
+/// How a shadow instance handles the messages (HARE_SHADOW).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShadowMode {
+    DryRun,     // the handlers are checked, but not run
+    Sandbox,    // the handlers run, with HARE_SHADOW set, from the script roots of the shadow instance
+}
+
+/// Shadow settings : an instance replaying production traffic, without effect on it.
+///
+/// The deliveries of a shadow instance are copies : they are all acknowledged, and no result,
+/// dead-lettered message or notification is published. What happened to each message is written
+/// to the `hare::shadow` log target.
+#[derive(Debug, Clone)]
+pub struct Shadow {
+    pub mode: ShadowMode,
+    pub exchange: Option<String>,   // production exchange (template) copied into a private queue, rather than consuming the queue of hare
+    pub bindings: Vec<String>,      // binding keys of the private queue
+}
+
+impl Shadow {
+
+    /// Reads the shadow settings : HARE_SHADOW, HARE_SHADOW_EXCHANGE and HARE_SHADOW_BINDINGS.
+    ///
+    /// @return the settings, None when HARE_SHADOW is not set
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the mode is unknown, or if the exchange is set without mode.
+    pub fn load(config: &HareConfig) -> Result<Option<Self>, String> {
+        let exchange = config.get("HARE_SHADOW_EXCHANGE");
+        let mode = match config.get("HARE_SHADOW").as_deref() {
+            None if exchange.is_some() => return Err("HARE_SHADOW_EXCHANGE requires HARE_SHADOW".to_string()),
+            None => return Ok(None),
+            Some("dry-run") => ShadowMode::DryRun,
+            Some("sandbox") => ShadowMode::Sandbox,
+            Some(other) => return Err(format!("invalid HARE_SHADOW {:?}, expected dry-run or sandbox", other)),
+        };
+        let bindings = config.get("HARE_SHADOW_BINDINGS")
+            .map(|keys| keys.split(',').map(str::trim).filter(|key| !key.is_empty()).map(str::to_string).collect())
+            .unwrap_or_else(|| vec!["#".to_string()]);
+        Ok(Some(Shadow { mode, exchange, bindings }))
+    }
+
+    /// Records what happened to a message : a `hare::shadow` log record, and the shadow outcomes metric.
+    pub fn record(&self, metrics: &Metrics, delivery: &Delivery, handler: &str, outcome: &Outcome) {
+        let mut record = serde_json::json!({
+            "handler": handler,
+            "exchange": delivery.exchange.as_str(),
+            "routing_key": delivery.routing_key.as_str(),
+            "message_id": delivery.properties.message_id().as_ref().map(|id| id.as_str()),
+            "correlation_id": delivery.properties.correlation_id().as_ref().map(|id| id.as_str()),
+        });
+        let name = match outcome {
+            Outcome::Executed(execution) if self.mode == ShadowMode::DryRun && execution.details.as_ref().is_some_and(|details| details["dry_run"] == true) => {
+                record["script"] = execution.details.as_ref().map(|details| details["script"].clone()).unwrap_or_default();
+                "would-run"
+            }
+            Outcome::Executed(execution) => {
+                record["exit_code"] = execution.exit_code.into();
+                record["duration_ms"] = (execution.duration.as_millis() as u64).into();
+                if execution.exit_code == Some(0) { "succeeded" } else { "failed" }
+            }
+            Outcome::Skipped => "skipped",
+            Outcome::Deferred(delay) => {
+                record["delay_ms"] = (delay.as_millis() as u64).into();
+                "deferred"
+            }
+            Outcome::Rejected => "rejected",
+            Outcome::Missing => "missing",
+        };
+        record["outcome"] = name.into();
+        log::info!(target: "hare::shadow", "{}", record);
+        metrics.increment(&metrics::SHADOW_OUTCOMES, &[("outcome", name)]);
+    }
+}
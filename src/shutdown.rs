@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use serde::Serialize;
+
+/// Set once a shutdown is requested (SIGTERM or SIGINT).
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Routing key of the shutdown report, published to the result exchange.
+pub const SHUTDOWN_ROUTING_KEY: &str = "_hare.shutdown";
+
+/// How often the shutdown flag is checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Installs the SIGTERM and SIGINT handlers.
+///
+/// The first signal requests a graceful shutdown : hare stops consuming, lets the running job
+/// finish, and reports. A second signal exits at once.
+pub fn install() {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // Safety: the handler only touches an atomic, and calls _exit, which is async-signal-safe
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as usize;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        // Safety: _exit is async-signal-safe
+        unsafe { libc::_exit(1) };
+    }
+}
+
+/// Whether a shutdown was requested.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Waits until a shutdown is requested.
+pub async fn wait() {
+    while !requested() {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Summary of a run, emitted on shutdown.
+///
+/// Fleet tooling can check it to verify that an instance stopped cleanly during a rolling
+/// upgrade : no job abandoned, and no result lost.
+#[derive(Serialize, Debug, Default)]
+pub struct ShutdownReport {
+    pub uptime_secs: u64,           // time since hare started
+    pub messages: u64,              // messages handled
+    pub executions: u64,            // executions of handlers
+    pub failures: u64,              // failed executions
+    pub jobs_drained: u64,          // jobs running when the shutdown was requested, and finished
+    pub jobs_abandoned: u64,        // jobs still running when the shutdown timeout expired
+    pub deferred: u64,              // deferred messages not requeued yet, returned to the queue by the broker
+    pub pending_publications: u64,  // messages not confirmed by the broker
+    pub pending_persisted: bool,    // whether the pending messages are kept in the outbox, for the next start
+}
+
+impl ShutdownReport {
+
+    /// Logs the report, as a JSON object.
+    pub fn log(&self) {
+        log::info!("Shutdown report: {}", serde_json::to_string(self).unwrap_or_default());
+    }
+}
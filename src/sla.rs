@@ -0,0 +1,123 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+use crate::cluster;
+use crate::manifest::SlaPolicy;
+use crate::metrics::{self, Metrics};
+use crate::publisher::OutgoingMessage;
+
+/// Routing key of the SLA notifications published to the result exchange.
+pub const SLA_ROUTING_KEY: &str = "_hare.sla";
+
+/// Maximum number of notifications waiting to be published, the oldest ones are dropped past it.
+const MAX_PENDING: usize = 64;
+
+/// A change of the SLA status of a handler, to notify.
+pub struct Notification {
+    handler: String,        // handler of the message
+    breached: bool,         // whether the handler breached its SLA, or is back within it
+    limit: Duration,        // how long after its arrival a message must start
+    waited: Duration,       // how long after its arrival the message started
+}
+
+/// SLA of the handlers : how long after their arrival their messages must start.
+///
+/// Every late message is logged and counted in `hare_sla_breaches_total`. An alert is logged, and a
+/// notification queued for the result exchange, when a handler breaches its SLA, and when one of its
+/// messages starts in time again.
+pub struct SlaTracker {
+    breached: Mutex<HashSet<String>>,       // handlers whose last message started late
+    pending: Mutex<VecDeque<Notification>>, // notifications waiting to be published
+    queued: Notify,                         // wakes up the consumer loop when a notification is queued
+}
+
+impl SlaTracker {
+
+    /// Creates a tracker, with every handler within its SLA.
+    ///
+    /// @return SlaTracker
+    ///
+    pub fn new() -> Self {
+        SlaTracker { breached: Mutex::new(HashSet::new()), pending: Mutex::new(VecDeque::new()), queued: Notify::new() }
+    }
+
+    /// Checks how long after its arrival a message started against the SLA of its handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `metrics` - the metrics registry
+    /// * `handler` - the handler of the message
+    /// * `policy` - the SLA of the handler
+    /// * `waited` - the time from the arrival of the message to its start
+    ///
+    pub fn check(&self, metrics: &Metrics, handler: &str, policy: &SlaPolicy, waited: Duration) {
+        let late = waited > policy.start_within;
+        if late {
+            log::warn!("Handler {} started {} after the arrival of its message, over its SLA of {}",
+                handler, humantime::format_duration(round(waited)), humantime::format_duration(policy.start_within));
+            metrics.increment(&metrics::SLA_BREACHES, &[("handler", handler)]);
+        }
+
+        let mut breached = self.breached.lock().unwrap();
+        let changed = match late {
+            true => breached.insert(handler.to_string()),
+            false => breached.remove(handler),
+        };
+        if !changed {
+            return;
+        }
+        match late {
+            true => log::error!("ALERT handler {} breached its SLA: its messages start more than {} after their arrival",
+                handler, humantime::format_duration(policy.start_within)),
+            false => log::info!("Handler {} is back within its SLA", handler),
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() == MAX_PENDING {
+            pending.pop_front();
+        }
+        pending.push_back(Notification { handler: handler.to_string(), breached: late, limit: policy.start_within, waited });
+        self.queued.notify_one();
+    }
+
+    /// Waits until a notification is queued.
+    pub async fn wait(&self) {
+        self.queued.notified().await
+    }
+
+    /// Takes the queued notifications, as messages to publish to the result exchange.
+    ///
+    /// The body describes the change : `{"handler": "deploy", "status": "breached", "sla_secs": 120,
+    /// "waited_secs": 187.2, "host": "web-01", "instance": "web-01-4242", "timestamp": "..."}`, the
+    /// status being "breached" or "recovered".
+    ///
+    /// @return the messages, in the order of the changes
+    ///
+    pub fn take(&self, exchange: &str, instance: &str) -> Vec<OutgoingMessage> {
+        self.pending.lock().unwrap().drain(..).map(|notification| {
+            let body = serde_json::json!({
+                "handler": notification.handler,
+                "status": if notification.breached { "breached" } else { "recovered" },
+                "sla_secs": notification.limit.as_secs_f64(),
+                "waited_secs": round(notification.waited).as_secs_f64(),
+                "host": cluster::hostname(),
+                "instance": instance,
+                "timestamp": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            });
+            OutgoingMessage {
+                exchange: exchange.to_string(),
+                routing_key: SLA_ROUTING_KEY.to_string(),
+                body: body.to_string().into_bytes(),
+                properties: lapin::BasicProperties::default()
+                    .with_content_type("application/json".into())
+                    .with_delivery_mode(2),
+            }
+        }).collect()
+    }
+}
+
+/// A duration rounded to the tenth of a second, for the logs and notifications.
+fn round(duration: Duration) -> Duration {
+    Duration::from_millis(duration.as_millis() as u64 / 100 * 100)
+}
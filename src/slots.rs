@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Delay before a message waiting for an execution slot of its handler is requeued.
+pub const DEFER_DELAY: Duration = Duration::from_secs(5);
+
+/// Running executions of the handlers with a `concurrency` limit, on this instance.
+///
+/// A handler at its limit gets no slot : its message is deferred, and the other handlers keep
+/// running meanwhile.
+pub struct HandlerSlots {
+    running: Mutex<HashMap<String, usize>>, // running executions, per handler
+}
+
+/// An execution slot of a handler, released when dropped.
+pub struct Slot<'a> {
+    slots: &'a HandlerSlots,
+    handler: String,
+}
+
+impl HandlerSlots {
+
+    /// Creates the slots, all free.
+    ///
+    /// @return HandlerSlots
+    ///
+    pub fn new() -> Self {
+        HandlerSlots { running: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes an execution slot of a handler, if it runs less than `limit` executions.
+    ///
+    /// @return the slot, None if the handler is at its limit
+    ///
+    pub fn acquire(&self, handler: &str, limit: usize) -> Option<Slot<'_>> {
+        let mut running = self.running.lock().unwrap();
+        let count = running.entry(handler.to_string()).or_default();
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(Slot { slots: self, handler: handler.to_string() })
+    }
+
+    /// Releases a slot of a handler.
+    fn release(&self, handler: &str) {
+        let mut running = self.running.lock().unwrap();
+        if let Some(count) = running.get_mut(handler) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                running.remove(handler);
+            }
+        }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.slots.release(&self.handler);
+    }
+}
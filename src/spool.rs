@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use bytes::Bytes;
+use tokio::sync::Notify;
+use crate::harehandler::HareError;
+
+/// Name of the spool directory, inside the state directory.
+pub const SPOOL_DIR: &str = "spool";
+
+/// Name of the directory of the job results, inside the state directory.
+pub const RESULTS_DIR: &str = "results";
+
+/// How long the results of the jobs are kept.
+const RESULT_RETENTION: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// A job waiting in the spool.
+pub struct SpooledJob {
+    pub id: String,                         // job id, also the name of the spool entry
+    pub handler: String,                    // message type, name of the handler
+    pub headers: HashMap<String, String>,   // message headers
+    pub content_type: Option<String>,       // content type of the body, if given
+    pub body: Bytes,                        // message body, a slice of the spool entry
+    pub not_before: u64,                    // the job is deferred until this time, in seconds since epoch
+}
+
+/// State of a submitted job.
+pub enum JobStatus {
+    Pending,            // waiting in the spool, or running
+    Done(Vec<u8>),      // finished, with its result (JSON)
+    Unknown,            // no such job, or its result expired
+}
+
+/// Local persistent spool of the jobs submitted over HTTP, in agent mode.
+///
+/// Each job is stored in its own file of the spool directory, named after its id so that the
+/// jobs run in submission order. The file holds a JSON line with the job metadata (handler,
+/// headers, content type, deferral) followed by the raw message body, written atomically like the outbox
+/// entries. A job stays in the spool until it has run, so that jobs survive a restart; its
+/// result is then written to the results directory.
+pub struct Spool {
+    dir: PathBuf,           // spool directory
+    results: PathBuf,       // results directory
+    sequence: AtomicU64,    // disambiguates jobs submitted during the same nanosecond
+    submitted: Notify,      // wakes up the agent when a job is submitted
+}
+
+impl Spool {
+
+    /// Opens the spool of a state directory.
+    ///
+    /// @return Spool
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the spool directories cannot be created.
+    pub fn open(state_dir: &Path) -> Result<Self, HareError> {
+        let spool = Spool {
+            dir: state_dir.join(SPOOL_DIR),
+            results: state_dir.join(RESULTS_DIR),
+            sequence: AtomicU64::new(0),
+            submitted: Notify::new(),
+        };
+        fs::create_dir_all(&spool.dir)?;
+        fs::create_dir_all(&spool.results)?;
+        Ok(spool)
+    }
+
+    /// Stores a new job in the spool.
+    ///
+    /// @return the id of the job
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the job could not be written.
+    pub fn submit(&self, handler: &str, headers: HashMap<String, String>, content_type: Option<&str>, body: &[u8]) -> Result<String, HareError> {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let sequence = self.sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        let id = format!("{:020}-{:06}", timestamp, sequence % 1_000_000);
+        self.write(&SpooledJob { id: id.clone(), handler: handler.to_string(), headers,
+            content_type: content_type.map(str::to_string), body: Bytes::copy_from_slice(body), not_before: 0 })?;
+        self.submitted.notify_one();
+        Ok(id)
+    }
+
+    /// Takes the oldest job that is due, waiting for one if needed.
+    ///
+    /// Entries that cannot be parsed are logged and left in place.
+    ///
+    /// @return the next job
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the spool directory cannot be read.
+    pub async fn next(&self) -> Result<SpooledJob, HareError> {
+        loop {
+            let mut names: Vec<String> = fs::read_dir(&self.dir)?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| !name.starts_with('.'))
+                .collect();
+            names.sort();
+
+            let now = now();
+            let mut wake_up = None;
+            for name in names {
+                match self.read(&name) {
+                    Some(job) if job.not_before <= now => return Ok(job),
+                    Some(job) => wake_up = Some(wake_up.unwrap_or(u64::MAX).min(job.not_before)),
+                    None => log::error!("Invalid spool entry {}, ignored", self.dir.join(&name).display()),
+                }
+            }
+
+            // wait for a new job, or for the first deferred job to be due
+            let delay = wake_up.map(|at| Duration::from_secs(at.saturating_sub(now))).unwrap_or(Duration::from_secs(60));
+            let _ = tokio::time::timeout(delay, self.submitted.notified()).await;
+        }
+    }
+
+    /// Defers a job : it stays in the spool, and is not run again before the delay.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the job could not be written.
+    pub fn defer(&self, mut job: SpooledJob, delay: Duration) -> Result<(), HareError> {
+        job.not_before = now() + delay.as_secs().max(1);
+        self.write(&job)
+    }
+
+    /// Removes a job from the spool, and stores its result.
+    ///
+    /// The results older than a week are removed at the same time.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the result could not be written, or the job removed.
+    pub fn complete(&self, job: &SpooledJob, result: &[u8]) -> Result<(), HareError> {
+        let tmp = self.results.join(format!(".{}.tmp", job.id));
+        fs::write(&tmp, result)?;
+        fs::rename(&tmp, self.results.join(format!("{}.json", job.id)))?;
+        fs::remove_file(self.dir.join(&job.id))?;
+
+        for entry in fs::read_dir(&self.results)?.filter_map(|entry| entry.ok()) {
+            let expired = entry.metadata().and_then(|metadata| metadata.modified()).ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > RESULT_RETENTION);
+            if expired {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        Ok(())
+    }
+
+    /// The state of a job.
+    ///
+    /// @return JobStatus
+    ///
+    pub fn status(&self, id: &str) -> JobStatus {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit() || c == '-') {
+            return JobStatus::Unknown;
+        }
+        if let Ok(result) = fs::read(self.results.join(format!("{}.json", id))) {
+            return JobStatus::Done(result);
+        }
+        if self.dir.join(id).exists() {
+            return JobStatus::Pending;
+        }
+        JobStatus::Unknown
+    }
+
+    /// Writes a spool entry atomically.
+    fn write(&self, job: &SpooledJob) -> Result<(), HareError> {
+        let metadata = serde_json::json!({
+            "handler": job.handler,
+            "headers": job.headers,
+            "content_type": job.content_type,
+            "not_before": job.not_before,
+        });
+
+        // the metadata line and the body are written in turn, the body is not copied into a buffer
+        let tmp = self.dir.join(format!(".{}.tmp", job.id));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(metadata.to_string().as_bytes())?;
+        file.write_all(b"\n")?;
+        file.write_all(&job.body)?;
+        drop(file);
+        fs::rename(&tmp, self.dir.join(&job.id))?;
+        Ok(())
+    }
+
+    /// Parses a spool entry.
+    fn read(&self, name: &str) -> Option<SpooledJob> {
+        let content = Bytes::from(fs::read(self.dir.join(name)).ok()?);
+        let newline = content.iter().position(|b| *b == b'\n')?;
+        let metadata: serde_json::Value = serde_json::from_slice(&content[..newline]).ok()?;
+        Some(SpooledJob {
+            id: name.to_string(),
+            handler: metadata["handler"].as_str()?.to_string(),
+            headers: serde_json::from_value(metadata["headers"].clone()).ok()?,
+            content_type: metadata["content_type"].as_str().map(str::to_string),
+            body: content.slice(newline + 1..),
+            not_before: metadata["not_before"].as_u64().unwrap_or(0),
+        })
+    }
+}
+
+/// Current time, in seconds since epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
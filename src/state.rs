@@ -0,0 +1,168 @@
+use std::fs;
+use std::path::Path;
+use crate::harehandler::HareError;
+use crate::{accounting, outbox, stats};
+
+/// Version of the on-disk state layout written by this build of hare.
+///
+/// Bump this value and append a migration to `MIGRATIONS` whenever the content
+/// of the state directory changes in an incompatible way.
+pub const STATE_VERSION: u32 = 4;
+
+/// Name of the file holding the state schema version.
+const VERSION_FILE: &str = "VERSION";
+
+/// A migration brings the state directory from version `n - 1` to version `n`.
+///
+/// Migrations must be idempotent : if hare is interrupted after the migration
+/// but before the new version is recorded, the migration is run again.
+type Migration = fn(&Path) -> std::io::Result<()>;
+
+/// Ordered list of migrations, the migration at index `i` produces version `i + 1`.
+const MIGRATIONS: [Migration; STATE_VERSION as usize] = [
+    migrate_initial_layout,
+    migrate_outbox,
+    migrate_stats,
+    migrate_accounting,
+];
+
+/// Opens the state directory, and upgrades it to the current schema version.
+///
+/// The directory is created if needed. A directory without version file is
+/// considered to be at version 0 (fresh, or written before versioning existed).
+/// Hare refuses to start on a state directory written by a newer version,
+/// rather than silently ignoring or corrupting its content.
+///
+/// @return Result<(), HareError>
+///
+/// # Errors
+///
+/// This function will return an error if the state directory cannot be read or written,
+/// or if its version is not supported.
+pub fn migrate(root: &Path) -> Result<(), HareError> {
+    fs::create_dir_all(root)?;
+
+    let current = read_version(root)?;
+    if current > STATE_VERSION {
+        return Err(HareError::StateError(format!(
+            "state directory {} has version {}, this hare supports up to version {}",
+            root.display(), current, STATE_VERSION
+        )));
+    }
+
+    for version in current..STATE_VERSION {
+        log::info!("Migrating state directory {} to version {}", root.display(), version + 1);
+        MIGRATIONS[version as usize](root)?;
+        write_version(root, version + 1)?;
+    }
+
+    log::info!("State directory {} at version {}", root.display(), STATE_VERSION);
+    Ok(())
+}
+
+/// Reads the state version, 0 if the version file does not exist.
+fn read_version(root: &Path) -> Result<u32, HareError> {
+    match fs::read_to_string(root.join(VERSION_FILE)) {
+        Ok(content) => {
+            content.trim().parse::<u32>().map_err(|_| HareError::StateError(format!(
+                "invalid version file in state directory {}: {:?}", root.display(), content.trim()
+            )))
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Writes the state version atomically (write to a temporary file, then rename).
+fn write_version(root: &Path, version: u32) -> std::io::Result<()> {
+    let tmp = root.join(format!("{}.tmp", VERSION_FILE));
+    fs::write(&tmp, format!("{}\n", version))?;
+    fs::rename(&tmp, root.join(VERSION_FILE))
+}
+
+/// Version 1 : initial layout, nothing to convert.
+fn migrate_initial_layout(_root: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Version 2 : outbox of the messages not yet confirmed by the broker.
+fn migrate_outbox(root: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(root.join(outbox::OUTBOX_DIR))
+}
+
+/// Version 3 : cumulative statistics, kept across restarts.
+fn migrate_stats(root: &Path) -> std::io::Result<()> {
+    let path = root.join(stats::STATS_FILE);
+    if !path.exists() {
+        fs::write(path, "{}\n")?;
+    }
+    Ok(())
+}
+
+/// Version 4 : daily resource usage of the handlers.
+fn migrate_accounting(root: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(root.join(accounting::ACCOUNTING_DIR))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hare-state-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn a_fresh_directory_is_migrated_to_the_current_version() {
+        let dir = state_dir("fresh");
+        migrate(&dir).unwrap();
+        assert_eq!(fs::read_to_string(dir.join(VERSION_FILE)).unwrap(), format!("{}\n", STATE_VERSION));
+        assert!(dir.join(outbox::OUTBOX_DIR).is_dir());
+        assert_eq!(fs::read_to_string(dir.join(stats::STATS_FILE)).unwrap(), "{}\n");
+        assert!(dir.join(accounting::ACCOUNTING_DIR).is_dir());
+        assert!(!dir.join(format!("{}.tmp", VERSION_FILE)).exists());
+    }
+
+    #[test]
+    fn only_the_later_migrations_run() {
+        let dir = state_dir("partial");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(VERSION_FILE), "2\n").unwrap();
+        fs::write(dir.join(stats::STATS_FILE), "{\"messages\": 3}\n").unwrap();
+        migrate(&dir).unwrap();
+
+        assert_eq!(read_version(&dir).unwrap(), STATE_VERSION);
+        // the outbox of version 2 is not created again, the statistics are kept
+        assert!(!dir.join(outbox::OUTBOX_DIR).exists());
+        assert_eq!(fs::read_to_string(dir.join(stats::STATS_FILE)).unwrap(), "{\"messages\": 3}\n");
+        assert!(dir.join(accounting::ACCOUNTING_DIR).is_dir());
+    }
+
+    #[test]
+    fn the_migrations_run_again_after_an_interruption() {
+        let dir = state_dir("interrupted");
+        migrate(&dir).unwrap();
+        // the last migration ran, but its version was not recorded
+        write_version(&dir, STATE_VERSION - 1).unwrap();
+        migrate(&dir).unwrap();
+        assert_eq!(read_version(&dir).unwrap(), STATE_VERSION);
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert!(migration(&dir).is_ok(), "migration to version {} is not idempotent", index + 1);
+        }
+    }
+
+    #[test]
+    fn a_newer_or_invalid_version_is_refused() {
+        let dir = state_dir("newer");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(VERSION_FILE), format!("{}\n", STATE_VERSION + 1)).unwrap();
+        assert!(matches!(migrate(&dir), Err(HareError::StateError(error)) if error.contains("supports up to version")));
+        assert_eq!(read_version(&dir).unwrap(), STATE_VERSION + 1);
+
+        fs::write(dir.join(VERSION_FILE), "four\n").unwrap();
+        assert!(matches!(migrate(&dir), Err(HareError::StateError(error)) if error.contains("invalid version file")));
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+use serde::{Deserialize, Serialize};
+use crate::harehandler::HareError;
+use crate::metrics::{self, Metrics};
+
+/// Name of the statistics file, inside the state directory.
+pub const STATS_FILE: &str = "stats.json";
+
+/// Cumulative statistics of a hare instance, kept across restarts.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct Stats {
+    pub first_start: Option<u64>,       // first start, in seconds since epoch
+    pub last_start: Option<u64>,        // last start, in seconds since epoch
+    pub starts: u64,                    // number of starts
+    pub uptime_secs: u64,               // cumulated uptime, as of the last save
+    pub handlers: BTreeMap<String, HandlerStats>, // statistics per handler
+}
+
+/// Cumulative statistics of a handler.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct HandlerStats {
+    pub executions: u64,                // number of executions
+    pub failures: u64,                  // number of failed executions
+    pub last_success: Option<u64>,      // last successful execution, in seconds since epoch
+    pub last_failure: Option<u64>,      // last failed execution, in seconds since epoch
+}
+
+/// Reads the statistics of a state directory.
+///
+/// @return the statistics, empty if the file does not exist
+///
+/// # Errors
+///
+/// This function will return an error if the statistics file cannot be read or is invalid.
+pub fn read(state_dir: &Path) -> Result<Stats, HareError> {
+    match fs::read(state_dir.join(STATS_FILE)) {
+        Ok(content) => serde_json::from_slice(&content)
+            .map_err(|error| HareError::StateError(format!("invalid {}: {}", STATS_FILE, error))),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Stats::default()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Keeps the statistics of this instance, and saves them in the state directory.
+///
+/// The statistics are also exposed as metrics, seeded with the saved values at start so
+/// that they survive restarts.
+pub struct StatsStore {
+    path: Option<PathBuf>,      // statistics file, None without state directory
+    started: Instant,           // start of this instance
+    uptime_before: Mutex<u64>,  // cumulated uptime of the previous runs
+    stats: Mutex<Stats>,
+}
+
+impl StatsStore {
+
+    /// Creates the store, empty until loaded.
+    ///
+    /// @return StatsStore
+    ///
+    pub fn new(state_dir: Option<&str>) -> Self {
+        StatsStore {
+            path: state_dir.map(|dir| Path::new(dir).join(STATS_FILE)),
+            started: Instant::now(),
+            uptime_before: Mutex::new(0),
+            stats: Mutex::new(Stats::default()),
+        }
+    }
+
+    /// Loads the saved statistics, records this start, and seeds the metrics.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the statistics cannot be read or saved.
+    pub fn start(&self, metrics: &Metrics) -> Result<(), HareError> {
+        let mut stats = match &self.path {
+            Some(path) => read(path.parent().unwrap_or(Path::new(".")))?,
+            None => Stats::default(),
+        };
+        let now = now();
+        stats.first_start.get_or_insert(now);
+        stats.last_start = Some(now);
+        stats.starts += 1;
+        *self.uptime_before.lock().unwrap() = stats.uptime_secs;
+
+        for (handler, handler_stats) in &stats.handlers {
+            Self::expose(metrics, handler, handler_stats);
+        }
+        *self.stats.lock().unwrap() = stats;
+        self.save()
+    }
+
+    /// Records an execution of a handler.
+    pub fn record(&self, metrics: &Metrics, handler: &str, success: bool) {
+        {
+            let mut stats = self.stats.lock().unwrap();
+            let handler_stats = stats.handlers.entry(handler.to_string()).or_default();
+            handler_stats.executions += 1;
+            if success {
+                handler_stats.last_success = Some(now());
+            } else {
+                handler_stats.failures += 1;
+                handler_stats.last_failure = Some(now());
+            }
+            Self::expose(metrics, handler, handler_stats);
+        }
+        if let Err(error) = self.save() {
+            log::error!("Could not save the statistics: {}", error);
+        }
+    }
+
+    /// Time since this instance started.
+    pub fn uptime(&self) -> std::time::Duration {
+        self.started.elapsed()
+    }
+
+    /// Saves the statistics atomically, with the uptime up to now.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the statistics file cannot be written.
+    pub fn save(&self) -> Result<(), HareError> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let mut stats = self.stats.lock().unwrap();
+        stats.uptime_secs = *self.uptime_before.lock().unwrap() + self.started.elapsed().as_secs();
+
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&*stats).unwrap_or_default())?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Sets the metrics of a handler from its statistics.
+    fn expose(metrics: &Metrics, handler: &str, stats: &HandlerStats) {
+        metrics.set(&metrics::HANDLER_EXECUTIONS, &[("handler", handler)], stats.executions as f64);
+        metrics.set(&metrics::HANDLER_FAILURES, &[("handler", handler)], stats.failures as f64);
+        if let Some(last_success) = stats.last_success {
+            metrics.set(&metrics::LAST_SUCCESS, &[("handler", handler)], last_success as f64);
+        }
+    }
+}
+
+/// Current time, in seconds since epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
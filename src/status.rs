@@ -0,0 +1,107 @@
+use std::time::SystemTime;
+use lapin::message::Delivery;
+use crate::cluster;
+use crate::config::HareConfig;
+use crate::harehandler::{Execution, HareError};
+use crate::naming;
+use crate::publisher::OutgoingMessage;
+
+/// Default routing key of the status events : the name of the handler.
+pub const DEFAULT_ROUTING_KEY: &str = "{handler}";
+
+/// Longest excerpt of each output stream of the script kept in a status event, in bytes.
+const MAX_OUTPUT: usize = 4096;
+
+/// Status events of the executions, published to an exchange for the systems monitoring the deployments.
+///
+/// After each execution of a handler, an event is published to HARE_STATUS_EXCHANGE, with the routing
+/// key HARE_STATUS_ROUTING_KEY, where `{handler}` is replaced by the name of the handler and `{env}` by
+/// the environment name. The event gives the handler, the id of the message, the exit code, the duration
+/// and the end of the output of the script.
+pub struct StatusEvents {
+    pub exchange: String,   // status exchange (template)
+    routing_key: String,    // routing key of the events (template)
+}
+
+impl StatusEvents {
+
+    /// Reads the settings of the status events : HARE_STATUS_EXCHANGE and HARE_STATUS_ROUTING_KEY.
+    ///
+    /// @return the status events, None when HARE_STATUS_EXCHANGE is not set
+    ///
+    pub fn load(config: &HareConfig) -> Option<Self> {
+        Some(StatusEvents {
+            exchange: config.get("HARE_STATUS_EXCHANGE")?,
+            routing_key: config.get("HARE_STATUS_ROUTING_KEY").unwrap_or_else(|| DEFAULT_ROUTING_KEY.to_string()),
+        })
+    }
+
+    /// Renders the routing key of the events of a handler.
+    ///
+    /// @return the routing key
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the template is invalid (see `naming::render`).
+    pub fn routing_key(&self, handler: &str, environment: Option<&str>) -> Result<String, HareError> {
+        naming::render(&self.routing_key.replace("{handler}", handler), environment)
+    }
+
+    /// Builds the status event of an execution.
+    ///
+    /// # Arguments
+    ///
+    /// * `delivery` - the message of the execution
+    /// * `exchange` - the status exchange
+    /// * `routing_key` - the routing key of the event
+    /// * `instance` - the id of this instance
+    /// * `message_id` - the id of the message
+    /// * `execution` - the execution
+    ///
+    /// @return OutgoingMessage
+    ///
+    pub fn message(delivery: &Delivery, exchange: &str, routing_key: &str, instance: &str, message_id: &str, execution: &Execution) -> OutgoingMessage {
+        let (stdout, stdout_truncated) = tail(execution.stdout.as_deref());
+        let (stderr, stderr_truncated) = tail(execution.stderr.as_deref());
+        let body = serde_json::json!({
+            "handler": execution.handler,
+            "message_id": message_id,
+            "status": if execution.exit_code == Some(0) { "succeeded" } else { "failed" },
+            "exit_code": execution.exit_code,
+            "duration_ms": execution.duration.as_millis() as u64,
+            "stdout": stdout,
+            "stderr": stderr,
+            "truncated": stdout_truncated || stderr_truncated,
+            "host": cluster::hostname(),
+            "instance": instance,
+            "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        });
+
+        let mut properties = lapin::BasicProperties::default()
+            .with_content_type("application/json".into())
+            .with_delivery_mode(2);
+        if let Some(correlation_id) = delivery.properties.correlation_id() {
+            properties = properties.with_correlation_id(correlation_id.clone());
+        }
+        OutgoingMessage {
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+            body: body.to_string().into_bytes(),
+            properties,
+        }
+    }
+}
+
+/// The end of an output stream, at most MAX_OUTPUT bytes.
+///
+/// @return the end of the stream, and whether it was truncated
+///
+fn tail(output: Option<&str>) -> (Option<&str>, bool) {
+    let Some(output) = output else { return (None, false) };
+    let mut start = output.len().saturating_sub(MAX_OUTPUT);
+    // the excerpt does not start in the middle of a character
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    (Some(&output[start..]), start > 0)
+}
@@ -0,0 +1,151 @@
+use std::io::BufReader;
+use std::sync::Arc;
+use lapin::tcp::{RustlsConnector, TcpStream};
+use lapin::uri::{AMQPScheme, AMQPUri};
+use lapin::{Connection, ConnectionProperties};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use crate::config::HareConfig;
+use crate::harehandler::HareError;
+
+/// TLS settings of the broker connection, for `amqps://` URLs.
+///
+/// Without any of these settings, lapin connects with its defaults : the broker certificate is
+/// verified against the system trust store, and hare presents no client certificate.
+pub struct TlsSettings {
+    client: Arc<ClientConfig>,  // rustls configuration of the connection
+    pub ca_cert: Option<String>, // CA certificates trusted instead of the system trust store (PEM)
+    pub client_auth: bool,      // whether hare presents a client certificate (mutual TLS)
+    pub verify_peer: bool,      // whether the broker certificate is verified
+}
+
+impl TlsSettings {
+
+    /// Reads the TLS settings : HARE_AMQP_CA_CERT, HARE_AMQP_CLIENT_CERT, HARE_AMQP_CLIENT_KEY and
+    /// HARE_AMQP_TLS_VERIFY.
+    ///
+    /// @return the settings, None when none is set
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a certificate or key file cannot be read or holds no
+    /// valid PEM entry, or if only one of the client certificate and key is set.
+    pub fn load(config: &HareConfig) -> Result<Option<Self>, String> {
+        let ca_cert = config.get("HARE_AMQP_CA_CERT");
+        let client_cert = config.get("HARE_AMQP_CLIENT_CERT");
+        let client_key = config.get("HARE_AMQP_CLIENT_KEY");
+        let verify = config.get("HARE_AMQP_TLS_VERIFY");
+        if ca_cert.is_none() && client_cert.is_none() && client_key.is_none() && verify.is_none() {
+            return Ok(None);
+        }
+        let verify_peer = match verify.as_deref() {
+            None | Some("true") => true,
+            Some("false") => false,
+            Some(other) => return Err(format!("invalid HARE_AMQP_TLS_VERIFY {:?}, expected true or false", other)),
+        };
+
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|error| error.to_string())?;
+        let builder = match (verify_peer, &ca_cert) {
+            (false, _) => builder.dangerous().with_custom_certificate_verifier(Arc::new(NoVerification(provider))),
+            (true, Some(path)) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in certificates(path)? {
+                    roots.add(certificate).map_err(|error| format!("invalid CA certificate in {}: {}", path, error))?;
+                }
+                builder.with_root_certificates(roots)
+            }
+            (true, None) => {
+                let mut roots = RootCertStore::empty();
+                let (added, _) = roots.add_parsable_certificates(rustls_native_certs::load_native_certs()
+                    .map_err(|error| format!("cannot read the system trust store: {}", error))?);
+                if added == 0 {
+                    return Err("the system trust store holds no certificate, set HARE_AMQP_CA_CERT".to_string());
+                }
+                builder.with_root_certificates(roots)
+            }
+        };
+        let client = match (&client_cert, &client_key) {
+            (Some(cert), Some(key)) => builder.with_client_auth_cert(certificates(cert)?, private_key(key)?)
+                .map_err(|error| format!("invalid client certificate {} or key {}: {}", cert, key, error))?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err("HARE_AMQP_CLIENT_CERT and HARE_AMQP_CLIENT_KEY must be set together".to_string()),
+        };
+        Ok(Some(TlsSettings { client: Arc::new(client), ca_cert, client_auth: client_cert.is_some(), verify_peer }))
+    }
+}
+
+/// Connects to the broker, over TLS with the given settings for an `amqps://` URL.
+///
+/// # Errors
+///
+/// This function will return an error if the URL is invalid, or the connection fails.
+pub async fn connect(url: &str, tls: Option<&TlsSettings>) -> Result<Connection, HareError> {
+    let Some(tls) = tls else {
+        return Ok(Connection::connect(url, ConnectionProperties::default()).await?);
+    };
+    let uri: AMQPUri = url.parse().map_err(|error| HareError::ConfigError(format!("invalid HARE_AMQP_URL: {}", error)))?;
+    let connector = RustlsConnector::from(tls.client.clone());
+    // the handshake error type is lapin's
+    #[allow(clippy::result_large_err)]
+    let connect = move |uri: &AMQPUri| {
+        let address = format!("{}:{}", uri.authority.host, uri.authority.port);
+        let stream = match uri.query.connection_timeout {
+            Some(timeout) => TcpStream::connect_timeout(address, std::time::Duration::from_millis(timeout))?,
+            None => TcpStream::connect(address)?,
+        };
+        let stream = match uri.scheme {
+            AMQPScheme::AMQP => stream,
+            AMQPScheme::AMQPS => stream.into_rustls(&connector, &uri.authority.host)?,
+        };
+        stream.set_nonblocking(true)?;
+        Ok(stream)
+    };
+    Ok(Connection::connector(uri, Box::new(connect), ConnectionProperties::default()).await?)
+}
+
+/// Reads the certificates of a PEM file.
+fn certificates(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = std::fs::File::open(path).map_err(|error| format!("cannot read {}: {}", path, error))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>()
+        .map_err(|error| format!("invalid certificate in {}: {}", path, error))?;
+    if certificates.is_empty() {
+        return Err(format!("no certificate in {}", path));
+    }
+    Ok(certificates)
+}
+
+/// Reads the private key of a PEM file (PKCS#8, PKCS#1 or SEC1).
+fn private_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = std::fs::File::open(path).map_err(|error| format!("cannot read {}: {}", path, error))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|error| format!("invalid private key in {}: {}", path, error))?
+        .ok_or_else(|| format!("no private key in {}", path))
+}
+
+/// Accepts any broker certificate, with HARE_AMQP_TLS_VERIFY set to "false" ; the handshake
+/// signatures are still checked.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(&self, _: &CertificateDer<'_>, _: &[CertificateDer<'_>], _: &ServerName<'_>, _: &[u8], _: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,225 @@
+use std::time::Duration;
+use lapin::options::*;
+use lapin::types::{AMQPValue, FieldTable, LongString};
+use lapin::{Connection, ExchangeKind};
+use serde::Deserialize;
+use crate::harehandler::HareError;
+use crate::manifest;
+use crate::naming;
+
+/// Exchanges, queues and bindings declared on the broker at startup, so that hare works against a
+/// fresh broker.
+///
+/// They are read from the `[topology]` table of the configuration file ; the names are templates,
+/// where `{env}` is replaced by the environment name :
+///
+/// ```toml
+/// [[topology.exchanges]]
+/// name = "deploy-{env}"
+/// type = "topic"
+///
+/// [[topology.queues]]
+/// name = "deploy-{env}"
+/// message_ttl = "1h"
+/// dead_letter_exchange = "deploy-{env}.dlx"
+///
+/// [[topology.bindings]]
+/// queue = "deploy-{env}"
+/// exchange = "deploy-{env}"
+/// routing_key = "deploy.#"
+/// ```
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Declarations {
+    #[serde(default)]
+    pub exchanges: Vec<ExchangeDeclaration>,
+    #[serde(default)]
+    pub queues: Vec<QueueDeclaration>,
+    #[serde(default)]
+    pub bindings: Vec<BindingDeclaration>,
+}
+
+/// An exchange declared at startup.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExchangeDeclaration {
+    pub name: String,                       // exchange name (template)
+    #[serde(rename = "type", default = "default_exchange_type")]
+    pub kind: String,                       // direct, fanout, topic, headers, or a plugin type (e.g. x-delayed-message)
+    #[serde(default = "default_true")]
+    pub durable: bool,                      // whether the exchange survives a restart of the broker
+    #[serde(default)]
+    pub auto_delete: bool,                  // whether the exchange is deleted once its last binding is removed
+    #[serde(default)]
+    pub internal: bool,                     // whether the exchange only takes messages from other exchanges
+    #[serde(default)]
+    pub arguments: toml::Table,             // other arguments, e.g. alternate-exchange
+}
+
+/// A queue declared at startup.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QueueDeclaration {
+    pub name: String,                       // queue name (template)
+    #[serde(default = "default_true")]
+    pub durable: bool,                      // whether the queue survives a restart of the broker
+    #[serde(default)]
+    pub auto_delete: bool,                  // whether the queue is deleted once its last consumer is gone
+    #[serde(default, deserialize_with = "manifest::deserialize_optional_duration")]
+    pub message_ttl: Option<Duration>,      // how long a message may wait in the queue (x-message-ttl)
+    #[serde(default, deserialize_with = "manifest::deserialize_optional_duration")]
+    pub expires: Option<Duration>,          // how long the queue may stay unused before it is deleted (x-expires)
+    pub max_length: Option<i64>,            // most messages in the queue (x-max-length)
+    pub dead_letter_exchange: Option<String>, // exchange (template) of the expired and rejected messages (x-dead-letter-exchange)
+    pub dead_letter_routing_key: Option<String>, // routing key of the dead-lettered messages (x-dead-letter-routing-key)
+    pub queue_type: Option<String>,         // classic, quorum or stream (x-queue-type)
+    #[serde(default)]
+    pub arguments: toml::Table,             // other arguments, e.g. x-overflow
+}
+
+/// A binding of a queue to an exchange, declared at startup.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BindingDeclaration {
+    pub queue: String,                      // queue name (template)
+    pub exchange: String,                   // exchange name (template)
+    #[serde(default)]
+    pub routing_key: String,                // binding key (template)
+    #[serde(default)]
+    pub arguments: toml::Table,             // arguments, e.g. the headers matched by a headers exchange
+}
+
+fn default_exchange_type() -> String {
+    "topic".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Reads the `[topology]` table of the configuration file.
+///
+/// @return the declarations
+///
+/// # Errors
+///
+/// This function will return an error if the table is invalid, or an argument cannot be sent to the broker.
+pub fn parse(table: &toml::Table) -> Result<Declarations, String> {
+    let declarations = Declarations::deserialize(toml::Value::Table(table.clone()))
+        .map_err(|error| format!("invalid [topology] table: {}", error))?;
+    let arguments = declarations.exchanges.iter().map(|exchange| (&exchange.name, &exchange.arguments))
+        .chain(declarations.queues.iter().map(|queue| (&queue.name, &queue.arguments)))
+        .chain(declarations.bindings.iter().map(|binding| (&binding.queue, &binding.arguments)));
+    for (name, arguments) in arguments {
+        field_table(arguments).map_err(|error| format!("invalid [topology] arguments of {}: {}", name, error))?;
+    }
+    Ok(declarations)
+}
+
+impl Declarations {
+
+    /// Whether the queue of hare is declared here, rather than by hare with default settings.
+    pub fn declares_queue(&self, queue: &str, environment: Option<&str>) -> bool {
+        self.queues.iter().any(|declared| naming::render(&declared.name, environment).is_ok_and(|name| name == queue))
+    }
+
+    /// Declares the exchanges, then the queues, then the bindings.
+    ///
+    /// The declarations are idempotent : an exchange or a queue that already exists with the same
+    /// settings is left as is. The broker refuses to change the settings of an existing exchange or
+    /// queue : such a declaration fails, rather than being ignored.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error naming the declaration the broker refused.
+    pub async fn declare(&self, connection: &Connection, environment: Option<&str>) -> Result<(), HareError> {
+        // a refused declaration closes the channel, the channel of the consumer is left untouched
+        let channel = connection.create_channel().await?;
+        let failed = |what: String| move |error: lapin::Error| HareError::TopologyError(format!("{}: {}", what, error));
+
+        for exchange in &self.exchanges {
+            let name = naming::render(&exchange.name, environment)?;
+            let options = ExchangeDeclareOptions { durable: exchange.durable, auto_delete: exchange.auto_delete, internal: exchange.internal, ..ExchangeDeclareOptions::default() };
+            let arguments = field_table(&exchange.arguments).map_err(HareError::TopologyError)?;
+            log::info!("Declaring {} exchange {}", exchange.kind, name);
+            channel.exchange_declare(&name, exchange_kind(&exchange.kind), options, arguments).await
+                .map_err(failed(format!("cannot declare exchange {}", name)))?;
+        }
+
+        for queue in &self.queues {
+            let name = naming::render(&queue.name, environment)?;
+            let options = QueueDeclareOptions { durable: queue.durable, auto_delete: queue.auto_delete, ..QueueDeclareOptions::default() };
+            let arguments = queue.arguments(environment)?;
+            log::info!("Declaring queue {}", name);
+            channel.queue_declare(&name, options, arguments).await
+                .map_err(failed(format!("cannot declare queue {}", name)))?;
+        }
+
+        for binding in &self.bindings {
+            let (queue, exchange) = (naming::render(&binding.queue, environment)?, naming::render(&binding.exchange, environment)?);
+            let routing_key = naming::render(&binding.routing_key, environment)?;
+            let arguments = field_table(&binding.arguments).map_err(HareError::TopologyError)?;
+            log::info!("Binding queue {} to exchange {} with key {}", queue, exchange, routing_key);
+            channel.queue_bind(&queue, &exchange, &routing_key, QueueBindOptions::default(), arguments).await
+                .map_err(failed(format!("cannot bind queue {} to exchange {}", queue, exchange)))?;
+        }
+
+        let _ = channel.close(200, "topology declared").await;
+        Ok(())
+    }
+}
+
+impl QueueDeclaration {
+
+    /// The arguments of the queue : its settings, then the other arguments.
+    fn arguments(&self, environment: Option<&str>) -> Result<FieldTable, HareError> {
+        let mut arguments = field_table(&self.arguments).map_err(HareError::TopologyError)?;
+        let text = |value: String| AMQPValue::LongString(LongString::from(value));
+        if let Some(ttl) = self.message_ttl {
+            arguments.insert("x-message-ttl".into(), AMQPValue::LongLongInt(ttl.as_millis() as i64));
+        }
+        if let Some(expires) = self.expires {
+            arguments.insert("x-expires".into(), AMQPValue::LongLongInt(expires.as_millis() as i64));
+        }
+        if let Some(max_length) = self.max_length {
+            arguments.insert("x-max-length".into(), AMQPValue::LongLongInt(max_length));
+        }
+        if let Some(exchange) = &self.dead_letter_exchange {
+            arguments.insert("x-dead-letter-exchange".into(), text(naming::render(exchange, environment)?));
+        }
+        if let Some(routing_key) = &self.dead_letter_routing_key {
+            arguments.insert("x-dead-letter-routing-key".into(), text(naming::render(routing_key, environment)?));
+        }
+        if let Some(queue_type) = &self.queue_type {
+            arguments.insert("x-queue-type".into(), text(queue_type.clone()));
+        }
+        Ok(arguments)
+    }
+}
+
+/// The kind of an exchange, from its type name.
+fn exchange_kind(kind: &str) -> ExchangeKind {
+    match kind {
+        "direct" => ExchangeKind::Direct,
+        "fanout" => ExchangeKind::Fanout,
+        "topic" => ExchangeKind::Topic,
+        "headers" => ExchangeKind::Headers,
+        kind => ExchangeKind::Custom(kind.to_string()),
+    }
+}
+
+/// Converts the arguments of a declaration, strings, integers, floats and booleans.
+fn field_table(table: &toml::Table) -> Result<FieldTable, String> {
+    let mut arguments = FieldTable::default();
+    for (key, value) in table {
+        let value = match value {
+            toml::Value::String(text) => AMQPValue::LongString(LongString::from(text.as_str())),
+            toml::Value::Integer(number) => AMQPValue::LongLongInt(*number),
+            toml::Value::Float(number) => AMQPValue::Double(*number),
+            toml::Value::Boolean(flag) => AMQPValue::Boolean(*flag),
+            _ => return Err(format!("argument {} must be a string, a number or a boolean", key)),
+        };
+        arguments.insert(key.as_str().into(), value);
+    }
+    Ok(arguments)
+}
@@ -0,0 +1,51 @@
+use std::time::SystemTime;
+use lapin::BasicProperties;
+use lapin::types::{AMQPValue, FieldArray, FieldTable, LongString};
+
+/// Header holding the trace of a message : one entry per hare instance that published it again.
+pub const TRACE_HEADER: &str = "x-hare-trace";
+
+/// Maximum number of entries of a trace, the oldest ones are dropped past it.
+const MAX_ENTRIES: usize = 32;
+
+/// Appends an entry to the trace of a message that hare publishes again (forwarded to a partition,
+/// dead-lettered...).
+///
+/// The trace is an array of tables, in the order of the hops, each with the instance that published
+/// the message, the time, the handler of the message and what was done with it :
+/// `[{"instance": "web-01-4242", "timestamp": "2024-12-05T10:12:01Z", "handler": "deploy", "outcome": "forwarded"}]`.
+/// A trace header that is not an array is replaced.
+///
+/// # Arguments
+///
+/// * `properties` - the properties of the message, with its headers
+/// * `instance` - the id of this instance
+/// * `handler` - the handler of the message, "unknown" if it has none
+/// * `outcome` - what was done with the message, e.g. "forwarded"
+///
+/// @return the properties, with the entry appended to the trace header
+///
+pub fn append(properties: BasicProperties, instance: &str, handler: &str, outcome: &str) -> BasicProperties {
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    let mut entries = match headers.inner().get(TRACE_HEADER) {
+        Some(AMQPValue::FieldArray(entries)) => entries.as_slice().to_vec(),
+        _ => Vec::new(),
+    };
+
+    let mut entry = FieldTable::default();
+    entry.insert("instance".into(), string(instance));
+    entry.insert("timestamp".into(), string(&humantime::format_rfc3339_seconds(SystemTime::now()).to_string()));
+    entry.insert("handler".into(), string(handler));
+    entry.insert("outcome".into(), string(outcome));
+    entries.push(AMQPValue::FieldTable(entry));
+    if entries.len() > MAX_ENTRIES {
+        entries.drain(..entries.len() - MAX_ENTRIES);
+    }
+
+    headers.insert(TRACE_HEADER.into(), AMQPValue::FieldArray(FieldArray::from(entries)));
+    properties.with_headers(headers)
+}
+
+fn string(value: &str) -> AMQPValue {
+    AMQPValue::LongString(LongString::from(value))
+}
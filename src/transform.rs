@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ring::aead;
+use serde::{Deserialize, Deserializer};
+use crate::expr::{Context, Expression};
+
+/// Largest body a transform may produce, so that a small compressed body cannot exhaust the memory.
+const MAX_SIZE: usize = 64 * 1024 * 1024;
+
+/// Size of the nonce preceding the encrypted body.
+const NONCE_SIZE: usize = 12;
+
+/// A step of the body transformation pipeline of a handler, declared in its manifest :
+///
+/// ```toml
+/// [[transform]]
+/// decompress = "gzip"
+/// [[transform]]
+/// decrypt = { key_file = "/etc/hare/keys/deploy.key" }
+/// [[transform]]
+/// project = '{"version": body.release.version, "hosts": body.hosts}'
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Transform {
+    Decompress(Compression),    // decompresses the body
+    Decode(Encoding),           // decodes a text encoded body
+    Decrypt(Decryption),        // decrypts the body
+    Project(#[serde(deserialize_with = "deserialize_expression")] Expression), // replaces a JSON body with the value of an expression
+    Encode(Encoding),           // encodes the body as text
+    Compress(Compression),      // compresses the body
+}
+
+/// Compression format of a body.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// Text encoding of a binary body.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Encoding {
+    Base64,
+    Hex,
+}
+
+/// Decryption of a body encrypted with AES-256-GCM : the 12 bytes nonce, then the ciphertext and its tag.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Decryption {
+    pub key_file: String,       // file holding the 32 bytes key, hex encoded
+}
+
+/// Runs the transformation pipeline of a handler on a message body.
+///
+/// The steps run in order, each on the output of the previous one. A projection evaluates its
+/// expression against the body (decoded as JSON, or as a string), the headers and the content type
+/// of the message ; its output is JSON, so the content type becomes `application/json`. An encoded
+/// body becomes `text/plain`.
+///
+/// # Arguments
+///
+/// * `transforms` - the steps of the pipeline
+/// * `body` - the message body
+/// * `headers` - the message headers, for the projections
+/// * `content_type` - the content type of the message
+///
+/// @return the transformed body, and its content type
+///
+/// # Errors
+///
+/// This function will return an error, with the failed step, if a body cannot be decompressed,
+/// decoded or decrypted, a projection fails, or a body grows past 64 MiB.
+pub fn apply(transforms: &[Transform], body: &[u8], headers: &HashMap<String, String>, content_type: Option<String>) -> Result<(Vec<u8>, Option<String>), String> {
+    let mut body = body.to_vec();
+    let mut content_type = content_type;
+    for (step, transform) in transforms.iter().enumerate() {
+        let failed = |error: String| format!("transform {} ({}) failed: {}", step + 1, transform.name(), error);
+        body = match transform {
+            Transform::Decompress(Compression::Gzip) => limited(flate2::read::MultiGzDecoder::new(body.as_slice())).map_err(failed)?,
+            Transform::Decompress(Compression::Zstd) => {
+                let decoder = zstd::stream::read::Decoder::new(body.as_slice()).map_err(|error| failed(error.to_string()))?;
+                limited(decoder).map_err(failed)?
+            }
+            Transform::Decode(Encoding::Base64) => {
+                let text: Vec<u8> = body.iter().copied().filter(|c| !c.is_ascii_whitespace()).collect();
+                BASE64.decode(text).map_err(|error| failed(error.to_string()))?
+            }
+            Transform::Decode(Encoding::Hex) => hex::decode(String::from_utf8_lossy(&body).trim()).map_err(|error| failed(error.to_string()))?,
+            Transform::Decrypt(decryption) => decrypt(decryption, body).map_err(failed)?,
+            Transform::Project(expression) => {
+                let context = Context { headers, body: &body, content_type: content_type.as_deref(), exchange: "", routing_key: "" };
+                let value = expression.evaluate(&context).map_err(failed)?;
+                content_type = Some("application/json".to_string());
+                value.to_string().into_bytes()
+            }
+            Transform::Encode(encoding) => {
+                content_type = Some("text/plain".to_string());
+                match encoding {
+                    Encoding::Base64 => BASE64.encode(&body).into_bytes(),
+                    Encoding::Hex => hex::encode(&body).into_bytes(),
+                }
+            }
+            Transform::Compress(Compression::Gzip) => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&body).and_then(|_| encoder.finish()).map_err(|error| failed(error.to_string()))?
+            }
+            Transform::Compress(Compression::Zstd) => zstd::encode_all(body.as_slice(), 0).map_err(|error| failed(error.to_string()))?,
+        };
+        if body.len() > MAX_SIZE {
+            return Err(failed(format!("the body is larger than {} bytes", MAX_SIZE)));
+        }
+    }
+    Ok((body, content_type))
+}
+
+impl Transform {
+
+    /// The name of the step, as written in the manifest.
+    fn name(&self) -> &'static str {
+        match self {
+            Transform::Decompress(_) => "decompress",
+            Transform::Decode(_) => "decode",
+            Transform::Decrypt(_) => "decrypt",
+            Transform::Project(_) => "project",
+            Transform::Encode(_) => "encode",
+            Transform::Compress(_) => "compress",
+        }
+    }
+}
+
+/// Reads a decompressed body, up to the maximum size.
+fn limited(reader: impl Read) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+    reader.take(MAX_SIZE as u64 + 1).read_to_end(&mut body).map_err(|error| error.to_string())?;
+    if body.len() > MAX_SIZE {
+        return Err(format!("the decompressed body is larger than {} bytes", MAX_SIZE));
+    }
+    Ok(body)
+}
+
+/// Decrypts a body with the key of a key file.
+fn decrypt(decryption: &Decryption, mut body: Vec<u8>) -> Result<Vec<u8>, String> {
+    let key = std::fs::read_to_string(&decryption.key_file)
+        .map_err(|error| format!("cannot read the key file {}: {}", decryption.key_file, error))?;
+    let key = hex::decode(key.trim()).map_err(|_| format!("the key file {} is not hex encoded", decryption.key_file))?;
+    let key = aead::UnboundKey::new(&aead::AES_256_GCM, &key)
+        .map_err(|_| format!("the key of {} is not 32 bytes long", decryption.key_file))?;
+
+    if body.len() < NONCE_SIZE {
+        return Err("the body is shorter than the nonce".to_string());
+    }
+    let ciphertext = body.split_off(NONCE_SIZE);
+    let nonce = aead::Nonce::try_assume_unique_for_key(&body).map_err(|_| "invalid nonce".to_string())?;
+    let mut plaintext = ciphertext;
+    let length = aead::LessSafeKey::new(key).open_in_place(nonce, aead::Aad::empty(), &mut plaintext)
+        .map_err(|_| "the body cannot be decrypted with this key".to_string())?.len();
+    plaintext.truncate(length);
+    Ok(plaintext)
+}
+
+/// Deserializes an expression, checking its syntax when the manifest is loaded.
+fn deserialize_expression<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Expression, D::Error> {
+    let source = String::deserialize(deserializer)?;
+    Expression::parse(&source).map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,153 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Deserializer};
+
+/// Header asking to run a handler outside of its execution windows, its value gives the reason.
+pub const OVERRIDE_HEADER: &str = "x-hare-window-override";
+
+/// Longest delay before a deferred message of a closed handler is checked again.
+pub const RECHECK_DELAY: Duration = Duration::from_secs(60);
+
+/// Names of the days, from monday.
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+const DAY: u64 = 24 * 3600;
+const WEEK: u64 = 7 * DAY;
+
+/// Execution windows of a handler : when its messages may run, in the local time of the host.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WindowPolicy {
+    #[serde(deserialize_with = "deserialize_windows")]
+    pub allow: Vec<Window>,             // windows, e.g. "mon-fri 08:00-18:00"
+    #[serde(default)]
+    pub override_users: Vec<String>,    // publishers (AMQP user id) allowed to run the handler outside of its windows
+}
+
+/// A weekly window, e.g. "mon-fri 08:00-18:00", "sat,sun 10:00-12:00", or "22:00-06:00" for every night.
+///
+/// A window ending before its start ends the next day : "fri 22:00-02:00" closes on saturday at 2:00.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    days: [bool; 7],    // days the window opens, from monday
+    start: u64,         // opening time, in seconds since midnight
+    end: u64,           // closing time, in seconds since midnight, up to 24:00
+}
+
+impl Window {
+
+    /// Parses a window : optional days (names, ranges and lists, every day by default), and the hours.
+    ///
+    /// @return the window
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a day or a time is invalid, or the window is empty.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let (days, hours) = match value.rsplit_once(char::is_whitespace) {
+            Some((days, hours)) => (parse_days(days.trim())?, hours),
+            None => ([true; 7], value),
+        };
+        let (start, end) = hours.split_once('-').ok_or_else(|| format!("invalid window {:?}, expected e.g. \"mon-fri 08:00-18:00\"", value))?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end || start == DAY {
+            return Err(format!("empty window {:?}", value));
+        }
+        Ok(Window { days, start, end })
+    }
+
+    /// The intervals of the window over the week, in seconds since monday midnight, the last one may end the next week.
+    fn intervals(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let length = match self.end > self.start {
+            true => self.end - self.start,
+            false => DAY - self.start + self.end,
+        };
+        (0..7u64).filter(|day| self.days[*day as usize]).map(move |day| (day * DAY + self.start, day * DAY + self.start + length))
+    }
+}
+
+impl WindowPolicy {
+
+    /// Checks whether the handler may run at a time of the week.
+    ///
+    /// @return how long until a window opens, None if a window is open
+    ///
+    pub fn closed_for(&self, now: u64) -> Option<Duration> {
+        let mut wait = u64::MAX;
+        for (start, end) in self.allow.iter().flat_map(Window::intervals) {
+            if (start..end).contains(&now) || (start..end).contains(&(now + WEEK)) {
+                return None;
+            }
+            wait = wait.min((start + WEEK - now) % WEEK);
+        }
+        Some(Duration::from_secs(wait))
+    }
+
+    /// Checks whether a publisher may run the handler outside of its windows.
+    pub fn may_override(&self, user_id: Option<&str>) -> bool {
+        user_id.is_some_and(|user_id| self.override_users.iter().any(|user| user == user_id))
+    }
+}
+
+/// The current time of the week, in the local time of the host (TZ).
+///
+/// @return the seconds since monday midnight
+///
+pub fn local_now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default() as libc::time_t;
+    // Safety: tm is a plain C struct, filled by localtime_r
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // Safety: the pointers are valid for the duration of the call
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return 0;
+    }
+    // tm_wday counts from sunday
+    let day = (tm.tm_wday as u64 + 6) % 7;
+    day * DAY + tm.tm_hour as u64 * 3600 + tm.tm_min as u64 * 60 + tm.tm_sec.min(59) as u64
+}
+
+/// Parses the days of a window, e.g. "mon-fri" or "mon,wed,sat-sun".
+fn parse_days(value: &str) -> Result<[bool; 7], String> {
+    let day = |name: &str| DAYS.iter().position(|day| name.trim().eq_ignore_ascii_case(day))
+        .ok_or_else(|| format!("invalid day {:?}, expected {}", name.trim(), DAYS.join(", ")));
+    let mut days = [false; 7];
+    for part in value.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(part)?, day(part)?),
+        };
+        // a range may go over the week end, e.g. "fri-mon"
+        let mut current = first;
+        loop {
+            days[current] = true;
+            if current == last {
+                break;
+            }
+            current = (current + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+/// Parses a time of the day, "HH:MM", up to "24:00".
+///
+/// @return the seconds since midnight
+///
+fn parse_time(value: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid time {:?}, expected HH:MM", value);
+    let (hours, minutes) = value.trim().split_once(':').ok_or_else(invalid)?;
+    let (hours, minutes): (u64, u64) = (hours.parse().map_err(|_| invalid())?, minutes.parse().map_err(|_| invalid())?);
+    match (hours, minutes) {
+        (0..=23, 0..=59) | (24, 0) => Ok(hours * 3600 + minutes * 60),
+        _ => Err(invalid()),
+    }
+}
+
+/// Deserializes the windows of a handler, at least one.
+fn deserialize_windows<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Window>, D::Error> {
+    let windows = Vec::<String>::deserialize(deserializer)?;
+    if windows.is_empty() {
+        return Err(serde::de::Error::custom("no window, the handler would never run"));
+    }
+    windows.iter().map(|window| Window::parse(window).map_err(serde::de::Error::custom)).collect()
+}
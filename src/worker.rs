@@ -0,0 +1,113 @@
+use std::future::Future;
+use tokio::task::JoinSet;
+
+/// Default number of messages handled concurrently.
+pub const DEFAULT_CONCURRENCY: usize = 1;
+
+/// A bounded pool of concurrent jobs, fed by the consumer loop.
+///
+/// Each job (the handling of a delivery, until its outcome) runs in its own task, so that a job
+/// blocking its thread does not hold up the other jobs, nor the consumer loop. The consumer loop
+/// takes a new delivery only while the pool is not full, and completes the deliveries
+/// (acknowledgement, result message...) as their jobs finish, in any order.
+pub struct WorkerPool<T> {
+    capacity: usize,            // maximum number of concurrent jobs
+    running: JoinSet<T>,        // running jobs
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+
+    /// Creates an empty pool.
+    ///
+    /// @return WorkerPool
+    ///
+    pub fn new(capacity: usize) -> Self {
+        WorkerPool { capacity: capacity.max(1), running: JoinSet::new() }
+    }
+
+    /// Number of running jobs.
+    pub fn len(&self) -> usize {
+        self.running.len()
+    }
+
+    /// Whether no job is running.
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    /// Whether the pool runs as many jobs as it may.
+    pub fn is_full(&self) -> bool {
+        self.running.len() >= self.capacity
+    }
+
+    /// Starts a job.
+    pub fn push(&mut self, job: impl Future<Output = T> + Send + 'static) {
+        self.running.spawn(job);
+    }
+
+    /// Waits for the next job to finish.
+    ///
+    /// A job that panicked panics the caller, as the jobs catch the panics of the handlers.
+    ///
+    /// @return the output of the job, None if no job is running
+    ///
+    pub async fn next(&mut self) -> Option<T> {
+        match self.running.join_next().await? {
+            Ok(output) => Some(output),
+            Err(error) => std::panic::resume_unwind(error.into_panic()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn the_pool_is_bounded() {
+        let mut pool = WorkerPool::new(2);
+        assert!(pool.is_empty() && !pool.is_full());
+        pool.push(async { 1 });
+        assert!(!pool.is_full());
+        pool.push(async { 2 });
+        assert!(pool.is_full());
+        assert_eq!(pool.len(), 2);
+
+        pool.next().await.unwrap();
+        assert!(!pool.is_full());
+        assert_eq!(WorkerPool::<()>::new(0).capacity, 1);
+    }
+
+    #[tokio::test]
+    async fn the_pool_is_drained_in_the_order_the_jobs_finish() {
+        let mut pool = WorkerPool::new(3);
+        for (job, delay) in [(1, 200), (2, 0), (3, 100)] {
+            pool.push(async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                job
+            });
+        }
+        let mut finished = vec![];
+        while let Some(job) = pool.next().await {
+            finished.push(job);
+        }
+        assert_eq!(finished, [2, 3, 1]);
+        assert!(pool.is_empty());
+        assert_eq!(pool.next().await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn a_blocking_job_does_not_hold_up_the_others() {
+        let mut pool = WorkerPool::new(2);
+        let started = Instant::now();
+        pool.push(async {
+            std::thread::sleep(Duration::from_millis(500));
+            "blocking"
+        });
+        pool.push(async { "quick" });
+        assert_eq!(pool.next().await, Some("quick"));
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(pool.next().await, Some("blocking"));
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+
+/// Maximum nesting depth of an XML document.
+const MAX_DEPTH: usize = 128;
+
+/// Prefix of the variables set by the XPath extraction rules.
+pub const XML_PREFIX: &str = "HARE_XML_";
+
+/// An element of an XML document.
+///
+/// This is a minimal XML parser, enough for the payloads published by enterprise systems :
+/// elements, attributes, text, CDATA sections and the predefined and numeric entities. Comments
+/// and processing instructions are skipped, and documents with an internal DTD are refused.
+/// Namespace prefixes are kept as part of the names, e.g. `ns:release`.
+#[derive(Debug, Default)]
+pub struct Element {
+    name: String,                       // name of the element, with its prefix
+    attributes: Vec<(String, String)>,  // attributes, in order
+    children: Vec<Node>,                // child elements and text
+}
+
+/// A child of an element.
+#[derive(Debug)]
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+
+    /// The child elements.
+    fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    /// The text directly inside the element.
+    fn text(&self) -> String {
+        self.children.iter()
+            .filter_map(|node| match node {
+                Node::Text(text) => Some(text.as_str()),
+                Node::Element(_) => None,
+            })
+            .collect()
+    }
+
+    /// The text inside the element and its descendants, in document order.
+    fn string_value(&self) -> String {
+        self.children.iter()
+            .map(|node| match node {
+                Node::Text(text) => text.clone(),
+                Node::Element(element) => element.string_value(),
+            })
+            .collect()
+    }
+
+    /// The value of an attribute.
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Parses an XML document.
+///
+/// @return the root element
+///
+/// # Errors
+///
+/// This function will return an error if the document is not well-formed, is not UTF-8, nests
+/// elements deeper than 128 levels, or has an internal DTD.
+pub fn parse(body: &[u8]) -> Result<Element, String> {
+    let input = std::str::from_utf8(body).map_err(|_| "the document is not UTF-8".to_string())?;
+    let mut parser = Parser { input: input.trim_start_matches('\u{feff}'), position: 0 };
+    parser.skip_misc()?;
+    let root = parser.element(0)?;
+    parser.skip_misc()?;
+    if !parser.rest().is_empty() {
+        return Err(parser.error("content after the root element"));
+    }
+    Ok(root)
+}
+
+/// Reads an XML document.
+struct Parser<'a> {
+    input: &'a str,     // whole document
+    position: usize,    // offset of the next character to read
+}
+
+impl<'a> Parser<'a> {
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.position..]
+    }
+
+    fn error(&self, reason: &str) -> String {
+        format!("{} at offset {}", reason, self.position)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Skips up to and including a delimiter.
+    fn skip_past(&mut self, delimiter: &str) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let end = rest.find(delimiter).ok_or_else(|| self.error(&format!("missing \"{}\"", delimiter)))?;
+        self.position += end + delimiter.len();
+        Ok(&rest[..end])
+    }
+
+    /// Skips the whitespace, comments, processing instructions and document type outside the root element.
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with("<!DOCTYPE") {
+                if self.skip_past(">")?.contains('[') {
+                    return Err("documents with an internal DTD are not supported".to_string());
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reads a name, up to a whitespace, `/`, `=` or `>`.
+    fn name(&mut self) -> Result<String, String> {
+        let rest = self.rest();
+        let end = rest.find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '=' | '<')).unwrap_or(rest.len());
+        if end == 0 {
+            return Err(self.error("missing name"));
+        }
+        self.position += end;
+        Ok(rest[..end].to_string())
+    }
+
+    /// Reads an element, its start tag starting at the current position.
+    fn element(&mut self, depth: usize) -> Result<Element, String> {
+        if depth >= MAX_DEPTH {
+            return Err(self.error("elements nested too deeply"));
+        }
+        if !self.rest().starts_with('<') {
+            return Err(self.error("missing element"));
+        }
+        self.position += 1;
+        let mut element = Element { name: self.name()?, ..Element::default() };
+
+        // attributes
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.position += 2;
+                return Ok(element);
+            }
+            if self.rest().starts_with('>') {
+                self.position += 1;
+                break;
+            }
+            let name = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(self.error("missing attribute value"));
+            }
+            self.position += 1;
+            self.skip_whitespace();
+            let quote = match self.rest().chars().next() {
+                Some(quote @ ('"' | '\'')) => quote,
+                _ => return Err(self.error("unquoted attribute value")),
+            };
+            self.position += 1;
+            let value = self.skip_past(&quote.to_string())?.to_string();
+            element.attributes.push((name, decode(&value)?));
+        }
+
+        // content, up to the end tag
+        loop {
+            let rest = self.rest();
+            if rest.starts_with("</") {
+                self.position += 2;
+                let name = self.name()?;
+                if name != element.name {
+                    return Err(self.error(&format!("end tag {} does not match {}", name, element.name)));
+                }
+                self.skip_whitespace();
+                if !self.rest().starts_with('>') {
+                    return Err(self.error("malformed end tag"));
+                }
+                self.position += 1;
+                return Ok(element);
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<![CDATA[") {
+                self.position += "<![CDATA[".len();
+                let text = self.skip_past("]]>")?.to_string();
+                element.children.push(Node::Text(text));
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with('<') {
+                let child = self.element(depth + 1)?;
+                element.children.push(Node::Element(child));
+            } else if rest.is_empty() {
+                return Err(self.error(&format!("missing end tag of {}", element.name)));
+            } else {
+                let end = rest.find('<').unwrap_or(rest.len());
+                let text = decode(&rest[..end]).map_err(|error| self.error(&error))?;
+                self.position += end;
+                element.children.push(Node::Text(text));
+            }
+        }
+    }
+}
+
+/// Replaces the entity references of a text.
+fn decode(text: &str) -> Result<String, String> {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let end = rest[start..].find(';').ok_or("unterminated entity reference")? + start;
+        let entity = &rest[start + 1..end];
+        let character = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity.strip_prefix('#').and_then(|decimal| decimal.parse().ok()).and_then(char::from_u32),
+            },
+        };
+        decoded.push(character.ok_or_else(|| format!("unknown entity &{};", entity))?);
+        rest = &rest[end + 1..];
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+/// An XPath expression of an extraction rule.
+///
+/// Only absolute location paths are supported : steps separated by `/` (child) or `//`
+/// (descendant), each a name or `*`, optionally followed by a predicate `[n]`, `[@name]` or
+/// `[@name='value']`. The last step may select the text of the element (`text()`) or an
+/// attribute (`@name`), otherwise the whole text content of the element is selected.
+///
+/// For instance : `/release/version/text()`, `//artifact[@type='war']/@url`.
+#[derive(Debug, Clone)]
+pub struct XPath {
+    steps: Vec<Step>,   // location steps
+    target: Target,     // what is selected from the matching element
+}
+
+/// A location step of an XPath expression.
+#[derive(Debug, Clone)]
+struct Step {
+    descendant: bool,               // `//` : any descendant, instead of a child
+    name: String,                   // element name, `*` for any element
+    predicate: Option<Predicate>,   // filter of the matching elements
+}
+
+/// A predicate of a location step.
+#[derive(Debug, Clone)]
+enum Predicate {
+    Position(usize),                        // `[n]` : the n-th matching child, from 1
+    Attribute(String, Option<String>),      // `[@name]` or `[@name='value']`
+}
+
+/// What an XPath expression selects.
+#[derive(Debug, Clone)]
+enum Target {
+    Content,            // the text content of the element
+    Text,               // `text()` : the text directly inside the element
+    Attribute(String),  // `@name` : the value of an attribute
+}
+
+impl XPath {
+
+    /// Parses an XPath expression.
+    ///
+    /// @return XPath
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the expression is not an absolute location path
+    /// of the supported subset.
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("invalid XPath \"{}\": {}", expression, reason);
+        if !expression.starts_with('/') {
+            return Err(invalid("only absolute paths are supported"));
+        }
+
+        let mut steps = Vec::new();
+        let mut target = Target::Content;
+        let mut rest = expression;
+        while !rest.is_empty() {
+            let descendant = rest.starts_with("//");
+            rest = rest.strip_prefix("//").or_else(|| rest.strip_prefix('/')).ok_or_else(|| invalid("missing /"))?;
+
+            // the step ends at the next / outside of a predicate
+            let mut in_predicate = false;
+            let end = rest.find(|c: char| {
+                match c {
+                    '[' => in_predicate = true,
+                    ']' => in_predicate = false,
+                    _ => {}
+                }
+                c == '/' && !in_predicate
+            }).unwrap_or(rest.len());
+            let step = &rest[..end];
+            rest = &rest[end..];
+
+            if step == "text()" || step.starts_with('@') {
+                if !rest.is_empty() || descendant || steps.is_empty() {
+                    return Err(invalid("text() and attributes can only end a path"));
+                }
+                target = match step.strip_prefix('@') {
+                    Some(name) if !name.is_empty() => Target::Attribute(name.to_string()),
+                    Some(_) => return Err(invalid("missing attribute name")),
+                    None => Target::Text,
+                };
+                break;
+            }
+
+            let (name, predicate) = match step.split_once('[') {
+                Some((name, predicate)) => {
+                    let predicate = predicate.strip_suffix(']').ok_or_else(|| invalid("unterminated predicate"))?;
+                    (name, Some(Self::predicate(predicate).ok_or_else(|| invalid("unsupported predicate"))?))
+                }
+                None => (step, None),
+            };
+            if name.is_empty() || name.contains(|c: char| c.is_whitespace() || matches!(c, '[' | ']' | '@' | '(' | ')')) {
+                return Err(invalid("invalid step"));
+            }
+            steps.push(Step { descendant, name: name.to_string(), predicate });
+        }
+        if steps.is_empty() {
+            return Err(invalid("empty path"));
+        }
+        Ok(XPath { steps, target })
+    }
+
+    /// Parses the content of a predicate.
+    fn predicate(predicate: &str) -> Option<Predicate> {
+        let predicate = predicate.trim();
+        if let Ok(position) = predicate.parse::<usize>() {
+            return (position > 0).then_some(Predicate::Position(position));
+        }
+        let attribute = predicate.strip_prefix('@')?;
+        match attribute.split_once('=') {
+            Some((name, value)) => {
+                let value = value.trim();
+                let unquoted = value.strip_prefix('\'').and_then(|value| value.strip_suffix('\''))
+                    .or_else(|| value.strip_prefix('"').and_then(|value| value.strip_suffix('"')))?;
+                Some(Predicate::Attribute(name.trim().to_string(), Some(unquoted.to_string())))
+            }
+            None => Some(Predicate::Attribute(attribute.to_string(), None)),
+        }
+    }
+
+    /// Evaluates the expression on a document.
+    ///
+    /// @return the value selected from the first matching element, None if nothing matches
+    ///
+    pub fn evaluate(&self, root: &Element) -> Option<String> {
+        // the document node, parent of the root element
+        let document = Element::default();
+        let mut contexts: Vec<&Element> = vec![];
+        for (index, step) in self.steps.iter().enumerate() {
+            let parents: Vec<&Element> = match (index, step.descendant) {
+                (0, false) => vec![&document],
+                (0, true) => [&document, root].into_iter().chain(descendants(root)).collect(),
+                (_, false) => contexts,
+                (_, true) => {
+                    let mut parents: Vec<&Element> = vec![];
+                    for context in &contexts {
+                        for element in std::iter::once(*context).chain(descendants(context)) {
+                            if !parents.iter().any(|parent| std::ptr::eq(*parent, element)) {
+                                parents.push(element);
+                            }
+                        }
+                    }
+                    parents
+                }
+            };
+
+            contexts = vec![];
+            for parent in parents {
+                let children: Vec<&Element> = match std::ptr::eq(parent, &document) {
+                    true => vec![root],
+                    false => parent.elements().collect(),
+                };
+                let matching = children.into_iter().filter(|child| step.name == "*" || child.name == step.name);
+                match &step.predicate {
+                    Some(Predicate::Position(position)) => contexts.extend(matching.skip(position - 1).take(1)),
+                    Some(Predicate::Attribute(name, value)) => contexts.extend(matching.filter(|child| {
+                        child.attribute(name).is_some_and(|actual| value.as_ref().is_none_or(|value| actual == value))
+                    })),
+                    None => contexts.extend(matching),
+                }
+            }
+        }
+
+        // the first match is the first in document order, not in the order of the parents
+        let order: HashMap<*const Element, usize> = std::iter::once(root).chain(descendants(root))
+            .enumerate()
+            .map(|(position, element)| (element as *const Element, position))
+            .collect();
+        contexts.sort_by_key(|element| order.get(&(*element as *const Element)).copied());
+        contexts.into_iter().find_map(|element| match &self.target {
+            Target::Content => Some(element.string_value()),
+            Target::Text => Some(element.text()),
+            Target::Attribute(name) => element.attribute(name).map(str::to_string),
+        })
+    }
+}
+
+/// The descendant elements of an element, in document order.
+fn descendants(element: &Element) -> Vec<&Element> {
+    let mut found = vec![];
+    for child in element.elements() {
+        found.push(child);
+        found.extend(descendants(child));
+    }
+    found
+}
+
+/// Checks the name of the variable set by an extraction rule.
+///
+/// # Errors
+///
+/// This function will return an error if the name does not start with HARE_XML_, or is not made
+/// of uppercase letters, digits and `_`.
+pub fn check_variable(name: &str) -> Result<(), String> {
+    let valid = name.strip_prefix(XML_PREFIX)
+        .is_some_and(|suffix| !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'));
+    match valid {
+        true => Ok(()),
+        false => Err(format!("invalid variable name {}, expected {}<NAME> in uppercase", name, XML_PREFIX)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluates an XPath expression on a document.
+    fn select(document: &str, expression: &str) -> Option<String> {
+        XPath::parse(expression).unwrap().evaluate(&parse(document.as_bytes()).unwrap())
+    }
+
+    const RELEASE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- published by the build server -->
+<!DOCTYPE release>
+<ns:release xmlns:ns="urn:example:release" id="42">
+  <version>1.2.3</version>
+  <notes><![CDATA[fixes <b>login</b> & more]]></notes>
+  <artifacts>
+    <artifact type="jar" url="https://repo/app.jar"/>
+    <artifact type='war' url="https://repo/app.war?a=1&amp;b=2"/>
+  </artifacts>
+  <owner>ops <team>web</team> &lt;oncall&gt; &#233;&#xE9;</owner>
+</ns:release>
+"#;
+
+    #[test]
+    fn entities_are_decoded() {
+        assert_eq!(select(RELEASE, "/ns:release/owner/text()").as_deref(), Some("ops  <oncall> éé"));
+        assert_eq!(select(RELEASE, "/ns:release/owner").as_deref(), Some("ops web <oncall> éé"));
+        assert_eq!(select("<a>&quot;&apos;&amp;</a>", "/a").as_deref(), Some("\"'&"));
+        assert!(parse(b"<a>&nbsp;</a>").unwrap_err().contains("unknown entity"));
+        assert!(parse(b"<a>&amp</a>").unwrap_err().contains("unterminated entity"));
+    }
+
+    #[test]
+    fn cdata_is_kept_as_text() {
+        assert_eq!(select(RELEASE, "/ns:release/notes").as_deref(), Some("fixes <b>login</b> & more"));
+    }
+
+    #[test]
+    fn attributes_are_selected_and_filtered() {
+        assert_eq!(select(RELEASE, "/ns:release/@id").as_deref(), Some("42"));
+        assert_eq!(select(RELEASE, "//artifact[@type='war']/@url").as_deref(), Some("https://repo/app.war?a=1&b=2"));
+        assert_eq!(select(RELEASE, "//artifact[@type=\"jar\"]/@url").as_deref(), Some("https://repo/app.jar"));
+        assert_eq!(select(RELEASE, "//artifact[@url]/@type").as_deref(), Some("jar"));
+        assert_eq!(select(RELEASE, "//artifact[@type='ear']/@url"), None);
+        assert_eq!(select(RELEASE, "//artifact/@missing"), None);
+    }
+
+    #[test]
+    fn namespace_prefixes_are_part_of_the_names() {
+        assert_eq!(select(RELEASE, "/ns:release/version").as_deref(), Some("1.2.3"));
+        assert_eq!(select(RELEASE, "/release/version"), None);
+        assert_eq!(select(RELEASE, "/ns:release/@xmlns:ns").as_deref(), Some("urn:example:release"));
+    }
+
+    #[test]
+    fn paths_select_the_first_match() {
+        assert_eq!(select(RELEASE, "//version").as_deref(), Some("1.2.3"));
+        assert_eq!(select(RELEASE, "/*/artifacts/artifact[2]/@type").as_deref(), Some("war"));
+        assert_eq!(select(RELEASE, "/ns:release//artifact/@type").as_deref(), Some("jar"));
+        assert_eq!(select(RELEASE, "/*/artifacts/artifact[3]/@type"), None);
+        assert_eq!(select("<a><b><c>1</c></b><c>2</c></a>", "//c").as_deref(), Some("1"));
+        assert_eq!(select("<a><b><c>1</c></b><c>2</c></a>", "/a/c").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn malformed_documents_are_rejected() {
+        for malformed in ["", "text", "<a>", "<a></b>", "<a><b></a>", "<a x=1/>", "<a x/>", "<a/><b/>", "<a><![CDATA[x</a>", "<a><!-- x</a>"] {
+            assert!(parse(malformed.as_bytes()).is_err(), "{:?} accepted", malformed);
+        }
+        assert_eq!(parse(b"<a></b>").unwrap_err(), "end tag b does not match a at offset 6");
+        assert!(parse(b"\xff<a/>").unwrap_err().contains("UTF-8"));
+        assert!(parse(b"<!DOCTYPE a [<!ENTITY x \"y\">]><a/>").unwrap_err().contains("internal DTD"));
+        let deep = format!("{}{}", "<a>".repeat(MAX_DEPTH + 1), "</a>".repeat(MAX_DEPTH + 1));
+        assert!(parse(deep.as_bytes()).unwrap_err().contains("nested too deeply"));
+    }
+
+    #[test]
+    fn invalid_paths_are_rejected() {
+        for invalid in ["release", "/", "/a/text()/b", "//@id", "/@id", "/a[0]", "/a[@id", "/a[last()]", "/a b"] {
+            assert!(XPath::parse(invalid).is_err(), "{:?} accepted", invalid);
+        }
+    }
+}